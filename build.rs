@@ -0,0 +1,24 @@
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Generates the messages and the server of the gRPC API from proto/sqliterg.proto, with the
+// vendored protoc, so that it needn't be installed.
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+    tonic_prost_build::configure()
+        .build_client(false)
+        .compile_protos(&["proto/sqliterg.proto"], &["proto"])?;
+    Ok(())
+}
@@ -0,0 +1,204 @@
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Logging of the requests to the databases (access log) and of the slow queries, on the
+// standard output, in the format chosen from the command line.
+
+use std::{sync::OnceLock, time::Duration};
+
+use actix_web::{body::BodySize, body::MessageBody, dev::ServiceResponse, web, HttpMessage};
+use chrono::{SecondsFormat, Utc};
+use clap::ValueEnum;
+use serde_json::{json, Value as JsonValue};
+
+use crate::req_res::ReqTransactionItem;
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum LogFormat {
+    /// One JSON object per line
+    Json,
+    /// Common Log Format, followed by the number of items and the duration in seconds
+    Common,
+}
+
+static FORMAT: OnceLock<Option<LogFormat>> = OnceLock::new();
+static TRUST_PROXY_HEADERS: OnceLock<bool> = OnceLock::new();
+
+/// Sets the format of the access log; with None, only the slow queries are logged, as text.
+/// The client address is the one of the connection, unless the headers of a proxy are trusted.
+pub fn init(format: Option<LogFormat>, trust_proxy_headers: bool) {
+    let _ = FORMAT.set(format);
+    let _ = TRUST_PROXY_HEADERS.set(trust_proxy_headers);
+}
+
+fn format() -> Option<LogFormat> {
+    FORMAT.get().copied().flatten()
+}
+
+/// What the handler of the requests knows about a request, to be logged when it's complete.
+/// It's stored in the extensions of the request; the user only once authenticated.
+#[derive(Clone)]
+pub struct AccessInfo {
+    pub user: Option<String>,
+    pub items: usize,
+}
+
+// query parameters whose values are secrets: the credentials of the change feed (with
+// INLINE auth) and the tokens of the web services
+const SECRET_PARAMS: [&str; 2] = ["password", "token"];
+
+/// The path and the query string of the request, with the values of the secrets redacted
+fn redacted_uri(path: &str, query: &str) -> String {
+    if query.is_empty() {
+        return path.to_string();
+    }
+    let query: Vec<String> = query
+        .split('&')
+        .map(|kv| {
+            // the name, decoded
+            let name = web::Query::<Vec<(String, String)>>::from_query(kv)
+                .ok()
+                .and_then(|q| q.into_inner().into_iter().next())
+                .map(|(k, _)| k);
+            match name {
+                Some(name) if SECRET_PARAMS.contains(&name.as_str()) => format!("{}=***", name),
+                _ => kv.to_string(),
+            }
+        })
+        .collect();
+    format!("{}?{}", path, query.join("&"))
+}
+
+/// Logs a request to a database, if the access log is enabled
+pub fn log_access<B: MessageBody>(db_name: &str, res: &ServiceResponse<B>, duration: Duration) {
+    let format = match format() {
+        Some(f) => f,
+        None => return,
+    };
+
+    let req = res.request();
+    let info = req.extensions().get::<AccessInfo>().cloned();
+    let user = info.as_ref().and_then(|i| i.user.to_owned());
+    let items = info.as_ref().map(|i| i.items);
+    let client_ip = match TRUST_PROXY_HEADERS.get() {
+        Some(true) => req
+            .connection_info()
+            .realip_remote_addr()
+            .map(str::to_string),
+        _ => req.peer_addr().map(|a| a.ip().to_string()),
+    };
+    let client_ip = client_ip.as_deref().unwrap_or("-");
+    let status = res.status().as_u16();
+    let bytes = match res.response().body().size() {
+        BodySize::Sized(n) => n,
+        _ => 0,
+    };
+    let now = Utc::now();
+
+    match format {
+        LogFormat::Json => println!(
+            "{}",
+            json!({
+                "ts": now.to_rfc3339_opts(SecondsFormat::Millis, true),
+                "db": db_name,
+                "method": req.method().as_str(),
+                "path": req.path(),
+                "user": user,
+                "clientIp": client_ip,
+                "items": items,
+                "status": status,
+                "bytes": bytes,
+                "durationMs": duration.as_secs_f64() * 1000.0,
+            })
+        ),
+        LogFormat::Common => println!(
+            "{} - {} [{}] \"{} {} {:?}\" {} {} {} {:.6}",
+            client_ip,
+            user.as_deref().unwrap_or("-"),
+            now.format("%d/%b/%Y:%H:%M:%S %z"),
+            req.method(),
+            redacted_uri(req.path(), req.query_string()),
+            req.version(),
+            status,
+            bytes,
+            items.map(|i| i.to_string()).unwrap_or("-".to_string()),
+            duration.as_secs_f64(),
+        ),
+    }
+}
+
+/// The shape of the parameters of an item, without their values
+fn params_shape(item: &ReqTransactionItem) -> JsonValue {
+    fn shape(values: &JsonValue) -> JsonValue {
+        match values {
+            JsonValue::Object(map) => json!({ "named": map.keys().collect::<Vec<_>>() }),
+            JsonValue::Array(arr) => json!({ "positional": arr.len() }),
+            _ => JsonValue::Null,
+        }
+    }
+
+    match (&item.values, &item.values_batch) {
+        (Some(values), _) => shape(values),
+        (None, Some(batch)) => json!({
+            "batch": batch.len(),
+            "each": batch.first().map(shape).unwrap_or(JsonValue::Null),
+        }),
+        (None, None) => JsonValue::Null,
+    }
+}
+
+/// Logs an item of a request that took more than the threshold of the database. For stored
+/// statements only their id is logged; the values of the parameters are never logged.
+pub fn log_slow_query(db_name: &str, idx: usize, item: &ReqTransactionItem, duration: Duration) {
+    let sql = item
+        .query
+        .as_deref()
+        .or(item.statement.as_deref())
+        .unwrap_or_default();
+    let (sql, stored_statement) = match sql.strip_prefix('^') {
+        Some(id) => (None, Some(id)),
+        None => (Some(sql), None),
+    };
+    let params = params_shape(item);
+    let now = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+    let duration_ms = duration.as_secs_f64() * 1000.0;
+
+    match format() {
+        Some(LogFormat::Json) => println!(
+            "{}",
+            json!({
+                "ts": now,
+                "slowQuery": true,
+                "db": db_name,
+                "idx": idx,
+                "sql": sql,
+                "storedStatement": stored_statement,
+                "params": params,
+                "durationMs": duration_ms,
+            })
+        ),
+        _ => println!(
+            "{} SLOW QUERY db={} idx={} durationMs={:.3} params={} {}",
+            now,
+            db_name,
+            idx,
+            duration_ms,
+            params,
+            match stored_statement {
+                Some(id) => format!("storedStatement={}", id),
+                None => format!("sql={:?}", sql.unwrap_or_default()),
+            }
+        ),
+    }
+}
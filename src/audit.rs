@@ -0,0 +1,288 @@
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Audit log of the statements that modified a database, for the requests and the macros.
+// An entry is written after the commit, as a JSON line in a file or as a row of a SQLite
+// database; both are rotated when they exceed a size.
+
+use std::{
+    collections::HashMap,
+    fs::{metadata, remove_file, rename, OpenOptions},
+    io::Write,
+    sync::{Mutex, OnceLock},
+};
+
+use chrono::{SecondsFormat, Utc};
+use eyre::Result;
+use rusqlite::{params, Connection};
+use serde_json::{json, Value as JsonValue};
+
+use crate::{commons::file_exists, db_config::Audit};
+
+pub const DEFAULT_MAX_SIZE_MB: u64 = 100;
+pub const DEFAULT_NUM_FILES: usize = 5;
+
+const REDACTED: &str = "***";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditedStatement {
+    pub idx: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sql: Option<String>,
+    #[serde(rename = "storedStatement")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stored_statement: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub values: Option<JsonValue>,
+    #[serde(rename = "valuesBatch")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub values_batch: Option<Vec<JsonValue>>,
+    #[serde(rename = "rowsUpdated")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rows_updated: Option<usize>,
+    #[serde(rename = "rowsUpdatedBatch")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rows_updated_batch: Option<Vec<usize>>,
+}
+
+impl AuditedStatement {
+    /// `text` is the SQL as given, or a reference to a stored statement ("^id")
+    pub fn new(idx: usize, text: &str) -> AuditedStatement {
+        let (sql, stored_statement) = match text.strip_prefix('^') {
+            Some(id) => (None, Some(id.to_string())),
+            None => (Some(text.to_string()), None),
+        };
+        AuditedStatement {
+            idx,
+            sql,
+            stored_statement,
+            values: None,
+            values_batch: None,
+            rows_updated: None,
+            rows_updated_batch: None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    ts: String,
+    db: &'a str,
+    user: Option<&'a str>,
+    source: &'a str,
+    statements: &'a [AuditedStatement],
+}
+
+enum Sink {
+    File(String),
+    // the connection is closed while rotating
+    Db(String, Option<Connection>),
+}
+
+struct AuditLog {
+    sink: Sink,
+    max_size: u64,
+    num_files: usize,
+    redact_params: Vec<String>,
+    redact_all: bool,
+}
+
+static AUDITS: OnceLock<Mutex<HashMap<String, AuditLog>>> = OnceLock::new();
+
+fn audits() -> &'static Mutex<HashMap<String, AuditLog>> {
+    AUDITS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+fn open_db(path: &str) -> Result<Connection> {
+    let conn = Connection::open(path)?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS AUDIT_LOG (
+            TS TEXT NOT NULL,
+            DB TEXT NOT NULL,
+            USER TEXT,
+            SOURCE TEXT NOT NULL,
+            STATEMENTS TEXT NOT NULL
+        )",
+        [],
+    )?;
+    Ok(conn)
+}
+
+impl AuditLog {
+    fn path(&self) -> &str {
+        match &self.sink {
+            Sink::File(path) => path,
+            Sink::Db(path, _) => path,
+        }
+    }
+
+    fn redact(&self, values: &JsonValue) -> JsonValue {
+        match values {
+            JsonValue::Object(map) => JsonValue::Object(
+                map.iter()
+                    .map(|(k, v)| {
+                        let redacted = self.redact_all
+                            || self
+                                .redact_params
+                                .iter()
+                                .any(|p| p.trim_start_matches(':').eq_ignore_ascii_case(k));
+                        (
+                            k.to_owned(),
+                            if redacted {
+                                json!(REDACTED)
+                            } else {
+                                v.to_owned()
+                            },
+                        )
+                    })
+                    .collect(),
+            ),
+            // positional parameters have no name
+            JsonValue::Array(arr) if self.redact_all => {
+                JsonValue::Array(arr.iter().map(|_| json!(REDACTED)).collect())
+            }
+            other => other.to_owned(),
+        }
+    }
+
+    /// Moves the log to "<path>.1", shifting the previous ones and dropping the oldest
+    fn rotate_if_needed(&mut self) -> Result<()> {
+        let size = metadata(self.path()).map(|m| m.len()).unwrap_or(0);
+        if size < self.max_size {
+            return Ok(());
+        }
+
+        if let Sink::Db(_, conn) = &mut self.sink {
+            if let Some(c) = conn.take() {
+                c.close().map_err(|(_, e)| e)?;
+            }
+        }
+
+        let path = self.path().to_string();
+        let _ = remove_file(format!("{}.{}", path, self.num_files));
+        for i in (1..self.num_files).rev() {
+            let from = format!("{}.{}", path, i);
+            if file_exists(&from) {
+                rename(&from, format!("{}.{}", path, i + 1))?;
+            }
+        }
+        if self.num_files > 0 {
+            rename(&path, format!("{}.1", path))?;
+        } else {
+            remove_file(&path)?;
+        }
+
+        if let Sink::Db(path, conn) = &mut self.sink {
+            *conn = Some(open_db(path)?);
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, entry: &Entry) -> Result<()> {
+        self.rotate_if_needed()?;
+        match &mut self.sink {
+            Sink::File(path) => {
+                let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+                writeln!(file, "{}", serde_json::to_string(entry)?)?;
+            }
+            Sink::Db(path, conn) => {
+                if conn.is_none() {
+                    *conn = Some(open_db(path)?);
+                }
+                conn.as_ref().unwrap().execute(
+                    "INSERT INTO AUDIT_LOG (TS, DB, USER, SOURCE, STATEMENTS) VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![
+                        entry.ts,
+                        entry.db,
+                        entry.user,
+                        entry.source,
+                        serde_json::to_string(entry.statements)?
+                    ],
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sets up the audit log of a database; the paths were already resolved and checked
+pub fn init(db_name: &str, audit: &Audit) -> Result<()> {
+    let sink = match (&audit.file, &audit.db) {
+        (Some(file), None) => {
+            OpenOptions::new().create(true).append(true).open(file)?;
+            Sink::File(file.to_owned())
+        }
+        (None, Some(db)) => Sink::Db(db.to_owned(), Some(open_db(db)?)),
+        _ => return Err(eyre!("exactly one of file and db must be specified")),
+    };
+    audits().lock().unwrap().insert(
+        db_name.to_string(),
+        AuditLog {
+            sink,
+            max_size: audit.max_size_mb.unwrap_or(DEFAULT_MAX_SIZE_MB) * 1024 * 1024,
+            num_files: audit.num_files.unwrap_or(DEFAULT_NUM_FILES),
+            redact_params: audit.redact_params.to_owned(),
+            redact_all: audit.redact_all,
+        },
+    );
+    Ok(())
+}
+
+pub fn is_enabled(db_name: &str) -> bool {
+    audits().lock().unwrap().contains_key(db_name)
+}
+
+/// Whether a statement may modify the database
+pub fn is_write(conn: &Connection, sql: &str) -> bool {
+    conn.prepare(sql)
+        .map(|stmt| !stmt.readonly())
+        .unwrap_or(true)
+}
+
+/// Writes an entry for the statements of a committed transaction (or of a macro), redacting
+/// the values as configured. `source` is "request", "macro:<id>", "pgwire", "grpc", "rest" or
+/// "graphql". Does nothing if the database has no audit log, or if there are no statements.
+pub fn record(db_name: &str, user: Option<&str>, source: &str, statements: Vec<AuditedStatement>) {
+    if statements.is_empty() {
+        return;
+    }
+
+    let mut audits = audits().lock().unwrap();
+    let log = match audits.get_mut(db_name) {
+        Some(log) => log,
+        None => return,
+    };
+
+    let statements: Vec<AuditedStatement> = statements
+        .into_iter()
+        .map(|mut s| {
+            s.values = s.values.as_ref().map(|v| log.redact(v));
+            s.values_batch = s
+                .values_batch
+                .as_ref()
+                .map(|vb| vb.iter().map(|v| log.redact(v)).collect());
+            s
+        })
+        .collect();
+    let entry = Entry {
+        ts: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        db: db_name,
+        user,
+        source,
+        statements: &statements,
+    };
+    if let Err(e) = log.write(&entry) {
+        eprintln!("Writing the audit log of db '{}': {}", db_name, e);
+    }
+}
@@ -14,6 +14,7 @@
 
 use std::ops::DerefMut;
 
+use actix_web::{http::header, web, HttpRequest};
 use actix_web_httpauth::headers::authorization::{Authorization, Basic};
 use rusqlite::named_params;
 
@@ -21,8 +22,8 @@ use crate::{
     commons::{equal_case_insensitive, sha256},
     db_config::Auth,
     db_config::{AuthMode, Credentials},
-    req_res::ReqCredentials,
-    MUTEXES,
+    logic::{busy_msg, lock_db},
+    req_res::{ReqCredentials, Token},
 };
 
 /// Given the provided password and the expected ones (unhashed and hashed), returns if
@@ -40,6 +41,21 @@ pub fn process_creds(
     }
 }
 
+/// Checks the token of a request to a server-wide endpoint; it can be passed as the "token"
+/// query parameter or as a bearer token, in the Authorization header.
+pub fn process_req_token(req: &HttpRequest, expected: &str) -> bool {
+    let by_query = web::Query::<Token>::from_query(req.query_string())
+        .ok()
+        .and_then(|t| t.into_inner().token);
+    let by_header = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .map(str::to_string);
+    process_creds(&by_query.or(by_header), &Some(expected.to_owned()), &None)
+}
+
 fn auth_by_credentials(user: String, password: String, creds: &Vec<Credentials>) -> bool {
     for c in creds {
         // TODO hash table lookup? I don't expect the credentials list to grow very much, so it may be overkill (and use memory)
@@ -50,9 +66,13 @@ fn auth_by_credentials(user: String, password: String, creds: &Vec<Credentials>)
     false
 }
 
-fn auth_by_query(user: String, password: String, query: &str, db_name: &str) -> bool {
-    let db_lock = MUTEXES.get().unwrap().get(db_name).unwrap();
-    let mut db_lock_guard = db_lock.lock().unwrap();
+fn auth_by_query(
+    user: String,
+    password: String,
+    query: &str,
+    db_name: &str,
+) -> Result<bool, String> {
+    let mut db_lock_guard = lock_db(db_name).ok_or_else(|| busy_msg(db_name))?;
     let conn = db_lock_guard.deref_mut();
 
     let res = conn.query_row(
@@ -60,34 +80,70 @@ fn auth_by_query(user: String, password: String, query: &str, db_name: &str) ->
         named_params! {":user": user, ":password":password},
         |_| Ok(()),
     );
-    res.is_ok()
+    Ok(res.is_ok())
 }
 
+/// Returns the authenticated user, or None if the authentication failed; Err, with the
+/// message, if it can't be checked because the database is locked (see lock_db).
 pub fn process_auth(
     auth_config: &Auth,
     auth_inline: &Option<ReqCredentials>,
     auth_header: &Option<Authorization<Basic>>,
     db_name: &str,
-) -> bool {
+) -> Result<Option<String>, String> {
     let (user, password) = match auth_config.mode {
         AuthMode::HttpBasic => match auth_header {
             Some(auth_header) => (
                 auth_header.as_ref().user_id().to_string(),
                 auth_header.as_ref().password().unwrap().to_string(),
             ),
-            None => return false,
+            None => return Ok(None),
         },
         AuthMode::Inline => match auth_inline {
             Some(auth_inline) => (auth_inline.user.to_owned(), auth_inline.password.to_owned()),
-            None => return false,
+            None => return Ok(None),
         },
     };
 
+    Ok(check_credentials(auth_config, &user, &password, db_name)?.then_some(user))
+}
+
+/// process_auth, for the handlers: the authentication by query locks the database, so it's
+/// performed in a thread that can be blocked
+pub async fn process_auth_async(
+    auth_config: &Auth,
+    auth_inline: &Option<ReqCredentials>,
+    auth_header: &Option<Authorization<Basic>>,
+    db_name: &str,
+) -> Result<Option<String>, String> {
+    if auth_config.by_query.is_none() {
+        return process_auth(auth_config, auth_inline, auth_header, db_name);
+    }
+    let auth_config = auth_config.to_owned();
+    let auth_inline = auth_inline.to_owned();
+    let auth_header = auth_header.to_owned();
+    let db_name = db_name.to_string();
+    web::block(move || process_auth(&auth_config, &auth_inline, &auth_header, &db_name))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+/// Checks a user and a password, by the credentials or by the query of the configuration
+pub fn check_credentials(
+    auth_config: &Auth,
+    user: &str,
+    password: &str,
+    db_name: &str,
+) -> Result<bool, String> {
     match &auth_config.by_credentials {
-        Some(creds) => auth_by_credentials(user, password, creds),
+        Some(creds) => Ok(auth_by_credentials(
+            user.to_string(),
+            password.to_string(),
+            creds,
+        )),
         None => match &auth_config.by_query {
-            Some(query) => auth_by_query(user, password, query, db_name),
-            None => false,
+            Some(query) => auth_by_query(user.to_string(), password.to_string(), query, db_name),
+            None => Ok(false),
         },
     }
 }
@@ -12,118 +12,471 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{ops::DerefMut, path::Path as SysPath, time::Duration};
-
-use actix_web::{
-    rt::{
-        spawn,
-        time::{interval_at, sleep, Instant},
-    },
-    web, Responder,
+use std::{
+    collections::HashMap,
+    fs::remove_file,
+    ops::DerefMut,
+    path::Path,
+    sync::{Mutex, OnceLock},
+    thread,
+    time::Duration,
+};
+
+use actix_web::{rt::time::sleep, web, Either, HttpRequest};
+use chrono::{DateTime, Utc};
+use eyre::Result;
+use rusqlite::{
+    backup::{Backup as OnlineBackup, StepResult},
+    Connection, OpenFlags,
 };
-use rusqlite::Connection;
 
 use crate::{
     auth::process_creds,
-    commons::{abort, delete_old_files, file_exists},
-    db_config::Backup,
+    backup_codec::{encode, is_plain, PrivateDir},
+    backup_files::{add_to_manifest, download_manifest, gen_bkp_file, upload_manifest},
+    commons::{abort, file_exists},
+    db_config::{Backup, BackupTarget, Macro, S3Target},
+    history::{runs, track, track_with_parent, JobId, Trigger},
+    logic::busy_msg,
+    macros::exec_macros_by_id,
     main_config::Db,
-    req_res::{Response, Token},
+    metrics::record_auth_failure,
+    pgwire::in_transaction,
+    req_res::{BackupProgress, BackupProgressResponse, Response, RunsResponse, Token},
+    retention::{apply_remote_retention, apply_retention},
+    s3::S3Client,
+    scheduler::{is_periodic, spawn_scheduled, Schedule},
+    telemetry::request_context,
     MUTEXES,
 };
 
-fn gen_bkp_file(directory: &str, filepath: &str) -> String {
-    let path = SysPath::new(directory);
-    let fpath = SysPath::new(filepath);
-    let base_name = fpath.file_stem().unwrap().to_str().unwrap();
-    let extension = fpath.extension();
-    let intermission = crate::commons::now();
+pub const DEFAULT_PAGES_PER_STEP: i32 = 100;
+// between steps, to let the requests waiting for the lock go on
+const STEP_PAUSE: Duration = Duration::from_millis(1);
+const BUSY_PAUSE: Duration = Duration::from_millis(100);
+// a database written more often than it can be copied would restart the copy forever
+const MAX_RESTARTS: usize = 10;
 
-    let new_file_name = match extension {
-        Some(e) => format!("{}_{}.{}", base_name, intermission, e.to_str().unwrap()),
-        None => format!("{}_{}", base_name, intermission),
-    };
-    let new_file_path = path.join(new_file_name);
+/// Access to the live connection of a database: direct at startup and shutdown, when
+/// nothing else can use it, or through its mutex while the server is running.
+pub enum LiveConn<'a> {
+    Direct(&'a mut Connection),
+    Locked(&'a Mutex<Connection>),
+}
+
+impl LiveConn<'_> {
+    fn with<T>(&mut self, f: impl FnOnce(&mut Connection) -> T) -> T {
+        match self {
+            LiveConn::Direct(conn) => f(conn),
+            LiveConn::Locked(mutex) => f(mutex.lock().unwrap().deref_mut()),
+        }
+    }
+}
+
+static PROGRESS: OnceLock<Mutex<HashMap<String, BackupProgress>>> = OnceLock::new();
+
+fn progress() -> &'static Mutex<HashMap<String, BackupProgress>> {
+    PROGRESS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Marks a backup of the database as in progress, for as long as it's alive
+struct InProgress(String);
+
+impl InProgress {
+    fn start(db_name: &str) -> Option<InProgress> {
+        let mut progress = progress().lock().unwrap();
+        if progress.contains_key(db_name) {
+            return None;
+        }
+        progress.insert(
+            db_name.to_string(),
+            BackupProgress {
+                started: Utc::now(),
+                page_count: 0,
+                remaining: 0,
+            },
+        );
+        Some(InProgress(db_name.to_string()))
+    }
+
+    fn update(&self, page_count: i32, remaining: i32) {
+        if let Some(p) = progress().lock().unwrap().get_mut(&self.0) {
+            p.page_count = page_count;
+            p.remaining = remaining;
+        }
+    }
+}
 
-    new_file_path.into_os_string().into_string().unwrap()
+impl Drop for InProgress {
+    fn drop(&mut self) {
+        progress().lock().unwrap().remove(&self.0);
+    }
 }
 
-fn do_backup(bkp_dir: &str, num_files: usize, db_path: &str, conn: &Connection) -> Response {
-    if !file_exists(bkp_dir) {
+/// Copies the live database into a file with the online backup API. File-based databases
+/// are read from a dedicated connection, a few pages at a time, and the live connection
+/// is locked only for the duration of each step; if the database is written in between,
+/// SQLite restarts the copy. After MAX_RESTARTS, the rest is copied in a single step,
+/// with the live connection locked. In-memory databases can only be read from the live
+/// connection, so they are copied in a single step.
+fn copy_db(
+    bkp: &Backup,
+    dst_file: &str,
+    live: &mut LiveConn,
+    in_progress: &InProgress,
+) -> Result<()> {
+    let mut dst = Connection::open(dst_file)?;
+    let src_path = live.with(|conn| conn.path().filter(|p| !p.is_empty()).map(str::to_string));
+
+    match src_path {
+        None => live.with(|conn| -> Result<()> {
+            let backup = OnlineBackup::new(conn, &mut dst)?;
+            backup.step(-1)?;
+            let p = backup.progress();
+            in_progress.update(p.pagecount, p.remaining);
+            Ok(())
+        })?,
+        Some(src_path) => {
+            let src = Connection::open_with_flags(src_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+            let backup = OnlineBackup::new(&src, &mut dst)?;
+            let pages_per_step = bkp.pages_per_step.unwrap_or(DEFAULT_PAGES_PER_STEP);
+            let mut restarts = 0;
+            let mut remaining = None;
+            loop {
+                let pages = if restarts < MAX_RESTARTS {
+                    pages_per_step
+                } else {
+                    -1
+                };
+                let res = live.with(|_| backup.step(pages))?;
+                let p = backup.progress();
+                in_progress.update(p.pagecount, p.remaining);
+                // more pages to copy than before: it restarted
+                if remaining.is_some_and(|r| p.remaining > r) {
+                    restarts += 1;
+                }
+                remaining = Some(p.remaining);
+                match res {
+                    StepResult::Done => break,
+                    StepResult::More => thread::sleep(STEP_PAUSE),
+                    _ => thread::sleep(BUSY_PAUSE), // busy or locked
+                }
+            }
+        }
+    }
+
+    // the copy inherits the journal mode of the database; it's reset so that the
+    // backup is a single, self-contained file
+    dst.query_row("PRAGMA journal_mode = DELETE", [], |_| Ok(()))?;
+    Ok(())
+}
+
+fn do_backup(bkp: &Backup, db_name: &str, db_path: &str, live: &mut LiveConn) -> Response {
+    let bkp_dir = &bkp.backup_dir;
+    if !bkp_dir.is_empty() && !file_exists(bkp_dir) {
         return Response::new_err(404, -1, format!("Backup dir '{}' not found", bkp_dir));
     }
 
-    let file = gen_bkp_file(bkp_dir, db_path);
+    let in_progress = match InProgress::start(db_name) {
+        Some(ip) => ip,
+        None => {
+            return Response::new_err(
+                409,
+                -1,
+                format!("A backup of database '{}' is already in progress", db_name),
+            )
+        }
+    };
+
+    // for an object storage, the file is prepared in a private directory, then uploaded;
+    // the directory is removed when dropped
+    let staging = match &bkp.target {
+        Some(BackupTarget::S3(_)) => match PrivateDir::new_in(bkp_dir) {
+            Ok(dir) => Some(dir),
+            Err(e) => return Response::new_err(500, -1, e.to_string()),
+        },
+        None => None,
+    };
+    let dir = staging
+        .as_ref()
+        .map(|dir| dir.path(""))
+        .unwrap_or(bkp_dir.to_owned());
+
+    let mut created = Utc::now();
+    let mut file = gen_bkp_file(bkp, &dir, db_path, &created);
     if file_exists(&file) {
-        Response::new_err(409, -1, format!("File '{}' already exists", file))
+        // only one backup at a time, but two can happen in the same millisecond
+        thread::sleep(Duration::from_millis(1));
+        created = Utc::now();
+        file = gen_bkp_file(bkp, &dir, db_path, &created);
+        if file_exists(&file) {
+            return Response::new_err(409, -1, format!("File '{}' already exists", file));
+        }
+    }
+
+    // if compressed/encrypted, the plain copy is made in a private temp dir, then encoded
+    // into the backup dir; the temp dir is removed when dropped
+    let mut res = if is_plain(bkp) {
+        copy_db(bkp, &file, live, &in_progress).map_err(|e| e.to_string())
     } else {
-        match conn.execute("VACUUM INTO ?1", [&file]) {
-            Ok(_) => match delete_old_files(&file, num_files) {
-                Ok(_) => Response::new_ok(vec![]),
-                Err(e) => Response::new_err(
-                    500,
-                    -1,
-                    format!("Database backed up but error in deleting old files: {}", e),
+        PrivateDir::new()
+            .and_then(|dir| {
+                let copy_file = dir.new_file("copy.db")?;
+                copy_db(bkp, &copy_file, live, &in_progress)?;
+                Ok((dir, copy_file))
+            })
+            .map_err(|e| e.to_string())
+            .and_then(|(_dir, copy_file)| {
+                encode(&copy_file, &file, bkp).map_err(|e| format!("Encoding backup file: {}", e))
+            })
+    };
+    if res.is_err() {
+        let _ = remove_file(&file);
+    }
+
+    if let Some(BackupTarget::S3(s3)) = &bkp.target {
+        return match res {
+            Ok(_) => upload_backup(bkp, s3, db_path, &file, &created),
+            Err(e) => Response::new_err(500, -1, e),
+        };
+    }
+
+    if res.is_ok() {
+        res = add_to_manifest(db_path, &file, &created).map_err(|e| {
+            format!(
+                "Database backed up but error in writing the manifest: {}",
+                e
+            )
+        });
+    }
+
+    match res {
+        Ok(_) => match apply_retention(bkp, db_path) {
+            Ok(_) => Response::new_ok(vec![]),
+            Err(e) => Response::new_err(
+                500,
+                -1,
+                format!("Database backed up but error in deleting old files: {}", e),
+            ),
+        },
+        Err(e) => Response::new_err(500, -1, e),
+    }
+}
+
+/// Uploads the backup file to the object storage and records it in the manifest there,
+/// then deletes the old backups from the storage. It waits for the network, so (as all the
+/// backups while the server is running) it must be executed in a thread that can be blocked.
+fn upload_backup(
+    bkp: &Backup,
+    s3: &S3Target,
+    db_path: &str,
+    file: &str,
+    created: &DateTime<Utc>,
+) -> Response {
+    let path = Path::new(file);
+    let client = match S3Client::new(s3).and_then(|client| {
+        let name = path.file_name().unwrap().to_str().unwrap();
+        client.upload(file, &format!("{}{}", s3.prefix, name))?;
+        Ok(client)
+    }) {
+        Ok(client) => client,
+        Err(e) => return Response::new_err(500, -1, format!("Uploading backup file: {}", e)),
+    };
+
+    // the manifest is updated in the staging directory, next to the file
+    let dir = path.parent().unwrap();
+    let res = download_manifest(&client, &s3.prefix, dir, db_path)
+        .and_then(|_| add_to_manifest(db_path, file, created))
+        .and_then(|_| upload_manifest(&client, &s3.prefix, dir, db_path));
+    if let Err(e) = res {
+        return Response::new_err(
+            500,
+            -1,
+            format!(
+                "Database backed up but error in writing the manifest: {}",
+                e
+            ),
+        );
+    }
+
+    match apply_remote_retention(bkp, db_path, &client, &s3.prefix) {
+        Ok(_) => Response::new_ok(vec![]),
+        Err(e) => Response::new_err(
+            500,
+            -1,
+            format!("Database backed up but error in deleting old files: {}", e),
+        ),
+    }
+}
+
+/// Performs the backup, preceded by the preBackup macros and followed by the postBackup
+/// ones. If a preBackup macro fails the backup is not performed; the postBackup macros
+/// are executed only if the backup succeeded.
+fn do_backup_with_hooks(
+    bkp: &Backup,
+    macros: &HashMap<String, Macro>,
+    db_name: &str,
+    db_path: &str,
+    live: &mut LiveConn,
+) -> Response {
+    if let Some(pre) = &bkp.pre_backup {
+        let res =
+            live.with(|conn| exec_macros_by_id(pre, macros, db_name, Trigger::BackupHook, conn));
+        if !res.success {
+            return Response::new_err(
+                res.status_code,
+                -1,
+                format!(
+                    "Backup not performed, preBackup failed: {}",
+                    res.message.unwrap()
                 ),
-            },
-            Err(e) => Response::new_err(500, -1, e.to_string()),
+            );
+        }
+    }
+
+    let res = do_backup(bkp, db_name, db_path, live);
+
+    if let (true, Some(post)) = (res.success, &bkp.post_backup) {
+        let res =
+            live.with(|conn| exec_macros_by_id(post, macros, db_name, Trigger::BackupHook, conn));
+        if !res.success {
+            return Response::new_err(
+                res.status_code,
+                -1,
+                format!(
+                    "Database backed up but postBackup failed: {}",
+                    res.message.unwrap()
+                ),
+            );
         }
     }
+
+    res
 }
 
-pub async fn handler(
-    db_conf: web::Data<Db>,
-    db_name: web::Data<String>,
-    token: web::Query<Token>,
-) -> impl Responder {
-    let db_name = db_name.to_string();
+/// Checks that the database has a backup.execution.webService node, and that the token
+/// matches it.
+pub async fn check_backup_ws<'a>(
+    db_conf: &'a Db,
+    db_name: &str,
+    token: &Option<String>,
+) -> Result<&'a Backup, Response> {
     match &db_conf.conf.backup {
         Some(bkp) => match &bkp.execution.web_service {
             Some(bkp_ws) => {
-                if !process_creds(&token.token, &bkp_ws.auth_token, &bkp_ws.hashed_auth_token) {
+                if !process_creds(token, &bkp_ws.auth_token, &bkp_ws.hashed_auth_token) {
+                    record_auth_failure(db_name, "token");
                     sleep(Duration::from_millis(1000)).await;
 
-                    return Response::new_err(
+                    return Err(Response::new_err(
                         bkp_ws.auth_error_code,
                         -1,
                         format!("In database '{}', backup: token mismatch", db_name),
-                    );
+                    ));
                 }
-
-                let db_lock = MUTEXES.get().unwrap().get(&db_name).unwrap();
-                let mut db_lock_guard = db_lock.lock().unwrap();
-                let conn = db_lock_guard.deref_mut();
-
-                do_backup(&bkp.backup_dir, bkp.num_files, &db_conf.path, conn)
+                Ok(bkp)
             }
-            None => Response::new_err(
+            None => Err(Response::new_err(
                 404,
                 -1,
                 format!(
                     "Database '{}' doesn't have a backup.execution.webService node",
                     db_name
                 ),
-            ),
+            )),
         },
-        None => Response::new_err(
+        None => Err(Response::new_err(
             404,
             -1,
             format!("Database '{}' doesn't have a backup node", db_name),
-        ),
+        )),
     }
 }
 
+pub async fn handler(
+    req: HttpRequest,
+    db_conf: web::Data<Db>,
+    db_name: web::Data<String>,
+    token: web::Query<Token>,
+) -> Response {
+    let db_name = db_name.to_string();
+    let bkp = match check_backup_ws(&db_conf, &db_name, &token.token).await {
+        Ok(bkp) => bkp.to_owned(),
+        Err(res) => return res,
+    };
+
+    if in_transaction(&db_name) {
+        return Response::new_err(503, -1, busy_msg(&db_name));
+    }
+
+    // the backup takes the lock only for short steps, so it's performed in a thread
+    // that can be blocked
+    let macros = db_conf.macros.to_owned();
+    let db_path = db_conf.backup_base_path(&db_name);
+    let db_lock = MUTEXES.get().unwrap().get(&db_name).unwrap();
+    let span_ctx = request_context(&req);
+    web::block(move || {
+        let job = JobId::for_backup(&db_name);
+        track_with_parent(&job, Trigger::WebService, span_ctx, || {
+            do_backup_with_hooks(
+                &bkp,
+                &macros,
+                &db_name,
+                &db_path,
+                &mut LiveConn::Locked(db_lock),
+            )
+        })
+    })
+    .await
+    .unwrap_or_else(|e| Response::new_err(500, -1, e.to_string()))
+}
+
+/// Lists the last runs of the backup. Uses the token of the webService node, that must be present.
+pub async fn runs_handler(
+    db_conf: web::Data<Db>,
+    db_name: web::Data<String>,
+    token: web::Query<Token>,
+) -> Either<Response, web::Json<RunsResponse>> {
+    if let Err(res) = check_backup_ws(&db_conf, &db_name, &token.token).await {
+        return Either::Left(res);
+    }
+
+    let job = JobId::for_backup(&db_name);
+    Either::Right(web::Json(RunsResponse { runs: runs(&job) }))
+}
+
+/// Tells whether a backup is in progress and, if so, how many pages are left to copy.
+/// Uses the token of the webService node, that must be present.
+pub async fn progress_handler(
+    db_conf: web::Data<Db>,
+    db_name: web::Data<String>,
+    token: web::Query<Token>,
+) -> Either<Response, web::Json<BackupProgressResponse>> {
+    if let Err(res) = check_backup_ws(&db_conf, &db_name, &token.token).await {
+        return Either::Left(res);
+    }
+
+    let progress = progress().lock().unwrap().get(db_name.as_str()).cloned();
+    Either::Right(web::Json(BackupProgressResponse {
+        in_progress: progress.is_some(),
+        progress,
+    }))
+}
+
 pub fn bootstrap_backup(
     is_new_db: bool,
     bkp: &Backup,
+    macros: &HashMap<String, Macro>,
     db_name: &String,
     db_path: &str,
-    conn: &Connection,
+    conn: &mut Connection,
 ) {
     let bkp = bkp.to_owned();
     let bex = &bkp.execution;
     if bex.on_startup || (is_new_db && bex.on_create) {
-        let res = do_backup(&bkp.backup_dir, bkp.num_files, db_path, conn);
+        let res = track(&JobId::for_backup(db_name), Trigger::Startup, || {
+            do_backup_with_hooks(&bkp, macros, db_name, db_path, &mut LiveConn::Direct(conn))
+        });
         if !res.success {
             abort(format!(
                 "Backup of database '{}': {}",
@@ -139,48 +492,71 @@ pub fn bootstrap_backup(
     if bex.on_startup {
         println!("    - performed on server startup");
     }
-    if bex.period > 0 {
+    if is_periodic(bex) {
         println!("    - performed periodically");
     }
+    if bex.on_shutdown {
+        println!("    - performed on server shutdown");
+    }
     if bex.web_service.is_some() {
         println!("    - callable via web service");
     }
 }
 
-pub fn periodic_backup(bkp: &Backup, db_name: String, db_path: String) {
+pub fn periodic_backup(
+    bkp: &Backup,
+    macros: HashMap<String, Macro>,
+    db_name: String,
+    db_path: String,
+) {
     let bkp = bkp.to_owned();
-    let period = bkp.execution.period;
-    let bkp_dir = bkp.backup_dir;
-    let num_files = bkp.num_files;
-    if period > 0 {
-        let period = period as u64 * 60;
-        spawn(async move {
-            let p = Duration::from_secs(period);
-            let first_start = Instant::now().checked_add(p).unwrap();
-            let mut interval = interval_at(first_start, p);
-
-            loop {
-                interval.tick().await;
-
-                if !file_exists(&bkp_dir) {
-                    eprintln!("Backup dir '{}' not found", bkp_dir);
-                    return;
-                }
+    // the schedule was already validated while loading the config
+    if let Ok(Some(schedule)) = Schedule::from_execution(&bkp.execution) {
+        let job = JobId::for_backup(&db_name);
+        let missed_runs = bkp.execution.missed_runs.to_owned();
+        spawn_scheduled(schedule, missed_runs, job, move || {
+            let db_lock = MUTEXES.get().unwrap().get(&db_name).unwrap();
 
-                let file = gen_bkp_file(&bkp_dir, &db_path);
-                if file_exists(&file) {
-                    eprintln!("File '{}' already exists", file);
-                } else {
-                    let db_lock = MUTEXES.get().unwrap().get(&db_name).unwrap();
-                    let mut db_lock_guard = db_lock.lock().unwrap();
-                    let conn = db_lock_guard.deref_mut();
-
-                    let res = do_backup(&bkp_dir, num_files, &db_path, conn);
-                    if !res.success {
-                        eprintln!("Backing up '{}': {}", db_name, res.message.unwrap())
-                    }
-                }
+            let res = do_backup_with_hooks(
+                &bkp,
+                &macros,
+                &db_name,
+                &db_path,
+                &mut LiveConn::Locked(db_lock),
+            );
+            if !res.success {
+                eprintln!(
+                    "Backing up '{}': {}",
+                    db_name,
+                    res.message.as_deref().unwrap_or("unknown error")
+                )
             }
+            res
         });
     }
 }
+
+pub fn shutdown_backup(db_conf: &Db, db_name: &str, conn: &mut Connection) {
+    if let Some(bkp) = &db_conf.conf.backup {
+        if bkp.execution.on_shutdown {
+            let res = track(&JobId::for_backup(db_name), Trigger::Shutdown, || {
+                do_backup_with_hooks(
+                    bkp,
+                    &db_conf.macros,
+                    db_name,
+                    &db_conf.backup_base_path(db_name),
+                    &mut LiveConn::Direct(conn),
+                )
+            });
+            if res.success {
+                println!("Database '{}' backed up", db_name);
+            } else {
+                eprintln!(
+                    "Backing up '{}': {}",
+                    db_name,
+                    res.message.as_deref().unwrap_or("unknown error")
+                )
+            }
+        }
+    }
+}
@@ -0,0 +1,393 @@
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Compression and encryption of the backup files.
+//
+// Encryption is AES-256-GCM, applied in chunks (STREAM construction) so that the
+// file doesn't have to fit in memory. The file is:
+//   - a magic header (8 bytes) and a random nonce prefix (7 bytes);
+//   - one or more chunks, each of (up to) CHUNK_SIZE bytes of plaintext plus a 16 bytes
+//     tag. The nonce of each chunk is: nonce prefix + counter (4 bytes, BE) + a byte
+//     that is 1 for the last chunk, 0 otherwise.
+
+use std::{
+    env,
+    fs::{read_to_string, remove_dir_all, DirBuilder, File, OpenOptions},
+    io::{copy, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+
+use eyre::Result;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression as GzLevel};
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM},
+    rand::{SecureRandom, SystemRandom},
+};
+
+use crate::{
+    commons::resolve_tilde,
+    db_config::{Backup, Compression},
+};
+
+const MAGIC: &[u8; 8] = b"SQRGAES1";
+const NONCE_PREFIX_SIZE: usize = 7;
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+
+/// Reads an AES-256 key from a file, as 64 hex characters
+pub fn read_key(key_file: &str) -> Result<[u8; 32]> {
+    let key_file = resolve_tilde(&key_file.to_string());
+    let hex_key =
+        read_to_string(&key_file).map_err(|e| eyre!("reading key file '{}': {}", key_file, e))?;
+    let bytes = hex::decode(hex_key.trim())
+        .map_err(|_| eyre!("key file '{}' must contain 64 hex characters", key_file))?;
+    bytes
+        .try_into()
+        .map_err(|_| eyre!("key file '{}' must contain 64 hex characters", key_file))
+}
+
+static PRIVATE_DIRS: AtomicU64 = AtomicU64::new(0);
+
+/// A directory in the system's temp dir, that only the current user can access; it's
+/// removed, with its content, when dropped. The plain copy of a database that is then
+/// compressed/encrypted is staged here, so that it's never readable in the backup dir and
+/// isn't left there if the process crashes; and so are the files that are uploaded to, or
+/// downloaded from, an object storage.
+pub struct PrivateDir(PathBuf);
+
+impl PrivateDir {
+    pub fn new() -> Result<PrivateDir> {
+        PrivateDir::new_in("")
+    }
+
+    /// In the given directory, or in the system's temp dir if empty
+    pub fn new_in(parent: &str) -> Result<PrivateDir> {
+        let parent = match parent {
+            "" => env::temp_dir(),
+            p => PathBuf::from(p),
+        };
+        let path = parent.join(format!(
+            "sqliterg-{}-{}",
+            process::id(),
+            PRIVATE_DIRS.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut builder = DirBuilder::new();
+        #[cfg(unix)]
+        builder.mode(0o700);
+        // fails if it exists already, e.g. if someone else created it
+        builder
+            .create(&path)
+            .map_err(|e| eyre!("creating temp dir '{}': {}", path.display(), e))?;
+        Ok(PrivateDir(path))
+    }
+
+    /// Creates an empty file in the directory, only readable and writable by the current
+    /// user, and returns its path
+    pub fn new_file(&self, name: &str) -> Result<String> {
+        let path = self.0.join(name);
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        options.open(&path)?;
+        Ok(path.to_str().unwrap().to_string())
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.0
+    }
+
+    /// The path of a file in the directory, that isn't created
+    pub fn path(&self, name: &str) -> String {
+        self.0.join(name).to_str().unwrap().to_string()
+    }
+}
+
+impl Drop for PrivateDir {
+    fn drop(&mut self) {
+        let _ = remove_dir_all(&self.0);
+    }
+}
+
+/// The extension(s) to append to the backup file name
+pub fn extension(bkp: &Backup) -> String {
+    let mut ret = String::new();
+    match bkp.compression {
+        Some(Compression::Zstd) => ret.push_str(".zst"),
+        Some(Compression::Gzip) => ret.push_str(".gz"),
+        None => (),
+    }
+    if bkp.encryption.is_some() {
+        ret.push_str(".enc");
+    }
+    ret
+}
+
+pub fn is_plain(bkp: &Backup) -> bool {
+    bkp.compression.is_none() && bkp.encryption.is_none()
+}
+
+struct Encryptor<W: Write> {
+    inner: W,
+    key: LessSafeKey,
+    nonce_prefix: [u8; NONCE_PREFIX_SIZE],
+    counter: u32,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> Encryptor<W> {
+    fn new(mut inner: W, key: &[u8; 32]) -> Result<Encryptor<W>> {
+        let mut nonce_prefix = [0u8; NONCE_PREFIX_SIZE];
+        SystemRandom::new()
+            .fill(&mut nonce_prefix)
+            .map_err(|_| eyre!("cannot generate a nonce"))?;
+        inner.write_all(MAGIC)?;
+        inner.write_all(&nonce_prefix)?;
+
+        let key = UnboundKey::new(&AES_256_GCM, key).map_err(|_| eyre!("invalid key"))?;
+        Ok(Encryptor {
+            inner,
+            key: LessSafeKey::new(key),
+            nonce_prefix,
+            counter: 0,
+            buf: Vec::with_capacity(CHUNK_SIZE),
+        })
+    }
+
+    fn seal_chunk(&mut self, len: usize, last: bool) -> std::io::Result<()> {
+        let mut nonce = [0u8; 12];
+        nonce[..NONCE_PREFIX_SIZE].copy_from_slice(&self.nonce_prefix);
+        nonce[NONCE_PREFIX_SIZE..11].copy_from_slice(&self.counter.to_be_bytes());
+        nonce[11] = last as u8;
+
+        let mut chunk: Vec<u8> = self.buf.drain(..len).collect();
+        self.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::empty(),
+                &mut chunk,
+            )
+            .map_err(|_| std::io::Error::other("encryption failed"))?;
+        self.inner.write_all(&chunk)?;
+
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or_else(|| std::io::Error::other("file too big to encrypt"))?;
+        Ok(())
+    }
+
+    fn finish(mut self) -> std::io::Result<W> {
+        let len = self.buf.len();
+        self.seal_chunk(len, true)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for Encryptor<W> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        // a full chunk is sealed only when there's more data, so that the last one
+        // can be marked as such in finish()
+        while self.buf.len() > CHUNK_SIZE {
+            self.seal_chunk(CHUNK_SIZE, false)?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct Decryptor<R: Read> {
+    inner: R,
+    key: LessSafeKey,
+    nonce_prefix: [u8; NONCE_PREFIX_SIZE],
+    counter: u32,
+    // ciphertext read in advance, to know if the current chunk is the last one
+    pending: Vec<u8>,
+    plain: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<R: Read> Decryptor<R> {
+    fn new(mut inner: R, key: &[u8; 32]) -> Result<Decryptor<R>> {
+        let mut header = [0u8; MAGIC.len() + NONCE_PREFIX_SIZE];
+        inner
+            .read_exact(&mut header)
+            .map_err(|_| eyre!("not an encrypted backup file"))?;
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(eyre!("not an encrypted backup file"));
+        }
+        let mut nonce_prefix = [0u8; NONCE_PREFIX_SIZE];
+        nonce_prefix.copy_from_slice(&header[MAGIC.len()..]);
+
+        let key = UnboundKey::new(&AES_256_GCM, key).map_err(|_| eyre!("invalid key"))?;
+        Ok(Decryptor {
+            inner,
+            key: LessSafeKey::new(key),
+            nonce_prefix,
+            counter: 0,
+            pending: Vec::with_capacity(CHUNK_SIZE + TAG_SIZE + 1),
+            plain: vec![],
+            pos: 0,
+            done: false,
+        })
+    }
+
+    fn open_chunk(&mut self) -> std::io::Result<()> {
+        // reads a full chunk plus one byte: if it's there, the chunk is not the last one
+        while self.pending.len() <= CHUNK_SIZE + TAG_SIZE {
+            let mut buf = [0u8; 8192];
+            let to_read = (CHUNK_SIZE + TAG_SIZE + 1 - self.pending.len()).min(buf.len());
+            let n = self.inner.read(&mut buf[..to_read])?;
+            if n == 0 {
+                break;
+            }
+            self.pending.extend_from_slice(&buf[..n]);
+        }
+        let last = self.pending.len() <= CHUNK_SIZE + TAG_SIZE;
+        let len = self.pending.len().min(CHUNK_SIZE + TAG_SIZE);
+
+        let mut nonce = [0u8; 12];
+        nonce[..NONCE_PREFIX_SIZE].copy_from_slice(&self.nonce_prefix);
+        nonce[NONCE_PREFIX_SIZE..11].copy_from_slice(&self.counter.to_be_bytes());
+        nonce[11] = last as u8;
+
+        let mut chunk: Vec<u8> = self.pending.drain(..len).collect();
+        let plain = self
+            .key
+            .open_in_place(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::empty(),
+                &mut chunk,
+            )
+            .map_err(|_| std::io::Error::other("decryption failed: wrong key or damaged file"))?;
+        self.plain = plain.to_vec();
+        self.pos = 0;
+        self.done = last;
+
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or_else(|| std::io::Error::other("file too big to decrypt"))?;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Decryptor<R> {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.plain.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.open_chunk()?;
+        }
+        let n = out.len().min(self.plain.len() - self.pos);
+        out[..n].copy_from_slice(&self.plain[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+enum Sink {
+    Plain(BufWriter<File>),
+    Encrypted(Box<Encryptor<BufWriter<File>>>),
+}
+
+impl Write for Sink {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Sink::Plain(w) => w.write(data),
+            Sink::Encrypted(w) => w.write(data),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Sink::Plain(w) => w.flush(),
+            Sink::Encrypted(w) => w.flush(),
+        }
+    }
+}
+
+impl Sink {
+    fn finish(self) -> std::io::Result<()> {
+        match self {
+            Sink::Plain(mut w) => w.flush(),
+            Sink::Encrypted(w) => w.finish().map(|_| ()),
+        }
+    }
+}
+
+/// Writes the (plain) source file to the destination, compressed and/or encrypted as
+/// specified in the backup configuration
+pub fn encode(src: &str, dst: &str, bkp: &Backup) -> Result<()> {
+    let mut input = BufReader::new(File::open(src)?);
+    let output = BufWriter::new(File::create(dst)?);
+    let mut sink = match &bkp.encryption {
+        Some(enc) => Sink::Encrypted(Box::new(Encryptor::new(output, &read_key(&enc.key_file)?)?)),
+        None => Sink::Plain(output),
+    };
+
+    match bkp.compression {
+        Some(Compression::Zstd) => {
+            let mut encoder = zstd::Encoder::new(&mut sink, 0)?;
+            copy(&mut input, &mut encoder)?;
+            encoder.finish()?;
+        }
+        Some(Compression::Gzip) => {
+            let mut encoder = GzEncoder::new(&mut sink, GzLevel::default());
+            copy(&mut input, &mut encoder)?;
+            encoder.finish()?;
+        }
+        None => {
+            copy(&mut input, &mut sink)?;
+        }
+    }
+
+    sink.finish()?;
+    Ok(())
+}
+
+/// Writes the destination as a plain database file, decrypting and/or decompressing the
+/// source according to its extension(s)
+pub fn decode(src: &str, dst: &str, key_file: Option<&str>) -> Result<()> {
+    let mut name = src;
+    let mut input: Box<dyn Read> = Box::new(BufReader::new(File::open(src)?));
+    if let Some(n) = name.strip_suffix(".enc") {
+        let key_file = key_file
+            .ok_or_else(|| eyre!("the file is encrypted, but no key file is configured"))?;
+        input = Box::new(Decryptor::new(input, &read_key(key_file)?)?);
+        name = n;
+    }
+    if name.ends_with(".zst") {
+        input = Box::new(zstd::Decoder::new(input)?);
+    } else if name.ends_with(".gz") {
+        input = Box::new(GzDecoder::new(input));
+    }
+
+    let mut output = BufWriter::new(File::create(dst)?);
+    copy(&mut input, &mut output)?;
+    output.flush()?;
+    Ok(())
+}
@@ -0,0 +1,239 @@
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Naming of the backup files, and the manifest that describes them.
+
+use std::{
+    fs::{read_to_string, write, File},
+    io::{BufReader, Read},
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone, Utc};
+use eyre::Result;
+use ring::digest::{Context, SHA256};
+
+use crate::{backup_codec::extension, db_config::Backup, s3::S3Client};
+
+pub const DEFAULT_FILE_TEMPLATE: &str = "{db}_{ts}{ext}";
+// UTC with milliseconds, e.g. 20231204T153012.345Z: always 20 characters
+pub const TS_FORMAT: &str = "%Y%m%dT%H%M%S%.3fZ";
+const TS_LEN: usize = 20;
+// up to v0.18, local time with minutes
+const LEGACY_TS_FORMAT: &str = "%Y%m%d-%H%M";
+const LEGACY_TS_LEN: usize = 13;
+
+pub fn check_file_template(template: &str) -> Result<()> {
+    if template.matches("{ts}").count() != 1 {
+        return Err(eyre!("fileTemplate must contain '{{ts}}' exactly once"));
+    }
+    // otherwise the backups of two databases in the same dir couldn't be told apart, and
+    // the retention of one would delete the files of the other
+    if !template.contains("{db}") {
+        return Err(eyre!("fileTemplate must contain '{{db}}'"));
+    }
+    if template.contains('/') || template.contains('\\') {
+        return Err(eyre!("fileTemplate must be a file name, not a path"));
+    }
+    Ok(())
+}
+
+/// The parts of the file name before and after the timestamp
+fn template_parts(bkp: &Backup, db_path: &str) -> (String, String) {
+    let db_path = Path::new(db_path);
+    let stem = db_path.file_stem().unwrap().to_str().unwrap();
+    let ext = match db_path.extension() {
+        Some(e) => format!(".{}", e.to_str().unwrap()),
+        None => String::new(),
+    };
+    let template = bkp
+        .file_template
+        .as_deref()
+        .unwrap_or(DEFAULT_FILE_TEMPLATE)
+        .replace("{db}", stem)
+        .replace("{ext}", &ext);
+    // the template was validated
+    let (before, after) = template.split_once("{ts}").unwrap();
+    (before.to_string(), after.to_string())
+}
+
+/// The path in the directory of the backup file taken at the given time, with the extensions
+/// for compression and encryption
+pub fn gen_bkp_file(bkp: &Backup, dir: &str, db_path: &str, time: &DateTime<Utc>) -> String {
+    let (before, after) = template_parts(bkp, db_path);
+    let file_name = format!(
+        "{}{}{}{}",
+        before,
+        time.format(TS_FORMAT),
+        after,
+        extension(bkp)
+    );
+    Path::new(dir)
+        .join(file_name)
+        .into_os_string()
+        .into_string()
+        .unwrap()
+}
+
+/// If the file name is one of the backups of the database, returns its timestamp
+pub fn backup_time(bkp: &Backup, file_name: &str, db_path: &str) -> Option<DateTime<Utc>> {
+    // backups or restores in progress
+    if file_name.ends_with(".tmp") {
+        return None;
+    }
+    let (before, after) = template_parts(bkp, db_path);
+    let rest = file_name.strip_prefix(&before)?;
+
+    if rest.len() >= TS_LEN && rest.is_char_boundary(TS_LEN) {
+        let (ts, rest) = rest.split_at(TS_LEN);
+        if rest.starts_with(&after) {
+            if let Ok(time) = NaiveDateTime::parse_from_str(ts, TS_FORMAT) {
+                return Some(time.and_utc());
+            }
+        }
+    }
+
+    if rest.len() >= LEGACY_TS_LEN && rest.is_char_boundary(LEGACY_TS_LEN) {
+        let (ts, rest) = rest.split_at(LEGACY_TS_LEN);
+        if rest.starts_with(&after) {
+            if let Ok(time) = NaiveDateTime::parse_from_str(ts, LEGACY_TS_FORMAT) {
+                return Local
+                    .from_local_datetime(&time)
+                    .earliest()
+                    .map(|t| t.with_timezone(&Utc));
+            }
+        }
+    }
+
+    None
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub file: String,
+    pub created: DateTime<Utc>,
+    pub size: u64,
+    pub sha256: String,
+    #[serde(rename = "sourceDb")]
+    pub source_db: String,
+    #[serde(rename = "sqliteVersion")]
+    pub sqlite_version: String,
+    #[serde(rename = "sqlitergVersion")]
+    pub sqliterg_version: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    backups: Vec<ManifestEntry>,
+}
+
+/// Each database has its own manifest in the backup directory (or under the prefix of the
+/// object storage), "<db name>.manifest.json"
+pub fn manifest_name(db_path: &str) -> String {
+    let stem = Path::new(db_path).file_stem().unwrap().to_str().unwrap();
+    format!("{}.manifest.json", stem)
+}
+
+fn manifest_file(dir: &Path, db_path: &str) -> PathBuf {
+    dir.join(manifest_name(db_path))
+}
+
+fn load_manifest(file: &Path) -> Manifest {
+    read_to_string(file)
+        .ok()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or_default()
+}
+
+fn save_manifest(file: &Path, manifest: &Manifest) -> Result<()> {
+    write(file, serde_json::to_string_pretty(manifest)?)?;
+    Ok(())
+}
+
+pub fn file_sha256(file: &str) -> Result<String> {
+    let mut reader = BufReader::new(File::open(file)?);
+    let mut context = Context::new(&SHA256);
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        context.update(&buf[..n]);
+    }
+    Ok(hex::encode(context.finish().as_ref()))
+}
+
+/// Records a new backup file in the manifest, in the directory of the file
+pub fn add_to_manifest(db_path: &str, bkp_file: &str, created: &DateTime<Utc>) -> Result<()> {
+    let path = Path::new(bkp_file);
+    let entry = ManifestEntry {
+        file: path.file_name().unwrap().to_str().unwrap().to_string(),
+        created: created.to_owned(),
+        size: path.metadata()?.len(),
+        sha256: file_sha256(bkp_file)?,
+        source_db: db_path.to_string(),
+        sqlite_version: rusqlite::version().to_string(),
+        sqliterg_version: env!("CARGO_PKG_VERSION").to_string(),
+    };
+
+    let manifest_file = manifest_file(path.parent().unwrap(), db_path);
+    let mut manifest = load_manifest(&manifest_file);
+    manifest.backups.push(entry);
+    save_manifest(&manifest_file, &manifest)
+}
+
+/// Removes from the manifest in the directory the files that don't exist anymore, as told
+/// by the given function
+pub fn prune_manifest(dir: &Path, db_path: &str, exists: impl Fn(&str) -> bool) -> Result<()> {
+    let manifest_file = manifest_file(dir, db_path);
+    let mut manifest = load_manifest(&manifest_file);
+    manifest.backups.retain(|e| exists(&e.file));
+    save_manifest(&manifest_file, &manifest)
+}
+
+/// The checksum of a backup file, if it's recorded in the manifest of its directory
+pub fn manifest_sha256(bkp_file: &str, db_path: &str) -> Option<String> {
+    let path = Path::new(bkp_file);
+    let file_name = path.file_name()?.to_str()?;
+    let manifest = load_manifest(&manifest_file(path.parent()?, db_path));
+    manifest
+        .backups
+        .into_iter()
+        .find(|e| e.file == file_name)
+        .map(|e| e.sha256)
+}
+
+/// Downloads the manifest of the database from under the prefix of the object storage into
+/// the directory; false if there's none yet
+pub fn download_manifest(
+    client: &S3Client,
+    prefix: &str,
+    dir: &Path,
+    db_path: &str,
+) -> Result<bool> {
+    let key = format!("{}{}", prefix, manifest_name(db_path));
+    if !client.list(&key)?.iter().any(|obj| obj.key == key) {
+        return Ok(false);
+    }
+    client.download(&key, manifest_file(dir, db_path).to_str().unwrap())?;
+    Ok(true)
+}
+
+/// Uploads the manifest of the database in the directory to under the prefix of the object
+/// storage
+pub fn upload_manifest(client: &S3Client, prefix: &str, dir: &Path, db_path: &str) -> Result<()> {
+    let key = format!("{}{}", prefix, manifest_name(db_path));
+    client.upload(manifest_file(dir, db_path).to_str().unwrap(), &key)
+}
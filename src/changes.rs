@@ -0,0 +1,503 @@
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Change feed: the row-level changes to a database, streamed as Server-Sent Events at
+// /<db>/changes and sent to the webhooks. They're captured by SQLite hooks on the connection,
+// kept aside until the transaction commits (or dropped if it rolls back), and published after
+// the commit by the code that holds the connection. The ones for the webhooks are written to
+// their outbox before the commit, in the same transaction.
+
+use std::{
+    collections::HashMap,
+    mem::take,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::Duration,
+};
+
+use actix_web::{
+    http::header::{self, Header},
+    rt::{spawn, time::sleep},
+    web::{self, Bytes},
+    Either, HttpRequest, HttpResponse,
+};
+use actix_web_httpauth::headers::authorization::{Authorization, Basic};
+use rusqlite::{
+    hooks::{Action, AuthAction, AuthContext, Authorization as SqlAuthorization},
+    Connection,
+};
+use serde_json::{Map as JsonMap, Value as JsonValue};
+use tokio::sync::mpsc::{channel, Sender};
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+
+use crate::{
+    auth::process_auth_async,
+    db_config::{AuthMode, ChangeFeed, Webhook},
+    logic::val_db2val_json,
+    main_config::Db,
+    metrics::record_auth_failure,
+    req_res::{ChangeEvent, ChangesQuery, ReqCredentials, Response},
+    webhooks::{self, Outbox},
+};
+
+// events buffered for a client; one that falls further behind is disconnected
+const CLIENT_BUFFER: usize = 1024;
+// a comment is sent periodically, so that proxies don't close an idle stream and the
+// clients that went away are noticed
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(Clone)]
+pub struct Change {
+    pub op: &'static str,
+    pub table: String,
+    pub rowid: i64,
+    // already written to the outbox of the webhooks
+    queued: bool,
+}
+
+/// Whether a table is in a list of tables; None means all of them
+pub fn in_tables(tables: &Option<Vec<String>>, table: &str) -> bool {
+    match tables {
+        Some(tables) => tables.iter().any(|t| t.eq_ignore_ascii_case(table)),
+        None => true,
+    }
+}
+
+struct Subscriber {
+    // None: all the tables of the feed
+    tables: Option<Vec<String>>,
+    rows: bool,
+    tx: Sender<Bytes>,
+}
+
+impl Subscriber {
+    fn follows(&self, table: &str) -> bool {
+        in_tables(&self.tables, table)
+    }
+}
+
+struct Feed {
+    // the tables that the clients can follow; None if there's no change feed
+    tables: Option<Option<Vec<String>>>,
+    // the tables watched by the webhooks; None if there are no webhooks
+    webhook_tables: Option<Option<Vec<String>>>,
+    subscribers: Mutex<Vec<Subscriber>>,
+    // read by the hooks, to do nothing when nobody is listening
+    has_subscribers: AtomicBool,
+    // the changes of the running transaction
+    pending: Mutex<Vec<Change>>,
+    // the changes committed and not yet published
+    committed: Mutex<Vec<Change>>,
+    last_id: AtomicU64,
+}
+
+impl Feed {
+    fn captures(&self, table: &str) -> bool {
+        if table.eq_ignore_ascii_case(webhooks::OUTBOX_TABLE) {
+            return false;
+        }
+        let for_webhooks = match &self.webhook_tables {
+            Some(tables) => in_tables(tables, table),
+            None => false,
+        };
+        let for_clients = match &self.tables {
+            Some(tables) => {
+                self.has_subscribers.load(Ordering::Relaxed) && in_tables(tables, table)
+            }
+            None => false,
+        };
+        for_webhooks || for_clients
+    }
+
+    fn update_has_subscribers(&self, subscribers: &[Subscriber]) {
+        self.has_subscribers
+            .store(!subscribers.is_empty(), Ordering::Relaxed);
+    }
+}
+
+static FEEDS: OnceLock<Mutex<HashMap<String, Arc<Feed>>>> = OnceLock::new();
+
+fn feeds() -> &'static Mutex<HashMap<String, Arc<Feed>>> {
+    FEEDS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+fn feed(db_name: &str) -> Option<Arc<Feed>> {
+    feeds().lock().unwrap().get(db_name).cloned()
+}
+
+fn keepalive() {
+    spawn(async {
+        loop {
+            sleep(KEEPALIVE_INTERVAL).await;
+            let feeds: Vec<Arc<Feed>> = feeds().lock().unwrap().values().cloned().collect();
+            for feed in feeds {
+                let mut subscribers = feed.subscribers.lock().unwrap();
+                subscribers.retain(|s| s.tx.try_send(Bytes::from(": keepalive\n\n")).is_ok());
+                feed.update_has_subscribers(&subscribers);
+            }
+        }
+    });
+}
+
+/// Installs the hooks that capture the changes on the connection of a database, for its
+/// change feed and/or its webhooks
+pub fn init(
+    db_name: &str,
+    change_feed: &Option<ChangeFeed>,
+    webhooks: &Option<Vec<Webhook>>,
+    conn: &Connection,
+) {
+    let webhook_tables = webhooks.as_ref().map(|whs| {
+        let mut tables = vec![];
+        for wh in whs {
+            match &wh.tables {
+                Some(t) => tables.extend(t.iter().cloned()),
+                None => return None,
+            }
+        }
+        Some(tables)
+    });
+    let feed = Arc::new(Feed {
+        tables: change_feed.as_ref().map(|cf| cf.tables.to_owned()),
+        webhook_tables,
+        subscribers: Mutex::new(vec![]),
+        has_subscribers: AtomicBool::new(false),
+        pending: Mutex::new(vec![]),
+        committed: Mutex::new(vec![]),
+        last_id: AtomicU64::new(0),
+    });
+
+    let f = feed.clone();
+    conn.update_hook(Some(
+        move |action: Action, schema: &str, table: &str, rowid: i64| {
+            // attached databases are not followed
+            if schema != "main" || !f.captures(table) {
+                return;
+            }
+            let op = match action {
+                Action::SQLITE_INSERT => "insert",
+                Action::SQLITE_UPDATE => "update",
+                Action::SQLITE_DELETE => "delete",
+                _ => return,
+            };
+            f.pending.lock().unwrap().push(Change {
+                op,
+                table: table.to_string(),
+                rowid,
+                queued: false,
+            });
+        },
+    ));
+    let f = feed.clone();
+    // a DELETE without WHERE would truncate the table without calling the update hook;
+    // "ignoring" it makes SQLite delete the rows one by one. But a DROP TABLE is authorized
+    // as a DELETE too, on sqlite_master and then on the table, and there "ignoring" it
+    // would skip the drop.
+    let mut dropping: Option<String> = None;
+    conn.authorizer(Some(move |ctx: AuthContext| {
+        let ret = match ctx.action {
+            AuthAction::DropTable { table_name } | AuthAction::DropTempTable { table_name } => {
+                dropping = Some(table_name.to_string());
+                return SqlAuthorization::Allow;
+            }
+            AuthAction::Delete { table_name }
+                if ctx.database_name == Some("main")
+                    && !table_name.starts_with("sqlite_")
+                    && dropping.as_deref() != Some(table_name)
+                    && f.captures(table_name) =>
+            {
+                SqlAuthorization::Ignore
+            }
+            _ => SqlAuthorization::Allow,
+        };
+        dropping = None;
+        ret
+    }));
+    let f = feed.clone();
+    conn.commit_hook(Some(move || {
+        let mut changes = take(&mut *f.pending.lock().unwrap());
+        f.committed.lock().unwrap().append(&mut changes);
+        false // the commit goes on
+    }));
+    let f = feed.clone();
+    conn.rollback_hook(Some(move || f.pending.lock().unwrap().clear()));
+
+    let mut feeds = feeds().lock().unwrap();
+    if change_feed.is_some() && !feeds.values().any(|f| f.tables.is_some()) {
+        keepalive();
+    }
+    feeds.insert(db_name.to_string(), feed);
+}
+
+/// The row as it is now, i.e. after the commit; None if it's not there anymore
+fn fetch_row(conn: &Connection, table: &str, rowid: i64) -> Option<JsonValue> {
+    let sql = format!(
+        "SELECT * FROM \"{}\" WHERE rowid = ?1",
+        table.replace('"', "\"\"")
+    );
+    let mut stmt = conn.prepare_cached(&sql).ok()?;
+    let column_names: Vec<String> = stmt
+        .column_names()
+        .iter()
+        .map(|cn| cn.to_string())
+        .collect();
+    stmt.query_row([rowid], |row| {
+        let mut map: JsonMap<String, JsonValue> = JsonMap::new();
+        for (i, col_name) in column_names.iter().enumerate() {
+            map.insert(col_name.to_string(), val_db2val_json(row.get_unwrap(i)));
+        }
+        Ok(JsonValue::Object(map))
+    })
+    .ok()
+}
+
+fn format_event(id: u64, event: &ChangeEvent) -> Bytes {
+    Bytes::from(format!(
+        "id: {}\nevent: change\ndata: {}\n\n",
+        id,
+        serde_json::to_string(event).unwrap()
+    ))
+}
+
+/// The changes that a webhook is interested in, with the rows if one wants them
+fn for_webhooks(
+    outbox: &Outbox,
+    conn: &Connection,
+    changes: Vec<Change>,
+) -> Vec<(Change, Option<JsonValue>)> {
+    changes
+        .into_iter()
+        .filter_map(|change| {
+            let wants_row = outbox.wants(&change)?;
+            let row = if wants_row && change.op != "delete" {
+                fetch_row(conn, &change.table, change.rowid)
+            } else {
+                None
+            };
+            Some((change, row))
+        })
+        .collect()
+}
+
+/// Writes the changes of the running transaction to the outbox of the webhooks, so that they're
+/// committed with it. To be called just before the commit.
+pub fn write_outbox(db_name: &str, conn: &Connection) -> eyre::Result<()> {
+    let (feed, outbox) = match (feed(db_name), webhooks::outbox(db_name)) {
+        (Some(feed), Some(outbox)) => (feed, outbox),
+        _ => return Ok(()),
+    };
+    let changes: Vec<Change> = feed
+        .pending
+        .lock()
+        .unwrap()
+        .iter_mut()
+        .filter(|c| !c.queued)
+        .map(|c| {
+            c.queued = true;
+            c.clone()
+        })
+        .collect();
+    if changes.is_empty() {
+        return Ok(());
+    }
+    outbox.write(conn, &for_webhooks(&outbox, conn, changes))
+}
+
+/// Sends the committed changes to the clients that follow their tables, and wakes up the
+/// delivery of the webhooks. To be called after a commit, with the connection still locked.
+pub fn publish(db_name: &str, conn: &Connection) {
+    let feed = match feed(db_name) {
+        Some(feed) => feed,
+        None => return,
+    };
+    let changes = take(&mut *feed.committed.lock().unwrap());
+    if changes.is_empty() {
+        return;
+    }
+    let outbox = webhooks::outbox(db_name);
+
+    let mut subscribers = feed.subscribers.lock().unwrap();
+    let mut not_queued = vec![];
+    for change in changes {
+        if subscribers.iter().any(|s| s.follows(&change.table)) {
+            let row = if change.op != "delete"
+                && subscribers
+                    .iter()
+                    .any(|s| s.rows && s.follows(&change.table))
+            {
+                fetch_row(conn, &change.table, change.rowid)
+            } else {
+                None
+            };
+
+            let id = feed.last_id.fetch_add(1, Ordering::Relaxed) + 1;
+            let mut event = ChangeEvent {
+                table: &change.table,
+                op: change.op,
+                rowid: change.rowid,
+                row: None,
+            };
+            let without_row = format_event(id, &event);
+            event.row = row;
+            let with_row = format_event(id, &event);
+
+            subscribers.retain(|s| {
+                if !s.follows(&change.table) {
+                    return true;
+                }
+                let msg = if s.rows { &with_row } else { &without_row };
+                s.tx.try_send(msg.clone()).is_ok()
+            });
+        }
+
+        if !change.queued {
+            not_queued.push(change);
+        }
+    }
+    feed.update_has_subscribers(&subscribers);
+    drop(subscribers);
+
+    if let Some(outbox) = outbox {
+        if !not_queued.is_empty() {
+            // made outside of a transaction: they can only be written after the fact
+            let changes = for_webhooks(&outbox, conn, not_queued);
+            let res = (|| -> eyre::Result<()> {
+                let tx = conn.unchecked_transaction()?;
+                outbox.write(&tx, &changes)?;
+                tx.commit()?;
+                Ok(())
+            })();
+            if let Err(e) = res {
+                eprintln!(
+                    "Writing the outbox of the webhooks of db '{}': {}",
+                    db_name, e
+                );
+            }
+        }
+        outbox.notify();
+    }
+}
+
+fn check_request(
+    db_name: &str,
+    conf: &ChangeFeed,
+    query: &ChangesQuery,
+) -> Result<Option<Vec<String>>, Response> {
+    if query.rows && !conf.include_rows {
+        return Err(Response::new_err(
+            400,
+            -1,
+            format!("In database '{}', the change feed has no rows", db_name),
+        ));
+    }
+
+    let tables: Option<Vec<String>> = query.tables.as_ref().map(|t| {
+        t.split(',')
+            .map(|t| t.trim().to_string())
+            .filter(|t| !t.is_empty())
+            .collect()
+    });
+    if let (Some(tables), Some(allowed)) = (&tables, &conf.tables) {
+        for t in tables {
+            if !allowed.iter().any(|a| a.eq_ignore_ascii_case(t)) {
+                return Err(Response::new_err(
+                    400,
+                    -1,
+                    format!(
+                        "In database '{}', table '{}' is not in the change feed",
+                        db_name, t
+                    ),
+                ));
+            }
+        }
+    }
+    Ok(tables)
+}
+
+/// Streams the changes as Server-Sent Events, optionally filtered by table ("tables" query
+/// parameter) and with the new rows ("rows").
+pub async fn handler(
+    req: HttpRequest,
+    db_conf: web::Data<Db>,
+    db_name: web::Data<String>,
+    query: web::Query<ChangesQuery>,
+) -> Either<Response, HttpResponse> {
+    let db_name = db_name.to_string();
+    let conf = match &db_conf.conf.change_feed {
+        Some(conf) => conf,
+        None => {
+            return Either::Left(Response::new_err(
+                404,
+                -1,
+                format!("Database '{}' doesn't have a changeFeed node", db_name),
+            ))
+        }
+    };
+
+    if let Some(ac) = &db_conf.conf.auth {
+        let ac_headers = match ac.mode {
+            AuthMode::HttpBasic => Authorization::<Basic>::parse(&req).ok(),
+            AuthMode::Inline => None,
+        };
+        let creds = match (&query.user, &query.password) {
+            (Some(user), Some(password)) => Some(ReqCredentials {
+                user: user.to_owned(),
+                password: password.to_owned(),
+            }),
+            _ => None,
+        };
+        match process_auth_async(ac, &creds, &ac_headers, &db_name).await {
+            Ok(Some(_)) => (),
+            Err(msg) => return Either::Left(Response::new_err(503, -1, msg)),
+            Ok(None) => {
+                record_auth_failure(&db_name, "credentials");
+                sleep(Duration::from_millis(1000)).await;
+
+                return Either::Left(Response::new_err(
+                    ac.auth_error_code,
+                    -1,
+                    "Authorization failed".to_string(),
+                ));
+            }
+        }
+    }
+
+    let tables = match check_request(&db_name, conf, &query) {
+        Ok(tables) => tables,
+        Err(res) => return Either::Left(res),
+    };
+
+    let feed = feed(&db_name).unwrap();
+    let (tx, rx) = channel(CLIENT_BUFFER);
+    // so that the headers are sent right away
+    let _ = tx.try_send(Bytes::from(": connected\n\n"));
+    let mut subscribers = feed.subscribers.lock().unwrap();
+    subscribers.push(Subscriber {
+        tables,
+        rows: query.rows,
+        tx,
+    });
+    feed.update_has_subscribers(&subscribers);
+
+    Either::Right(
+        HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .insert_header((header::CACHE_CONTROL, "no-cache"))
+            // for nginx
+            .insert_header(("X-Accel-Buffering", "no"))
+            .streaming(ReceiverStream::new(rx).map(Ok::<_, actix_web::Error>)),
+    )
+}
@@ -12,16 +12,22 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
 
-use crate::commons::{assert, is_dir, resolve_tilde};
+use crate::{
+    access_log::LogFormat,
+    commons::{assert, is_dir, resolve_tilde},
+};
 
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about = None)]
 #[command(
     help_template = "{name} {version}\n {about-section}\n {usage-heading} {usage}\n {all-args} {tab}"
 )]
+#[command(args_conflicts_with_subcommands = true)]
 pub struct AppConfig {
+    #[command(subcommand)]
+    pub command: Option<Command>,
     #[arg(
         long,
         value_name = "HOST",
@@ -54,11 +60,103 @@ pub struct AppConfig {
         default_value = "index.html"
     )]
     pub index_file: String,
+    #[arg(long, help = "Exposes the metrics, in Prometheus format, at /metrics")]
+    pub metrics: bool,
+    #[arg(
+        long,
+        value_name = "TOKEN",
+        help = "If --metrics is configured, the token to read them",
+        requires = "metrics"
+    )]
+    pub metrics_token: Option<String>,
+    #[arg(
+        long,
+        value_name = "TOKEN",
+        help = "Exposes the versions and the databases of this instance at /info, readable with this token"
+    )]
+    pub info_token: Option<String>,
+    #[arg(
+        long,
+        help = "Exposes the OpenAPI description of the web services at /openapi.json"
+    )]
+    pub openapi: bool,
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        help = "Logs each request to a database on the standard output, in this format"
+    )]
+    pub access_log: Option<LogFormat>,
+    #[arg(
+        long,
+        help = "If --access-log is configured, logs the client address from the Forwarded or X-Forwarded-For headers, that must be set by a trusted reverse proxy",
+        requires = "access_log"
+    )]
+    pub trust_proxy_headers: bool,
+    #[arg(
+        long,
+        value_name = "URL",
+        help = "Exports traces to this OpenTelemetry collector, over OTLP/HTTP with JSON encoding [e.g. \"http://localhost:4318\"]"
+    )]
+    pub otlp_endpoint: Option<String>,
+    #[arg(
+        long,
+        value_name = "NAME",
+        help = "If --otlp-endpoint is configured, the service name of the traces",
+        default_value = "sqliterg",
+        requires = "otlp_endpoint"
+    )]
+    pub otlp_service_name: String,
+    #[arg(
+        long,
+        value_name = "PORT",
+        help = "Serves the databases also with the PostgreSQL wire protocol, on this port"
+    )]
+    pub pg_port: Option<u16>,
+    #[arg(
+        long,
+        value_name = "PORT",
+        help = "Serves the databases also with a gRPC API, on this port"
+    )]
+    pub grpc_port: Option<u16>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    #[command(about = "Restores a backup file into a database, then exits")]
+    Restore(RestoreArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct RestoreArgs {
+    #[arg(
+        long,
+        value_name = "DB_PATH",
+        help = "Path of the database to restore into [format: \"dbFilePath[::configFilePath]\"]"
+    )]
+    pub db: String,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "The backup file to restore",
+        required_unless_present = "to",
+        conflicts_with = "to"
+    )]
+    pub file: Option<String>,
+    #[arg(
+        long,
+        value_name = "TIMESTAMP",
+        help = "Restores the database as it was at this time (RFC 3339), from the WAL archive"
+    )]
+    pub to: Option<String>,
 }
 
 pub fn parse_cli() -> AppConfig {
     let mut ret = AppConfig::parse();
 
+    if ret.command.is_some() {
+        return ret;
+    }
+
     assert(
         ret.db.len() + ret.mem_db.len() > 0 || ret.serve_dir.is_some(),
         "no database and no dir to serve specified".to_string(),
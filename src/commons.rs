@@ -12,17 +12,9 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use chrono::{Datelike, Local, Timelike};
 use eyre::Result;
 use ring::digest::{Context, SHA256};
-use std::{
-    borrow::Borrow,
-    collections::HashMap,
-    fs::{read_dir, remove_file},
-    ops::Deref,
-    path::Path,
-    process::exit,
-};
+use std::{borrow::Borrow, collections::HashMap, ops::Deref, path::Path, process::exit};
 
 // General utils
 
@@ -75,43 +67,6 @@ pub fn equal_case_insensitive(s1: &str, s2: &str) -> bool {
     s1.to_lowercase() == s2.to_lowercase()
 }
 
-pub fn now() -> String {
-    let current_datetime = Local::now();
-
-    let year = current_datetime.year();
-    let month = current_datetime.month();
-    let day = current_datetime.day();
-    let hour = current_datetime.hour();
-    let minute = current_datetime.minute();
-
-    format!("{:04}{:02}{:02}-{:02}{:02}", year, month, day, hour, minute)
-}
-
-pub fn delete_old_files(dir: &str, files_to_keep: usize) -> Result<()> {
-    let path = Path::new(dir);
-    let dir = path.parent().map(|parent| parent.to_path_buf()).unwrap();
-
-    let mut entries: Vec<_> = read_dir(dir)?.filter_map(|entry| entry.ok()).collect();
-
-    entries.sort_by(|a, b| {
-        let a_meta = a.metadata().unwrap();
-        let b_meta = b.metadata().unwrap();
-        a_meta.modified().unwrap().cmp(&b_meta.modified().unwrap())
-    });
-
-    let num_entries = entries.len();
-
-    if num_entries > files_to_keep {
-        for entry in entries.iter().take(num_entries - files_to_keep) {
-            if entry.path().is_file() {
-                remove_file(entry.path())?;
-            }
-        }
-    }
-
-    Ok(())
-}
-
 pub fn check_stored_stmt<'a>(
     sql: &'a String,
     stored_statements: &'a HashMap<String, String>,
@@ -184,7 +139,7 @@ pub struct PositionalParamsContainer(Vec<Box<dyn rusqlite::types::ToSql>>);
 
 impl PositionalParamsContainer {
     pub fn slice(&self) -> Vec<&dyn rusqlite::types::ToSql> {
-        self.0.iter().map(|el| (el.borrow())).collect()
+        self.0.iter().map(|el| el.borrow()).collect()
     }
 }
 
@@ -50,10 +50,53 @@ pub struct Credentials {
     pub hashed_password: Option<String>,
 }
 
+#[derive(Debug, Default, Deserialize, Clone, PartialEq)]
+pub enum HttpMethod {
+    #[default]
+    #[serde(rename = "GET")]
+    Get,
+    #[serde(rename = "POST")]
+    Post,
+}
+
+#[derive(Debug, Default, Deserialize, Clone)]
+pub enum ParamType {
+    #[serde(rename = "INTEGER")]
+    Integer,
+    #[serde(rename = "REAL")]
+    Real,
+    #[default]
+    #[serde(rename = "TEXT")]
+    Text,
+    #[serde(rename = "BOOLEAN")]
+    Boolean,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct HttpParam {
+    // as in the SQL, without the ':'
+    pub name: String,
+    #[serde(rename = "type")]
+    #[serde(default)]
+    pub param_type: ParamType,
+    #[serde(default = "default_as_false")]
+    pub required: bool,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct StoredStatementHttp {
+    // GET for queries, POST for statements
+    #[serde(default)]
+    pub method: HttpMethod,
+    #[serde(default)]
+    pub params: Vec<HttpParam>,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct StoredStatement {
     pub id: String,
     pub sql: String,
+    pub http: Option<StoredStatementHttp>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -67,6 +110,15 @@ pub struct ExecutionWebService {
     pub hashed_auth_token: Option<String>,
 }
 
+#[derive(Debug, Default, Deserialize, Clone)]
+pub enum MissedRuns {
+    #[default]
+    #[serde(rename = "SKIP")]
+    Skip,
+    #[serde(rename = "CATCH_UP_ONCE")]
+    CatchUpOnce,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct ExecutionMode {
     #[serde(rename = "onCreate")]
@@ -75,31 +127,203 @@ pub struct ExecutionMode {
     #[serde(rename = "onStartup")]
     #[serde(default = "default_as_false")]
     pub on_startup: bool,
+    #[serde(rename = "onShutdown")]
+    #[serde(default = "default_as_false")]
+    pub on_shutdown: bool,
     #[serde(default = "default_as_zero")]
     pub period: i32,
+    pub cron: Option<String>,
+    #[serde(rename = "timeZone")]
+    pub time_zone: Option<String>,
+    #[serde(rename = "missedRuns")]
+    #[serde(default)]
+    pub missed_runs: MissedRuns,
     #[serde(rename = "webService")]
     pub web_service: Option<ExecutionWebService>,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+pub struct MacroStepDef {
+    pub statement: Option<String>,
+    #[serde(rename = "macro")]
+    pub macro_id: Option<String>,
+    pub condition: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum MacroStep {
+    Statement(String),
+    Step(MacroStepDef),
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Macro {
     pub id: String,
     #[serde(rename = "disableTransaction")]
     #[serde(default = "default_as_false")]
     pub disable_transaction: bool,
-    pub statements: Vec<String>,
+    pub statements: Vec<MacroStep>,
     pub execution: ExecutionMode,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+pub enum Compression {
+    #[serde(rename = "zstd")]
+    Zstd,
+    #[serde(rename = "gzip")]
+    Gzip,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Encryption {
+    #[serde(rename = "keyFile")]
+    pub key_file: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Retention {
+    #[serde(default)]
+    pub hourly: usize,
+    #[serde(default)]
+    pub daily: usize,
+    #[serde(default)]
+    pub weekly: usize,
+    #[serde(default)]
+    pub monthly: usize,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct S3Target {
+    pub endpoint: String,
+    pub bucket: String,
+    #[serde(default)]
+    pub prefix: String,
+    pub region: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "type")]
+pub enum BackupTarget {
+    S3(S3Target),
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Backup {
-    #[serde(rename = "backupDir")]
+    // with a target, it's the local staging directory, by default the temp one
+    #[serde(rename = "backupDir", default)]
     pub backup_dir: String,
+    pub target: Option<BackupTarget>,
     #[serde(rename = "numFiles")]
     pub num_files: usize,
+    pub retention: Option<Retention>,
+    #[serde(rename = "fileTemplate")]
+    pub file_template: Option<String>,
+    pub compression: Option<Compression>,
+    pub encryption: Option<Encryption>,
+    #[serde(rename = "pagesPerStep")]
+    pub pages_per_step: Option<i32>,
+    #[serde(rename = "preBackup")]
+    pub pre_backup: Option<Vec<String>>,
+    #[serde(rename = "postBackup")]
+    pub post_backup: Option<Vec<String>>,
     pub execution: ExecutionMode,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+pub struct WalArchive {
+    pub dir: String,
+    // seconds
+    pub interval: Option<u64>,
+    // minutes
+    #[serde(rename = "snapshotPeriod")]
+    pub snapshot_period: Option<u64>,
+    #[serde(rename = "numGenerations")]
+    pub num_generations: Option<usize>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Audit {
+    // exactly one of file (JSON lines) and db (SQLite)
+    pub file: Option<String>,
+    pub db: Option<String>,
+    #[serde(rename = "maxSizeMb")]
+    pub max_size_mb: Option<u64>,
+    #[serde(rename = "numFiles")]
+    pub num_files: Option<usize>,
+    #[serde(rename = "redactParams")]
+    #[serde(default)]
+    pub redact_params: Vec<String>,
+    #[serde(rename = "redactAll")]
+    #[serde(default = "default_as_false")]
+    pub redact_all: bool,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ChangeFeed {
+    // the tables that can be followed; all of them if absent
+    pub tables: Option<Vec<String>>,
+    #[serde(rename = "includeRows")]
+    #[serde(default = "default_as_false")]
+    pub include_rows: bool,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct WebhookRetry {
+    // 0 means forever
+    #[serde(rename = "maxAttempts")]
+    pub max_attempts: Option<u32>,
+    // seconds
+    #[serde(rename = "initialDelay")]
+    pub initial_delay: Option<u64>,
+    // seconds
+    #[serde(rename = "maxDelay")]
+    pub max_delay: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Webhook {
+    pub url: String,
+    // all of them if absent
+    pub tables: Option<Vec<String>>,
+    // insert, update, delete; all of them if absent
+    pub operations: Option<Vec<String>>,
+    pub secret: Option<String>,
+    #[serde(rename = "includeRows")]
+    #[serde(default = "default_as_false")]
+    pub include_rows: bool,
+    #[serde(rename = "batchSize")]
+    pub batch_size: Option<usize>,
+    pub retry: Option<WebhookRetry>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RestTable {
+    pub name: String,
+    // read, insert, update, delete; all of them if absent
+    pub operations: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Rest {
+    // the tables (or views) that are exposed
+    pub tables: Vec<RestTable>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct GraphQl {
+    // the tables (or views) that are exposed
+    pub tables: Vec<String>,
+    // the rows of a list field without a limit, and the maximum limit
+    #[serde(rename = "defaultLimit")]
+    pub default_limit: Option<usize>,
+    #[serde(rename = "maxLimit")]
+    pub max_limit: Option<usize>,
+    // the fields that a query can select, counting the nested ones
+    #[serde(rename = "maxComplexity")]
+    pub max_complexity: Option<usize>,
+}
+
 #[derive(Debug, Default, Deserialize, Clone)]
 pub struct DbConfig {
     pub auth: Option<Auth>,
@@ -117,6 +341,18 @@ pub struct DbConfig {
     pub stored_statements: Option<Vec<StoredStatement>>,
     pub macros: Option<Vec<Macro>>,
     pub backup: Option<Backup>,
+    #[serde(rename = "walArchive")]
+    pub wal_archive: Option<WalArchive>,
+    #[serde(rename = "runHistorySize")]
+    pub run_history_size: Option<usize>,
+    #[serde(rename = "slowQueryMs")]
+    pub slow_query_ms: Option<u64>,
+    pub audit: Option<Audit>,
+    #[serde(rename = "changeFeed")]
+    pub change_feed: Option<ChangeFeed>,
+    pub webhooks: Option<Vec<Webhook>>,
+    pub rest: Option<Rest>,
+    pub graphql: Option<GraphQl>,
 }
 
 pub fn parse_dbconf(filename: &String) -> Result<DbConfig> {
@@ -0,0 +1,1161 @@
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The GraphQL endpoint, at /<db>/graphql. The schema is generated from the tables and views
+// (sqlite_master, pragma_table_info), with their foreign keys as relations, and it's cached
+// until the schema of the database changes. A request is executed in a single transaction,
+// as in logic::process: a blocking task locks the database and runs the SQL that the
+// resolvers send it, then commits if there were no errors, or rolls back.
+
+use std::{
+    collections::HashMap,
+    ops::DerefMut,
+    sync::{mpsc, Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    http::header::Header, rt::time::sleep, web, Either, HttpMessage, HttpRequest, HttpResponse,
+};
+use actix_web_httpauth::headers::authorization::{Authorization, Basic};
+use async_graphql::{
+    dynamic::{
+        Enum, Field, FieldFuture, FieldValue, InputObject, InputValue, Object, ObjectAccessor,
+        ResolverContext, Scalar, Schema, Type, TypeRef,
+    },
+    Error, Request as GqlRequest, Response as GqlResponse, Result, ServerError, Value as GqlValue,
+};
+use rusqlite::{params_from_iter, types::Value, Connection};
+use serde_json::Value as JsonValue;
+use tokio::{sync::oneshot, task::spawn_blocking};
+
+use crate::{
+    access_log::AccessInfo,
+    audit::{self, AuditedStatement},
+    auth::process_auth_async,
+    changes,
+    db_config::GraphQl,
+    logic::{busy_msg, lock_db, val_db2val_json},
+    main_config::Db,
+    metrics::{record_auth_failure, record_process},
+    req_res::{ReqCredentials, Response},
+    rest::{audited, placeholder, quote, val_json2val_db},
+};
+
+// the relations can nest indefinitely
+const MAX_DEPTH: usize = 16;
+// the rows of a list field without a limit, and the maximum limit; a nested list is fetched
+// for each row of its parent, all while holding the database
+pub const DEFAULT_LIMIT: usize = 100;
+pub const MAX_LIMIT: usize = 1000;
+// the fields that a query can select
+const DEFAULT_MAX_COMPLEXITY: usize = 500;
+
+const VALUE: &str = "Value";
+const SCALARS: [&str; 4] = [TypeRef::INT, TypeRef::FLOAT, TypeRef::STRING, VALUE];
+// the tables with these names would clash with the other types
+const RESERVED: [&str; 9] = [
+    "Query",
+    "Mutation",
+    "OrderDirection",
+    VALUE,
+    TypeRef::INT,
+    TypeRef::FLOAT,
+    TypeRef::STRING,
+    TypeRef::BOOLEAN,
+    TypeRef::ID,
+];
+
+static SCHEMAS: OnceLock<Mutex<HashMap<String, (i64, Schema)>>> = OnceLock::new();
+
+fn schemas() -> &'static Mutex<HashMap<String, (i64, Schema)>> {
+    SCHEMAS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+struct Column {
+    name: String,
+    scalar: &'static str,
+    not_null: bool,
+}
+
+/// A foreign key, seen from one of its sides: `column` is in this table, `ref_column` is in
+/// `table`. The side of the referencing table has one row, the other side many.
+struct Relation {
+    field: String,
+    column: String,
+    table: String,
+    ref_column: String,
+    many: bool,
+}
+
+struct Table {
+    name: String,
+    is_view: bool,
+    columns: Vec<Column>,
+    pk: Option<usize>,
+    relations: Vec<Relation>,
+}
+
+impl Table {
+    fn column(&self, name: &str) -> Result<&Column> {
+        self.columns
+            .iter()
+            .find(|c| c.name == name)
+            .ok_or_else(|| Error::new(format!("Unknown column '{}'", name)))
+    }
+
+    fn has_field(&self, name: &str) -> bool {
+        self.columns.iter().any(|c| c.name == name)
+            || self.relations.iter().any(|r| r.field == name)
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c == '_' || c.is_ascii_alphabetic())
+        && chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+        && !name.starts_with("__")
+}
+
+/// The affinity rules of SQLite; but the NUMERIC columns often hold dates or booleans, so
+/// they're left untyped, as the columns without a declared type.
+fn scalar(decltype: &str) -> &'static str {
+    let t = decltype.to_ascii_uppercase();
+    if t.contains("INT") {
+        TypeRef::INT
+    } else if t.contains("CHAR") || t.contains("CLOB") || t.contains("TEXT") {
+        TypeRef::STRING
+    } else if t.contains("REAL") || t.contains("FLOA") || t.contains("DOUB") {
+        TypeRef::FLOAT
+    } else {
+        VALUE
+    }
+}
+
+/// The tables and views to expose, with the columns and the relations. Those whose names
+/// aren't valid in GraphQL are skipped, and so are the foreign keys on more than a column.
+fn introspect(conn: &Connection, conf: &GraphQl) -> rusqlite::Result<Vec<Table>> {
+    let mut stmt = conn.prepare(
+        "SELECT name, type = 'view' FROM sqlite_master WHERE type IN ('table', 'view') AND name NOT LIKE 'sqlite\\_%' ESCAPE '\\' ORDER BY name",
+    )?;
+    let names = stmt
+        .query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, bool>(1)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut tables = vec![];
+    for (name, is_view) in names {
+        if !is_valid_name(&name)
+            || RESERVED.contains(&name.as_str())
+            || !conf.tables.iter().any(|t| t.eq_ignore_ascii_case(&name))
+        {
+            continue;
+        }
+        let mut stmt = conn.prepare(
+            "SELECT name, type, \"notnull\", pk FROM pragma_table_info(?1) ORDER BY cid",
+        )?;
+        let cols = stmt
+            .query_map([&name], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, bool>(2)?,
+                    row.get::<_, i64>(3)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let pks: Vec<&String> = cols.iter().filter(|c| c.3 > 0).map(|c| &c.0).collect();
+        let pk = match pks[..] {
+            [pk] => Some(pk.to_owned()),
+            _ => None,
+        };
+        let columns: Vec<Column> = cols
+            .into_iter()
+            .filter(|c| is_valid_name(&c.0))
+            .map(|(name, decltype, not_null, _)| Column {
+                name,
+                scalar: scalar(&decltype),
+                not_null,
+            })
+            .collect();
+        if columns.is_empty() {
+            continue;
+        }
+        tables.push(Table {
+            name,
+            is_view,
+            pk: pk.and_then(|pk| columns.iter().position(|c| c.name == pk)),
+            columns,
+            relations: vec![],
+        });
+    }
+
+    for i in 0..tables.len() {
+        let mut stmt = conn.prepare(
+            "SELECT \"table\", \"from\", \"to\" FROM pragma_foreign_key_list(?1) WHERE id IN (SELECT id FROM pragma_foreign_key_list(?1) GROUP BY id HAVING count(*) = 1)",
+        )?;
+        let fks = stmt
+            .query_map([&tables[i].name], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        for (ref_table, from, to) in fks {
+            let j = match tables
+                .iter()
+                .position(|t| t.name.eq_ignore_ascii_case(&ref_table))
+            {
+                Some(j) => j,
+                None => continue,
+            };
+            let find = |t: &Table, name: &str| {
+                t.columns
+                    .iter()
+                    .find(|c| c.name.eq_ignore_ascii_case(name))
+                    .map(|c| c.name.to_owned())
+            };
+            // without "to", it's the primary key of the referenced table
+            let (from, to) = match (
+                find(&tables[i], &from),
+                match to {
+                    Some(to) => find(&tables[j], &to),
+                    None => tables[j].pk.map(|pk| tables[j].columns[pk].name.to_owned()),
+                },
+            ) {
+                (Some(from), Some(to)) => (from, to),
+                _ => continue,
+            };
+
+            let one = Relation {
+                field: format!("{}_by_{}", tables[j].name, from),
+                column: from.to_owned(),
+                table: tables[j].name.to_owned(),
+                ref_column: to.to_owned(),
+                many: false,
+            };
+            if !tables[i].has_field(&one.field) {
+                tables[i].relations.push(one);
+            }
+            let many = Relation {
+                field: format!("{}_list_by_{}", tables[i].name, from),
+                column: to,
+                table: tables[i].name.to_owned(),
+                ref_column: from,
+                many: true,
+            };
+            if !tables[j].has_field(&many.field) {
+                tables[j].relations.push(many);
+            }
+        }
+    }
+    Ok(tables)
+}
+
+/// A row, as a parent value for the fields
+struct Row(HashMap<String, Value>);
+
+fn db2gql(v: Value) -> GqlValue {
+    GqlValue::from_json(val_db2val_json(v)).unwrap_or_default()
+}
+
+fn gql2db(v: &GqlValue) -> Value {
+    val_json2val_db(&v.clone().into_json().unwrap_or_default())
+}
+
+fn fetch(conn: &Connection, sql: &str, params: &[Value]) -> Result<Vec<Row>> {
+    let mut stmt = conn.prepare(sql)?;
+    let names: Vec<String> = stmt.column_names().iter().map(|c| c.to_string()).collect();
+    let mut rows = stmt.query(params_from_iter(params.iter()))?;
+    let mut ret = vec![];
+    while let Some(row) = rows.next()? {
+        ret.push(Row(names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (name.to_owned(), row.get_unwrap(i)))
+            .collect()));
+    }
+    Ok(ret)
+}
+
+fn write(
+    conn: &Connection,
+    audit: &mut Vec<AuditedStatement>,
+    sql: &str,
+    params: &[Value],
+) -> Result<Vec<Row>> {
+    let rows = fetch(conn, sql, params)?;
+    audit.push(audited(audit.len(), sql, params, rows.len()));
+    Ok(rows)
+}
+
+/// A "<table>_filter" object: the conditions on the columns, and "and", "or" and "not"
+fn filter_sql(table: &Table, filter: &GqlValue, params: &mut Vec<Value>) -> Result<String> {
+    let obj = match filter {
+        GqlValue::Object(obj) => obj,
+        _ => return Ok("1".to_string()),
+    };
+    let mut conds = vec![];
+    for (key, val) in obj {
+        match (key.as_str(), val) {
+            (_, GqlValue::Null) => {}
+            (op @ ("and" | "or"), GqlValue::List(items)) => {
+                let (sep, empty) = match op {
+                    "and" => (" AND ", "1"),
+                    _ => (" OR ", "0"),
+                };
+                let subs = items
+                    .iter()
+                    .map(|item| filter_sql(table, item, params))
+                    .collect::<Result<Vec<String>>>()?;
+                conds.push(match subs.is_empty() {
+                    true => empty.to_string(),
+                    false => format!("(({}))", subs.join(&format!("){}(", sep))),
+                });
+            }
+            ("not", _) => conds.push(format!("NOT ({})", filter_sql(table, val, params)?)),
+            (col, GqlValue::Object(ops)) => {
+                let c = quote(&table.column(col)?.name);
+                for (op, val) in ops {
+                    conds.push(match (op.as_str(), val) {
+                        (_, GqlValue::Null) => continue,
+                        ("is_null", GqlValue::Boolean(true)) => format!("{} IS NULL", c),
+                        ("is_null", _) => format!("{} IS NOT NULL", c),
+                        ("in", GqlValue::List(vals)) if vals.is_empty() => "0".to_string(),
+                        ("in", GqlValue::List(vals)) => {
+                            let ps: Vec<String> = vals
+                                .iter()
+                                .map(|v| placeholder(params, gql2db(v)))
+                                .collect();
+                            format!("{} IN ({})", c, ps.join(", "))
+                        }
+                        (op, val) => {
+                            let sql_op = match op {
+                                "eq" => "=",
+                                "neq" => "<>",
+                                "gt" => ">",
+                                "gte" => ">=",
+                                "lt" => "<",
+                                "lte" => "<=",
+                                "like" => "LIKE",
+                                "glob" => "GLOB",
+                                _ => return Err(Error::new(format!("Unknown operator '{}'", op))),
+                            };
+                            format!("{} {} {}", c, sql_op, placeholder(params, gql2db(val)))
+                        }
+                    });
+                }
+            }
+            _ => return Err(Error::new(format!("Invalid filter on '{}'", key))),
+        }
+    }
+    Ok(match conds.is_empty() {
+        true => "1".to_string(),
+        false => conds.join(" AND "),
+    })
+}
+
+/// A list of "<table>_order" objects, each with columns and directions
+fn order_sql(table: &Table, order_by: &GqlValue) -> Result<String> {
+    let items = match order_by {
+        GqlValue::List(items) => items.iter().collect(),
+        _ => vec![order_by],
+    };
+    let mut terms = vec![];
+    for item in items {
+        if let GqlValue::Object(obj) = item {
+            for (col, dir) in obj {
+                let dir = match dir {
+                    GqlValue::Enum(d) if d.as_str() == "DESC" => "DESC",
+                    GqlValue::String(d) if d == "DESC" => "DESC",
+                    GqlValue::Null => continue,
+                    _ => "ASC",
+                };
+                terms.push(format!("{} {}", quote(&table.column(col)?.name), dir));
+            }
+        }
+    }
+    Ok(terms.join(", "))
+}
+
+#[derive(Default)]
+struct ListArgs {
+    filter: Option<GqlValue>,
+    order_by: Option<GqlValue>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+/// The limit of the rows of a list field: the default one, and the maximum
+#[derive(Clone, Copy)]
+struct Limits {
+    default: i64,
+    max: i64,
+}
+
+impl ListArgs {
+    fn parse(args: &ObjectAccessor, limits: Limits) -> Result<ListArgs> {
+        let limit = match args.get("limit").map(|v| v.i64()).transpose()? {
+            None => limits.default,
+            Some(limit) if (0..=limits.max).contains(&limit) => limit,
+            Some(_) => {
+                return Err(Error::new(format!(
+                    "limit must be between 0 and {}",
+                    limits.max
+                )))
+            }
+        };
+        Ok(ListArgs {
+            filter: args.get("where").map(|v| v.as_value().to_owned()),
+            order_by: args.get("order_by").map(|v| v.as_value().to_owned()),
+            limit: Some(limit),
+            offset: args.get("offset").map(|v| v.i64()).transpose()?,
+        })
+    }
+}
+
+fn where_sql(
+    table: &Table,
+    link: Option<(&str, Value)>,
+    filter: &Option<GqlValue>,
+    params: &mut Vec<Value>,
+) -> Result<String> {
+    let mut conds = vec![];
+    if let Some((col, v)) = link {
+        conds.push(format!("{} = {}", quote(col), placeholder(params, v)));
+    }
+    if let Some(filter) = filter {
+        conds.push(filter_sql(table, filter, params)?);
+    }
+    Ok(match conds.is_empty() {
+        true => String::new(),
+        false => format!(" WHERE {}", conds.join(" AND ")),
+    })
+}
+
+fn select(
+    conn: &Connection,
+    table: &Table,
+    link: Option<(&str, Value)>,
+    args: &ListArgs,
+) -> Result<Vec<Row>> {
+    let mut params = vec![];
+    let mut sql = format!(
+        "SELECT * FROM {}{}",
+        quote(&table.name),
+        where_sql(table, link, &args.filter, &mut params)?
+    );
+    if let Some(order_by) = &args.order_by {
+        let order = order_sql(table, order_by)?;
+        if !order.is_empty() {
+            sql.push_str(&format!(" ORDER BY {}", order));
+        }
+    }
+    if args.limit.is_some() || args.offset.is_some() {
+        let p = placeholder(&mut params, Value::Integer(args.limit.unwrap_or(-1)));
+        sql.push_str(&format!(" LIMIT {}", p));
+        if let Some(offset) = args.offset {
+            let p = placeholder(&mut params, Value::Integer(offset));
+            sql.push_str(&format!(" OFFSET {}", p));
+        }
+    }
+    fetch(conn, &sql, &params)
+}
+
+fn count(conn: &Connection, table: &Table, filter: &Option<GqlValue>) -> Result<i64> {
+    let mut params = vec![];
+    let sql = format!(
+        "SELECT count(*) FROM {}{}",
+        quote(&table.name),
+        where_sql(table, None, filter, &mut params)?
+    );
+    Ok(conn.query_row(&sql, params_from_iter(params.iter()), |row| row.get(0))?)
+}
+
+fn insert(
+    conn: &Connection,
+    table: &Table,
+    objects: &GqlValue,
+    audit: &mut Vec<AuditedStatement>,
+) -> Result<Vec<Row>> {
+    let objects = match objects {
+        GqlValue::List(objects) => objects.iter().collect(),
+        _ => vec![objects],
+    };
+    let mut ret = vec![];
+    for obj in objects {
+        let mut params = vec![];
+        let mut cols = vec![];
+        let mut ps = vec![];
+        if let GqlValue::Object(obj) = obj {
+            for (k, v) in obj {
+                cols.push(quote(&table.column(k)?.name));
+                ps.push(placeholder(&mut params, gql2db(v)));
+            }
+        }
+        let sql = match cols.is_empty() {
+            true => format!(
+                "INSERT INTO {} DEFAULT VALUES RETURNING *",
+                quote(&table.name)
+            ),
+            false => format!(
+                "INSERT INTO {} ({}) VALUES ({}) RETURNING *",
+                quote(&table.name),
+                cols.join(", "),
+                ps.join(", ")
+            ),
+        };
+        ret.append(&mut write(conn, audit, &sql, &params)?);
+    }
+    Ok(ret)
+}
+
+fn update(
+    conn: &Connection,
+    table: &Table,
+    link: Option<(&str, Value)>,
+    filter: &Option<GqlValue>,
+    set: &GqlValue,
+    audit: &mut Vec<AuditedStatement>,
+) -> Result<Vec<Row>> {
+    let set = match set {
+        GqlValue::Object(set) if !set.is_empty() => set,
+        _ => return Err(Error::new("The columns to set must be specified")),
+    };
+    let mut params = vec![];
+    let mut sets = vec![];
+    for (k, v) in set {
+        let col = quote(&table.column(k)?.name);
+        sets.push(format!("{} = {}", col, placeholder(&mut params, gql2db(v))));
+    }
+    let sql = format!(
+        "UPDATE {} SET {}{} RETURNING *",
+        quote(&table.name),
+        sets.join(", "),
+        where_sql(table, link, filter, &mut params)?
+    );
+    write(conn, audit, &sql, &params)
+}
+
+fn delete(
+    conn: &Connection,
+    table: &Table,
+    link: Option<(&str, Value)>,
+    filter: &Option<GqlValue>,
+    audit: &mut Vec<AuditedStatement>,
+) -> Result<Vec<Row>> {
+    let mut params = vec![];
+    let sql = format!(
+        "DELETE FROM {}{} RETURNING *",
+        quote(&table.name),
+        where_sql(table, link, filter, &mut params)?
+    );
+    write(conn, audit, &sql, &params)
+}
+
+type Job = Box<dyn FnOnce(&Connection, &mut Vec<AuditedStatement>) + Send>;
+
+enum Message {
+    Job(Job),
+    // whether to commit, and where to signal the outcome
+    Finish(bool, oneshot::Sender<Result<(), String>>),
+}
+
+/// Sends the SQL of the resolvers to the task that holds the transaction
+#[derive(Clone)]
+struct Executor(mpsc::Sender<Message>);
+
+impl Executor {
+    async fn run<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection, &mut Vec<AuditedStatement>) -> Result<T> + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        self.0
+            .send(Message::Job(Box::new(move |conn, audit| {
+                let _ = tx.send(f(conn, audit));
+            })))
+            .map_err(|_| Error::new("The transaction is closed"))?;
+        rx.await
+            .map_err(|_| Error::new("The transaction is closed"))?
+    }
+}
+
+fn rows(rows: Vec<Row>) -> Option<FieldValue<'static>> {
+    Some(FieldValue::list(
+        rows.into_iter().map(FieldValue::owned_any),
+    ))
+}
+
+fn first_row(rows: Vec<Row>) -> Option<FieldValue<'static>> {
+    rows.into_iter().next().map(FieldValue::owned_any)
+}
+
+/// The value of a column of the parent row, unless it's NULL
+fn parent_value(ctx: &ResolverContext, col: &str) -> Result<Option<Value>> {
+    let row = ctx.parent_value.try_downcast_ref::<Row>()?;
+    Ok(row.0.get(col).filter(|v| **v != Value::Null).cloned())
+}
+
+fn with_list_args(field: Field, table: &str, limits: Limits) -> Field {
+    field
+        .argument(InputValue::new(
+            "where",
+            TypeRef::named(format!("{}_filter", table)),
+        ))
+        .argument(InputValue::new(
+            "order_by",
+            TypeRef::named_nn_list(format!("{}_order", table)),
+        ))
+        .argument(
+            InputValue::new("limit", TypeRef::named(TypeRef::INT)).description(format!(
+                "At most {}; {} if absent",
+                limits.max, limits.default
+            )),
+        )
+        .argument(InputValue::new("offset", TypeRef::named(TypeRef::INT)))
+}
+
+/// The rows of a table, optionally those linked to the parent row
+fn list_field(
+    name: &str,
+    table: &Arc<Table>,
+    link: Option<(String, String)>,
+    limits: Limits,
+) -> Field {
+    let t = table.clone();
+    let field = Field::new(name, TypeRef::named_nn_list_nn(&table.name), move |ctx| {
+        let t = t.clone();
+        let link = link.clone();
+        FieldFuture::new(async move {
+            let args = ListArgs::parse(&ctx.args, limits)?;
+            let link = match link {
+                Some((parent_col, col)) => match parent_value(&ctx, &parent_col)? {
+                    Some(v) => Some((col, v)),
+                    None => return Ok(rows(vec![])),
+                },
+                None => None,
+            };
+            let exec = ctx.data::<Executor>()?;
+            let res = exec
+                .run(move |conn, _| {
+                    select(
+                        conn,
+                        &t,
+                        link.as_ref().map(|(c, v)| (c.as_str(), v.to_owned())),
+                        &args,
+                    )
+                })
+                .await?;
+            Ok(rows(res))
+        })
+    });
+    with_list_args(field, &table.name, limits)
+}
+
+/// The row of a table with a value in a column, taken from an argument or from the parent row
+fn one_field(name: &str, table: &Arc<Table>, col: &str, parent_col: Option<String>) -> Field {
+    let t = table.clone();
+    let col = col.to_string();
+    let arg = parent_col.is_none().then(|| col.to_owned());
+    let mut field = Field::new(name, TypeRef::named(&table.name), move |ctx| {
+        let t = t.clone();
+        let col = col.to_owned();
+        let parent_col = parent_col.to_owned();
+        FieldFuture::new(async move {
+            let v = match &parent_col {
+                Some(parent_col) => match parent_value(&ctx, parent_col)? {
+                    Some(v) => v,
+                    None => return Ok(None),
+                },
+                None => gql2db(ctx.args.try_get(&col)?.as_value()),
+            };
+            let exec = ctx.data::<Executor>()?;
+            let res = exec
+                .run(move |conn, _| {
+                    let args = ListArgs {
+                        limit: Some(1),
+                        ..Default::default()
+                    };
+                    select(conn, &t, Some((&col, v)), &args)
+                })
+                .await?;
+            Ok(first_row(res))
+        })
+    });
+    if let Some(arg) = arg {
+        let c = table.column(&arg).unwrap();
+        field = field.argument(InputValue::new(arg, TypeRef::named_nn(c.scalar)));
+    }
+    field
+}
+
+fn count_field(table: &Arc<Table>) -> Field {
+    let t = table.clone();
+    Field::new(
+        format!("{}_count", table.name),
+        TypeRef::named_nn(TypeRef::INT),
+        move |ctx| {
+            let t = t.clone();
+            FieldFuture::new(async move {
+                let filter = ctx.args.get("where").map(|v| v.as_value().to_owned());
+                let exec = ctx.data::<Executor>()?;
+                let res = exec.run(move |conn, _| count(conn, &t, &filter)).await?;
+                Ok(Some(FieldValue::value(res)))
+            })
+        },
+    )
+    .argument(InputValue::new(
+        "where",
+        TypeRef::named(format!("{}_filter", table.name)),
+    ))
+}
+
+fn mutation_fields(table: &Arc<Table>) -> Vec<Field> {
+    let name = &table.name;
+    let mut fields = vec![];
+
+    let t = table.clone();
+    fields.push(
+        Field::new(
+            format!("insert_{}", name),
+            TypeRef::named_nn_list_nn(name),
+            move |ctx| {
+                let t = t.clone();
+                FieldFuture::new(async move {
+                    let objects = ctx.args.try_get("objects")?.as_value().to_owned();
+                    let exec = ctx.data::<Executor>()?;
+                    let res = exec
+                        .run(move |conn, audit| insert(conn, &t, &objects, audit))
+                        .await?;
+                    Ok(rows(res))
+                })
+            },
+        )
+        .argument(InputValue::new(
+            "objects",
+            TypeRef::named_nn_list_nn(format!("{}_input", name)),
+        )),
+    );
+
+    let t = table.clone();
+    fields.push(
+        Field::new(
+            format!("update_{}", name),
+            TypeRef::named_nn_list_nn(name),
+            move |ctx| {
+                let t = t.clone();
+                FieldFuture::new(async move {
+                    let filter = Some(ctx.args.try_get("where")?.as_value().to_owned());
+                    let set = ctx.args.try_get("set")?.as_value().to_owned();
+                    let exec = ctx.data::<Executor>()?;
+                    let res = exec
+                        .run(move |conn, audit| update(conn, &t, None, &filter, &set, audit))
+                        .await?;
+                    Ok(rows(res))
+                })
+            },
+        )
+        .argument(InputValue::new(
+            "where",
+            TypeRef::named_nn(format!("{}_filter", name)),
+        ))
+        .argument(InputValue::new(
+            "set",
+            TypeRef::named_nn(format!("{}_input", name)),
+        )),
+    );
+
+    let t = table.clone();
+    fields.push(
+        Field::new(
+            format!("delete_{}", name),
+            TypeRef::named_nn_list_nn(name),
+            move |ctx| {
+                let t = t.clone();
+                FieldFuture::new(async move {
+                    let filter = Some(ctx.args.try_get("where")?.as_value().to_owned());
+                    let exec = ctx.data::<Executor>()?;
+                    let res = exec
+                        .run(move |conn, audit| delete(conn, &t, None, &filter, audit))
+                        .await?;
+                    Ok(rows(res))
+                })
+            },
+        )
+        .argument(InputValue::new(
+            "where",
+            TypeRef::named_nn(format!("{}_filter", name)),
+        )),
+    );
+
+    let pk = match table.pk {
+        Some(pk) => &table.columns[pk],
+        None => return fields,
+    };
+
+    let t = table.clone();
+    let col = pk.name.to_owned();
+    fields.push(
+        Field::new(
+            format!("update_{}_by_pk", name),
+            TypeRef::named(name),
+            move |ctx| {
+                let t = t.clone();
+                let col = col.to_owned();
+                FieldFuture::new(async move {
+                    let v = gql2db(ctx.args.try_get(&col)?.as_value());
+                    let set = ctx.args.try_get("set")?.as_value().to_owned();
+                    let exec = ctx.data::<Executor>()?;
+                    let res = exec
+                        .run(move |conn, audit| {
+                            update(conn, &t, Some((&col, v)), &None, &set, audit)
+                        })
+                        .await?;
+                    Ok(first_row(res))
+                })
+            },
+        )
+        .argument(InputValue::new(&pk.name, TypeRef::named_nn(pk.scalar)))
+        .argument(InputValue::new(
+            "set",
+            TypeRef::named_nn(format!("{}_input", name)),
+        )),
+    );
+
+    let t = table.clone();
+    let col = pk.name.to_owned();
+    fields.push(
+        Field::new(
+            format!("delete_{}_by_pk", name),
+            TypeRef::named(name),
+            move |ctx| {
+                let t = t.clone();
+                let col = col.to_owned();
+                FieldFuture::new(async move {
+                    let v = gql2db(ctx.args.try_get(&col)?.as_value());
+                    let exec = ctx.data::<Executor>()?;
+                    let res = exec
+                        .run(move |conn, audit| delete(conn, &t, Some((&col, v)), &None, audit))
+                        .await?;
+                    Ok(first_row(res))
+                })
+            },
+        )
+        .argument(InputValue::new(&pk.name, TypeRef::named_nn(pk.scalar))),
+    );
+
+    fields
+}
+
+fn build_schema(
+    tables: Vec<Table>,
+    conf: &GraphQl,
+    mutations: bool,
+) -> std::result::Result<Schema, String> {
+    if tables.is_empty() {
+        return Err("There are no tables or views to expose".to_string());
+    }
+    let limits = Limits {
+        default: conf.default_limit.unwrap_or(DEFAULT_LIMIT) as i64,
+        max: conf.max_limit.unwrap_or(MAX_LIMIT) as i64,
+    };
+    let tables: Vec<Arc<Table>> = tables.into_iter().map(Arc::new).collect();
+    let mut types: Vec<Type> = vec![
+        Scalar::new(VALUE)
+            .description("Any SQLite value; a BLOB is an array of bytes")
+            .into(),
+        Enum::new("OrderDirection").item("ASC").item("DESC").into(),
+    ];
+    for s in SCALARS {
+        let mut cmp = InputObject::new(format!("{}_comparison", s));
+        for op in ["eq", "neq", "gt", "gte", "lt", "lte"] {
+            cmp = cmp.field(InputValue::new(op, TypeRef::named(s)));
+        }
+        cmp = cmp
+            .field(InputValue::new("in", TypeRef::named_nn_list(s)))
+            .field(InputValue::new("is_null", TypeRef::named(TypeRef::BOOLEAN)));
+        if s == TypeRef::STRING || s == VALUE {
+            cmp = cmp
+                .field(InputValue::new("like", TypeRef::named(TypeRef::STRING)))
+                .field(InputValue::new("glob", TypeRef::named(TypeRef::STRING)));
+        }
+        types.push(cmp.into());
+    }
+
+    let mut query = Object::new("Query");
+    let mut mutation = Object::new("Mutation");
+    let mut has_mutations = false;
+    for table in &tables {
+        let name = &table.name;
+
+        let mut obj = Object::new(name);
+        let mut filter = InputObject::new(format!("{}_filter", name))
+            .field(InputValue::new(
+                "and",
+                TypeRef::named_nn_list(format!("{}_filter", name)),
+            ))
+            .field(InputValue::new(
+                "or",
+                TypeRef::named_nn_list(format!("{}_filter", name)),
+            ))
+            .field(InputValue::new(
+                "not",
+                TypeRef::named(format!("{}_filter", name)),
+            ));
+        let mut order = InputObject::new(format!("{}_order", name));
+        let mut input = InputObject::new(format!("{}_input", name));
+        for c in &table.columns {
+            let col = c.name.to_owned();
+            let ty = match c.not_null {
+                true => TypeRef::named_nn(c.scalar),
+                false => TypeRef::named(c.scalar),
+            };
+            obj = obj.field(Field::new(&c.name, ty, move |ctx| {
+                FieldFuture::from_value(
+                    ctx.parent_value
+                        .downcast_ref::<Row>()
+                        .and_then(|row| row.0.get(&col))
+                        .map(|v| db2gql(v.to_owned())),
+                )
+            }));
+            filter = filter.field(InputValue::new(
+                &c.name,
+                TypeRef::named(format!("{}_comparison", c.scalar)),
+            ));
+            order = order.field(InputValue::new(&c.name, TypeRef::named("OrderDirection")));
+            input = input.field(InputValue::new(&c.name, TypeRef::named(c.scalar)));
+        }
+        for r in &table.relations {
+            let target = match tables.iter().find(|t| t.name == r.table) {
+                Some(target) => target,
+                None => continue,
+            };
+            let link = (r.column.to_owned(), r.ref_column.to_owned());
+            obj = obj.field(match r.many {
+                true => list_field(&r.field, target, Some(link), limits),
+                false => one_field(&r.field, target, &r.ref_column, Some(r.column.to_owned())),
+            });
+        }
+        types.extend([obj.into(), filter.into(), order.into()]);
+
+        query = query
+            .field(list_field(name, table, None, limits))
+            .field(count_field(table));
+        if let Some(pk) = table.pk {
+            query = query.field(one_field(
+                &format!("{}_by_pk", name),
+                table,
+                &table.columns[pk].name,
+                None,
+            ));
+        }
+
+        if mutations && !table.is_view {
+            for field in mutation_fields(table) {
+                mutation = mutation.field(field);
+            }
+            types.push(input.into());
+            has_mutations = true;
+        }
+    }
+
+    let mut builder = Schema::build("Query", has_mutations.then_some("Mutation"), None)
+        .register(query)
+        .limit_depth(MAX_DEPTH)
+        .limit_complexity(conf.max_complexity.unwrap_or(DEFAULT_MAX_COMPLEXITY));
+    if has_mutations {
+        builder = builder.register(mutation);
+    }
+    for t in types {
+        builder = builder.register(t);
+    }
+    builder.finish().map_err(|e| e.to_string())
+}
+
+/// The schema of a database, from the cache if it didn't change since it was generated
+fn schema(
+    db_name: &str,
+    conn: &Connection,
+    conf: &GraphQl,
+    read_only: bool,
+) -> std::result::Result<Schema, String> {
+    let version: i64 = conn
+        .query_row("PRAGMA schema_version", [], |row| row.get(0))
+        .map_err(|e| e.to_string())?;
+    let mut cache = schemas().lock().unwrap();
+    if let Some((v, schema)) = cache.get(db_name) {
+        if *v == version {
+            return Ok(schema.clone());
+        }
+    }
+    let tables = introspect(conn, conf).map_err(|e| e.to_string())?;
+    let schema = build_schema(tables, conf, !read_only)?;
+    cache.insert(db_name.to_string(), (version, schema.clone()));
+    Ok(schema)
+}
+
+/// Holds the transaction of a request: sends the schema, runs the jobs until the request is
+/// finished, then commits or rolls back. If the request is dropped, it rolls back.
+fn worker(
+    db_name: String,
+    conf: GraphQl,
+    read_only: bool,
+    user: Option<String>,
+    ready: oneshot::Sender<std::result::Result<Schema, (u16, String)>>,
+    jobs: mpsc::Receiver<Message>,
+) {
+    let start = Instant::now();
+    let mut db_lock_guard = match lock_db(&db_name) {
+        Some(guard) => guard,
+        None => {
+            let _ = ready.send(Err((503, busy_msg(&db_name))));
+            return;
+        }
+    };
+    let lock_wait = start.elapsed();
+    let conn = db_lock_guard.deref_mut();
+    let tx = match conn.transaction() {
+        Ok(tx) => tx,
+        Err(e) => {
+            let _ = ready.send(Err((500, e.to_string())));
+            return;
+        }
+    };
+
+    match schema(&db_name, &tx, &conf, read_only) {
+        Ok(schema) => {
+            let _ = ready.send(Ok(schema));
+        }
+        Err(msg) => {
+            let _ = ready.send(Err((500, msg)));
+            return;
+        }
+    }
+
+    let mut audit = vec![];
+    let (commit, done) = loop {
+        match jobs.recv() {
+            Ok(Message::Job(job)) => job(&tx, &mut audit),
+            Ok(Message::Finish(commit, done)) => break (commit, done),
+            Err(_) => return,
+        }
+    };
+
+    if !commit {
+        let _ = done.send(tx.rollback().map_err(|e| e.to_string()));
+        return;
+    }
+    if let Err(e) = changes::write_outbox(&db_name, &tx) {
+        let _ = done.send(Err(e.to_string()));
+        return;
+    }
+    if let Err(e) = tx.commit() {
+        let _ = done.send(Err(e.to_string()));
+        return;
+    }
+    if !audit.is_empty() {
+        changes::publish(&db_name, conn);
+        audit::record(&db_name, user.as_deref(), "graphql", audit);
+    }
+    record_process(&db_name, start.elapsed(), lock_wait);
+    let _ = done.send(Ok(()));
+}
+
+pub async fn handler(
+    req: HttpRequest,
+    body: web::Bytes,
+    db_conf: web::Data<Db>,
+    db_name: web::Data<String>,
+) -> Either<Response, HttpResponse> {
+    let db_name = db_name.to_string();
+    let err = |code: u16, msg: String| Either::Left(Response::new_err(code, -1, msg));
+    let conf = match &db_conf.conf.graphql {
+        Some(conf) => conf.to_owned(),
+        None => {
+            return err(
+                404,
+                format!("Database '{}' doesn't have a graphql node", db_name),
+            )
+        }
+    };
+
+    // with INLINE auth, the credentials are in the body, next to the query
+    let mut body: JsonValue = match serde_json::from_slice(&body) {
+        Ok(body) => body,
+        Err(e) => return err(400, e.to_string()),
+    };
+    let credentials = match body
+        .as_object_mut()
+        .and_then(|o| o.remove("credentials"))
+        .map(serde_json::from_value::<ReqCredentials>)
+        .transpose()
+    {
+        Ok(credentials) => credentials,
+        Err(e) => return err(400, e.to_string()),
+    };
+    let request: GqlRequest = match serde_json::from_value(body) {
+        Ok(request) => request,
+        Err(e) => return err(400, e.to_string()),
+    };
+
+    let mut user = None;
+    if let Some(ac) = &db_conf.conf.auth {
+        let ac_headers = Authorization::<Basic>::parse(&req).ok();
+        match process_auth_async(ac, &credentials, &ac_headers, &db_name).await {
+            Ok(Some(u)) => user = Some(u),
+            Err(msg) => return err(503, msg),
+            Ok(None) => {
+                record_auth_failure(&db_name, "credentials");
+                sleep(Duration::from_millis(1000)).await;
+
+                return err(ac.auth_error_code, "Authorization failed".to_string());
+            }
+        }
+    }
+    req.extensions_mut().insert(AccessInfo {
+        user: user.to_owned(),
+        items: 1,
+    });
+
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let (jobs_tx, jobs_rx) = mpsc::channel();
+    let read_only = db_conf.conf.read_only;
+    let worker_db_name = db_name.to_owned();
+    spawn_blocking(move || worker(worker_db_name, conf, read_only, user, ready_tx, jobs_rx));
+    let schema = match ready_rx.await {
+        Ok(Ok(schema)) => schema,
+        Ok(Err((code, msg))) => return err(code, msg),
+        Err(_) => return err(500, "The transaction is closed".to_string()),
+    };
+
+    let exec = Executor(jobs_tx);
+    let mut res = schema.execute(request.data(exec.clone())).await;
+    let (done_tx, done_rx) = oneshot::channel();
+    let outcome = match exec.0.send(Message::Finish(res.is_ok(), done_tx)) {
+        Ok(_) => done_rx
+            .await
+            .unwrap_or_else(|_| Err("The transaction is closed".to_string())),
+        Err(_) => Err("The transaction is closed".to_string()),
+    };
+    if let Err(msg) = outcome {
+        res = GqlResponse::from_errors(vec![ServerError::new(msg, None)]);
+    }
+
+    Either::Right(HttpResponse::Ok().json(res))
+}
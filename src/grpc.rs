@@ -0,0 +1,518 @@
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// A gRPC service, for service-to-service calls: Execute is the transaction protocol (as for
+// POST /<db>) with typed values, Query streams the rows of a single query in batches. The
+// messages and the server are generated from proto/sqliterg.proto (see build.rs).
+//
+// It runs on its own tokio runtime; the calls are executed on its blocking threads, as the
+// connections to the databases are synchronous.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    mem::take,
+    net::TcpListener,
+    ops::DerefMut,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use actix_web::http::header::HeaderValue;
+use actix_web_httpauth::headers::authorization::{Authorization, Basic, Scheme};
+use prost::Message;
+use rusqlite::types::Value as SqlValue;
+use serde_json::{Map as JsonMap, Value as JsonValue};
+use tokio::{
+    runtime,
+    sync::mpsc::{self, error::TrySendError},
+    task::spawn_blocking,
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{
+    metadata::{MetadataMap, MetadataValue},
+    transport::{server::TcpIncoming, Server},
+    Status,
+};
+
+use crate::{
+    access_log::log_slow_query,
+    audit::{self, AuditedStatement},
+    auth::process_auth,
+    changes,
+    commons::check_stored_stmt,
+    logic::{self, busy_msg, lock_db, val_db2val_json, Params},
+    main_config::Db,
+    metrics::{record_auth_failure, record_process},
+    req_res::{self, ReqCredentials, ReqTransactionItem, TypedParams, TypedValues},
+};
+
+mod pb {
+    tonic::include_proto!("sqliterg");
+}
+
+use pb::{
+    sqliterg_server::{Sqliterg, SqlitergServer},
+    transaction_item, value, Credentials, QueryRequest, QueryResponse, Request, Response,
+    ResponseItem, Row, TransactionItem, Value, Values,
+};
+
+const DEFAULT_BATCH_SIZE: usize = 500;
+// batches buffered for a streaming client
+const STREAM_BUFFER: usize = 4;
+// size of the batches kept aside while the query runs, for a client that reads slower than
+// that; then the query fails, rather than taking unbounded memory
+const MAX_PENDING_SIZE: usize = 64 * 1024 * 1024;
+// a streaming client that doesn't read for this long is dropped
+const STREAM_SEND_TIMEOUT: Duration = Duration::from_secs(30);
+
+// the values
+
+fn val_grpc2val_db(val: Value) -> SqlValue {
+    match val.kind {
+        None | Some(value::Kind::Null(_)) => SqlValue::Null,
+        Some(value::Kind::Integer(v)) => SqlValue::Integer(v),
+        Some(value::Kind::Real(v)) => SqlValue::Real(v),
+        Some(value::Kind::Text(v)) => SqlValue::Text(v),
+        Some(value::Kind::Blob(v)) => SqlValue::Blob(v),
+        Some(value::Kind::Boolean(v)) => SqlValue::Integer(v as i64),
+    }
+}
+
+fn val_db2val_grpc(val: SqlValue) -> Value {
+    let kind = match val {
+        SqlValue::Null => value::Kind::Null(true),
+        SqlValue::Integer(v) => value::Kind::Integer(v),
+        SqlValue::Real(v) => value::Kind::Real(v),
+        SqlValue::Text(v) => value::Kind::Text(v),
+        SqlValue::Blob(v) => value::Kind::Blob(v),
+    };
+    Value { kind: Some(kind) }
+}
+
+fn row(values: Vec<SqlValue>) -> Row {
+    Row {
+        values: values.into_iter().map(val_db2val_grpc).collect(),
+    }
+}
+
+/// None if there are no values
+fn typed_params(values: Values) -> Result<Option<TypedParams>, String> {
+    if !values.named.is_empty() && !values.positional.is_empty() {
+        return Err("Values are both positional and named".to_string());
+    }
+    Ok(if !values.named.is_empty() {
+        Some(TypedParams::Named(
+            values
+                .named
+                .into_iter()
+                .map(|(k, v)| (format!(":{}", k), val_grpc2val_db(v)))
+                .collect(),
+        ))
+    } else if !values.positional.is_empty() {
+        Some(TypedParams::Positional(
+            values.positional.into_iter().map(val_grpc2val_db).collect(),
+        ))
+    } else {
+        None
+    })
+}
+
+/// As the values of a JSON request, for the audit and the slow query log
+fn to_json(params: &TypedParams) -> JsonValue {
+    match params {
+        TypedParams::Positional(p) => {
+            JsonValue::Array(p.iter().cloned().map(val_db2val_json).collect())
+        }
+        TypedParams::Named(p) => JsonValue::Object(
+            p.iter()
+                .map(|(k, v)| (k[1..].to_string(), val_db2val_json(v.to_owned())))
+                .collect::<JsonMap<String, JsonValue>>(),
+        ),
+    }
+}
+
+fn to_req_item(item: TransactionItem) -> Result<ReqTransactionItem, String> {
+    let (query, statement) = match item.kind {
+        Some(transaction_item::Kind::Query(q)) => (Some(q), None),
+        Some(transaction_item::Kind::Statement(s)) => (None, Some(s)),
+        None => (None, None),
+    };
+    if query.is_some() && !item.values_batch.is_empty() {
+        return Err("values_batch is only for statements".to_string());
+    }
+    let values = item.values.map(typed_params).transpose()?.flatten();
+    let values_batch = match item.values_batch.is_empty() {
+        true => None,
+        false => Some(
+            item.values_batch
+                .into_iter()
+                .map(|v| Ok(typed_params(v)?.unwrap_or(TypedParams::Positional(vec![]))))
+                .collect::<Result<Vec<TypedParams>, String>>()?,
+        ),
+    };
+    Ok(ReqTransactionItem {
+        no_fail: item.no_fail,
+        query,
+        statement,
+        values: values.as_ref().map(to_json),
+        values_batch: values_batch
+            .as_ref()
+            .map(|b| b.iter().map(to_json).collect()),
+        typed: Some(TypedValues {
+            values,
+            values_batch,
+        }),
+    })
+}
+
+fn to_grpc_item(item: req_res::ResponseItem) -> ResponseItem {
+    let (columns, result_set) = match item.typed_result_set {
+        Some(rs) => (rs.columns, rs.rows.into_iter().map(row).collect()),
+        None => (vec![], vec![]),
+    };
+    ResponseItem {
+        success: item.success,
+        error: item.error,
+        columns,
+        result_set,
+        rows_updated: item.rows_updated.map(|n| n as i64),
+        rows_updated_batch: item
+            .rows_updated_batch
+            .unwrap_or_default()
+            .into_iter()
+            .map(|n| n as i64)
+            .collect(),
+    }
+}
+
+// the calls
+
+fn status(code: u16, msg: String) -> Status {
+    match code {
+        400 => Status::invalid_argument(msg),
+        409 => Status::failed_precondition(msg),
+        503 => Status::unavailable(msg),
+        _ => Status::internal(msg),
+    }
+}
+
+fn with_req_idx(mut status: Status, idx: usize) -> Status {
+    status
+        .metadata_mut()
+        .insert("sqliterg-req-idx", MetadataValue::from(idx));
+    status
+}
+
+fn internal(e: impl ToString) -> Status {
+    Status::internal(e.to_string())
+}
+
+/// Authenticates a call, with the "authorization" metadata (HTTP_BASIC) or the credentials
+/// in the request (INLINE). Returns the user, if the database has auth.
+fn authenticate(
+    db_name: &str,
+    db: &Db,
+    metadata: &MetadataMap,
+    credentials: Option<Credentials>,
+) -> Result<Option<String>, Status> {
+    let ac = match &db.conf.auth {
+        Some(ac) => ac,
+        None => return Ok(None),
+    };
+    let ac_headers = metadata
+        .get("authorization")
+        .and_then(|v| HeaderValue::from_bytes(v.as_bytes()).ok())
+        .and_then(|h| Basic::parse(&h).ok())
+        .map(Authorization::from);
+    let credentials = credentials.map(|c| ReqCredentials {
+        user: c.user,
+        password: c.password,
+    });
+    match process_auth(ac, &credentials, &ac_headers, db_name) {
+        Ok(Some(u)) => Ok(Some(u)),
+        Err(msg) => Err(Status::unavailable(msg)),
+        Ok(None) => {
+            record_auth_failure(db_name, "credentials");
+            thread::sleep(Duration::from_millis(1000));
+
+            Err(Status::unauthenticated("Authorization failed"))
+        }
+    }
+}
+
+fn find_db<'a>(dbs: &'a HashMap<String, Arc<Db>>, db_name: &str) -> Result<&'a Db, Status> {
+    dbs.get(db_name)
+        .map(|db| db.as_ref())
+        .ok_or_else(|| Status::not_found(format!("Database '{}' not found", db_name)))
+}
+
+/// Executes a transaction, as a request to POST /<db> with the typed values
+fn execute(
+    dbs: &HashMap<String, Arc<Db>>,
+    metadata: &MetadataMap,
+    req: Request,
+) -> Result<Response, Status> {
+    let db_name = req.db.as_str();
+    let db = find_db(dbs, db_name)?;
+    let user = authenticate(db_name, db, metadata, req.credentials)?;
+    let transaction = req
+        .transaction
+        .into_iter()
+        .enumerate()
+        .map(|(idx, item)| {
+            to_req_item(item).map_err(|msg| with_req_idx(Status::invalid_argument(msg), idx))
+        })
+        .collect::<Result<Vec<ReqTransactionItem>, Status>>()?;
+    let http_req = req_res::Request {
+        credentials: None,
+        transaction,
+    };
+
+    let res = logic::process(
+        db_name,
+        &http_req,
+        &db.stored_statements,
+        &db.conf,
+        user,
+        "grpc",
+        None,
+    )
+    .map_err(internal)?;
+    if !res.success {
+        let status = status(res.status_code, res.message.unwrap_or_default());
+        return Err(match res.req_idx {
+            Some(idx) if idx >= 0 => with_req_idx(status, idx as usize),
+            _ => status,
+        });
+    }
+    Ok(Response {
+        results: res
+            .results
+            .unwrap_or_default()
+            .into_iter()
+            .map(to_grpc_item)
+            .collect(),
+    })
+}
+
+type QueryStream = ReceiverStream<Result<QueryResponse, Status>>;
+
+/// Executes a query, sending its rows in batches. The batches that the client doesn't take
+/// while the query runs are kept aside (up to MAX_PENDING_SIZE) and sent after the
+/// database is released, so that a slow client doesn't hold it. Returns false if the client
+/// went away.
+fn stream_query(
+    dbs: &HashMap<String, Arc<Db>>,
+    metadata: &MetadataMap,
+    req: QueryRequest,
+    sender: &mpsc::Sender<Result<QueryResponse, Status>>,
+) -> Result<bool, Status> {
+    let db_name = req.db.as_str();
+    let db = find_db(dbs, db_name)?;
+    let user = authenticate(db_name, db, metadata, req.credentials)?;
+    let typed = match req.values {
+        Some(values) => typed_params(values).map_err(Status::invalid_argument)?,
+        None => None,
+    };
+    let params = typed.as_ref().map(Params::from_typed);
+    let sql = check_stored_stmt(
+        &req.query,
+        &db.stored_statements,
+        db.conf.use_only_stored_statements,
+    )
+    .map_err(|e| Status::failed_precondition(e.to_string()))?;
+    let batch_size = match req.batch_size {
+        0 => DEFAULT_BATCH_SIZE,
+        n => n as usize,
+    };
+    let mut pending = VecDeque::new();
+    let mut pending_size = 0;
+    // false if the client went away
+    let mut send = |msg: QueryResponse| -> Result<bool, Status> {
+        let msg = if pending.is_empty() {
+            match sender.try_send(Ok(msg)) {
+                Ok(()) => return Ok(true),
+                Err(TrySendError::Full(Ok(msg))) => msg,
+                Err(_) => return Ok(false),
+            }
+        } else {
+            msg
+        };
+        pending_size += msg.encoded_len();
+        if pending_size > MAX_PENDING_SIZE {
+            return Err(Status::resource_exhausted(
+                "The client doesn't read the rows fast enough",
+            ));
+        }
+        pending.push_back(msg);
+        Ok(true)
+    };
+
+    let start = Instant::now();
+    let mut db_lock_guard =
+        lock_db(db_name).ok_or_else(|| Status::unavailable(busy_msg(db_name)))?;
+    let lock_wait = start.elapsed();
+    let conn = db_lock_guard.deref_mut();
+    let tx = conn.transaction().map_err(internal)?;
+
+    let mut stmt = tx.prepare(sql).map_err(internal)?;
+    let is_write = !stmt.readonly();
+    let mut msg = QueryResponse {
+        columns: stmt.column_names().iter().map(|c| c.to_string()).collect(),
+        rows: vec![],
+    };
+    let columns = msg.columns.len();
+    let mut rows = match &params {
+        Some(p) => p.query(&mut stmt),
+        None => stmt.query([]),
+    }
+    .map_err(internal)?;
+    let mut first = true;
+    while let Some(r) = rows.next().map_err(internal)? {
+        msg.rows
+            .push(row((0..columns).map(|i| r.get_unwrap(i)).collect()));
+        if msg.rows.len() == batch_size {
+            // the transaction is rolled back when dropped
+            if !send(take(&mut msg))? {
+                return Ok(false);
+            }
+            first = false;
+        }
+    }
+    if (first || !msg.rows.is_empty()) && !send(msg)? {
+        return Ok(false);
+    }
+    drop(rows);
+    drop(stmt);
+
+    let values = typed.as_ref().map(to_json);
+    if let Some(threshold) = db.conf.slow_query_ms {
+        let elapsed = start.elapsed() - lock_wait;
+        if elapsed >= Duration::from_millis(threshold) {
+            let logged = ReqTransactionItem {
+                no_fail: false,
+                query: Some(req.query.to_owned()),
+                statement: None,
+                values: values.to_owned(),
+                values_batch: None,
+                typed: None,
+            };
+            log_slow_query(db_name, 0, &logged, elapsed);
+        }
+    }
+
+    if is_write {
+        changes::write_outbox(db_name, &tx).map_err(internal)?;
+    }
+    tx.commit().map_err(internal)?;
+    if is_write {
+        changes::publish(db_name, conn);
+        if audit::is_enabled(db_name) {
+            let mut a = AuditedStatement::new(0, &req.query);
+            a.values = values;
+            audit::record(db_name, user.as_deref(), "grpc", vec![a]);
+        }
+    }
+    record_process(db_name, start.elapsed(), lock_wait);
+    drop(db_lock_guard);
+
+    let rt = runtime::Handle::current();
+    for msg in pending {
+        if rt
+            .block_on(sender.send_timeout(Ok(msg), STREAM_SEND_TIMEOUT))
+            .is_err()
+        {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+// the server
+
+struct Service {
+    dbs: Arc<HashMap<String, Arc<Db>>>,
+}
+
+#[tonic::async_trait]
+impl Sqliterg for Service {
+    async fn execute(
+        &self,
+        request: tonic::Request<Request>,
+    ) -> Result<tonic::Response<Response>, Status> {
+        let dbs = self.dbs.clone();
+        let (metadata, _, req) = request.into_parts();
+        spawn_blocking(move || execute(&dbs, &metadata, req))
+            .await
+            .map_err(internal)?
+            .map(tonic::Response::new)
+    }
+
+    type QueryStream = QueryStream;
+
+    async fn query(
+        &self,
+        request: tonic::Request<QueryRequest>,
+    ) -> Result<tonic::Response<Self::QueryStream>, Status> {
+        let dbs = self.dbs.clone();
+        let (metadata, _, req) = request.into_parts();
+        let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+        spawn_blocking(move || {
+            if let Err(status) = stream_query(&dbs, &metadata, req, &sender) {
+                // after the batches that were sent, that the client may not read
+                let _ = runtime::Handle::current()
+                    .block_on(sender.send_timeout(Err(status), STREAM_SEND_TIMEOUT));
+            }
+        });
+        Ok(tonic::Response::new(ReceiverStream::new(receiver)))
+    }
+}
+
+/// Binds the port and serves the gRPC API, on a thread with its own runtime
+pub fn start(bind_host: &str, port: u16, db_map: &HashMap<String, Db>) -> io::Result<()> {
+    let listener = TcpListener::bind((bind_host, port))?;
+    listener.set_nonblocking(true)?;
+    let service = Service {
+        dbs: Arc::new(
+            db_map
+                .iter()
+                .map(|(name, db)| (name.to_owned(), Arc::new(db.to_owned())))
+                .collect(),
+        ),
+    };
+    let rt = runtime::Builder::new_multi_thread()
+        .thread_name("grpc")
+        .enable_all()
+        .build()?;
+    thread::spawn(move || {
+        rt.block_on(async move {
+            let incoming = match tokio::net::TcpListener::from_std(listener) {
+                Ok(l) => TcpIncoming::from(l),
+                Err(e) => {
+                    eprintln!("gRPC listener: {}", e);
+                    return;
+                }
+            };
+            if let Err(e) = Server::builder()
+                .serve_with_incoming(SqlitergServer::new(service), incoming)
+                .await
+            {
+                eprintln!("gRPC server: {}", e);
+            }
+        })
+    });
+    Ok(())
+}
@@ -0,0 +1,240 @@
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{read_to_string, rename, write},
+    io::ErrorKind,
+    sync::{Mutex, OnceLock},
+    time::Instant,
+};
+
+use chrono::{DateTime, Utc};
+
+use serde_json::Value as JsonValue;
+
+use crate::{
+    metrics::record_job_run,
+    req_res::Response,
+    telemetry::{Span, SpanContext},
+};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum Trigger {
+    #[serde(rename = "STARTUP")]
+    Startup,
+    #[serde(rename = "SCHEDULE")]
+    Schedule,
+    #[serde(rename = "CATCH_UP")]
+    CatchUp,
+    #[serde(rename = "WEB_SERVICE")]
+    WebService,
+    #[serde(rename = "SHUTDOWN")]
+    Shutdown,
+    #[serde(rename = "BACKUP_HOOK")]
+    BackupHook,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Run {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub trigger: Trigger,
+    pub success: bool,
+    #[serde(rename = "rowsUpdated")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rows_updated: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct JobHistory {
+    #[serde(rename = "lastScheduledRun")]
+    last_scheduled_run: Option<DateTime<Utc>>,
+    #[serde(default)]
+    runs: VecDeque<Run>,
+}
+
+struct DbHistory {
+    state_file: Option<String>,
+    size: usize,
+    jobs: HashMap<String, JobHistory>,
+}
+
+impl DbHistory {
+    /// Writes a temporary file and renames it over the state file, so that a crash while
+    /// writing doesn't leave it corrupted
+    fn save(&self) {
+        if let Some(state_file) = &self.state_file {
+            let tmp_file = format!("{}.tmp", state_file);
+            let res = serde_json::to_string(&self.jobs)
+                .map_err(|e| eyre!(e))
+                .and_then(|s| write(&tmp_file, s).map_err(|e| eyre!(e)))
+                .and_then(|_| rename(&tmp_file, state_file).map_err(|e| eyre!(e)));
+            if let Err(e) = res {
+                eprintln!("Saving state file '{}': {}", state_file, e);
+            }
+        }
+    }
+}
+
+pub const DEFAULT_HISTORY_SIZE: usize = 50;
+
+static HISTORIES: OnceLock<Mutex<HashMap<String, DbHistory>>> = OnceLock::new();
+
+fn histories() -> &'static Mutex<HashMap<String, DbHistory>> {
+    HISTORIES.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// A macro or the backup of a database, whose runs are tracked
+#[derive(Debug, Clone)]
+pub struct JobId {
+    pub db_name: String,
+    pub key: String,
+}
+
+impl JobId {
+    pub fn for_macro(db_name: &str, macro_id: &str) -> JobId {
+        JobId {
+            db_name: db_name.to_string(),
+            key: format!("macro:{}", macro_id),
+        }
+    }
+
+    pub fn for_backup(db_name: &str) -> JobId {
+        JobId {
+            db_name: db_name.to_string(),
+            key: "backup".to_string(),
+        }
+    }
+}
+
+fn load(state_file: &str) -> eyre::Result<HashMap<String, JobHistory>> {
+    let s = match read_to_string(state_file) {
+        Ok(s) => s,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(eyre!("Reading state file '{}': {}", state_file, e)),
+    };
+    serde_json::from_str(&s).map_err(|e| {
+        eyre!(
+            "Parsing state file '{}': {}; delete it to start with an empty history",
+            state_file,
+            e
+        )
+    })
+}
+
+/// Sets up the history of a database, loading it from the state file (if any). At most
+/// `size` runs are kept for each job. A state file that can't be read is an error, because
+/// without the last runs the missed ones wouldn't be caught up.
+pub fn init(db_name: &str, state_file: Option<String>, size: usize) -> eyre::Result<()> {
+    let jobs = match &state_file {
+        Some(sf) => load(sf)?,
+        None => HashMap::new(),
+    };
+    histories().lock().unwrap().insert(
+        db_name.to_string(),
+        DbHistory {
+            state_file,
+            size,
+            jobs,
+        },
+    );
+    Ok(())
+}
+
+pub fn last_scheduled_run(job: &JobId) -> Option<DateTime<Utc>> {
+    let histories = histories().lock().unwrap();
+    histories
+        .get(&job.db_name)
+        .and_then(|h| h.jobs.get(&job.key))
+        .and_then(|jh| jh.last_scheduled_run)
+}
+
+/// Most recent first
+pub fn runs(job: &JobId) -> Vec<Run> {
+    let histories = histories().lock().unwrap();
+    histories
+        .get(&job.db_name)
+        .and_then(|h| h.jobs.get(&job.key))
+        .map(|jh| jh.runs.iter().rev().cloned().collect())
+        .unwrap_or_default()
+}
+
+fn record(job: &JobId, run: Run) {
+    let mut histories = histories().lock().unwrap();
+    if let Some(h) = histories.get_mut(&job.db_name) {
+        let size = h.size;
+        let jh = h.jobs.entry(job.key.to_owned()).or_default();
+        if matches!(run.trigger, Trigger::Schedule | Trigger::CatchUp) {
+            jh.last_scheduled_run = Some(run.start);
+        }
+        jh.runs.push_back(run);
+        while jh.runs.len() > size {
+            jh.runs.pop_front();
+        }
+        h.save();
+    }
+}
+
+/// Executes a job, recording its outcome in the history
+pub fn track<F>(job: &JobId, trigger: Trigger, f: F) -> Response
+where
+    F: FnOnce() -> Response,
+{
+    track_with_parent(job, trigger, None, f)
+}
+
+/// As track, with the span of the run child of `parent` (e.g. of a request to a web service)
+pub fn track_with_parent<F>(
+    job: &JobId,
+    trigger: Trigger,
+    parent: Option<SpanContext>,
+    f: F,
+) -> Response
+where
+    F: FnOnce() -> Response,
+{
+    let mut span = Span::start(&job.key, parent);
+    span.set_str("sqliterg.db", &job.db_name);
+    if let Ok(JsonValue::String(t)) = serde_json::to_value(trigger) {
+        span.set_str("sqliterg.trigger", &t);
+    }
+
+    let start = Utc::now();
+    let started = Instant::now();
+    let res = span.in_scope(f);
+    record_job_run(&job.db_name, &job.key, res.success, started.elapsed());
+    let rows_updated = res.results.as_ref().and_then(|items| {
+        let rows: Vec<usize> = items.iter().filter_map(|i| i.rows_updated).collect();
+        (!rows.is_empty()).then(|| rows.iter().sum())
+    });
+    if !res.success {
+        span.set_error(res.message.as_deref().unwrap_or("unknown error"));
+    }
+    span.end();
+    record(
+        job,
+        Run {
+            start,
+            end: Utc::now(),
+            trigger,
+            success: res.success,
+            rows_updated,
+            error: res.message.to_owned(),
+        },
+    );
+    res
+}
@@ -12,24 +12,53 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{collections::HashMap, ops::DerefMut, time::Duration};
+use std::{
+    collections::HashMap,
+    ops::DerefMut,
+    sync::MutexGuard,
+    time::{Duration, Instant},
+};
 
-use actix_web::{http::header::Header, rt::time::sleep, web, HttpRequest, Responder};
+use actix_web::{http::header::Header, rt::time::sleep, web, HttpMessage, HttpRequest, Responder};
 use actix_web_httpauth::headers::authorization::{Authorization, Basic};
 use eyre::Result;
-use rusqlite::{types::Value, ToSql, Transaction};
+use rusqlite::{types::Value, Connection, Rows, Statement, ToSql, Transaction};
 use serde_json::{json, Map as JsonMap, Value as JsonValue};
 
 use crate::{
-    auth::process_auth,
+    access_log::{log_slow_query, AccessInfo},
+    audit::{self, AuditedStatement},
+    auth::process_auth_async,
+    changes,
     commons::{check_stored_stmt, NamedParamsContainer, PositionalParamsContainer},
     db_config::{AuthMode, DbConfig},
     main_config::Db,
-    req_res::{self, Response, ResponseItem},
+    metrics::{record_auth_failure, record_process},
+    pgwire,
+    req_res::{self, ReqTransactionItem, Response, ResponseItem, TypedParams, TypedResultSet},
+    telemetry::{request_context, Span, SpanContext},
     MUTEXES,
 };
 
-fn val_db2val_json(val: Value) -> JsonValue {
+/// Locks the connection of a database. It waits for the statements and requests that hold
+/// it, but not for a PostgreSQL client in a transaction, that may keep it for long: None in
+/// that case. It blocks, so in the handlers it must be called in web::block.
+pub fn lock_db(db_name: &str) -> Option<MutexGuard<'static, Connection>> {
+    if pgwire::in_transaction(db_name) {
+        return None;
+    }
+    let db_lock = MUTEXES.get().unwrap().get(db_name).unwrap();
+    Some(db_lock.lock().unwrap())
+}
+
+pub fn busy_msg(db_name: &str) -> String {
+    format!(
+        "Database '{}' is locked by a transaction of a PostgreSQL client",
+        db_name
+    )
+}
+
+pub fn val_db2val_json(val: Value) -> JsonValue {
     match val {
         Value::Null => JsonValue::Null,
         Value::Integer(v) => json!(v),
@@ -71,34 +100,106 @@ fn calc_positional_params(params: &Vec<JsonValue>) -> PositionalParamsContainer
     PositionalParamsContainer::from(ret_params)
 }
 
-#[allow(clippy::type_complexity)]
-fn do_query(
-    tx: &Transaction,
-    sql: &str,
-    values: &Option<JsonValue>,
-) -> Result<(Option<Vec<JsonValue>>, Option<usize>, Option<Vec<usize>>)> {
+pub enum Params {
+    Named(NamedParamsContainer),
+    Positional(PositionalParamsContainer),
+}
+
+impl Params {
+    fn from_json(p: &JsonValue) -> Result<Params> {
+        match p {
+            JsonValue::Object(map) => Ok(Params::Named(calc_named_params(map))),
+            JsonValue::Array(array) => Ok(Params::Positional(calc_positional_params(array))),
+            _ => Err(eyre!("Values are neither positional nor named".to_string())),
+        }
+    }
+
+    pub fn from_typed(p: &TypedParams) -> Params {
+        match p {
+            TypedParams::Named(p) => Params::Named(NamedParamsContainer::from(
+                p.iter()
+                    .map(|(k, v)| (k.to_owned(), Box::new(v.to_owned()) as Box<dyn ToSql>))
+                    .collect::<Vec<_>>(),
+            )),
+            TypedParams::Positional(p) => Params::Positional(PositionalParamsContainer::from(
+                p.iter()
+                    .map(|v| Box::new(v.to_owned()) as Box<dyn ToSql>)
+                    .collect::<Vec<_>>(),
+            )),
+        }
+    }
+
+    /// The values of an item, the typed ones if present
+    fn values(trx_item: &ReqTransactionItem) -> Result<Option<Params>> {
+        match &trx_item.typed {
+            Some(typed) => Ok(typed.values.as_ref().map(Params::from_typed)),
+            None => trx_item.values.as_ref().map(Params::from_json).transpose(),
+        }
+    }
+
+    fn values_batch(trx_item: &ReqTransactionItem) -> Result<Option<Vec<Params>>> {
+        match &trx_item.typed {
+            Some(typed) => Ok(typed
+                .values_batch
+                .as_ref()
+                .map(|b| b.iter().map(Params::from_typed).collect())),
+            None => trx_item
+                .values_batch
+                .as_ref()
+                .map(|b| b.iter().map(Params::from_json).collect())
+                .transpose(),
+        }
+    }
+
+    pub fn query<'a>(&self, stmt: &'a mut Statement) -> rusqlite::Result<Rows<'a>> {
+        match self {
+            Params::Named(p) => stmt.query(p.slice().as_slice()),
+            Params::Positional(p) => stmt.query(p.slice().as_slice()),
+        }
+    }
+
+    fn execute(&self, stmt: &mut Statement) -> rusqlite::Result<usize> {
+        match self {
+            Params::Named(p) => stmt.execute(p.slice().as_slice()),
+            Params::Positional(p) => stmt.execute(p.slice().as_slice()),
+        }
+    }
+}
+
+// result set, typed result set, rows updated, rows updated in a batch
+type Outcome = (
+    Option<Vec<JsonValue>>,
+    Option<TypedResultSet>,
+    Option<usize>,
+    Option<Vec<usize>>,
+);
+
+fn do_query(tx: &Transaction, sql: &str, trx_item: &ReqTransactionItem) -> Result<Outcome> {
+    let values = Params::values(trx_item)?;
     let mut stmt = tx.prepare(sql)?;
     let column_names: Vec<String> = stmt
         .column_names()
         .iter()
         .map(|cn| cn.to_string())
         .collect();
-    let mut rows = match values {
-        Some(p) => {
-            // FIXME this code is repeated three times; I wish I could make a common
-            //       function but, no matter how I try, it seems not to be possible
-            if p.is_object() {
-                let map = p.as_object().unwrap();
-                stmt.query(calc_named_params(map).slice().as_slice())?
-            } else if p.is_array() {
-                let array = p.as_array().unwrap();
-                stmt.query(calc_positional_params(array).slice().as_slice())?
-            } else {
-                return Err(eyre!("Values are neither positional nor named".to_string()));
-            }
-        }
+    let mut rows = match &values {
+        Some(p) => p.query(&mut stmt)?,
         None => stmt.query([])?,
     };
+    if trx_item.typed.is_some() {
+        let mut typed = TypedResultSet {
+            columns: column_names,
+            rows: vec![],
+        };
+        while let Some(row) = rows.next()? {
+            typed.rows.push(
+                (0..typed.columns.len())
+                    .map(|i| row.get_unwrap(i))
+                    .collect(),
+            );
+        }
+        return Ok((None, Some(typed), None, None));
+    }
     let mut response = vec![];
     loop {
         let row = rows.next();
@@ -117,71 +218,71 @@ fn do_query(
             Err(e) => return Err(eyre!(e.to_string())),
         }
     }
-    Ok((Some(response), None, None))
+    Ok((Some(response), None, None, None))
 }
 
-#[allow(clippy::type_complexity)]
-fn do_statement(
-    tx: &Transaction,
-    sql: &str,
-    values: &Option<JsonValue>,
-    values_batch: &Option<Vec<JsonValue>>,
-) -> Result<(Option<Vec<JsonValue>>, Option<usize>, Option<Vec<usize>>)> {
-    Ok(if values.is_none() && values_batch.is_none() {
-        let changed_rows = tx.execute(sql, [])?;
-        (None, Some(changed_rows), None)
-    } else if values.is_some() {
-        let p = values.as_ref().unwrap();
-        let changed_rows = if p.is_object() {
-            let map = p.as_object().unwrap();
-            tx.execute(sql, calc_named_params(map).slice().as_slice())?
-        } else if p.is_array() {
-            let array = p.as_array().unwrap();
-            tx.execute(sql, calc_positional_params(array).slice().as_slice())?
-        } else {
-            return Err(eyre!("Values are neither positional nor named".to_string()));
-        };
-
-        (None, Some(changed_rows), None)
-    } else {
-        // values_batch.is_some()
-        let mut stmt = tx.prepare(sql)?;
-        let mut ret = vec![];
-        for p in values_batch.as_ref().unwrap() {
-            let changed_rows = if p.is_object() {
-                let map = p.as_object().unwrap();
-                stmt.execute(calc_named_params(map).slice().as_slice())?
-            } else if p.is_array() {
-                let array = p.as_array().unwrap();
-                stmt.execute(calc_positional_params(array).slice().as_slice())?
-            } else {
-                return Err(eyre!("Values are neither positional nor named".to_string()));
-            };
-
-            ret.push(changed_rows);
+fn do_statement(tx: &Transaction, sql: &str, trx_item: &ReqTransactionItem) -> Result<Outcome> {
+    let values = Params::values(trx_item)?;
+    let values_batch = Params::values_batch(trx_item)?;
+    Ok(match (values, values_batch) {
+        (None, None) => {
+            let changed_rows = tx.execute(sql, [])?;
+            (None, None, Some(changed_rows), None)
+        }
+        (Some(p), _) => {
+            let changed_rows = p.execute(&mut tx.prepare(sql)?)?;
+            (None, None, Some(changed_rows), None)
+        }
+        (None, Some(batch)) => {
+            let mut stmt = tx.prepare(sql)?;
+            let mut ret = vec![];
+            for p in batch {
+                ret.push(p.execute(&mut stmt)?);
+            }
+            (None, None, None, Some(ret))
         }
-        (None, None, Some(ret))
     })
 }
 
-fn process(
+/// Executes the items of the request in a transaction, on the locked connection
+#[allow(clippy::too_many_arguments)]
+fn execute(
     db_name: &str,
-    http_req: web::Json<req_res::Request>,
+    http_req: &req_res::Request,
     stored_statements: &HashMap<String, String>,
     dbconf: &DbConfig,
+    user: Option<String>,
+    source: &str,
+    span_ctx: Option<SpanContext>,
+    conn: &mut Connection,
 ) -> Result<Response> {
-    let db_lock = MUTEXES.get().unwrap().get(db_name).unwrap();
-    let mut db_lock_guard = db_lock.lock().unwrap();
-    let conn = db_lock_guard.deref_mut();
     let tx = conn.transaction()?;
 
     let mut results = vec![];
     let mut failed: Option<(u16, usize, String)> = None; // http code, index, error
+    let audit_enabled = audit::is_enabled(db_name);
+    let mut audited = vec![];
 
     for (idx, trx_item) in http_req.transaction.iter().enumerate() {
-        #[allow(clippy::type_complexity)]
+        let item_start = Instant::now();
+        let mut item_span = Span::start(
+            if trx_item.query.is_some() {
+                "query"
+            } else {
+                "statement"
+            },
+            span_ctx,
+        );
+        item_span.set_str("db.system", "sqlite");
+        item_span.set_int("sqliterg.item.index", idx as i64);
+        if let Some(text) = trx_item.query.as_ref().or(trx_item.statement.as_ref()) {
+            match text.strip_prefix('^') {
+                Some(id) => item_span.set_str("sqliterg.stored_statement", id),
+                None => item_span.set_str("db.query.text", text),
+            }
+        }
         let ret: Result<
-            (Option<Vec<JsonValue>>, Option<usize>, Option<Vec<usize>>),
+            Outcome,
             (u16, String), // Error: (http code, message)
         > = if trx_item.query.is_some() == trx_item.statement.is_some() {
             Err((
@@ -190,7 +291,7 @@ fn process(
             ))
         } else if let Some(query) = &trx_item.query {
             match check_stored_stmt(query, stored_statements, dbconf.use_only_stored_statements) {
-                Ok(sql) => match do_query(&tx, sql, &trx_item.values) {
+                Ok(sql) => match do_query(&tx, sql, trx_item) {
                     Ok(ok_payload) => Ok(ok_payload),
                     Err(err) => Err((500, err.to_string())),
                 },
@@ -208,7 +309,7 @@ fn process(
                 stored_statements,
                 dbconf.use_only_stored_statements,
             ) {
-                Ok(sql) => match do_statement(&tx, sql, &trx_item.values, &trx_item.values_batch) {
+                Ok(sql) => match do_statement(&tx, sql, trx_item) {
                     Ok(ok_payload) => Ok(ok_payload),
                     Err(err) => Err((500, err.to_string())),
                 },
@@ -216,6 +317,38 @@ fn process(
             }
         };
 
+        if let Err(err) = &ret {
+            item_span.set_error(&err.1);
+        }
+        item_span.end();
+
+        if let Some(threshold) = dbconf.slow_query_ms {
+            let elapsed = item_start.elapsed();
+            if elapsed >= Duration::from_millis(threshold) {
+                log_slow_query(db_name, idx, trx_item, elapsed);
+            }
+        }
+
+        if audit_enabled {
+            if let Ok(val) = &ret {
+                // the item succeeded, so the statement is there and resolves
+                let text = trx_item
+                    .query
+                    .as_ref()
+                    .or(trx_item.statement.as_ref())
+                    .unwrap();
+                let sql = check_stored_stmt(text, stored_statements, false).unwrap();
+                if audit::is_write(&tx, sql) {
+                    let mut a = AuditedStatement::new(idx, text);
+                    a.values = trx_item.values.to_owned();
+                    a.values_batch = trx_item.values_batch.to_owned();
+                    a.rows_updated = val.2;
+                    a.rows_updated_batch = val.3.to_owned();
+                    audited.push(a);
+                }
+            }
+        }
+
         if !trx_item.no_fail {
             if let Err(err) = ret {
                 failed = Some((err.0, idx, err.1));
@@ -228,8 +361,9 @@ fn process(
                 success: true,
                 error: None,
                 result_set: val.0,
-                rows_updated: val.1,
-                rows_updated_batch: val.2,
+                rows_updated: val.2,
+                rows_updated_batch: val.3,
+                typed_result_set: val.1,
             },
             Err(err) => ResponseItem {
                 success: false,
@@ -237,20 +371,60 @@ fn process(
                 result_set: None,
                 rows_updated: None,
                 rows_updated_batch: None,
+                typed_result_set: None,
             },
         });
     }
 
-    Ok(match failed {
+    let res = match failed {
         Some(f) => {
             tx.rollback()?;
             Response::new_err(f.0, f.1 as isize, f.2)
         }
         None => {
+            changes::write_outbox(db_name, &tx)?;
             tx.commit()?;
+            changes::publish(db_name, conn);
+            audit::record(db_name, user.as_deref(), source, audited);
             Response::new_ok(results)
         }
-    })
+    };
+    Ok(res)
+}
+
+pub fn process(
+    db_name: &str,
+    http_req: &req_res::Request,
+    stored_statements: &HashMap<String, String>,
+    dbconf: &DbConfig,
+    user: Option<String>,
+    source: &str,
+    span_ctx: Option<SpanContext>,
+) -> Result<Response> {
+    let start = Instant::now();
+    let lock_span = Span::start("lock wait", span_ctx);
+    let mut db_lock_guard = match lock_db(db_name) {
+        Some(guard) => guard,
+        None => {
+            lock_span.end();
+            return Ok(Response::new_err(503, -1, busy_msg(db_name)));
+        }
+    };
+    let lock_wait = start.elapsed();
+    lock_span.end();
+    let res = execute(
+        db_name,
+        http_req,
+        stored_statements,
+        dbconf,
+        user,
+        source,
+        span_ctx,
+        db_lock_guard.deref_mut(),
+    );
+    // also when it fails, e.g. because the commit finds the database busy
+    record_process(db_name, start.elapsed(), lock_wait);
+    res
 }
 
 pub async fn handler(
@@ -259,25 +433,64 @@ pub async fn handler(
     db_conf: web::Data<Db>,
     db_name: web::Data<String>,
 ) -> impl Responder {
+    let ac_headers = match &db_conf.conf.auth {
+        Some(ac) if matches!(ac.mode, AuthMode::HttpBasic) => {
+            Authorization::<Basic>::parse(&req).ok()
+        }
+        _ => None,
+    };
+
+    // for the access log, also if the authentication fails
+    let items = body.transaction.len();
+    req.extensions_mut()
+        .insert(AccessInfo { user: None, items });
+
+    let span_ctx = request_context(&req);
+    let mut auth_user = None;
     if let Some(ac) = &db_conf.conf.auth {
-        let ac_headers = if matches!(
-            db_conf.conf.auth.as_ref().unwrap().mode,
-            AuthMode::HttpBasic
-        ) {
-            match Authorization::<Basic>::parse(&req) {
-                Ok(a) => Some(a),
-                Err(_) => None,
+        let mut auth_span = Span::start("auth", span_ctx);
+        let res = process_auth_async(ac, &body.credentials, &ac_headers, &db_name).await;
+        if !matches!(res, Ok(Some(_))) {
+            auth_span.set_error("Authorization failed");
+        }
+        auth_span.end();
+        match res {
+            Ok(Some(u)) => {
+                req.extensions_mut().insert(AccessInfo {
+                    user: Some(u.to_owned()),
+                    items,
+                });
+                auth_user = Some(u);
             }
-        } else {
-            None
-        };
-
-        if !process_auth(ac, &body.credentials, &ac_headers, &db_name) {
-            sleep(Duration::from_millis(1000)).await;
+            Err(msg) => return Response::new_err(503, -1, msg),
+            Ok(None) => {
+                record_auth_failure(&db_name, "credentials");
+                sleep(Duration::from_millis(1000)).await;
 
-            return Response::new_err(ac.auth_error_code, -1, "Authorization failed".to_string());
+                return Response::new_err(
+                    ac.auth_error_code,
+                    -1,
+                    "Authorization failed".to_string(),
+                );
+            }
         }
     }
 
-    process(&db_name, body, &db_conf.stored_statements, &db_conf.conf).unwrap()
+    // in a thread that can be blocked, as it waits for the database
+    let body = body.into_inner();
+    web::block(move || {
+        process(
+            &db_name,
+            &body,
+            &db_conf.stored_statements,
+            &db_conf.conf,
+            auth_user,
+            "request",
+            span_ctx,
+        )
+    })
+    .await
+    .map_err(|e| eyre!(e.to_string()))
+    .and_then(|res| res)
+    .unwrap_or_else(|e| Response::new_err(500, -1, e.to_string()))
 }
@@ -15,22 +15,26 @@
 use std::{collections::HashMap, ops::DerefMut, time::Duration};
 
 use actix_web::{
-    rt::{
-        spawn,
-        time::{interval_at, sleep, Instant},
-    },
+    rt::time::sleep,
     web::{self, Path},
-    Responder,
+    Either, HttpRequest, Responder,
 };
 use eyre::Result;
-use rusqlite::Connection;
+use rusqlite::{types::ValueRef, Connection};
 
 use crate::{
+    audit::{self, AuditedStatement},
     auth::process_creds,
+    changes,
     commons::{check_stored_stmt, if_abort_eyre},
-    db_config::{DbConfig, Macro},
+    db_config::{DbConfig, Macro, MacroStep},
+    history::{runs, track, track_with_parent, JobId, Trigger},
+    logic::{busy_msg, lock_db},
     main_config::Db,
-    req_res::{Response, ResponseItem, Token},
+    metrics::record_auth_failure,
+    req_res::{Response, ResponseItem, RunsResponse, Token},
+    scheduler::{is_periodic, spawn_scheduled, Schedule},
+    telemetry::request_context,
     MUTEXES,
 };
 
@@ -39,49 +43,188 @@ pub fn resolve_macros(
     dbconf: &mut DbConfig,
     stored_statements: &HashMap<String, String>,
 ) -> HashMap<String, Macro> {
+    let resolve = |sql: &String| -> String {
+        if_abort_eyre(check_stored_stmt(sql, stored_statements, false)).to_owned()
+    };
+
     let mut ret: HashMap<String, Macro> = HashMap::new();
     if let Some(ms) = &mut dbconf.macros {
         for macr in ms {
-            let mut statements: Vec<String> = vec![];
-            #[allow(clippy::unnecessary_to_owned)]
-            for statement in macr.statements.to_owned() {
-                let statement =
-                    if_abort_eyre(check_stored_stmt(&statement, stored_statements, false));
-                statements.push(statement.to_owned());
+            for step in macr.statements.iter_mut() {
+                match step {
+                    MacroStep::Statement(statement) => *statement = resolve(statement),
+                    MacroStep::Step(def) => {
+                        def.statement = def.statement.as_ref().map(resolve);
+                        def.condition = def.condition.as_ref().map(resolve);
+                    }
+                }
             }
-            macr.statements = statements;
             ret.insert(macr.id.to_owned(), macr.to_owned());
         }
     }
     ret
 }
 
-fn exec_macro_single_notrx(macr: &Macro, conn: &mut Connection) -> Response {
-    let mut ret = vec![];
-    for (i, statement) in macr.statements.iter().enumerate() {
-        let changed_rows = conn.execute(statement, []);
-        match changed_rows {
-            Ok(cr) => {
-                ret.push(ResponseItem {
-                    success: true,
-                    error: None,
-                    result_set: None,
-                    rows_updated: Some(cr),
-                    rows_updated_batch: None,
-                });
+fn check_macro_calls(
+    macr: &Macro,
+    macros: &HashMap<String, Macro>,
+    call_stack: &mut Vec<String>,
+) -> Result<()> {
+    call_stack.push(macr.id.to_owned());
+    for (i, step) in macr.statements.iter().enumerate() {
+        if let MacroStep::Step(def) = step {
+            if def.statement.is_some() == def.macro_id.is_some() {
+                return Err(eyre!(
+                    "Macro '{}', index {}: exactly one of 'statement' and 'macro' must be provided",
+                    macr.id,
+                    i
+                ));
             }
-            Err(e) => {
-                return Response::new_err(500, i as isize, e.to_string());
+            if let Some(id) = &def.macro_id {
+                if call_stack.contains(id) {
+                    return Err(eyre!(
+                        "Macro '{}', index {}: recursive call to macro '{}'",
+                        macr.id,
+                        i,
+                        id
+                    ));
+                }
+                match macros.get(id) {
+                    Some(called) => check_macro_calls(called, macros, call_stack)?,
+                    None => {
+                        return Err(eyre!(
+                            "Macro '{}', index {}: macro '{}' not found",
+                            macr.id,
+                            i,
+                            id
+                        ))
+                    }
+                }
+            }
+        }
+    }
+    call_stack.pop();
+    Ok(())
+}
+
+/// Checks that the steps are well-formed, and that the macros they call exist and don't
+/// (directly or indirectly) call themselves
+pub fn check_macros(macros: &HashMap<String, Macro>) -> Result<()> {
+    for macr in macros.values() {
+        check_macro_calls(macr, macros, &mut vec![])?;
+    }
+    Ok(())
+}
+
+/// A condition is satisfied if the query returns a row whose first column is not NULL,
+/// zero or an empty string/blob
+fn eval_condition(condition: &str, conn: &Connection) -> rusqlite::Result<bool> {
+    let mut stmt = conn.prepare(condition)?;
+    let mut rows = stmt.query([])?;
+    Ok(match rows.next()? {
+        Some(row) => match row.get_ref(0)? {
+            ValueRef::Null => false,
+            ValueRef::Integer(i) => i != 0,
+            ValueRef::Real(f) => f != 0.0,
+            ValueRef::Text(t) => !t.is_empty(),
+            ValueRef::Blob(b) => !b.is_empty(),
+        },
+        None => false,
+    })
+}
+
+/// Executes the steps of a macro. Called macros are executed in the same transaction
+/// (if any) of the caller. Returns an error as (index, message). The executed statements
+/// are added to `audited`, with the index of the step of the outermost macro.
+fn exec_steps(
+    macr: &Macro,
+    macros: &HashMap<String, Macro>,
+    conn: &Connection,
+    audited: &mut Vec<AuditedStatement>,
+    outer_idx: Option<usize>,
+) -> Result<Vec<ResponseItem>, (usize, String)> {
+    let mut ret = vec![];
+    for (i, step) in macr.statements.iter().enumerate() {
+        let (statement, macro_id, condition) = match step {
+            MacroStep::Statement(statement) => (Some(statement), None, None),
+            MacroStep::Step(def) => (
+                def.statement.as_ref(),
+                def.macro_id.as_ref(),
+                def.condition.as_ref(),
+            ),
+        };
+
+        if let Some(condition) = condition {
+            match eval_condition(condition, conn) {
+                Ok(true) => (),
+                Ok(false) => {
+                    // skipped
+                    ret.push(ResponseItem {
+                        success: true,
+                        error: None,
+                        result_set: None,
+                        rows_updated: None,
+                        rows_updated_batch: None,
+                        typed_result_set: None,
+                    });
+                    continue;
+                }
+                Err(e) => return Err((i, format!("in condition: {}", e))),
             }
         }
+
+        let changed_rows = match (statement, macro_id) {
+            (Some(statement), _) => {
+                let changed_rows = conn
+                    .execute(statement, [])
+                    .map_err(|e| (i, e.to_string()))?;
+                let mut a = AuditedStatement::new(outer_idx.unwrap_or(i), statement);
+                a.rows_updated = Some(changed_rows);
+                audited.push(a);
+                changed_rows
+            }
+            (None, Some(id)) => {
+                // existence was already checked while loading the config
+                let called = macros.get(id).unwrap();
+                exec_steps(called, macros, conn, audited, outer_idx.or(Some(i)))
+                    .map_err(|(j, e)| (i, format!("in macro '{}', index {}: {}", id, j, e)))?
+                    .iter()
+                    .filter_map(|item| item.rows_updated)
+                    .sum()
+            }
+            (None, None) => unreachable!(),
+        };
+        ret.push(ResponseItem {
+            success: true,
+            error: None,
+            result_set: None,
+            rows_updated: Some(changed_rows),
+            rows_updated_batch: None,
+            typed_result_set: None,
+        });
     }
 
-    Response::new_ok(ret)
+    Ok(ret)
 }
 
-fn exec_macro_single(macr: &Macro, conn: &mut Connection) -> Response {
+fn exec_macro_single(
+    macr: &Macro,
+    macros: &HashMap<String, Macro>,
+    db_name: &str,
+    conn: &mut Connection,
+) -> Response {
+    let mut audited = vec![];
+    let source = format!("macro:{}", macr.id);
+
     if macr.disable_transaction {
-        return exec_macro_single_notrx(macr, conn);
+        let res = exec_steps(macr, macros, conn, &mut audited, None);
+        // without a transaction, what was executed before a failure stays
+        changes::publish(db_name, conn);
+        audit::record(db_name, None, &source, audited);
+        return match res {
+            Ok(ret) => Response::new_ok(ret),
+            Err((i, e)) => Response::new_err(500, i as isize, e),
+        };
     }
 
     let tx = match conn.transaction() {
@@ -95,62 +238,112 @@ fn exec_macro_single(macr: &Macro, conn: &mut Connection) -> Response {
         }
     };
 
-    let mut ret = vec![];
-    for (i, statement) in macr.statements.iter().enumerate() {
-        let changed_rows = tx.execute(statement, []);
-        match changed_rows {
-            Ok(cr) => {
-                ret.push(ResponseItem {
-                    success: true,
-                    error: None,
-                    result_set: None,
-                    rows_updated: Some(cr),
-                    rows_updated_batch: None,
-                });
-            }
-            Err(e) => {
-                let _ = tx.rollback();
-                return Response::new_err(500, i as isize, e.to_string());
+    match exec_steps(macr, macros, &tx, &mut audited, None) {
+        Ok(ret) => match changes::write_outbox(db_name, &tx)
+            .and_then(|_| tx.commit().map_err(Into::into))
+        {
+            Ok(_) => {
+                changes::publish(db_name, conn);
+                audit::record(db_name, None, &source, audited);
+                Response::new_ok(ret)
             }
+            Err(_) => Response::new_err(500, -1, format!("Commit failed for macro '{}'", macr.id)),
+        },
+        Err((i, e)) => {
+            let _ = tx.rollback();
+            Response::new_err(500, i as isize, e)
         }
     }
-
-    match tx.commit() {
-        Ok(_) => Response::new_ok(ret),
-        Err(_) => Response::new_err(500, -1, format!("Commit failed for macro '{}'", macr.id)),
-    }
 }
 
 pub fn bootstrap_db_macros(
     is_new_db: bool,
     db_conf: &DbConfig,
+    macros: &HashMap<String, Macro>,
     db_name: &String,
     conn: &mut Connection,
 ) -> Result<()> {
-    match &db_conf.macros {
-        Some(macros) => {
-            for macr in macros {
-                if macr.execution.on_startup || (is_new_db && macr.execution.on_create) {
-                    let res = exec_macro_single(macr, conn);
-                    if !res.success {
-                        return Result::Err(eyre!(
-                            "In macro '{}' of db '{}', index {}: {}",
-                            macr.id,
-                            db_name,
-                            res.req_idx.unwrap_or(-1),
-                            res.message.unwrap_or("unknown error".to_string())
-                        ));
-                    };
-                }
+    if let Some(macro_list) = &db_conf.macros {
+        for macr in macro_list {
+            if macr.execution.on_startup || (is_new_db && macr.execution.on_create) {
+                let job = JobId::for_macro(db_name, &macr.id);
+                let res = track(&job, Trigger::Startup, || {
+                    exec_macro_single(macr, macros, db_name, conn)
+                });
+                if !res.success {
+                    return Result::Err(eyre!(
+                        "In macro '{}' of db '{}', index {}: {}",
+                        macr.id,
+                        db_name,
+                        res.req_idx.unwrap_or(-1),
+                        res.message.unwrap_or("unknown error".to_string())
+                    ));
+                };
             }
         }
-        None => (),
     }
 
     Result::Ok(())
 }
 
+/// Executes the macros marked to run on shutdown, in the order of the config file
+pub fn shutdown_db_macros(db_conf: &Db, db_name: &str, conn: &mut Connection) {
+    if let Some(macros) = &db_conf.conf.macros {
+        for macr in macros {
+            if macr.execution.on_shutdown {
+                let job = JobId::for_macro(db_name, &macr.id);
+                let res = track(&job, Trigger::Shutdown, || {
+                    exec_macro_single(macr, &db_conf.macros, db_name, conn)
+                });
+                if res.success {
+                    println!("Macro '{}' executed for db '{}'", macr.id, db_name);
+                } else {
+                    eprintln!(
+                        "In macro '{}' of db '{}', index {}: {}",
+                        macr.id,
+                        db_name,
+                        res.req_idx.unwrap_or(-1),
+                        res.message.as_deref().unwrap_or("unknown error")
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Executes a list of macros referenced by id, e.g. the hooks of a backup. Stops at the
+/// first failure, returning it.
+pub fn exec_macros_by_id(
+    ids: &[String],
+    macros: &HashMap<String, Macro>,
+    db_name: &str,
+    trigger: Trigger,
+    conn: &mut Connection,
+) -> Response {
+    for id in ids {
+        // existence was already checked while loading the config
+        let macr = macros.get(id).unwrap();
+        let job = JobId::for_macro(db_name, id);
+        let res = track(&job, trigger, || {
+            exec_macro_single(macr, macros, db_name, conn)
+        });
+        if !res.success {
+            return Response::new_err(
+                res.status_code,
+                res.req_idx.unwrap_or(-1),
+                format!(
+                    "In macro '{}': {}",
+                    id,
+                    res.message.as_deref().unwrap_or("unknown error")
+                ),
+            );
+        }
+    }
+    Response::new_ok(vec![])
+}
+
 pub async fn handler(
+    req: HttpRequest,
     db_conf: web::Data<Db>,
     db_name: web::Data<String>,
     macro_name: Path<String>,
@@ -163,6 +356,7 @@ pub async fn handler(
         Some(macr) => match &macr.execution.web_service {
             Some(mex_ws) => {
                 if !process_creds(&token.token, &mex_ws.auth_token, &mex_ws.hashed_auth_token) {
+                    record_auth_failure(&db_name, "token");
                     sleep(Duration::from_millis(1000)).await;
 
                     return Response::new_err(
@@ -175,11 +369,23 @@ pub async fn handler(
                     );
                 }
 
-                let db_lock = MUTEXES.get().unwrap().get(&db_name).unwrap();
-                let mut db_lock_guard = db_lock.lock().unwrap();
-                let conn = db_lock_guard.deref_mut();
+                // in a thread that can be blocked, as it waits for the database
+                let (db_conf, span_ctx) = (db_conf.clone(), request_context(&req));
+                web::block(move || {
+                    let mut db_lock_guard = match lock_db(&db_name) {
+                        Some(guard) => guard,
+                        None => return Response::new_err(503, -1, busy_msg(&db_name)),
+                    };
+                    let conn = db_lock_guard.deref_mut();
 
-                exec_macro_single(macr, conn)
+                    let macr = &db_conf.macros[&macro_name];
+                    let job = JobId::for_macro(&db_name, &macro_name);
+                    track_with_parent(&job, Trigger::WebService, span_ctx, || {
+                        exec_macro_single(macr, &db_conf.macros, &db_name, conn)
+                    })
+                })
+                .await
+                .unwrap_or_else(|e| Response::new_err(500, -1, e.to_string()))
             }
             None => Response::new_err(
                 404,
@@ -201,62 +407,86 @@ pub async fn handler(
     }
 }
 
-pub fn periodic_macro(macr: Macro, db_name: String) {
-    if macr.execution.period > 0 {
-        spawn(async move {
-            let p = Duration::from_secs(macr.execution.period as u64 * 60);
-            let first_start = Instant::now().checked_add(p).unwrap();
-            let mut interval = interval_at(first_start, p);
-
-            loop {
-                interval.tick().await; // skip first execution
-
-                let db_lock = MUTEXES.get().unwrap().get(&db_name).unwrap();
-                let mut db_lock_guard = db_lock.lock().unwrap();
-                let conn = db_lock_guard.deref_mut();
-
-                let tx = match conn.transaction() {
-                    Ok(tx) => tx,
-                    Err(_) => {
-                        eprintln!(
-                            "Transaction open failed for db '{}', macro '{}'",
-                            db_name, macr.id,
-                        );
-                        return;
-                    }
-                };
+/// Lists the last runs of a macro. Uses the token of the webService node, that must be present.
+pub async fn runs_handler(
+    db_conf: web::Data<Db>,
+    db_name: web::Data<String>,
+    macro_name: Path<String>,
+    token: web::Query<Token>,
+) -> Either<Response, web::Json<RunsResponse>> {
+    let db_name = db_name.to_string();
+    let macro_name = macro_name.to_string();
 
-                for (i, statement) in macr.statements.iter().enumerate() {
-                    match tx.execute(statement, []) {
-                        Ok(_) => (),
-                        Err(e) => {
-                            let _ = tx.rollback();
-                            eprintln!(
-                                "In macro '{}' of db '{}', index {}: {}",
-                                macr.id, db_name, i, e
-                            );
-                            return;
-                        }
-                    }
-                }
+    match db_conf.macros.get(&macro_name) {
+        Some(macr) => match &macr.execution.web_service {
+            Some(mex_ws) => {
+                if !process_creds(&token.token, &mex_ws.auth_token, &mex_ws.hashed_auth_token) {
+                    record_auth_failure(&db_name, "token");
+                    sleep(Duration::from_millis(1000)).await;
 
-                match tx.commit() {
-                    Ok(_) => println!("Macro '{}' executed for db '{}'", macr.id, db_name),
-                    Err(e) => {
-                        eprintln!(
-                            "Commit failed for startup macros in db '{}': {}",
-                            db_name, e
-                        );
-                    }
+                    return Either::Left(Response::new_err(
+                        mex_ws.auth_error_code,
+                        -1,
+                        format!(
+                            "In database '{}', macro '{}': token mismatch",
+                            db_name, macro_name
+                        ),
+                    ));
                 }
+
+                let job = JobId::for_macro(&db_name, &macro_name);
+                Either::Right(web::Json(RunsResponse { runs: runs(&job) }))
             }
+            None => Either::Left(Response::new_err(
+                404,
+                -1,
+                format!(
+                    "In database '{}', macro '{}' doesn't have an execution.webService node",
+                    db_name, macro_name
+                ),
+            )),
+        },
+        None => Either::Left(Response::new_err(
+            404,
+            -1,
+            format!(
+                "Database '{}' doesn't have a macro named '{}'",
+                db_name, macro_name
+            ),
+        )),
+    }
+}
+
+pub fn periodic_macro(macr: Macro, macros: HashMap<String, Macro>, db_name: String) {
+    // the schedule was already validated while loading the config
+    if let Ok(Some(schedule)) = Schedule::from_execution(&macr.execution) {
+        let job = JobId::for_macro(&db_name, &macr.id);
+        let missed_runs = macr.execution.missed_runs.to_owned();
+        spawn_scheduled(schedule, missed_runs, job, move || {
+            let db_lock = MUTEXES.get().unwrap().get(&db_name).unwrap();
+            let mut db_lock_guard = db_lock.lock().unwrap();
+            let conn = db_lock_guard.deref_mut();
+
+            let res = exec_macro_single(&macr, &macros, &db_name, conn);
+            if res.success {
+                println!("Macro '{}' executed for db '{}'", macr.id, db_name);
+            } else {
+                eprintln!(
+                    "In macro '{}' of db '{}', index {}: {}",
+                    macr.id,
+                    db_name,
+                    res.req_idx.unwrap_or(-1),
+                    res.message.as_deref().unwrap_or("unknown error")
+                );
+            }
+            res
         });
     }
 }
 
-pub fn count_macros(macros: HashMap<String, Macro>) -> [usize; 4] {
-    // return [num_on_create, num_on_startup, num_periodic, num_exposed_via_webservice]
-    let mut ret = [0, 0, 0, 0];
+pub fn count_macros(macros: HashMap<String, Macro>) -> [usize; 5] {
+    // return [num_on_create, num_on_startup, num_periodic, num_exposed_via_webservice, num_on_shutdown]
+    let mut ret = [0, 0, 0, 0, 0];
     for macr in macros.values() {
         let e = &macr.execution;
         if e.on_create {
@@ -265,12 +495,15 @@ pub fn count_macros(macros: HashMap<String, Macro>) -> [usize; 4] {
         if e.on_startup {
             ret[1] += 1;
         }
-        if e.period > 0 {
+        if is_periodic(e) {
             ret[2] += 1;
         }
         if e.web_service.is_some() {
             ret[3] += 1;
         }
+        if e.on_shutdown {
+            ret[4] += 1;
+        }
     }
     ret
 }
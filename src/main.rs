@@ -19,36 +19,64 @@ extern crate eyre;
 
 use std::{
     collections::HashMap,
-    ops::Deref,
+    ops::{Deref, DerefMut},
     sync::{Mutex, OnceLock},
+    time::Instant,
 };
 
 use actix_cors::Cors;
 use actix_files::Files;
 use actix_web::{
+    dev::Service,
     guard,
-    web::{route, scope, Data},
-    App, HttpServer, Scope,
+    web::{resource, route, scope, Data},
+    App, HttpServer,
 };
 use rusqlite::Connection;
 
+mod access_log;
+mod audit;
 pub mod auth;
 mod backup;
+mod backup_codec;
+mod backup_files;
+mod changes;
 pub mod commandline;
 pub mod commons;
 pub mod db_config;
+mod graphql;
+mod grpc;
+mod history;
 mod logic;
 mod macros;
 pub mod main_config;
+mod metrics;
+mod openapi;
+mod pgwire;
 pub mod req_res;
+mod rest;
+mod restore;
+mod retention;
+mod s3;
+mod scheduler;
+mod status;
+mod stored_routes;
+mod telemetry;
+mod wal_archive;
+mod webhooks;
+mod ws;
 
-use crate::{commandline::parse_cli, db_config::AuthMode, main_config::compose_db_map};
+use crate::{
+    commandline::{parse_cli, Command},
+    db_config::{AuthMode, HttpMethod},
+    main_config::compose_db_map,
+};
 
 pub const CURRENT_PROTO_VERSION: u8 = 1;
 
 pub static MUTEXES: OnceLock<HashMap<String, Mutex<Connection>>> = OnceLock::new();
 
-fn get_sqlite_version() -> String {
+pub fn get_sqlite_version() -> String {
     let conn: Connection = Connection::open_in_memory().unwrap();
     conn.query_row("SELECT sqlite_version()", [], |row| row.get(0))
         .unwrap()
@@ -65,6 +93,20 @@ async fn main() -> std::io::Result<()> {
 
     let cli = parse_cli();
 
+    if let Some(Command::Restore(args)) = &cli.command {
+        restore::restore_cli(args);
+        return Ok(());
+    }
+
+    // before the databases are set up, to count the startup runs of macros and backups
+    if cli.metrics {
+        metrics::enable();
+    }
+    access_log::init(cli.access_log, cli.trust_proxy_headers);
+    if let Some(endpoint) = &cli.otlp_endpoint {
+        telemetry::init(endpoint, &cli.otlp_service_name);
+    }
+
     // side effect of compose_db_map: populate MUTEXES
     // aborts on error
     let db_map = compose_db_map(&cli);
@@ -74,12 +116,76 @@ async fn main() -> std::io::Result<()> {
         println!("  - with index file: {}", &cli.index_file);
     };
 
+    if cli.info_token.is_some() {
+        println!("- serving info at /info");
+    }
+
+    // generated now, as it's the same for all the workers
+    let openapi = cli.openapi.then(|| openapi::generate(&db_map));
+    if openapi.is_some() {
+        println!("- serving the OpenAPI description at /openapi.json");
+    }
+
+    if let Some(endpoint) = &cli.otlp_endpoint {
+        println!("- exporting traces to {}", endpoint);
+    }
+
+    if cli.metrics {
+        println!("- serving metrics at /metrics");
+        if cli.metrics_token.is_some() {
+            println!("  - with token authentication");
+        }
+    }
+
+    if let Some(pg_port) = cli.pg_port {
+        pgwire::start(&cli.bind_host, pg_port, &db_map)?;
+        println!("- PostgreSQL protocol on {}:{}", cli.bind_host, pg_port);
+    }
+    if let Some(grpc_port) = cli.grpc_port {
+        grpc::start(&cli.bind_host, grpc_port, &db_map)?;
+        println!("- gRPC on {}:{}", cli.bind_host, grpc_port);
+    }
+
+    // kept for executing the shutdown tasks, as db_map is moved into the server
+    let db_map_at_shutdown = db_map.to_owned();
+
     let app_lambda = move || {
         let dir = cli.serve_dir.to_owned();
         let index_file = cli.index_file.to_owned();
-        let mut a = App::new();
+        let mut a = App::new()
+            .app_data(Data::new(db_map.to_owned()))
+            .route(
+                "/healthz",
+                route().guard(guard::Get()).to(status::health_handler),
+            )
+            .route(
+                "/readyz",
+                route().guard(guard::Get()).to(status::ready_handler),
+            );
+        if cli.metrics {
+            a = a.service(
+                resource("/metrics")
+                    .app_data(Data::new(cli.metrics_token.to_owned()))
+                    .route(route().guard(guard::Get()).to(metrics::handler)),
+            );
+        }
+        if let Some(info_token) = &cli.info_token {
+            a = a.service(
+                resource("/info")
+                    .app_data(Data::new(info_token.to_owned()))
+                    .route(route().guard(guard::Get()).to(status::info_handler)),
+            );
+        }
+        if let Some(spec) = &openapi {
+            a = a.service(
+                resource("/openapi.json")
+                    .app_data(Data::new(spec.to_owned()))
+                    .route(route().guard(guard::Get()).to(openapi::handler)),
+            );
+        }
         for (db_name, db_conf) in db_map.iter() {
-            let scop: Scope = scope(format!("/{}", db_name.to_owned()).deref())
+            let name_for_logs = db_name.to_owned();
+            let scop = scope(format!("/{}", db_name.to_owned()).deref())
                 .app_data(Data::new(db_name.to_owned()))
                 .app_data(Data::new(db_conf.to_owned()))
                 .route(
@@ -89,23 +195,113 @@ async fn main() -> std::io::Result<()> {
                         .guard(guard::Header("content-type", "application/json"))
                         .to(logic::handler),
                 )
+                .route("/ws", route().guard(guard::Get()).to(ws::handler))
+                .route("/changes", route().guard(guard::Get()).to(changes::handler))
+                .route(
+                    "/tables/{table}",
+                    route()
+                        .guard(
+                            guard::Any(guard::Get())
+                                .or(guard::Post())
+                                .or(guard::Patch())
+                                .or(guard::Delete()),
+                        )
+                        .to(rest::handler),
+                )
+                .route(
+                    "/tables/{table}/{pk}",
+                    route()
+                        .guard(
+                            guard::Any(guard::Get())
+                                .or(guard::Patch())
+                                .or(guard::Delete()),
+                        )
+                        .to(rest::handler),
+                )
+                .route(
+                    "/q/{id}",
+                    route()
+                        .guard(guard::Any(guard::Get()).or(guard::Post()))
+                        .to(stored_routes::handler),
+                )
+                .route(
+                    "/graphql",
+                    route().guard(guard::Post()).to(graphql::handler),
+                )
                 .route(
                     "/macro/{macro_name}",
                     route()
                         .guard(guard::Any(guard::Get()).or(guard::Post()))
                         .to(macros::handler),
                 )
+                .route(
+                    "/macro/{macro_name}/runs",
+                    route().guard(guard::Get()).to(macros::runs_handler),
+                )
                 .route(
                     "/backup",
                     route()
                         .guard(guard::Any(guard::Get()).or(guard::Post()))
                         .to(backup::handler),
-                );
+                )
+                .route(
+                    "/backup/runs",
+                    route().guard(guard::Get()).to(backup::runs_handler),
+                )
+                .route(
+                    "/backup/progress",
+                    route().guard(guard::Get()).to(backup::progress_handler),
+                )
+                .route(
+                    "/backup/list",
+                    route().guard(guard::Get()).to(restore::list_handler),
+                )
+                .route(
+                    "/backup/restore",
+                    route().guard(guard::Post()).to(restore::restore_handler),
+                )
+                .wrap_fn(move |req, srv| {
+                    let db_name = name_for_logs.to_owned();
+                    let start = Instant::now();
+                    let mut span = telemetry::start_request_span(req.request());
+                    span.set_str("sqliterg.db", &db_name);
+                    let fut = srv.call(req);
+                    async move {
+                        let res = fut.await?;
+                        let status = res.status();
+                        if let Some(pattern) = res.request().match_pattern() {
+                            span.set_name(&format!("{} {}", res.request().method(), pattern));
+                        }
+                        span.set_int("http.response.status_code", status.as_u16() as i64);
+                        if status.is_server_error() {
+                            span.set_error(status.canonical_reason().unwrap_or("server error"));
+                        }
+                        span.end();
+                        metrics::record_request(&db_name, status.as_u16());
+                        access_log::log_access(&db_name, &res, start.elapsed());
+                        Ok(res)
+                    }
+                });
 
             match &db_conf.conf.cors_origin {
                 Some(orig) => {
+                    let mut methods = vec!["POST"];
+                    let http_get = db_conf
+                        .conf
+                        .stored_statements
+                        .iter()
+                        .flatten()
+                        .any(|ss| matches!(&ss.http, Some(h) if h.method == HttpMethod::Get));
+                    if db_conf.conf.change_feed.is_some() || db_conf.conf.rest.is_some() || http_get
+                    {
+                        // for EventSource, the REST endpoints and the stored queries
+                        methods.push("GET");
+                    }
+                    if db_conf.conf.rest.is_some() {
+                        methods.extend(["PATCH", "DELETE"]);
+                    }
                     let mut cors = Cors::default()
-                        .allowed_methods(vec!["POST"])
+                        .allowed_methods(methods)
                         .allowed_header("content-type");
                     if db_conf.conf.auth.is_some()
                         && matches!(
@@ -135,5 +331,21 @@ async fn main() -> std::io::Result<()> {
 
     let bind_addr = format!("{}:{}", cli.bind_host, cli.port);
     println!("- Listening on {}", &bind_addr);
-    HttpServer::new(app_lambda).bind(bind_addr)?.run().await
+    HttpServer::new(app_lambda).bind(bind_addr)?.run().await?;
+
+    // the server was stopped gracefully; perform the shutdown macros, then the backups,
+    //   then archive the last WAL frames
+    for (db_name, db_conf) in db_map_at_shutdown.iter() {
+        let db_lock = MUTEXES.get().unwrap().get(db_name).unwrap();
+        let mut db_lock_guard = db_lock.lock().unwrap();
+        let conn = db_lock_guard.deref_mut();
+
+        macros::shutdown_db_macros(db_conf, db_name, conn);
+        backup::shutdown_backup(db_conf, db_name, conn);
+        wal_archive::shutdown_wal_archive(db_conf, db_name, conn);
+    }
+
+    telemetry::flush();
+
+    Ok(())
 }
@@ -14,18 +14,34 @@
 
 use std::fs::remove_file;
 use std::sync::Mutex;
-use std::{collections::HashMap, path::Path};
+use std::{
+    collections::HashMap,
+    path::{absolute, Path},
+};
 
 use rusqlite::Connection;
 
-use crate::backup::{bootstrap_backup, periodic_backup};
+use crate::audit::{self, DEFAULT_MAX_SIZE_MB};
+use crate::backup::{bootstrap_backup, periodic_backup, DEFAULT_PAGES_PER_STEP};
+use crate::backup_codec::read_key;
+use crate::backup_files::check_file_template;
+use crate::changes;
 use crate::commandline::AppConfig;
 use crate::commons::{
-    abort, assert, file_exists, if_abort_rusqlite, is_dir, is_file_in_directory, resolve_tilde,
-    split_on_first_double_colon,
+    abort, assert, file_exists, if_abort_eyre, if_abort_rusqlite, is_dir, is_file_in_directory,
+    resolve_tilde, split_on_first_double_colon,
+};
+use crate::db_config::{parse_dbconf, AuthMode, BackupTarget, DbConfig, Macro};
+use crate::graphql;
+use crate::history::{self, DEFAULT_HISTORY_SIZE};
+use crate::macros::{
+    bootstrap_db_macros, check_macros, count_macros, periodic_macro, resolve_macros,
 };
-use crate::db_config::{parse_dbconf, DbConfig, Macro};
-use crate::macros::{bootstrap_db_macros, count_macros, periodic_macro, resolve_macros};
+use crate::rest;
+use crate::s3::S3Client;
+use crate::scheduler::Schedule;
+use crate::wal_archive::{start_wal_archive, DEFAULT_INTERVAL, DEFAULT_NUM_GENERATIONS};
+use crate::webhooks;
 use crate::MUTEXES;
 
 #[derive(Debug, Clone)]
@@ -39,7 +55,19 @@ pub struct Db {
     pub macros: HashMap<String, Macro>,
 }
 
-fn split_path(path: &str) -> (String, String, String) {
+impl Db {
+    /// What the backup files are named after: the file for file-based databases, the
+    /// name for in-memory ones (whose path is a connection string)
+    pub fn backup_base_path(&self, db_name: &str) -> String {
+        if self.is_mem {
+            db_name.to_string()
+        } else {
+            self.path.to_owned()
+        }
+    }
+}
+
+pub fn split_path(path: &str) -> (String, String, String) {
     // returns (db_path, yaml, db_name)
     let (mut db_path, mut yaml) = split_on_first_double_colon(path);
     db_path = resolve_tilde(&db_path);
@@ -93,12 +121,205 @@ fn compose_single_db(
             b.num_files > 0,
             "backup: num_files must be 1 or more".to_string(),
         );
-        let bd = resolve_tilde(&b.backup_dir);
         assert(
-            is_dir(&bd),
-            format!("backup directory does not exist: {}", bd),
+            b.pages_per_step.unwrap_or(DEFAULT_PAGES_PER_STEP) > 0,
+            "backup: pagesPerStep must be 1 or more".to_string(),
+        );
+        match &b.target {
+            Some(BackupTarget::S3(s3)) => {
+                if let Err(e) = S3Client::new(s3) {
+                    abort(format!("backup: S3 target: {}", e));
+                }
+            }
+            None => assert(
+                !b.backup_dir.is_empty(),
+                "backup: backupDir or target must be specified".to_string(),
+            ),
+        }
+        // with a target, it's optional: the staging is in the temp directory otherwise
+        if !b.backup_dir.is_empty() {
+            let bd = resolve_tilde(&b.backup_dir);
+            assert(
+                is_dir(&bd),
+                format!("backup directory does not exist: {}", bd),
+            );
+            b.backup_dir = bd;
+        }
+        if let Err(e) = Schedule::from_execution(&b.execution) {
+            abort(format!("backup: {}", e));
+        }
+        if let Some(ft) = &b.file_template {
+            if let Err(e) = check_file_template(ft) {
+                abort(format!("backup: {}", e));
+            }
+        }
+        if let Some(enc) = &b.encryption {
+            if let Err(e) = read_key(&enc.key_file) {
+                abort(format!("backup: {}", e));
+            }
+        }
+    }
+
+    if let Some(wa) = &mut dbconf.wal_archive {
+        assert(
+            !is_mem,
+            "walArchive: only file-based databases can be archived".to_string(),
+        );
+        let jm = dbconf.journal_mode.as_deref().unwrap_or("WAL");
+        assert(
+            jm.eq_ignore_ascii_case("WAL"),
+            "walArchive: the journal mode must be WAL".to_string(),
+        );
+        assert(
+            wa.interval.unwrap_or(DEFAULT_INTERVAL) > 0,
+            "walArchive: interval must be 1 or more".to_string(),
+        );
+        assert(
+            wa.num_generations.unwrap_or(DEFAULT_NUM_GENERATIONS) > 0,
+            "walArchive: numGenerations must be 1 or more".to_string(),
+        );
+        let dir = resolve_tilde(&wa.dir);
+        assert(
+            is_dir(&dir),
+            format!("WAL archive directory does not exist: {}", dir),
+        );
+        assert(
+            !is_file_in_directory(db_path, &dir),
+            format!(
+                "WAL archive config for '{}': archive dir cannot be the same as db file dir",
+                db_name
+            ),
+        );
+        wa.dir = dir;
+    }
+
+    if let Some(au) = &mut dbconf.audit {
+        assert(
+            au.file.is_some() != au.db.is_some(),
+            "audit: exactly one of file and db must be specified".to_string(),
+        );
+        assert(
+            au.max_size_mb.unwrap_or(DEFAULT_MAX_SIZE_MB) > 0,
+            "audit: maxSizeMb must be 1 or more".to_string(),
+        );
+        let path = resolve_tilde(au.file.as_ref().or(au.db.as_ref()).unwrap());
+        assert(
+            is_mem || absolute(&path).ok() != absolute(db_path).ok(),
+            "audit: the audit log cannot be the database itself".to_string(),
+        );
+        if au.file.is_some() {
+            au.file = Some(path.to_owned());
+        } else {
+            au.db = Some(path.to_owned());
+        }
+        if let Err(e) = audit::init(db_name, au) {
+            abort(format!("audit: opening '{}': {}", path, e));
+        }
+        println!("  - audit log to '{}'", path);
+    }
+
+    if let Some(cf) = &dbconf.change_feed {
+        if let Some(tables) = &cf.tables {
+            assert(
+                !tables.is_empty(),
+                "changeFeed: tables, if present, must not be empty".to_string(),
+            );
+        }
+    }
+
+    if let Some(whs) = &dbconf.webhooks {
+        assert(
+            !whs.is_empty(),
+            "webhooks: at least one must be specified".to_string(),
+        );
+        for (i, wh) in whs.iter().enumerate() {
+            assert(
+                wh.url.starts_with("http://") || wh.url.starts_with("https://"),
+                format!("webhooks: '{}' is not an HTTP(S) URL", wh.url),
+            );
+            assert(
+                !whs[..i].iter().any(|w| w.url == wh.url),
+                format!("webhooks: URL '{}' is specified more than once", wh.url),
+            );
+            if let Some(tables) = &wh.tables {
+                assert(
+                    !tables.is_empty(),
+                    format!(
+                        "webhooks: '{}': tables, if present, must not be empty",
+                        wh.url
+                    ),
+                );
+            }
+            if let Some(ops) = &wh.operations {
+                assert(
+                    !ops.is_empty()
+                        && ops.iter().all(|o| {
+                            webhooks::OPERATIONS
+                                .iter()
+                                .any(|op| op.eq_ignore_ascii_case(o))
+                        }),
+                    format!(
+                        "webhooks: '{}': operations must be some of insert, update and delete",
+                        wh.url
+                    ),
+                );
+            }
+            assert(
+                wh.batch_size.unwrap_or(webhooks::DEFAULT_BATCH_SIZE) > 0,
+                format!("webhooks: '{}': batchSize must be 1 or more", wh.url),
+            );
+        }
+    }
+
+    if let Some(rest) = &dbconf.rest {
+        assert(
+            !rest.tables.is_empty(),
+            "rest: at least one table must be specified".to_string(),
+        );
+        for (i, t) in rest.tables.iter().enumerate() {
+            assert(
+                !rest.tables[..i]
+                    .iter()
+                    .any(|o| o.name.eq_ignore_ascii_case(&t.name)),
+                format!("rest: table '{}' is specified more than once", t.name),
+            );
+            if let Some(ops) = &t.operations {
+                assert(
+                    !ops.is_empty()
+                        && ops
+                            .iter()
+                            .all(|o| rest::OPERATIONS.iter().any(|op| op.eq_ignore_ascii_case(o))),
+                    format!(
+                        "rest: '{}': operations must be some of read, insert, update and delete",
+                        t.name
+                    ),
+                );
+            }
+        }
+        assert(
+            !matches!(
+                dbconf.auth.as_ref().map(|a| &a.mode),
+                Some(AuthMode::Inline)
+            ),
+            "rest: with auth, the mode must be HTTP_BASIC".to_string(),
+        );
+    }
+
+    if let Some(gql) = &dbconf.graphql {
+        assert(
+            !gql.tables.is_empty(),
+            "graphql: at least one table must be specified".to_string(),
+        );
+        let default_limit = gql.default_limit.unwrap_or(graphql::DEFAULT_LIMIT);
+        let max_limit = gql.max_limit.unwrap_or(graphql::MAX_LIMIT);
+        assert(
+            default_limit >= 1 && default_limit <= max_limit,
+            "graphql: defaultLimit must be 1 or more, and not more than maxLimit".to_string(),
+        );
+        assert(
+            gql.max_complexity != Some(0),
+            "graphql: maxComplexity must be 1 or more".to_string(),
         );
-        b.backup_dir = bd;
     }
 
     if let Some(a) = &dbconf.auth {
@@ -130,6 +351,20 @@ fn compose_single_db(
         })
         .unwrap_or_default();
 
+    for ss in dbconf.stored_statements.iter().flatten() {
+        if let Some(http) = &ss.http {
+            for (i, p) in http.params.iter().enumerate() {
+                assert(
+                    !p.name.is_empty() && !http.params[..i].iter().any(|o| o.name == p.name),
+                    format!(
+                        "Stored statement '{}': http params must have distinct, non-empty names",
+                        ss.id
+                    ),
+                );
+            }
+        }
+    }
+
     if !stored_statements.is_empty() {
         println!(
             "  - {} stored statements configured",
@@ -138,6 +373,25 @@ fn compose_single_db(
         if dbconf.use_only_stored_statements {
             println!("    - allowing only stored statements for requests")
         }
+        let with_http = dbconf
+            .stored_statements
+            .iter()
+            .flatten()
+            .filter(|ss| ss.http.is_some())
+            .count();
+        if with_http > 0 {
+            assert(
+                !matches!(
+                    dbconf.auth.as_ref().map(|a| &a.mode),
+                    Some(AuthMode::Inline)
+                ),
+                "storedStatements: with http and auth, the mode must be HTTP_BASIC".to_string(),
+            );
+            println!(
+                "    - {} callable via HTTP, at /{}/q/<id>",
+                with_http, db_name
+            );
+        }
     }
 
     let macros: HashMap<String, Macro> = resolve_macros(&mut dbconf, &stored_statements);
@@ -147,7 +401,11 @@ fn compose_single_db(
             !macr.statements.is_empty(),
             format!("Macro '{}' does not have any statement", macr.id),
         );
+        if let Err(e) = Schedule::from_execution(&macr.execution) {
+            abort(format!("Macro '{}': {}", macr.id, e));
+        }
     }
+    if_abort_eyre(check_macros(&macros));
 
     if !macros.is_empty() {
         println!("  - {} macro(s) configured", macros.len());
@@ -164,11 +422,36 @@ fn compose_single_db(
         if count[3] > 0 {
             println!("    - {} callable via web service", count[3]);
         }
+        if count[4] > 0 {
+            println!("    - {} applied on server shutdown", count[4]);
+        }
+    }
+
+    if let Some(b) = &dbconf.backup {
+        for id in b.pre_backup.iter().chain(b.post_backup.iter()).flatten() {
+            assert(
+                macros.contains_key(id),
+                format!("backup: hook references unknown macro '{}'", id),
+            );
+        }
     }
 
+    // persists the history of macros and backup; in-memory databases don't survive
+    // a restart, so for them it's kept in memory
+    let state_file = if is_mem {
+        None
+    } else {
+        Some(format!("{}.sqliterg.json", db_path))
+    };
+    if_abort_eyre(history::init(
+        db_name,
+        state_file,
+        dbconf.run_history_size.unwrap_or(DEFAULT_HISTORY_SIZE),
+    ));
+
     let mut conn = if_abort_rusqlite(Connection::open(conn_string));
 
-    let res = bootstrap_db_macros(is_new_db, &dbconf, db_name, &mut conn);
+    let res = bootstrap_db_macros(is_new_db, &dbconf, &macros, db_name, &mut conn);
     if res.is_err() {
         let _ = conn.close();
         if !is_mem && is_new_db {
@@ -178,11 +461,11 @@ fn compose_single_db(
     }
 
     for macr in macros.values() {
-        periodic_macro(macr.to_owned(), db_name.to_owned());
+        periodic_macro(macr.to_owned(), macros.to_owned(), db_name.to_owned());
     }
 
     if let Some(backup) = dbconf.to_owned().backup {
-        if !is_mem {
+        if !is_mem && backup.target.is_none() {
             assert(
                 !is_file_in_directory(db_path, &backup.backup_dir),
                 format!(
@@ -192,9 +475,27 @@ fn compose_single_db(
             );
         }
 
-        bootstrap_backup(is_new_db, &backup, db_name, db_path, &conn);
+        bootstrap_backup(is_new_db, &backup, &macros, db_name, db_path, &mut conn);
+
+        periodic_backup(
+            &backup,
+            macros.to_owned(),
+            db_name.to_owned(),
+            db_path.to_owned(),
+        );
+    }
 
-        periodic_backup(&backup, db_name.to_owned(), conn_string.to_owned());
+    // before the database is made read-only, as the outbox may need to be created
+    if let Some(whs) = &dbconf.webhooks {
+        match webhooks::init(db_name, whs, dbconf.read_only, &conn) {
+            Ok(pending) => {
+                println!("  - {} webhook(s) configured", whs.len());
+                if pending > 0 {
+                    println!("    - {} event(s) pending delivery", pending);
+                }
+            }
+            Err(e) => abort(format!("webhooks: creating the outbox: {}", e)),
+        }
     }
 
     if dbconf.read_only {
@@ -206,6 +507,35 @@ fn compose_single_db(
     if_abort_rusqlite(conn.query_row(&format!("PRAGMA journal_mode = {}", jm), [], |_| Ok(())));
     println!("  - journal mode: {}", jm);
 
+    if let Some(wa) = &dbconf.wal_archive {
+        start_wal_archive(wa, db_name, db_path, &conn);
+    }
+
+    if dbconf.change_feed.is_some() || dbconf.webhooks.is_some() {
+        changes::init(db_name, &dbconf.change_feed, &dbconf.webhooks, &conn);
+    }
+    if let Some(cf) = &dbconf.change_feed {
+        println!("  - change feed at /{}/changes", db_name);
+        if cf.include_rows {
+            println!("    - with the rows");
+        }
+    }
+
+    if let Some(rest) = &dbconf.rest {
+        println!(
+            "  - REST endpoints at /{}/tables, for {} table(s)",
+            db_name,
+            rest.tables.len()
+        );
+    }
+    if let Some(gql) = &dbconf.graphql {
+        println!(
+            "  - GraphQL endpoint at /{}/graphql, for {} table(s)",
+            db_name,
+            gql.tables.len()
+        );
+    }
+
     let db_conf = Db {
         is_mem,
         path: conn_string.to_owned(),
@@ -216,7 +546,14 @@ fn compose_single_db(
     (db_conf, conn)
 }
 
+// the paths served at the root, before the databases
+const RESERVED_DB_NAMES: [&str; 5] = ["healthz", "readyz", "metrics", "info", "openapi.json"];
+
 fn check_db_name(db_name: &String, db_map: &HashMap<String, Db>) {
+    assert(
+        !RESERVED_DB_NAMES.contains(&db_name.as_str()),
+        format!("database name '{}' is reserved", db_name),
+    );
     assert(
         !db_map.contains_key(db_name),
         format!("database '{}' already defined", db_name),
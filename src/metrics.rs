@@ -0,0 +1,283 @@
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Metrics in the Prometheus text format, at /metrics. They are collected only if enabled
+// from the command line.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    fs::metadata,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use actix_web::{http::header::ContentType, rt::time::sleep, web, HttpRequest, HttpResponse};
+
+use crate::{auth::process_req_token, main_config::Db};
+
+// in seconds
+const BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Default)]
+struct Histogram {
+    // not cumulative; the last one is +Inf
+    counts: [u64; BUCKETS.len() + 1],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, d: Duration) {
+        let secs = d.as_secs_f64();
+        let idx = BUCKETS
+            .iter()
+            .position(|b| secs <= *b)
+            .unwrap_or(BUCKETS.len());
+        self.counts[idx] += 1;
+        self.sum += secs;
+        self.count += 1;
+    }
+}
+
+// the labels are kept sorted, for a stable output
+type Labels = Vec<(&'static str, String)>;
+
+#[derive(Default)]
+struct Metrics {
+    requests: BTreeMap<Labels, u64>,
+    request_duration: BTreeMap<Labels, Histogram>,
+    lock_wait: BTreeMap<Labels, Histogram>,
+    execution: BTreeMap<Labels, Histogram>,
+    auth_failures: BTreeMap<Labels, u64>,
+    job_runs: BTreeMap<Labels, u64>,
+    job_duration: BTreeMap<Labels, Histogram>,
+}
+
+static METRICS: OnceLock<Mutex<Metrics>> = OnceLock::new();
+
+/// Enables the collection of the metrics; until called, recording is a no-op
+pub fn enable() {
+    let _ = METRICS.set(Mutex::new(Metrics::default()));
+}
+
+fn with_metrics(f: impl FnOnce(&mut Metrics)) {
+    if let Some(m) = METRICS.get() {
+        f(&mut m.lock().unwrap());
+    }
+}
+
+fn db_label(db_name: &str) -> Labels {
+    vec![("db", db_name.to_string())]
+}
+
+pub fn record_request(db_name: &str, status: u16) {
+    with_metrics(|m| {
+        let labels = vec![("db", db_name.to_string()), ("status", status.to_string())];
+        *m.requests.entry(labels).or_default() += 1;
+    });
+}
+
+/// Timings of the processing of a request: the whole of it, the wait for the lock on the
+/// database and the execution of the statements
+pub fn record_process(db_name: &str, total: Duration, lock_wait: Duration) {
+    with_metrics(|m| {
+        let labels = db_label(db_name);
+        m.request_duration
+            .entry(labels.to_owned())
+            .or_default()
+            .observe(total);
+        m.lock_wait
+            .entry(labels.to_owned())
+            .or_default()
+            .observe(lock_wait);
+        m.execution
+            .entry(labels)
+            .or_default()
+            .observe(total.saturating_sub(lock_wait));
+    });
+}
+
+/// `kind` is "credentials" for the requests, "token" for the web services
+pub fn record_auth_failure(db_name: &str, kind: &'static str) {
+    with_metrics(|m| {
+        let labels = vec![("db", db_name.to_string()), ("kind", kind.to_string())];
+        *m.auth_failures.entry(labels).or_default() += 1;
+    });
+}
+
+/// A run of a macro ("macro:<id>") or of the backup ("backup")
+pub fn record_job_run(db_name: &str, job: &str, success: bool, duration: Duration) {
+    with_metrics(|m| {
+        let labels = vec![("db", db_name.to_string()), ("job", job.to_string())];
+        let mut run_labels = labels.to_owned();
+        run_labels.push(("success", success.to_string()));
+        *m.job_runs.entry(run_labels).or_default() += 1;
+        m.job_duration.entry(labels).or_default().observe(duration);
+    });
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn fmt_labels(labels: &[(&str, String)]) -> String {
+    labels
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn write_counter(out: &mut String, name: &str, help: &str, values: &BTreeMap<Labels, u64>) {
+    write_header(out, name, "counter", help);
+    for (labels, v) in values {
+        let _ = writeln!(out, "{}{{{}}} {}", name, fmt_labels(labels), v);
+    }
+}
+
+fn write_gauge(out: &mut String, name: &str, help: &str, values: &BTreeMap<Labels, u64>) {
+    write_header(out, name, "gauge", help);
+    for (labels, v) in values {
+        let _ = writeln!(out, "{}{{{}}} {}", name, fmt_labels(labels), v);
+    }
+}
+
+fn write_histogram(out: &mut String, name: &str, help: &str, values: &BTreeMap<Labels, Histogram>) {
+    write_header(out, name, "histogram", help);
+    for (labels, h) in values {
+        let labels = fmt_labels(labels);
+        let mut cumulative = 0;
+        for (i, count) in h.counts.iter().enumerate() {
+            cumulative += count;
+            let le = match BUCKETS.get(i) {
+                Some(b) => b.to_string(),
+                None => "+Inf".to_string(),
+            };
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name, labels, le, cumulative
+            );
+        }
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, h.sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, h.count);
+    }
+}
+
+/// Sizes of the files of the file-based databases, read at each scrape
+fn file_sizes(db_map: &HashMap<String, Db>) -> (BTreeMap<Labels, u64>, BTreeMap<Labels, u64>) {
+    let mut db_sizes = BTreeMap::new();
+    let mut wal_sizes = BTreeMap::new();
+    for (db_name, db) in db_map.iter().filter(|(_, db)| !db.is_mem) {
+        let size = |path: &str| metadata(path).map(|m| m.len()).unwrap_or(0);
+        db_sizes.insert(db_label(db_name), size(&db.path));
+        wal_sizes.insert(db_label(db_name), size(&format!("{}-wal", db.path)));
+    }
+    (db_sizes, wal_sizes)
+}
+
+fn render(db_map: &HashMap<String, Db>) -> String {
+    let mut out = String::new();
+    if let Some(m) = METRICS.get() {
+        let m = m.lock().unwrap();
+        write_counter(
+            &mut out,
+            "sqliterg_requests_total",
+            "Requests to the endpoints of a database, by HTTP status",
+            &m.requests,
+        );
+        write_histogram(
+            &mut out,
+            "sqliterg_request_duration_seconds",
+            "Time to process a request to a database",
+            &m.request_duration,
+        );
+        write_histogram(
+            &mut out,
+            "sqliterg_lock_wait_seconds",
+            "Time spent by a request waiting for the lock on the database",
+            &m.lock_wait,
+        );
+        write_histogram(
+            &mut out,
+            "sqliterg_execution_seconds",
+            "Time spent by a request executing on the database, with the lock held",
+            &m.execution,
+        );
+        write_counter(
+            &mut out,
+            "sqliterg_auth_failures_total",
+            "Failed authentications, of requests (credentials) or web services (token)",
+            &m.auth_failures,
+        );
+        write_counter(
+            &mut out,
+            "sqliterg_job_runs_total",
+            "Runs of the macros and of the backup, by outcome",
+            &m.job_runs,
+        );
+        write_histogram(
+            &mut out,
+            "sqliterg_job_duration_seconds",
+            "Duration of the runs of the macros and of the backup",
+            &m.job_duration,
+        );
+    }
+
+    let (db_sizes, wal_sizes) = file_sizes(db_map);
+    write_gauge(
+        &mut out,
+        "sqliterg_db_file_size_bytes",
+        "Size of the database file",
+        &db_sizes,
+    );
+    write_gauge(
+        &mut out,
+        "sqliterg_wal_file_size_bytes",
+        "Size of the WAL file of the database",
+        &wal_sizes,
+    );
+    out
+}
+
+/// Serves the metrics. If a token is configured, it must be passed as the "token" query
+/// parameter or as a bearer token, in the Authorization header.
+pub async fn handler(
+    req: HttpRequest,
+    db_map: web::Data<HashMap<String, Db>>,
+    metrics_token: web::Data<Option<String>>,
+) -> HttpResponse {
+    if let Some(expected) = metrics_token.as_ref() {
+        if !process_req_token(&req, expected) {
+            sleep(Duration::from_millis(1000)).await;
+            return HttpResponse::Unauthorized().finish();
+        }
+    }
+
+    HttpResponse::Ok()
+        .content_type(ContentType(
+            "text/plain; version=0.0.4; charset=utf-8".parse().unwrap(),
+        ))
+        .body(render(&db_map))
+}
@@ -0,0 +1,526 @@
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The OpenAPI description of the web services, served at /openapi.json with --openapi. It's
+// generated at startup from the configuration: for each database, the transaction endpoint
+// (with a schema for each stored statement, whose parameters are those that SQLite finds in
+// the SQL), the stored statements with an HTTP route, the macros and the backup exposed as
+// web services, and the authentication.
+
+use std::{collections::HashMap, ops::DerefMut};
+
+use actix_web::{http::header::ContentType, web, HttpResponse};
+use rusqlite::Connection;
+use serde_json::{json, Map as JsonMap, Value as JsonValue};
+
+use crate::{
+    db_config::{AuthMode, ExecutionWebService, HttpMethod, ParamType},
+    main_config::Db,
+    MUTEXES,
+};
+
+const BASIC: &str = "httpBasic";
+const TOKEN: &str = "token";
+
+/// The parameters of a stored statement, as SQLite sees them when preparing it
+enum Params {
+    // without the ':'
+    Named(Vec<String>),
+    Positional(usize),
+    // it couldn't be prepared, e.g. because it refers to a table created later
+    Unknown,
+}
+
+impl Params {
+    fn of(conn: &Connection, sql: &str) -> Params {
+        let stmt = match conn.prepare(sql) {
+            Ok(stmt) => stmt,
+            Err(_) => return Params::Unknown,
+        };
+        let count = stmt.parameter_count();
+        let names: Vec<&str> = (1..=count)
+            .filter_map(|i| stmt.parameter_name(i))
+            .filter_map(|n| n.strip_prefix(':'))
+            .collect();
+        match names.len() == count && count > 0 {
+            true => Params::Named(names.into_iter().map(str::to_string).collect()),
+            false => Params::Positional(count),
+        }
+    }
+
+    fn schema(&self) -> Option<JsonValue> {
+        match self {
+            Params::Named(names) => {
+                let props: JsonMap<String, JsonValue> =
+                    names.iter().map(|n| (n.to_owned(), json!({}))).collect();
+                Some(json!({
+                    "type": "object",
+                    "properties": props,
+                    "additionalProperties": false
+                }))
+            }
+            Params::Positional(0) => None,
+            Params::Positional(n) => Some(json!({
+                "type": "array",
+                "maxItems": n
+            })),
+            Params::Unknown => Some(json!({ "$ref": "#/components/schemas/Values" })),
+        }
+    }
+}
+
+/// The name of a component, that can only have some characters
+fn component(parts: &[&str]) -> String {
+    parts
+        .join(".")
+        .chars()
+        .map(
+            |c| match c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                true => c,
+                false => '_',
+            },
+        )
+        .collect()
+}
+
+fn common_schemas() -> JsonMap<String, JsonValue> {
+    // see req_res
+    let ret = json!({
+        "Values": {
+            "description": "Named (an object, whose keys are the parameters without the ':') or positional (an array) values",
+            "oneOf": [
+                { "type": "object", "additionalProperties": true },
+                { "type": "array", "items": {} }
+            ]
+        },
+        "Credentials": {
+            "type": "object",
+            "required": [ "user", "password" ],
+            "properties": {
+                "user": { "type": "string" },
+                "password": { "type": "string" }
+            }
+        },
+        "TransactionItem": {
+            "type": "object",
+            "description": "Exactly one of query and statement; a statement can be the id of a stored one, prefixed with '^'",
+            "properties": {
+                "query": { "type": "string" },
+                "statement": { "type": "string" },
+                "values": { "$ref": "#/components/schemas/Values" },
+                "valuesBatch": {
+                    "type": "array",
+                    "description": "Only for statements",
+                    "items": { "$ref": "#/components/schemas/Values" }
+                },
+                "noFail": { "type": "boolean", "default": false }
+            }
+        },
+        "ResponseItem": {
+            "type": "object",
+            "required": [ "success" ],
+            "properties": {
+                "success": { "type": "boolean" },
+                "error": { "type": "string" },
+                "resultSet": { "type": "array", "items": { "type": "object" } },
+                "rowsUpdated": { "type": "integer" },
+                "rowsUpdatedBatch": { "type": "array", "items": { "type": "integer" } }
+            }
+        },
+        "Response": {
+            "type": "object",
+            "properties": {
+                "results": { "type": "array", "items": { "$ref": "#/components/schemas/ResponseItem" } }
+            }
+        },
+        "Error": {
+            "type": "object",
+            "properties": {
+                "reqIdx": { "type": "integer", "description": "The index of the failed item, or -1" },
+                "message": { "type": "string" }
+            }
+        },
+        "Run": {
+            "type": "object",
+            "properties": {
+                "start": { "type": "string", "format": "date-time" },
+                "end": { "type": "string", "format": "date-time" },
+                "trigger": { "type": "string" },
+                "success": { "type": "boolean" },
+                "rowsUpdated": { "type": "integer" },
+                "error": { "type": "string" }
+            }
+        },
+        "RunsResponse": {
+            "type": "object",
+            "properties": {
+                "runs": { "type": "array", "items": { "$ref": "#/components/schemas/Run" } }
+            }
+        },
+        "Rows": {
+            "type": "array",
+            "items": { "type": "object" }
+        },
+        "RowsUpdated": {
+            "type": "object",
+            "properties": {
+                "rowsUpdated": { "type": "integer" }
+            }
+        },
+        "BackupProgressResponse": {
+            "type": "object",
+            "properties": {
+                "inProgress": { "type": "boolean" },
+                "started": { "type": "string", "format": "date-time" },
+                "pageCount": { "type": "integer" },
+                "remaining": { "type": "integer" }
+            }
+        }
+    });
+    match ret {
+        JsonValue::Object(map) => map,
+        _ => unreachable!(),
+    }
+}
+
+fn response(description: &str, schema: &str) -> JsonValue {
+    json!({
+        "description": description,
+        "content": {
+            "application/json": { "schema": { "$ref": format!("#/components/schemas/{}", schema) } }
+        }
+    })
+}
+
+/// The responses of an operation: the success, the client errors and the authentication error
+fn responses(ok: &str, ok_schema: &str, auth_error_code: Option<u16>) -> JsonValue {
+    let mut ret = JsonMap::new();
+    ret.insert("200".to_string(), response(ok, ok_schema));
+    ret.insert("400".to_string(), response("Malformed request", "Error"));
+    ret.insert("404".to_string(), response("Not found", "Error"));
+    ret.insert(
+        "500".to_string(),
+        response("Error while executing", "Error"),
+    );
+    if let Some(code) = auth_error_code {
+        ret.insert(code.to_string(), response("Authentication failed", "Error"));
+    }
+    JsonValue::Object(ret)
+}
+
+/// An operation of a web service authenticated by a token, if one is configured
+fn ws_operation(
+    id: String,
+    summary: String,
+    ok_schema: &str,
+    ws: &ExecutionWebService,
+    tag: &str,
+) -> JsonValue {
+    let has_token = ws.auth_token.is_some() || ws.hashed_auth_token.is_some();
+    let mut op = json!({
+        "operationId": id,
+        "summary": summary,
+        "tags": [ tag ],
+        "responses": responses("Success", ok_schema, has_token.then_some(ws.auth_error_code))
+    });
+    if has_token {
+        op["security"] = json!([ { TOKEN: [] } ]);
+    }
+    op
+}
+
+fn param_type(t: &ParamType) -> &'static str {
+    match t {
+        ParamType::Integer => "integer",
+        ParamType::Real => "number",
+        ParamType::Text => "string",
+        ParamType::Boolean => "boolean",
+    }
+}
+
+fn stored_statement_schema(db_name: &str, id: &str, params: &Params) -> JsonValue {
+    let mut props = json!({
+        "statement": { "type": "string", "enum": [ format!("^{}", id) ] },
+        "noFail": { "type": "boolean", "default": false }
+    });
+    if let Some(values) = params.schema() {
+        props["values"] = values.to_owned();
+        props["valuesBatch"] = json!({ "type": "array", "items": values });
+    }
+    let mut description = format!("Stored statement '{}' of database '{}'", id, db_name);
+    if let Params::Unknown = params {
+        description.push_str("; its parameters couldn't be determined at startup");
+    }
+    json!({
+        "type": "object",
+        "description": description,
+        "required": [ "statement" ],
+        "properties": props
+    })
+}
+
+fn db_paths(
+    db_name: &str,
+    db: &Db,
+    conn: &Connection,
+    paths: &mut JsonMap<String, JsonValue>,
+    schemas: &mut JsonMap<String, JsonValue>,
+) {
+    let conf = &db.conf;
+
+    // the transaction endpoint; the request is specific to the database, for the stored
+    // statements and the authentication
+    let mut items = vec![];
+    if !conf.use_only_stored_statements {
+        items.push(json!({ "$ref": "#/components/schemas/TransactionItem" }));
+    }
+    let mut ids: Vec<&String> = db.stored_statements.keys().collect();
+    ids.sort();
+    for id in ids {
+        let params = Params::of(conn, &db.stored_statements[id]);
+        let name = component(&[db_name, "statement", id]);
+        schemas.insert(
+            name.to_owned(),
+            stored_statement_schema(db_name, id, &params),
+        );
+        items.push(json!({ "$ref": format!("#/components/schemas/{}", name) }));
+    }
+    let mut request = json!({
+        "type": "object",
+        "required": [ "transaction" ],
+        "properties": {
+            "credentials": { "$ref": "#/components/schemas/Credentials" },
+            "transaction": { "type": "array", "minItems": 1, "items": { "anyOf": items } }
+        }
+    });
+    let mut description = "Executes the items in a transaction".to_string();
+    let mut security = None;
+    match conf.auth.as_ref().map(|a| &a.mode) {
+        Some(AuthMode::HttpBasic) => security = Some(json!([ { BASIC: [] } ])),
+        Some(AuthMode::Inline) => {
+            request["required"] = json!(["credentials", "transaction"]);
+            description.push_str("; the credentials are in the request");
+        }
+        None => {}
+    }
+    if conf.read_only {
+        description.push_str("; the database is read-only");
+    }
+    let request_name = component(&[db_name, "Request"]);
+    schemas.insert(request_name.to_owned(), request);
+
+    let mut op = json!({
+        "operationId": component(&[db_name, "transaction"]),
+        "summary": description,
+        "tags": [ db_name ],
+        "requestBody": {
+            "required": true,
+            "content": {
+                "application/json": { "schema": { "$ref": format!("#/components/schemas/{}", request_name) } }
+            }
+        },
+        "responses": responses(
+            "The results, one for each item",
+            "Response",
+            conf.auth.as_ref().map(|a| a.auth_error_code)
+        )
+    });
+    if let Some(security) = &security {
+        op["security"] = security.to_owned();
+    }
+    paths.insert(format!("/{}", db_name), json!({ "post": op }));
+
+    // the stored statements with an http node, see stored_routes
+    for ss in conf.stored_statements.iter().flatten() {
+        let Some(http) = &ss.http else {
+            continue;
+        };
+        let params: Vec<JsonValue> = http
+            .params
+            .iter()
+            .map(|p| {
+                json!({
+                    "name": p.name,
+                    "in": "query",
+                    "required": p.required,
+                    "schema": { "type": param_type(&p.param_type) }
+                })
+            })
+            .collect();
+        let (method, summary, ok_schema) = match http.method {
+            HttpMethod::Get => ("get", "Executes the query and returns its rows", "Rows"),
+            HttpMethod::Post => (
+                "post",
+                "Executes the statement and returns the number of changed rows",
+                "RowsUpdated",
+            ),
+        };
+        let mut op = json!({
+            "operationId": component(&[db_name, "q", &ss.id]),
+            "summary": format!("{}; stored statement '{}'", summary, ss.id),
+            "tags": [ db_name ],
+            "parameters": params,
+            "responses": responses(
+                "Success",
+                ok_schema,
+                conf.auth.as_ref().map(|a| a.auth_error_code)
+            )
+        });
+        op["responses"]["405"] = response("Called with the wrong method", "Error");
+        if http.method == HttpMethod::Post {
+            let props: JsonMap<String, JsonValue> = http
+                .params
+                .iter()
+                .map(|p| {
+                    (
+                        p.name.to_owned(),
+                        json!({ "type": param_type(&p.param_type) }),
+                    )
+                })
+                .collect();
+            op["requestBody"] = json!({
+                "description": "The parameters can also be in the body",
+                "required": false,
+                "content": {
+                    "application/json": {
+                        "schema": { "type": "object", "properties": props, "additionalProperties": false }
+                    }
+                }
+            });
+        }
+        if let Some(security) = &security {
+            op["security"] = security.to_owned();
+        }
+        let mut item = JsonMap::new();
+        item.insert(method.to_string(), op);
+        paths.insert(format!("/{}/q/{}", db_name, ss.id), JsonValue::Object(item));
+    }
+
+    let mut macros: Vec<_> = db
+        .macros
+        .values()
+        .filter_map(|m| m.execution.web_service.as_ref().map(|ws| (&m.id, ws)))
+        .collect();
+    macros.sort_by_key(|(id, _)| *id);
+    for (id, ws) in macros {
+        let op = ws_operation(
+            component(&[db_name, "macro", id]),
+            format!("Executes the macro '{}'", id),
+            "Response",
+            ws,
+            db_name,
+        );
+        let mut get = op.to_owned();
+        get["operationId"] = json!(format!("{}.get", op["operationId"].as_str().unwrap()));
+        paths.insert(
+            format!("/{}/macro/{}", db_name, id),
+            json!({ "get": get, "post": op }),
+        );
+        paths.insert(
+            format!("/{}/macro/{}/runs", db_name, id),
+            json!({
+                "get": ws_operation(
+                    component(&[db_name, "macro", id, "runs"]),
+                    format!("Lists the last runs of the macro '{}'", id),
+                    "RunsResponse",
+                    ws,
+                    db_name,
+                )
+            }),
+        );
+    }
+
+    if let Some(ws) = conf
+        .backup
+        .as_ref()
+        .and_then(|b| b.execution.web_service.as_ref())
+    {
+        let op = ws_operation(
+            component(&[db_name, "backup"]),
+            "Performs a backup".to_string(),
+            "Response",
+            ws,
+            db_name,
+        );
+        let mut get = op.to_owned();
+        get["operationId"] = json!(component(&[db_name, "backup", "get"]));
+        paths.insert(
+            format!("/{}/backup", db_name),
+            json!({ "get": get, "post": op }),
+        );
+        paths.insert(
+            format!("/{}/backup/runs", db_name),
+            json!({
+                "get": ws_operation(
+                    component(&[db_name, "backup", "runs"]),
+                    "Lists the last runs of the backup".to_string(),
+                    "RunsResponse",
+                    ws,
+                    db_name,
+                )
+            }),
+        );
+        paths.insert(
+            format!("/{}/backup/progress", db_name),
+            json!({
+                "get": ws_operation(
+                    component(&[db_name, "backup", "progress"]),
+                    "Tells whether a backup is in progress".to_string(),
+                    "BackupProgressResponse",
+                    ws,
+                    db_name,
+                )
+            }),
+        );
+    }
+}
+
+/// Generates the description, as JSON; the connections are used to prepare the stored
+/// statements, to find their parameters.
+pub fn generate(db_map: &HashMap<String, Db>) -> String {
+    let mut paths = JsonMap::new();
+    let mut schemas = common_schemas();
+
+    let mut db_names: Vec<&String> = db_map.keys().collect();
+    db_names.sort();
+    for db_name in db_names {
+        let db_lock = MUTEXES.get().unwrap().get(db_name).unwrap();
+        let mut db_lock_guard = db_lock.lock().unwrap();
+        let conn = db_lock_guard.deref_mut();
+        db_paths(db_name, &db_map[db_name], conn, &mut paths, &mut schemas);
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": env!("CARGO_PKG_NAME"),
+            "version": env!("CARGO_PKG_VERSION")
+        },
+        "paths": paths,
+        "components": {
+            "schemas": schemas,
+            "securitySchemes": {
+                BASIC: { "type": "http", "scheme": "basic" },
+                TOKEN: { "type": "apiKey", "in": "query", "name": "token" }
+            }
+        }
+    })
+    .to_string()
+}
+
+pub async fn handler(spec: web::Data<String>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .body(spec.to_string())
+}
@@ -0,0 +1,1363 @@
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The PostgreSQL wire protocol (v3), for psql, BI tools and ORMs: an optional listener where
+// a database is chosen by name, as the "database" of the connection. Both the simple and the
+// extended query protocols are supported, with the text and binary formats; the SQL is still
+// SQLite's. With `auth`, the credentials are checked as a cleartext password (the protocol
+// has no TLS here: use a tunnel on untrusted networks). Each client is served by a thread of
+// its own, that locks the connection of the database for a statement, or for the whole of an
+// explicit transaction; the latter is bounded in time, idle or not, and meanwhile the HTTP
+// requests to the database fail rather than wait.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, BufReader, ErrorKind, Read, Write},
+    mem::take,
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use ring::rand::{SecureRandom, SystemRandom};
+use rusqlite::{ffi, params_from_iter, types::Value, Connection, ErrorCode};
+use serde_json::Value as JsonValue;
+
+use crate::{
+    audit::{self, AuditedStatement},
+    auth::check_credentials,
+    changes,
+    commons::check_stored_stmt,
+    logic::val_db2val_json,
+    main_config::Db,
+    metrics::record_auth_failure,
+    MUTEXES,
+};
+
+const PROTOCOL_MAJOR: i32 = 3;
+const SSL_REQUEST: i32 = 80877103;
+const GSSENC_REQUEST: i32 = 80877104;
+const CANCEL_REQUEST: i32 = 80877102;
+
+const MAX_STARTUP_SIZE: usize = 10000;
+// before the authentication, a client can only send a password
+const MAX_AUTH_MESSAGE_SIZE: usize = 4096;
+const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+// each connection has a thread; the clients that don't authenticate in time are dropped
+const MAX_CONNECTIONS: usize = 100;
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+// a client idling in a transaction keeps the database locked for everybody else
+const IDLE_IN_TRANSACTION_TIMEOUT: Duration = Duration::from_secs(30);
+const TRANSACTION_TIMEOUT: Duration = Duration::from_secs(60);
+
+const SERVER_VERSION: &str = "14.0";
+
+// type OIDs, from pg_type
+const BOOL: u32 = 16;
+const BYTEA: u32 = 17;
+const INT8: u32 = 20;
+const INT2: u32 = 21;
+const INT4: u32 = 23;
+const TEXT: u32 = 25;
+const FLOAT4: u32 = 700;
+const FLOAT8: u32 = 701;
+const UNKNOWN: u32 = 705;
+const VARCHAR: u32 = 1043;
+
+// the databases that a client keeps locked in a transaction, across round trips
+static IN_TRANSACTION: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+
+fn in_transaction_set() -> &'static Mutex<HashSet<String>> {
+    IN_TRANSACTION.get_or_init(|| Mutex::new(HashSet::new()))
+}
+
+/// Whether a client keeps the database locked in a transaction, that can last up to
+/// TRANSACTION_TIMEOUT
+pub fn in_transaction(db_name: &str) -> bool {
+    in_transaction_set().lock().unwrap().contains(db_name)
+}
+
+fn set_in_transaction(db_name: &str, in_tx: bool) {
+    let mut set = in_transaction_set().lock().unwrap();
+    if in_tx {
+        set.insert(db_name.to_string());
+    } else {
+        set.remove(db_name);
+    }
+}
+
+struct PgError {
+    code: &'static str,
+    message: String,
+}
+
+impl PgError {
+    fn new(code: &'static str, message: impl Into<String>) -> PgError {
+        PgError {
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn malformed() -> PgError {
+        PgError::new("08P01", "Malformed message")
+    }
+}
+
+impl From<rusqlite::Error> for PgError {
+    fn from(e: rusqlite::Error) -> PgError {
+        let failure = match &e {
+            rusqlite::Error::SqliteFailure(f, msg) => Some((f, msg.as_deref().unwrap_or_default())),
+            rusqlite::Error::SqlInputError { error, msg, .. } => Some((error, msg.as_str())),
+            _ => None,
+        };
+        let code = match (failure, &e) {
+            (Some((f, msg)), _) => match (f.code, f.extended_code) {
+                (_, ffi::SQLITE_CONSTRAINT_UNIQUE | ffi::SQLITE_CONSTRAINT_PRIMARYKEY) => "23505",
+                (_, ffi::SQLITE_CONSTRAINT_NOTNULL) => "23502",
+                (_, ffi::SQLITE_CONSTRAINT_FOREIGNKEY) => "23503",
+                (_, ffi::SQLITE_CONSTRAINT_CHECK) => "23514",
+                (ErrorCode::ConstraintViolation, _) => "23000",
+                (ErrorCode::ReadOnly, _) => "25006",
+                (ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked, _) => "55P03",
+                (ErrorCode::TypeMismatch, _) => "42804",
+                (ErrorCode::AuthorizationForStatementDenied, _) => "42501",
+                _ if msg.contains("syntax error") => "42601",
+                _ if msg.starts_with("no such table") => "42P01",
+                _ if msg.starts_with("no such column") => "42703",
+                _ if msg.starts_with("no such function") => "42883",
+                _ if msg.contains("already exists") => "42P07",
+                _ => "XX000",
+            },
+            (_, rusqlite::Error::MultipleStatement) => "42601",
+            (_, rusqlite::Error::InvalidParameterCount(_, _)) => "08P01",
+            _ => "XX000",
+        };
+        PgError::new(code, e.to_string())
+    }
+}
+
+// reading
+
+fn read_message(r: &mut impl Read, max_size: usize) -> io::Result<(u8, Vec<u8>)> {
+    let mut header = [0u8; 5];
+    r.read_exact(&mut header)?;
+    let len = i32::from_be_bytes(header[1..5].try_into().unwrap());
+    if len < 4 || len as usize - 4 > max_size {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "Invalid message length",
+        ));
+    }
+    let mut payload = vec![0u8; len as usize - 4];
+    r.read_exact(&mut payload)?;
+    Ok((header[0], payload))
+}
+
+struct Payload<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Payload<'a> {
+    fn new(buf: &'a [u8]) -> Payload<'a> {
+        Payload { buf, pos: 0 }
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], PgError> {
+        let ret = self
+            .buf
+            .get(self.pos..self.pos + n)
+            .ok_or_else(PgError::malformed)?;
+        self.pos += n;
+        Ok(ret)
+    }
+
+    fn u8(&mut self) -> Result<u8, PgError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn i16(&mut self) -> Result<i16, PgError> {
+        Ok(i16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> Result<i32, PgError> {
+        Ok(i32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn cstr(&mut self) -> Result<String, PgError> {
+        let len = self.buf[self.pos..]
+            .iter()
+            .position(|b| *b == 0)
+            .ok_or_else(PgError::malformed)?;
+        let ret = String::from_utf8(self.bytes(len)?.to_vec()).map_err(|_| PgError::malformed())?;
+        self.pos += 1;
+        Ok(ret)
+    }
+
+    /// A list of format codes: none means all text, one applies to all the values
+    fn formats(&mut self, n: usize) -> Result<Vec<i16>, PgError> {
+        let count = self.i16()?;
+        let codes = (0..count)
+            .map(|_| self.i16())
+            .collect::<Result<Vec<i16>, PgError>>()?;
+        match codes.len() {
+            0 => Ok(vec![0; n]),
+            1 => Ok(vec![codes[0]; n]),
+            l if l == n => Ok(codes),
+            _ => Err(PgError::new(
+                "08P01",
+                format!("{} format codes given for {} values", codes.len(), n),
+            )),
+        }
+    }
+}
+
+// writing
+
+fn put_i16(buf: &mut Vec<u8>, v: i16) {
+    buf.extend_from_slice(&v.to_be_bytes());
+}
+
+fn put_i32(buf: &mut Vec<u8>, v: i32) {
+    buf.extend_from_slice(&v.to_be_bytes());
+}
+
+fn put_cstr(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(s.as_bytes());
+    buf.push(0);
+}
+
+fn error_body(severity: &str, e: &PgError) -> Vec<u8> {
+    let mut body = vec![];
+    for (field, value) in [
+        (b'S', severity),
+        (b'V', severity),
+        (b'C', e.code),
+        (b'M', &e.message),
+    ] {
+        body.push(field);
+        put_cstr(&mut body, value);
+    }
+    body.push(0);
+    body
+}
+
+// SQL text
+
+/// If a string literal, a quoted identifier or a comment starts at `i`, the index after it
+fn skip_literal(b: &[u8], i: usize) -> Option<usize> {
+    let end = |from: usize, pat: &[u8]| {
+        b.get(from..)
+            .and_then(|rest| rest.windows(pat.len()).position(|w| w == pat))
+            .map_or(b.len(), |p| from + p + pat.len())
+    };
+    match b[i] {
+        q @ (b'\'' | b'"' | b'`') => Some(end(i + 1, &[q])),
+        b'[' => Some(end(i + 1, b"]")),
+        b'-' if b.get(i + 1) == Some(&b'-') => Some(end(i + 2, b"\n")),
+        b'/' if b.get(i + 1) == Some(&b'*') => Some(end(i + 2, b"*/")),
+        _ => None,
+    }
+}
+
+/// The first `n` keywords of a statement, uppercase, skipping the comments
+fn keywords(sql: &str, n: usize) -> Vec<String> {
+    let b = sql.as_bytes();
+    let mut ret = vec![];
+    let mut i = 0;
+    while i < b.len() && ret.len() < n {
+        if let (b'-' | b'/', Some(next)) = (b[i], skip_literal(b, i)) {
+            i = next;
+        } else if b[i].is_ascii_alphabetic() {
+            let start = i;
+            while i < b.len() && (b[i].is_ascii_alphanumeric() || b[i] == b'_') {
+                i += 1;
+            }
+            ret.push(sql[start..i].to_ascii_uppercase());
+        } else if b[i].is_ascii_whitespace() || b[i] == b'(' {
+            i += 1;
+        } else {
+            break;
+        }
+    }
+    ret
+}
+
+/// Splits a query on the semicolons, except those in literals, comments and the bodies of
+/// the triggers
+fn split_statements(sql: &str) -> Vec<&str> {
+    let b = sql.as_bytes();
+    let mut ret = vec![];
+    let mut start = 0;
+    let mut i = 0;
+    while i < b.len() {
+        if let Some(next) = skip_literal(b, i) {
+            i = next;
+            continue;
+        }
+        if b[i] == b';' {
+            let stmt = &sql[start..i];
+            let kw = keywords(stmt, 4);
+            let in_trigger = kw.first().is_some_and(|k| k == "CREATE")
+                && kw.iter().any(|k| k == "TRIGGER")
+                && !stmt.trim_end().to_ascii_uppercase().ends_with("END");
+            if !in_trigger {
+                ret.push(stmt);
+                start = i + 1;
+            }
+        }
+        i += 1;
+    }
+    ret.push(&sql[start..]);
+    ret.into_iter().filter(|s| !s.trim().is_empty()).collect()
+}
+
+/// PostgreSQL's placeholders ($1, $2...) to SQLite's (?1, ?2...), that have the same meaning
+fn translate_params(sql: &str) -> String {
+    let b = sql.as_bytes();
+    let mut ret = String::with_capacity(sql.len());
+    let mut i = 0;
+    while i < b.len() {
+        if let Some(next) = skip_literal(b, i) {
+            ret.push_str(&sql[i..next]);
+            i = next;
+        } else if b[i] == b'$' && b.get(i + 1).is_some_and(u8::is_ascii_digit) {
+            ret.push('?');
+            i += 1;
+        } else {
+            let next = i + sql[i..].chars().next().unwrap().len_utf8();
+            ret.push_str(&sql[i..next]);
+            i = next;
+        }
+    }
+    ret
+}
+
+fn command_tag(sql: &str, has_columns: bool, rows: usize, changes: u64) -> String {
+    let kw = keywords(sql, 4);
+    let first = kw.first().map(String::as_str).unwrap_or_default();
+    let count = if has_columns { rows as u64 } else { changes };
+    match first {
+        "INSERT" | "REPLACE" => format!("INSERT 0 {}", count),
+        "UPDATE" | "DELETE" => format!("{} {}", first, count),
+        _ if has_columns => format!("SELECT {}", rows),
+        "END" => "COMMIT".to_string(),
+        "CREATE" | "DROP" | "ALTER" => {
+            let object = kw[1..]
+                .iter()
+                .find(|k| !matches!(k.as_str(), "TEMP" | "TEMPORARY" | "UNIQUE" | "VIRTUAL"));
+            match object {
+                Some(object) => format!("{} {}", first, object),
+                None => first.to_string(),
+            }
+        }
+        _ => first.to_string(),
+    }
+}
+
+// types and values
+
+/// The type of a column, by the affinity of its declared type; None if it has no declared
+/// type (see https://www.sqlite.org/datatype3.html)
+fn declared_type(decl: Option<&str>) -> Option<u32> {
+    let d = decl?.to_ascii_uppercase();
+    if d.is_empty() {
+        // BLOB affinity, but anything goes
+        return None;
+    }
+    Some(if d.contains("BOOL") {
+        BOOL
+    } else if d.contains("INT") {
+        INT8
+    } else if d.contains("CHAR") || d.contains("CLOB") || d.contains("TEXT") {
+        TEXT
+    } else if d.contains("BLOB") {
+        BYTEA
+    } else if d.contains("REAL") || d.contains("FLOA") || d.contains("DOUB") {
+        FLOAT8
+    } else {
+        // NUMERIC affinity: dates, decimals... whatever was stored
+        TEXT
+    })
+}
+
+/// The type of a column without a declared type, by its values
+fn inferred_type(rows: &[Vec<Value>], col: usize) -> u32 {
+    let mut ret = None;
+    for v in rows.iter().map(|r| &r[col]) {
+        let t = match v {
+            Value::Null => continue,
+            Value::Integer(_) => INT8,
+            Value::Real(_) => FLOAT8,
+            Value::Text(_) => TEXT,
+            Value::Blob(_) => BYTEA,
+        };
+        ret = match (ret, t) {
+            (None, t) => Some(t),
+            (Some(r), t) if r == t => Some(r),
+            (Some(INT8 | FLOAT8), INT8 | FLOAT8) => Some(FLOAT8),
+            _ => return TEXT,
+        };
+    }
+    ret.unwrap_or(TEXT)
+}
+
+fn type_len(oid: u32) -> i16 {
+    match oid {
+        BOOL => 1,
+        INT8 | FLOAT8 => 8,
+        _ => -1,
+    }
+}
+
+fn float_text(f: f64) -> String {
+    if f.is_nan() {
+        "NaN".to_string()
+    } else if f.is_infinite() {
+        if f > 0.0 { "Infinity" } else { "-Infinity" }.to_string()
+    } else {
+        f.to_string()
+    }
+}
+
+fn text_value(v: &Value, oid: u32) -> Vec<u8> {
+    match v {
+        Value::Null => vec![],
+        Value::Integer(i) if oid == BOOL => if *i != 0 { "t" } else { "f" }.into(),
+        Value::Integer(i) => i.to_string().into_bytes(),
+        Value::Real(f) => float_text(*f).into_bytes(),
+        Value::Text(s) => s.to_owned().into_bytes(),
+        Value::Blob(b) => format!("\\x{}", hex::encode(b)).into_bytes(),
+    }
+}
+
+fn binary_value(v: &Value, oid: u32, column: &str) -> Result<Vec<u8>, PgError> {
+    let as_f64 = || match v {
+        Value::Integer(i) => Some(*i as f64),
+        Value::Real(f) => Some(*f),
+        Value::Text(s) => s.trim().parse().ok(),
+        _ => None,
+    };
+    let as_i64 = || match v {
+        Value::Integer(i) => Some(*i),
+        Value::Real(f) if f.fract() == 0.0 => Some(*f as i64),
+        Value::Text(s) => s.trim().parse().ok(),
+        _ => None,
+    };
+    let ret = match oid {
+        INT8 => as_i64().map(|i| i.to_be_bytes().to_vec()),
+        FLOAT8 => as_f64().map(|f| f.to_be_bytes().to_vec()),
+        BOOL => as_i64().map(|i| vec![(i != 0) as u8]),
+        BYTEA => match v {
+            Value::Blob(b) => Some(b.to_owned()),
+            other => Some(text_value(other, TEXT)),
+        },
+        _ => Some(text_value(v, oid)),
+    };
+    ret.ok_or_else(|| {
+        PgError::new(
+            "22P02",
+            format!(
+                "A value of column '{}' can't be sent as type {}",
+                column, oid
+            ),
+        )
+    })
+}
+
+fn decode_param(raw: &[u8], oid: u32, binary: bool) -> Result<Value, PgError> {
+    let invalid = || {
+        PgError::new(
+            "22P02",
+            format!("Invalid value for a parameter of type {}", oid),
+        )
+    };
+    if binary {
+        let int = |len: usize| match raw.len() == len {
+            true => Ok(raw.iter().fold(0i64, |acc, b| (acc << 8) | *b as i64)),
+            false => Err(invalid()),
+        };
+        return match oid {
+            BOOL => Ok(Value::Integer(
+                (raw.first().ok_or_else(invalid)? != &0) as i64,
+            )),
+            INT2 => Ok(Value::Integer(int(2)? as i16 as i64)),
+            INT4 => Ok(Value::Integer(int(4)? as i32 as i64)),
+            INT8 => Ok(Value::Integer(int(8)?)),
+            FLOAT4 => Ok(Value::Real(
+                f32::from_be_bytes(raw.try_into().map_err(|_| invalid())?) as f64,
+            )),
+            FLOAT8 => Ok(Value::Real(f64::from_be_bytes(
+                raw.try_into().map_err(|_| invalid())?,
+            ))),
+            BYTEA => Ok(Value::Blob(raw.to_vec())),
+            0 | TEXT | VARCHAR | UNKNOWN => Ok(Value::Text(
+                String::from_utf8(raw.to_vec()).map_err(|_| invalid())?,
+            )),
+            _ => Err(PgError::new(
+                "0A000",
+                format!(
+                    "Binary format is not supported for parameters of type {}",
+                    oid
+                ),
+            )),
+        };
+    }
+
+    let s = std::str::from_utf8(raw).map_err(|_| invalid())?;
+    match oid {
+        BOOL => match s.trim().to_ascii_lowercase().as_str() {
+            "t" | "true" | "y" | "yes" | "on" | "1" => Ok(Value::Integer(1)),
+            "f" | "false" | "n" | "no" | "off" | "0" => Ok(Value::Integer(0)),
+            _ => Err(invalid()),
+        },
+        INT2 | INT4 | INT8 => s.trim().parse().map(Value::Integer).map_err(|_| invalid()),
+        FLOAT4 | FLOAT8 => s.trim().parse().map(Value::Real).map_err(|_| invalid()),
+        BYTEA => match s.strip_prefix("\\x") {
+            Some(h) => hex::decode(h).map(Value::Blob).map_err(|_| invalid()),
+            None => Ok(Value::Blob(raw.to_vec())),
+        },
+        _ => Ok(Value::Text(s.to_string())),
+    }
+}
+
+// the session
+
+struct Column {
+    name: String,
+    oid: u32,
+}
+
+struct Outcome {
+    columns: Vec<(String, Option<u32>)>,
+    rows: Vec<Vec<Value>>,
+    tag: String,
+}
+
+#[derive(Clone)]
+struct Prepared {
+    // as given, for the audit
+    text: String,
+    sql: String,
+    param_types: Vec<u32>,
+    // None for the statements that return no rows
+    columns: Option<Vec<(String, u32)>>,
+}
+
+struct Portal {
+    stmt: Prepared,
+    params: Vec<Value>,
+    formats: Vec<i16>,
+    // executed at the first Execute, then sent in chunks if requested
+    outcome: Option<Outcome>,
+    sent: usize,
+}
+
+struct Session {
+    db_name: String,
+    db: Arc<Db>,
+    user: Option<String>,
+    writer: TcpStream,
+    out: Vec<u8>,
+    // held while in a transaction
+    guard: Option<MutexGuard<'static, Connection>>,
+    // when the lock was taken, to bound the transactions
+    locked_at: Option<Instant>,
+    // a statement failed in the transaction: until it ends, the others are refused
+    failed: bool,
+    audited: Vec<AuditedStatement>,
+    statements: HashMap<String, Prepared>,
+    portals: HashMap<String, Portal>,
+}
+
+impl Session {
+    fn send(&mut self, tag: u8, body: &[u8]) {
+        self.out.push(tag);
+        put_i32(&mut self.out, body.len() as i32 + 4);
+        self.out.extend_from_slice(body);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.out.is_empty() {
+            self.writer.write_all(&take(&mut self.out))?;
+        }
+        Ok(())
+    }
+
+    fn error(&mut self, severity: &str, e: &PgError) {
+        self.send(b'E', &error_body(severity, e));
+    }
+
+    fn ready(&mut self) {
+        let status = match (self.failed, self.guard.is_some()) {
+            (true, _) => b'E',
+            (false, true) => b'T',
+            (false, false) => b'I',
+        };
+        self.send(b'Z', &[status]);
+    }
+
+    fn row_description(&mut self, columns: &[Column], formats: &[i16]) {
+        let mut body = vec![];
+        put_i16(&mut body, columns.len() as i16);
+        for (i, c) in columns.iter().enumerate() {
+            put_cstr(&mut body, &c.name);
+            put_i32(&mut body, 0);
+            put_i16(&mut body, 0);
+            put_i32(&mut body, c.oid as i32);
+            put_i16(&mut body, type_len(c.oid));
+            put_i32(&mut body, -1);
+            put_i16(&mut body, formats.get(i).copied().unwrap_or(0));
+        }
+        self.send(b'T', &body);
+    }
+
+    fn data_row(
+        &mut self,
+        row: &[Value],
+        columns: &[Column],
+        formats: &[i16],
+    ) -> Result<(), PgError> {
+        let mut body = vec![];
+        put_i16(&mut body, row.len() as i16);
+        for ((v, c), f) in row.iter().zip(columns).zip(formats) {
+            if let Value::Null = v {
+                put_i32(&mut body, -1);
+                continue;
+            }
+            let bytes = match f {
+                1 => binary_value(v, c.oid, &c.name)?,
+                _ => text_value(v, c.oid),
+            };
+            put_i32(&mut body, bytes.len() as i32);
+            body.extend_from_slice(&bytes);
+        }
+        self.send(b'D', &body);
+        Ok(())
+    }
+
+    fn conn(&mut self) -> &mut Connection {
+        if self.guard.is_none() {
+            let db_lock = MUTEXES.get().unwrap().get(&self.db_name).unwrap();
+            self.guard = Some(db_lock.lock().unwrap());
+            self.locked_at = Some(Instant::now());
+        }
+        self.guard.as_mut().unwrap()
+    }
+
+    fn unlock(&mut self) {
+        if self.guard.take().is_some() {
+            set_in_transaction(&self.db_name, false);
+        }
+        self.locked_at = None;
+    }
+
+    /// Releases the lock, unless in a transaction
+    fn release_if_idle(&mut self) {
+        match &self.guard {
+            Some(conn) if conn.is_autocommit() => self.unlock(),
+            Some(_) => set_in_transaction(&self.db_name, true),
+            None => (),
+        }
+    }
+
+    /// Rolls back the transaction in progress, if any, and releases the lock
+    fn abort(&mut self) {
+        if let Some(conn) = &self.guard {
+            if !conn.is_autocommit() {
+                let _ = conn.execute_batch("ROLLBACK");
+            }
+        }
+        self.unlock();
+        self.failed = false;
+        self.audited.clear();
+    }
+
+    /// Resolves a reference to a stored statement, checking `useOnlyStoredStatements`
+    fn resolve(&self, text: &str) -> Result<String, PgError> {
+        check_stored_stmt(
+            &text.to_string(),
+            &self.db.stored_statements,
+            self.db.conf.use_only_stored_statements,
+        )
+        .map(|s| s.to_owned())
+        .map_err(|e| PgError::new("42501", e.to_string()))
+    }
+
+    /// Executes a statement. After it, the lock is still held if it's in a transaction; when
+    /// the transaction is committed (or if there was none) the changes are published and
+    /// the audit log is written.
+    fn execute(&mut self, text: &str, sql: &str, params: Vec<Value>) -> Result<Outcome, PgError> {
+        let kw = keywords(sql, 2);
+        let first = kw.first().map(String::as_str).unwrap_or_default();
+        let ends_tx = matches!(first, "COMMIT" | "END" | "ROLLBACK");
+        if self.failed && !ends_tx {
+            return Err(PgError::new(
+                "25P02",
+                "Current transaction is aborted, commands ignored until end of transaction block",
+            ));
+        }
+        if first == "SET" {
+            // session settings of the drivers, that SQLite has no use for
+            return Ok(Outcome {
+                columns: vec![],
+                rows: vec![],
+                tag: "SET".to_string(),
+            });
+        }
+        // committing a failed transaction rolls it back
+        let (sql, rollback) = match (self.failed, first) {
+            (true, "COMMIT" | "END") => ("ROLLBACK", true),
+            _ => (
+                sql,
+                first == "ROLLBACK" && kw.get(1).is_none_or(|k| k != "TO"),
+            ),
+        };
+
+        let db_name = self.db_name.to_owned();
+        let read_only = self.db.conf.read_only;
+        let conn = self.conn();
+        // the events for the webhooks are committed with the changes: a statement out of a
+        // transaction gets one of its own
+        let wrap = conn.is_autocommit()
+            && matches!(first, "INSERT" | "UPDATE" | "DELETE" | "REPLACE" | "WITH");
+        let committing = !conn.is_autocommit() && matches!(first, "COMMIT" | "END") && !rollback;
+        if committing {
+            if let Err(e) = changes::write_outbox(&db_name, conn) {
+                self.abort();
+                return Err(PgError::new("XX000", e.to_string()));
+            }
+        }
+        if wrap {
+            if let Err(e) = conn.execute_batch("BEGIN") {
+                self.abort();
+                return Err(e.into());
+            }
+        }
+        let res = (|| -> Result<(Outcome, bool, u64), PgError> {
+            let mut stmt = conn.prepare(sql)?;
+            let write = !stmt.readonly();
+            if read_only && write {
+                return Err(PgError::new(
+                    "25006",
+                    "Cannot execute a statement that writes in a read-only database",
+                ));
+            }
+            let columns: Vec<(String, Option<u32>)> = stmt
+                .columns()
+                .iter()
+                .map(|c| (c.name().to_string(), declared_type(c.decl_type())))
+                .collect();
+            let mut rows = vec![];
+            let mut res = stmt.query(params_from_iter(params.iter()))?;
+            while let Some(row) = res.next()? {
+                rows.push(
+                    (0..columns.len())
+                        .map(|i| row.get::<_, Value>(i))
+                        .collect::<rusqlite::Result<Vec<Value>>>()?,
+                );
+            }
+            drop(res);
+            drop(stmt);
+            let changes = if write { conn.changes() } else { 0 };
+            let tag = command_tag(sql, !columns.is_empty(), rows.len(), changes);
+            if wrap {
+                changes::write_outbox(&db_name, conn)
+                    .map_err(|e| PgError::new("XX000", e.to_string()))?;
+                conn.execute_batch("COMMIT")?;
+            }
+            Ok((Outcome { columns, rows, tag }, write, changes))
+        })();
+        if wrap && !conn.is_autocommit() {
+            let _ = conn.execute_batch("ROLLBACK");
+        }
+        let in_tx = !conn.is_autocommit();
+
+        match res {
+            Ok((outcome, write, changes)) => {
+                if write && audit::is_enabled(&db_name) {
+                    let mut a = AuditedStatement::new(self.audited.len(), text);
+                    if !params.is_empty() {
+                        a.values = Some(JsonValue::Array(
+                            params.iter().cloned().map(val_db2val_json).collect(),
+                        ));
+                    }
+                    a.rows_updated = Some(changes as usize);
+                    self.audited.push(a);
+                }
+                if in_tx {
+                    if first == "ROLLBACK" {
+                        // to a savepoint
+                        self.failed = false;
+                    }
+                } else {
+                    let audited = take(&mut self.audited);
+                    let conn = self.conn();
+                    changes::publish(&db_name, conn);
+                    if !rollback {
+                        audit::record(&db_name, self.user.as_deref(), "pgwire", audited);
+                    }
+                    self.failed = false;
+                    self.unlock();
+                }
+                Ok(outcome)
+            }
+            Err(e) => {
+                if in_tx {
+                    self.failed = true;
+                } else {
+                    self.abort();
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn simple_query(&mut self, payload: &[u8]) {
+        let query = match Payload::new(payload).cstr() {
+            Ok(q) => q,
+            Err(e) => return self.error("ERROR", &e),
+        };
+        let statements = split_statements(&query);
+        if statements.is_empty() {
+            self.send(b'I', &[]);
+        }
+        for text in statements {
+            let text = text.trim();
+            let res = self
+                .resolve(text)
+                .and_then(|sql| self.execute(text, &sql, vec![]));
+            let outcome = match res {
+                Ok(o) => o,
+                Err(e) => {
+                    self.error("ERROR", &e);
+                    break;
+                }
+            };
+            if !outcome.columns.is_empty() {
+                let columns: Vec<Column> = outcome
+                    .columns
+                    .iter()
+                    .enumerate()
+                    .map(|(i, (name, oid))| Column {
+                        name: name.to_owned(),
+                        oid: oid.unwrap_or_else(|| inferred_type(&outcome.rows, i)),
+                    })
+                    .collect();
+                let formats = vec![0; columns.len()];
+                self.row_description(&columns, &formats);
+                for row in &outcome.rows {
+                    // text values never fail
+                    let _ = self.data_row(row, &columns, &formats);
+                }
+            }
+            let mut body = vec![];
+            put_cstr(&mut body, &outcome.tag);
+            self.send(b'C', &body);
+        }
+        self.ready();
+    }
+
+    fn parse(&mut self, p: &mut Payload) -> Result<(), PgError> {
+        let name = p.cstr()?;
+        let text = p.cstr()?.trim().trim_end_matches(';').trim().to_string();
+        let n = p.i16()?;
+        let mut param_types = (0..n)
+            .map(|_| p.i32().map(|t| t as u32))
+            .collect::<Result<Vec<u32>, PgError>>()?;
+
+        let sql = translate_params(&self.resolve(&text)?);
+        let columns = if sql.is_empty() || keywords(&sql, 1).first().is_some_and(|k| k == "SET") {
+            None
+        } else {
+            let conn = self.conn();
+            let res = conn.prepare(&sql).map(|stmt| {
+                let columns: Vec<(String, u32)> = stmt
+                    .columns()
+                    .iter()
+                    .map(|c| {
+                        let oid = declared_type(c.decl_type()).unwrap_or(TEXT);
+                        (c.name().to_string(), oid)
+                    })
+                    .collect();
+                (stmt.parameter_count(), columns)
+            });
+            self.release_if_idle();
+            let (count, columns) = res?;
+            if param_types.len() < count {
+                param_types.resize(count, 0);
+            }
+            (!columns.is_empty()).then_some(columns)
+        };
+
+        let prepared = Prepared {
+            text,
+            sql,
+            param_types,
+            columns,
+        };
+        self.statements.insert(name, prepared);
+        self.send(b'1', &[]);
+        Ok(())
+    }
+
+    fn bind(&mut self, p: &mut Payload) -> Result<(), PgError> {
+        let portal = p.cstr()?;
+        let name = p.cstr()?;
+        let stmt = self.statements.get(&name).cloned().ok_or_else(|| {
+            PgError::new(
+                "26000",
+                format!("Prepared statement '{}' does not exist", name),
+            )
+        })?;
+
+        let n = stmt.param_types.len();
+        let param_formats = p.formats(n)?;
+        let count = p.i16()? as usize;
+        if count != n {
+            return Err(PgError::new(
+                "08P01",
+                format!(
+                    "{} parameters given, but the statement requires {}",
+                    count, n
+                ),
+            ));
+        }
+        let mut params = vec![];
+        for (oid, format) in stmt.param_types.iter().zip(&param_formats) {
+            let len = p.i32()?;
+            let value = match len {
+                -1 => Value::Null,
+                len => decode_param(p.bytes(len.max(0) as usize)?, *oid, *format == 1)?,
+            };
+            params.push(value);
+        }
+        let num_columns = stmt.columns.as_ref().map_or(0, Vec::len);
+        let formats = p.formats(num_columns)?;
+
+        self.portals.insert(
+            portal,
+            Portal {
+                stmt,
+                params,
+                formats,
+                outcome: None,
+                sent: 0,
+            },
+        );
+        self.send(b'2', &[]);
+        Ok(())
+    }
+
+    fn describe(&mut self, p: &mut Payload) -> Result<(), PgError> {
+        let kind = p.u8()?;
+        let name = p.cstr()?;
+        let (stmt, formats) = match kind {
+            b'S' => {
+                let stmt = self.statements.get(&name).ok_or_else(|| {
+                    PgError::new(
+                        "26000",
+                        format!("Prepared statement '{}' does not exist", name),
+                    )
+                })?;
+                let mut body = vec![];
+                put_i16(&mut body, stmt.param_types.len() as i16);
+                for t in &stmt.param_types {
+                    put_i32(&mut body, (if *t == 0 { TEXT } else { *t }) as i32);
+                }
+                let stmt = stmt.clone();
+                self.send(b't', &body);
+                (stmt, vec![])
+            }
+            b'P' => {
+                let portal = self.portals.get(&name).ok_or_else(|| {
+                    PgError::new("34000", format!("Portal '{}' does not exist", name))
+                })?;
+                (portal.stmt.clone(), portal.formats.clone())
+            }
+            _ => return Err(PgError::malformed()),
+        };
+        match stmt.columns {
+            Some(columns) => {
+                let columns: Vec<Column> = columns
+                    .into_iter()
+                    .map(|(name, oid)| Column { name, oid })
+                    .collect();
+                self.row_description(&columns, &formats);
+            }
+            None => self.send(b'n', &[]),
+        }
+        Ok(())
+    }
+
+    fn execute_portal(&mut self, p: &mut Payload) -> Result<(), PgError> {
+        let name = p.cstr()?;
+        let max_rows = p.i32()?;
+        let mut portal = self
+            .portals
+            .remove(&name)
+            .ok_or_else(|| PgError::new("34000", format!("Portal '{}' does not exist", name)))?;
+        if portal.stmt.sql.is_empty() {
+            self.send(b'I', &[]);
+            return Ok(());
+        }
+        if portal.outcome.is_none() {
+            let params = take(&mut portal.params);
+            portal.outcome = Some(self.execute(&portal.stmt.text, &portal.stmt.sql, params)?);
+        }
+
+        let outcome = portal.outcome.as_ref().unwrap();
+        let columns: Vec<Column> = portal
+            .stmt
+            .columns
+            .iter()
+            .flatten()
+            .map(|(name, oid)| Column {
+                name: name.to_owned(),
+                oid: *oid,
+            })
+            .collect();
+        let end = match max_rows {
+            m if m > 0 => outcome.rows.len().min(portal.sent + m as usize),
+            _ => outcome.rows.len(),
+        };
+        for row in &outcome.rows[portal.sent..end] {
+            self.data_row(row, &columns, &portal.formats)?;
+        }
+        if end < outcome.rows.len() {
+            portal.sent = end;
+            self.portals.insert(name, portal);
+            self.send(b's', &[]);
+        } else {
+            let mut body = vec![];
+            put_cstr(&mut body, &outcome.tag);
+            self.send(b'C', &body);
+        }
+        Ok(())
+    }
+
+    fn close(&mut self, p: &mut Payload) -> Result<(), PgError> {
+        let kind = p.u8()?;
+        let name = p.cstr()?;
+        match kind {
+            b'S' => self.statements.remove(&name).map(|_| ()),
+            b'P' => self.portals.remove(&name).map(|_| ()),
+            _ => return Err(PgError::malformed()),
+        };
+        self.send(b'3', &[]);
+        Ok(())
+    }
+
+    fn serve(&mut self, reader: &mut BufReader<TcpStream>) {
+        // after an error in the extended protocol, the messages are discarded until a Sync
+        let mut skipping = false;
+        loop {
+            // while in a transaction, the client must go on and must finish in time
+            let left = self
+                .locked_at
+                .map(|t| TRANSACTION_TIMEOUT.saturating_sub(t.elapsed()));
+            let res = match left {
+                Some(left) if left.is_zero() => Err(ErrorKind::TimedOut.into()),
+                _ => reader
+                    .get_ref()
+                    .set_read_timeout(left.map(|l| l.min(IDLE_IN_TRANSACTION_TIMEOUT)))
+                    .and_then(|_| read_message(reader, MAX_MESSAGE_SIZE)),
+            };
+            let (tag, payload) = match res {
+                Ok(m) => m,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    let e = if self
+                        .locked_at
+                        .is_some_and(|t| t.elapsed() >= TRANSACTION_TIMEOUT)
+                    {
+                        PgError::new("25P04", "Terminating connection due to transaction timeout")
+                    } else {
+                        PgError::new(
+                            "25P03",
+                            "Terminating connection due to idle-in-transaction timeout",
+                        )
+                    };
+                    self.error("FATAL", &e);
+                    let _ = self.flush();
+                    break;
+                }
+                Err(_) => break,
+            };
+            if skipping && !matches!(tag, b'S' | b'X') {
+                continue;
+            }
+
+            let mut p = Payload::new(&payload);
+            let res = match tag {
+                b'Q' => {
+                    self.simple_query(&payload);
+                    Ok(())
+                }
+                b'P' => self.parse(&mut p),
+                b'B' => self.bind(&mut p),
+                b'D' => self.describe(&mut p),
+                b'E' => self.execute_portal(&mut p),
+                b'C' => self.close(&mut p),
+                b'S' => {
+                    skipping = false;
+                    self.portals.remove("");
+                    self.ready();
+                    Ok(())
+                }
+                b'H' => Ok(()),
+                b'X' => break,
+                other => {
+                    let e = PgError::new(
+                        "08P01",
+                        format!("Unsupported message type '{}'", other as char),
+                    );
+                    self.error("FATAL", &e);
+                    let _ = self.flush();
+                    break;
+                }
+            };
+            if let Err(e) = res {
+                self.error("ERROR", &e);
+                skipping = true;
+            }
+            self.release_if_idle();
+            if self.flush().is_err() {
+                break;
+            }
+        }
+        self.abort();
+    }
+}
+
+// startup
+
+/// Reads the startup packet, refusing encryption; None if the client gave up, or if it's a
+/// cancel request (not supported)
+fn read_startup(stream: &mut TcpStream) -> io::Result<Option<(i32, Vec<u8>)>> {
+    loop {
+        let mut len = [0u8; 4];
+        stream.read_exact(&mut len)?;
+        let len = i32::from_be_bytes(len) as usize;
+        if !(8..=MAX_STARTUP_SIZE).contains(&len) {
+            return Ok(None);
+        }
+        let mut payload = vec![0u8; len - 4];
+        stream.read_exact(&mut payload)?;
+        let code = i32::from_be_bytes(payload[0..4].try_into().unwrap());
+        match code {
+            SSL_REQUEST | GSSENC_REQUEST => stream.write_all(b"N")?,
+            CANCEL_REQUEST => return Ok(None),
+            _ => return Ok(Some((code, payload[4..].to_vec()))),
+        }
+    }
+}
+
+/// An error that closes the connection, before the session is established
+fn fatal(stream: &mut TcpStream, code: &'static str, message: String) {
+    let body = error_body("FATAL", &PgError::new(code, message));
+    let mut msg = vec![b'E'];
+    put_i32(&mut msg, body.len() as i32 + 4);
+    msg.extend_from_slice(&body);
+    let _ = stream.write_all(&msg);
+}
+
+fn handle(mut stream: TcpStream, dbs: &HashMap<String, Arc<Db>>) -> io::Result<()> {
+    let _ = stream.set_nodelay(true);
+    // until the session is served, that sets its own timeouts
+    stream.set_read_timeout(Some(STARTUP_TIMEOUT))?;
+    let (version, payload) = match read_startup(&mut stream)? {
+        Some(s) => s,
+        None => return Ok(()),
+    };
+    if version >> 16 != PROTOCOL_MAJOR {
+        fatal(
+            &mut stream,
+            "0A000",
+            format!(
+                "Unsupported frontend protocol {}.{}",
+                version >> 16,
+                version & 0xffff
+            ),
+        );
+        return Ok(());
+    }
+
+    let mut p = Payload::new(&payload);
+    let mut params = HashMap::new();
+    while let Ok(key) = p.cstr() {
+        if key.is_empty() {
+            break;
+        }
+        params.insert(key, p.cstr().unwrap_or_default());
+    }
+    // only the protocol 3.0, with no extensions
+    let unsupported: Vec<&String> = params.keys().filter(|k| k.starts_with("_pq_.")).collect();
+    let mut negotiate = vec![];
+    if version & 0xffff > 0 || !unsupported.is_empty() {
+        put_i32(&mut negotiate, PROTOCOL_MAJOR << 16);
+        put_i32(&mut negotiate, unsupported.len() as i32);
+        for k in unsupported {
+            put_cstr(&mut negotiate, k);
+        }
+    }
+
+    let user = match params.get("user") {
+        Some(u) => u.to_owned(),
+        None => {
+            fatal(&mut stream, "28000", "No user name specified".to_string());
+            return Ok(());
+        }
+    };
+    let db_name = params.get("database").unwrap_or(&user).to_owned();
+    let db = match dbs.get(&db_name) {
+        Some(db) => db.clone(),
+        None => {
+            fatal(
+                &mut stream,
+                "3D000",
+                format!("Database '{}' does not exist", db_name),
+            );
+            return Ok(());
+        }
+    };
+
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut s = Session {
+        db_name,
+        db,
+        user: None,
+        writer: stream,
+        out: vec![],
+        guard: None,
+        locked_at: None,
+        failed: false,
+        audited: vec![],
+        statements: HashMap::new(),
+        portals: HashMap::new(),
+    };
+    if !negotiate.is_empty() {
+        s.send(b'v', &negotiate);
+    }
+
+    let db = s.db.clone();
+    if let Some(ac) = &db.conf.auth {
+        // AuthenticationCleartextPassword
+        s.send(b'R', &3i32.to_be_bytes());
+        s.flush()?;
+        let password = match read_message(&mut reader, MAX_AUTH_MESSAGE_SIZE)? {
+            (b'p', payload) => Payload::new(&payload).cstr().ok(),
+            _ => None,
+        };
+        let ok = match password {
+            Some(pwd) => check_credentials(ac, &user, &pwd, &s.db_name),
+            None => Ok(false),
+        };
+        let ok = match ok {
+            Ok(ok) => ok,
+            Err(msg) => {
+                s.error("FATAL", &PgError::new("57P03", msg));
+                return s.flush();
+            }
+        };
+        if !ok {
+            record_auth_failure(&s.db_name, "credentials");
+            thread::sleep(Duration::from_millis(1000));
+
+            let e = PgError::new(
+                "28P01",
+                format!("Password authentication failed for user '{}'", user),
+            );
+            s.error("FATAL", &e);
+            return s.flush();
+        }
+        s.user = Some(user.to_owned());
+    }
+
+    // AuthenticationOk
+    s.send(b'R', &0i32.to_be_bytes());
+    let application_name = params.get("application_name").cloned().unwrap_or_default();
+    for (k, v) in [
+        ("server_version", SERVER_VERSION),
+        ("server_encoding", "UTF8"),
+        ("client_encoding", "UTF8"),
+        ("DateStyle", "ISO, MDY"),
+        ("IntervalStyle", "postgres"),
+        ("TimeZone", "UTC"),
+        ("integer_datetimes", "on"),
+        ("standard_conforming_strings", "on"),
+        ("is_superuser", "off"),
+        ("session_authorization", &user),
+        ("application_name", &application_name),
+    ] {
+        let mut body = vec![];
+        put_cstr(&mut body, k);
+        put_cstr(&mut body, v);
+        s.send(b'S', &body);
+    }
+    // BackendKeyData: cancel requests are not supported, but the drivers expect it
+    let mut key = [0u8; 8];
+    let _ = SystemRandom::new().fill(&mut key);
+    s.send(b'K', &key);
+    s.ready();
+    s.flush()?;
+
+    s.serve(&mut reader);
+    Ok(())
+}
+
+/// Starts listening for PostgreSQL clients, on a thread of its own
+pub fn start(bind_host: &str, port: u16, db_map: &HashMap<String, Db>) -> io::Result<()> {
+    let listener = TcpListener::bind((bind_host, port))?;
+    let dbs: Arc<HashMap<String, Arc<Db>>> = Arc::new(
+        db_map
+            .iter()
+            .map(|(name, db)| (name.to_owned(), Arc::new(db.to_owned())))
+            .collect(),
+    );
+    let connections = Arc::new(AtomicUsize::new(0));
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(mut stream) => {
+                    if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                        connections.fetch_sub(1, Ordering::SeqCst);
+                        fatal(&mut stream, "53300", "Too many connections".to_string());
+                        continue;
+                    }
+                    let (dbs, connections) = (dbs.clone(), connections.clone());
+                    thread::spawn(move || {
+                        if let Err(e) = handle(stream, &dbs) {
+                            if !matches!(
+                                e.kind(),
+                                ErrorKind::UnexpectedEof
+                                    | ErrorKind::WouldBlock
+                                    | ErrorKind::TimedOut
+                            ) {
+                                eprintln!("PostgreSQL connection: {}", e);
+                            }
+                        }
+                        connections.fetch_sub(1, Ordering::SeqCst);
+                    });
+                }
+                Err(e) => eprintln!("PostgreSQL listener: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
@@ -12,17 +12,21 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::commons::default_as_false;
+use std::collections::BTreeMap;
+
+use crate::{commons::default_as_false, history::Run};
 use actix_web::{
     body::BoxBody,
     http::{header::ContentType, StatusCode},
     HttpRequest, HttpResponse, Responder,
 };
+use chrono::{DateTime, Utc};
+use rusqlite::types::Value;
 use serde::{Deserialize, Serialize};
 
 use serde_json::Value as JsonValue;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 pub struct ReqCredentials {
     pub user: String,
     pub password: String,
@@ -38,6 +42,23 @@ pub struct ReqTransactionItem {
     pub values: Option<JsonValue>,
     #[serde(rename = "valuesBatch")]
     pub values_batch: Option<Vec<JsonValue>>,
+    // the values with their SQLite types, used instead of the JSON ones (that are
+    // kept for the logs and the audit) when the request doesn't come as JSON (gRPC)
+    #[serde(skip)]
+    pub typed: Option<TypedValues>,
+}
+
+#[derive(Debug)]
+pub enum TypedParams {
+    // the names are prefixed with ':'
+    Named(Vec<(String, Value)>),
+    Positional(Vec<Value>),
+}
+
+#[derive(Debug, Default)]
+pub struct TypedValues {
+    pub values: Option<TypedParams>,
+    pub values_batch: Option<Vec<TypedParams>>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -60,6 +81,15 @@ pub struct ResponseItem {
     #[serde(rename = "rowsUpdatedBatch")]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub rows_updated_batch: Option<Vec<usize>>,
+    // instead of result_set, for the typed requests
+    #[serde(skip)]
+    pub typed_result_set: Option<TypedResultSet>,
+}
+
+#[derive(Debug)]
+pub struct TypedResultSet {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Value>>,
 }
 
 #[derive(Debug, Serialize)]
@@ -116,3 +146,106 @@ impl Response {
 pub struct Token {
     pub token: Option<String>,
 }
+
+#[derive(Deserialize)]
+pub struct ChangesQuery {
+    // comma-separated
+    pub tables: Option<String>,
+    #[serde(default)]
+    pub rows: bool,
+    // for INLINE authentication, as there is no body
+    pub user: Option<String>,
+    pub password: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ChangeEvent<'a> {
+    pub table: &'a str,
+    pub op: &'a str,
+    pub rowid: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub row: Option<JsonValue>,
+}
+
+#[derive(Serialize)]
+pub struct RunsResponse {
+    pub runs: Vec<Run>,
+}
+
+#[derive(Deserialize)]
+pub struct RestoreRequest {
+    pub token: Option<String>,
+    pub file: String,
+}
+
+#[derive(Serialize)]
+pub struct BackupFile {
+    pub name: String,
+    pub size: u64,
+    pub modified: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+pub struct BackupListResponse {
+    pub files: Vec<BackupFile>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct BackupProgress {
+    pub started: DateTime<Utc>,
+    #[serde(rename = "pageCount")]
+    pub page_count: i32,
+    pub remaining: i32,
+}
+
+#[derive(Serialize)]
+pub struct BackupProgressResponse {
+    #[serde(rename = "inProgress")]
+    pub in_progress: bool,
+    #[serde(flatten)]
+    pub progress: Option<BackupProgress>,
+}
+
+#[derive(Serialize)]
+pub struct ReadyResponse {
+    pub ready: bool,
+    // "ok", or what went wrong
+    pub databases: BTreeMap<String, String>,
+}
+
+#[derive(Serialize)]
+pub struct DbInfo {
+    pub name: String,
+    #[serde(rename = "inMemory")]
+    pub in_memory: bool,
+    pub features: Vec<&'static str>,
+}
+
+#[derive(Serialize)]
+pub struct InfoResponse {
+    pub version: String,
+    #[serde(rename = "sqliteVersion")]
+    pub sqlite_version: String,
+    #[serde(rename = "protoVersion")]
+    pub proto_version: u8,
+    pub databases: Vec<DbInfo>,
+}
+
+/// A request over the WebSocket; the id is returned in the response, to correlate them
+#[derive(Deserialize)]
+pub struct WsRequest {
+    #[serde(default)]
+    pub id: Option<JsonValue>,
+    #[serde(flatten)]
+    pub request: Request,
+}
+
+#[derive(Serialize)]
+pub struct WsResponse {
+    pub id: Option<JsonValue>,
+    // as there's no HTTP status for each message
+    #[serde(rename = "statusCode")]
+    pub status_code: u16,
+    #[serde(flatten)]
+    pub response: Response,
+}
@@ -0,0 +1,625 @@
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// CRUD endpoints for the tables allowed in the "rest" node, at /<db>/tables/<table>[/<pk>].
+// The tables are introspected at each request, so that the endpoints follow the schema. The
+// filters, the ordering and the pagination are in the query string, PostgREST-like
+// (?col=eq.5&order=id.desc&limit=10); the SQL is generated with the identifiers checked
+// against the schema and quoted, and the values are always bound as parameters.
+
+use std::{
+    ops::DerefMut,
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    http::{header::Header, Method, StatusCode},
+    rt::time::sleep,
+    web, Either, HttpMessage, HttpRequest, HttpResponse,
+};
+use actix_web_httpauth::headers::authorization::{Authorization, Basic};
+use rusqlite::{params_from_iter, types::Value, Connection, ErrorCode};
+use serde_json::{Map as JsonMap, Value as JsonValue};
+
+use crate::{
+    access_log::AccessInfo,
+    audit::{self, AuditedStatement},
+    auth::process_auth_async,
+    changes,
+    logic::{busy_msg, lock_db, val_db2val_json},
+    main_config::Db,
+    metrics::{record_auth_failure, record_process},
+    req_res::Response,
+};
+
+pub const OPERATIONS: [&str; 4] = ["read", "insert", "update", "delete"];
+
+type RestResult<T> = Result<T, (u16, String)>;
+
+fn sql_err(e: rusqlite::Error) -> (u16, String) {
+    let code = match e.sqlite_error_code() {
+        Some(ErrorCode::ConstraintViolation) => 409,
+        _ => 500,
+    };
+    (code, e.to_string())
+}
+
+pub fn quote(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+struct Column {
+    name: String,
+    decltype: String,
+}
+
+enum Key {
+    Column(usize),
+    RowId,
+    Composite,
+}
+
+struct Table {
+    name: String,
+    columns: Vec<Column>,
+    key: Key,
+}
+
+impl Table {
+    fn introspect(conn: &Connection, name: &str) -> RestResult<Table> {
+        let mut stmt = conn
+            .prepare("SELECT name, type, pk FROM pragma_table_info(?1) ORDER BY cid")
+            .map_err(sql_err)?;
+        let cols = stmt
+            .query_map([name], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, i64>(2)?,
+                ))
+            })
+            .and_then(|rows| rows.collect::<rusqlite::Result<Vec<_>>>())
+            .map_err(sql_err)?;
+        if cols.is_empty() {
+            return Err((404, format!("Table '{}' not found", name)));
+        }
+
+        let pks: Vec<usize> = (0..cols.len()).filter(|i| cols[*i].2 > 0).collect();
+        let key = match pks.len() {
+            0 => Key::RowId,
+            1 => Key::Column(pks[0]),
+            _ => Key::Composite,
+        };
+        Ok(Table {
+            name: name.to_string(),
+            columns: cols
+                .into_iter()
+                .map(|(name, decltype, _)| Column { name, decltype })
+                .collect(),
+            key,
+        })
+    }
+
+    fn column(&self, name: &str) -> RestResult<&Column> {
+        self.columns
+            .iter()
+            .find(|c| c.name.eq_ignore_ascii_case(name))
+            .ok_or_else(|| (400, format!("Unknown column '{}'", name)))
+    }
+}
+
+/// A value from the URL. The column's affinity converts it when compared, but a column
+/// without a declared type has none, so for it numbers are bound as such.
+fn url_value(col: &Column, v: &str) -> Value {
+    if col.decltype.is_empty() {
+        if let Ok(i) = v.parse::<i64>() {
+            return Value::Integer(i);
+        }
+        if let Ok(f) = v.parse::<f64>() {
+            return Value::Real(f);
+        }
+    }
+    Value::Text(v.to_string())
+}
+
+pub fn val_json2val_db(v: &JsonValue) -> Value {
+    match v {
+        JsonValue::Null => Value::Null,
+        JsonValue::Bool(b) => Value::Integer(*b as i64),
+        JsonValue::Number(n) => match n.as_i64() {
+            Some(i) => Value::Integer(i),
+            None => Value::Real(n.as_f64().unwrap_or_default()),
+        },
+        JsonValue::String(s) => Value::Text(s.to_owned()),
+        _ => Value::Text(v.to_string()),
+    }
+}
+
+pub fn placeholder(params: &mut Vec<Value>, v: Value) -> String {
+    params.push(v);
+    format!("?{}", params.len())
+}
+
+/// A filter as "<op>.<value>", optionally prefixed by "not."
+fn filter(table: &Table, key: &str, expr: &str, params: &mut Vec<Value>) -> RestResult<String> {
+    let col = table.column(key)?;
+    let (negate, expr) = match expr.strip_prefix("not.") {
+        Some(expr) => (true, expr),
+        None => (false, expr),
+    };
+    let bad_filter = || {
+        (
+            400,
+            format!("Invalid filter '{}' for column '{}'", expr, key),
+        )
+    };
+    let (op, val) = expr.split_once('.').ok_or_else(bad_filter)?;
+    let c = quote(&col.name);
+
+    let cond = match op {
+        "eq" | "neq" | "gt" | "gte" | "lt" | "lte" => {
+            let sql_op = match op {
+                "eq" => "=",
+                "neq" => "<>",
+                "gt" => ">",
+                "gte" => ">=",
+                "lt" => "<",
+                _ => "<=",
+            };
+            let p = placeholder(params, url_value(col, val));
+            format!("{} {} {}", c, sql_op, p)
+        }
+        // '*' can be used instead of '%', that must be escaped in URLs
+        "like" => {
+            let p = placeholder(params, Value::Text(val.replace('*', "%")));
+            format!("{} LIKE {}", c, p)
+        }
+        "glob" => {
+            let p = placeholder(params, Value::Text(val.to_string()));
+            format!("{} GLOB {}", c, p)
+        }
+        "in" => {
+            let list = val
+                .strip_prefix('(')
+                .and_then(|v| v.strip_suffix(')'))
+                .ok_or_else(bad_filter)?;
+            let ps: Vec<String> = list
+                .split(',')
+                .map(|v| placeholder(params, url_value(col, v)))
+                .collect();
+            format!("{} IN ({})", c, ps.join(", "))
+        }
+        "is" => match val.to_ascii_lowercase().as_str() {
+            "null" => format!("{} IS NULL", c),
+            "true" => format!("{} IS TRUE", c),
+            "false" => format!("{} IS FALSE", c),
+            _ => return Err(bad_filter()),
+        },
+        _ => return Err((400, format!("Unknown operator '{}'", op))),
+    };
+    Ok(if negate {
+        format!("NOT ({})", cond)
+    } else {
+        cond
+    })
+}
+
+fn where_clause(
+    table: &Table,
+    pk: Option<&str>,
+    filters: &[(String, String)],
+    params: &mut Vec<Value>,
+) -> RestResult<String> {
+    let mut conds = vec![];
+    if let Some(pk) = pk {
+        conds.push(match table.key {
+            Key::Column(i) => {
+                let col = &table.columns[i];
+                let p = placeholder(params, url_value(col, pk));
+                format!("{} = {}", quote(&col.name), p)
+            }
+            Key::RowId => {
+                let rowid = pk
+                    .parse::<i64>()
+                    .map_err(|_| (400, format!("Invalid rowid '{}'", pk)))?;
+                format!("rowid = {}", placeholder(params, Value::Integer(rowid)))
+            }
+            Key::Composite => {
+                return Err((
+                    400,
+                    format!(
+                        "Table '{}' has a composite primary key: use the filters",
+                        table.name
+                    ),
+                ))
+            }
+        });
+    }
+    for (key, expr) in filters {
+        conds.push(filter(table, key, expr, params)?);
+    }
+    Ok(match conds.is_empty() {
+        true => String::new(),
+        false => format!(" WHERE {}", conds.join(" AND ")),
+    })
+}
+
+/// "<col>[.asc|.desc][.nullsfirst|.nullslast]", comma-separated
+fn order_by(table: &Table, spec: &str) -> RestResult<String> {
+    let mut terms = vec![];
+    for term in spec.split(',') {
+        let mut parts = term.split('.');
+        let col = table.column(parts.next().unwrap_or_default())?;
+        let (mut dir, mut nulls) = ("", "");
+        for part in parts {
+            match part {
+                "asc" => dir = " ASC",
+                "desc" => dir = " DESC",
+                "nullsfirst" => nulls = " NULLS FIRST",
+                "nullslast" => nulls = " NULLS LAST",
+                _ => return Err((400, format!("Invalid order '{}'", term))),
+            }
+        }
+        terms.push(format!("{}{}{}", quote(&col.name), dir, nulls));
+    }
+    Ok(terms.join(", "))
+}
+
+#[derive(Default)]
+struct RestQuery {
+    select: Option<String>,
+    order: Option<String>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+    filters: Vec<(String, String)>,
+}
+
+impl RestQuery {
+    fn parse(query_string: &str) -> RestResult<RestQuery> {
+        let pairs = web::Query::<Vec<(String, String)>>::from_query(query_string)
+            .map_err(|e| (400, e.to_string()))?
+            .into_inner();
+        let number = |k: &str, v: &str| {
+            v.parse::<u32>()
+                .map(i64::from)
+                .map_err(|_| (400, format!("Invalid {} '{}'", k, v)))
+        };
+        let mut ret = RestQuery::default();
+        for (k, v) in pairs {
+            match k.as_str() {
+                "select" => ret.select = Some(v),
+                "order" => ret.order = Some(v),
+                "limit" => ret.limit = Some(number(&k, &v)?),
+                "offset" => ret.offset = Some(number(&k, &v)?),
+                _ => ret.filters.push((k, v)),
+            }
+        }
+        Ok(ret)
+    }
+}
+
+fn fetch(conn: &Connection, sql: &str, params: &[Value]) -> RestResult<Vec<JsonValue>> {
+    let mut stmt = conn.prepare(sql).map_err(sql_err)?;
+    let names: Vec<String> = stmt.column_names().iter().map(|c| c.to_string()).collect();
+    let mut rows = stmt
+        .query(params_from_iter(params.iter()))
+        .map_err(sql_err)?;
+    let mut ret = vec![];
+    while let Some(row) = rows.next().map_err(sql_err)? {
+        let mut map = JsonMap::new();
+        for (i, name) in names.iter().enumerate() {
+            map.insert(name.to_owned(), val_db2val_json(row.get_unwrap(i)));
+        }
+        ret.push(JsonValue::Object(map));
+    }
+    Ok(ret)
+}
+
+pub fn audited(idx: usize, sql: &str, params: &[Value], rows: usize) -> AuditedStatement {
+    let mut a = AuditedStatement::new(idx, sql);
+    a.values = Some(JsonValue::Array(
+        params.iter().cloned().map(val_db2val_json).collect(),
+    ));
+    a.rows_updated = Some(rows);
+    a
+}
+
+fn select(
+    conn: &Connection,
+    table: &Table,
+    pk: Option<&str>,
+    q: &RestQuery,
+) -> RestResult<Vec<JsonValue>> {
+    let cols = match &q.select {
+        None => "*".to_string(),
+        Some(s) => s
+            .split(',')
+            .map(|c| table.column(c.trim()).map(|c| quote(&c.name)))
+            .collect::<RestResult<Vec<String>>>()?
+            .join(", "),
+    };
+    let mut params = vec![];
+    let mut sql = format!(
+        "SELECT {} FROM {}{}",
+        cols,
+        quote(&table.name),
+        where_clause(table, pk, &q.filters, &mut params)?
+    );
+    if let Some(order) = &q.order {
+        sql.push_str(&format!(" ORDER BY {}", order_by(table, order)?));
+    }
+    if q.limit.is_some() || q.offset.is_some() {
+        let p = placeholder(&mut params, Value::Integer(q.limit.unwrap_or(-1)));
+        sql.push_str(&format!(" LIMIT {}", p));
+        if let Some(offset) = q.offset {
+            let p = placeholder(&mut params, Value::Integer(offset));
+            sql.push_str(&format!(" OFFSET {}", p));
+        }
+    }
+    fetch(conn, &sql, &params)
+}
+
+fn insert(
+    conn: &Connection,
+    table: &Table,
+    body: &JsonValue,
+    audit: &mut Vec<AuditedStatement>,
+) -> RestResult<Vec<JsonValue>> {
+    let bad_body = || {
+        (
+            400,
+            "The body must be an object or an array of objects".to_string(),
+        )
+    };
+    let items = match body {
+        JsonValue::Array(items) => items.iter().collect(),
+        JsonValue::Object(_) => vec![body],
+        _ => return Err(bad_body()),
+    };
+    let mut ret = vec![];
+    for (idx, item) in items.into_iter().enumerate() {
+        let obj = item.as_object().ok_or_else(bad_body)?;
+        let mut params = vec![];
+        let sql = if obj.is_empty() {
+            format!(
+                "INSERT INTO {} DEFAULT VALUES RETURNING *",
+                quote(&table.name)
+            )
+        } else {
+            let mut cols = vec![];
+            let mut ps = vec![];
+            for (k, v) in obj {
+                cols.push(quote(&table.column(k)?.name));
+                ps.push(placeholder(&mut params, val_json2val_db(v)));
+            }
+            format!(
+                "INSERT INTO {} ({}) VALUES ({}) RETURNING *",
+                quote(&table.name),
+                cols.join(", "),
+                ps.join(", ")
+            )
+        };
+        let mut rows = fetch(conn, &sql, &params)?;
+        audit.push(audited(idx, &sql, &params, rows.len()));
+        ret.append(&mut rows);
+    }
+    Ok(ret)
+}
+
+fn update(
+    conn: &Connection,
+    table: &Table,
+    pk: Option<&str>,
+    q: &RestQuery,
+    body: &JsonValue,
+    audit: &mut Vec<AuditedStatement>,
+) -> RestResult<Vec<JsonValue>> {
+    let obj = match body.as_object() {
+        Some(obj) if !obj.is_empty() => obj,
+        _ => return Err((400, "The body must be a non-empty object".to_string())),
+    };
+    let mut params = vec![];
+    let mut sets = vec![];
+    for (k, v) in obj {
+        let col = quote(&table.column(k)?.name);
+        sets.push(format!(
+            "{} = {}",
+            col,
+            placeholder(&mut params, val_json2val_db(v))
+        ));
+    }
+    let where_clause = where_clause(table, pk, &q.filters, &mut params)?;
+    if where_clause.is_empty() {
+        return Err((400, "A primary key or some filters are needed".to_string()));
+    }
+    let sql = format!(
+        "UPDATE {} SET {}{} RETURNING *",
+        quote(&table.name),
+        sets.join(", "),
+        where_clause
+    );
+    let rows = fetch(conn, &sql, &params)?;
+    audit.push(audited(0, &sql, &params, rows.len()));
+    Ok(rows)
+}
+
+fn delete(
+    conn: &Connection,
+    table: &Table,
+    pk: Option<&str>,
+    q: &RestQuery,
+    audit: &mut Vec<AuditedStatement>,
+) -> RestResult<Vec<JsonValue>> {
+    let mut params = vec![];
+    let where_clause = where_clause(table, pk, &q.filters, &mut params)?;
+    if where_clause.is_empty() {
+        return Err((400, "A primary key or some filters are needed".to_string()));
+    }
+    let sql = format!(
+        "DELETE FROM {}{} RETURNING *",
+        quote(&table.name),
+        where_clause
+    );
+    let rows = fetch(conn, &sql, &params)?;
+    audit.push(audited(0, &sql, &params, rows.len()));
+    Ok(rows)
+}
+
+fn process(
+    db_name: &str,
+    table_name: &str,
+    method: &Method,
+    pk: Option<&str>,
+    q: &RestQuery,
+    body: &JsonValue,
+    user: Option<String>,
+) -> RestResult<Vec<JsonValue>> {
+    let start = Instant::now();
+    let mut db_lock_guard = lock_db(db_name).ok_or_else(|| (503, busy_msg(db_name)))?;
+    let lock_wait = start.elapsed();
+    let conn = db_lock_guard.deref_mut();
+    let tx = conn.transaction().map_err(sql_err)?;
+
+    let table = Table::introspect(&tx, table_name)?;
+    let mut audit = vec![];
+    let rows = match *method {
+        Method::GET => select(&tx, &table, pk, q)?,
+        Method::POST => insert(&tx, &table, body, &mut audit)?,
+        Method::PATCH => update(&tx, &table, pk, q, body, &mut audit)?,
+        _ => delete(&tx, &table, pk, q, &mut audit)?,
+    };
+
+    if *method != Method::GET {
+        changes::write_outbox(db_name, &tx).map_err(|e| (500, e.to_string()))?;
+    }
+    tx.commit().map_err(sql_err)?;
+    if *method != Method::GET {
+        changes::publish(db_name, conn);
+        audit::record(db_name, user.as_deref(), "rest", audit);
+    }
+    record_process(db_name, start.elapsed(), lock_wait);
+    Ok(rows)
+}
+
+pub async fn handler(
+    req: HttpRequest,
+    body: web::Bytes,
+    db_conf: web::Data<Db>,
+    db_name: web::Data<String>,
+) -> Either<Response, HttpResponse> {
+    let db_name = db_name.to_string();
+    let err = |code: u16, msg: String| Either::Left(Response::new_err(code, -1, msg));
+    let rest = match &db_conf.conf.rest {
+        Some(rest) => rest,
+        None => {
+            return err(
+                404,
+                format!("Database '{}' doesn't have a rest node", db_name),
+            )
+        }
+    };
+
+    // only HTTP_BASIC, it's checked at startup
+    let mut user = None;
+    if let Some(ac) = &db_conf.conf.auth {
+        let ac_headers = Authorization::<Basic>::parse(&req).ok();
+        match process_auth_async(ac, &None, &ac_headers, &db_name).await {
+            Ok(Some(u)) => user = Some(u),
+            Err(msg) => return err(503, msg),
+            Ok(None) => {
+                record_auth_failure(&db_name, "credentials");
+                sleep(Duration::from_millis(1000)).await;
+
+                return err(ac.auth_error_code, "Authorization failed".to_string());
+            }
+        }
+    }
+    req.extensions_mut().insert(AccessInfo {
+        user: user.to_owned(),
+        items: 1,
+    });
+
+    let table_name = req.match_info().get("table").unwrap_or_default();
+    let table = match rest
+        .tables
+        .iter()
+        .find(|t| t.name.eq_ignore_ascii_case(table_name))
+    {
+        Some(t) => t,
+        None => return err(404, format!("Table '{}' not found", table_name)),
+    };
+    let op = match *req.method() {
+        Method::GET => "read",
+        Method::POST => "insert",
+        Method::PATCH => "update",
+        _ => "delete",
+    };
+    if !table
+        .operations
+        .as_ref()
+        .is_none_or(|ops| ops.iter().any(|o| o.eq_ignore_ascii_case(op)))
+    {
+        return err(
+            405,
+            format!(
+                "Operation '{}' is not allowed on table '{}'",
+                op, table.name
+            ),
+        );
+    }
+    if op != "read" && db_conf.conf.read_only {
+        return err(405, format!("Database '{}' is read-only", db_name));
+    }
+
+    let q = match RestQuery::parse(req.query_string()) {
+        Ok(q) => q,
+        Err((code, msg)) => return err(code, msg),
+    };
+    let body = match op {
+        "insert" | "update" => match serde_json::from_slice(&body) {
+            Ok(body) => body,
+            Err(e) => return err(400, e.to_string()),
+        },
+        _ => JsonValue::Null,
+    };
+    let pk = req.match_info().get("pk");
+
+    // in a thread that can be blocked, as it waits for the database
+    let res = {
+        let (db_name, table_name) = (db_name.to_owned(), table.name.to_owned());
+        let (method, pk_owned) = (req.method().to_owned(), pk.map(str::to_owned));
+        web::block(move || {
+            process(
+                &db_name,
+                &table_name,
+                &method,
+                pk_owned.as_deref(),
+                &q,
+                &body,
+                user,
+            )
+        })
+        .await
+        .unwrap_or_else(|e| Err((500, e.to_string())))
+    };
+    match res {
+        Ok(mut rows) => Either::Right(match pk {
+            Some(_) if rows.is_empty() => return err(404, "Row not found".to_string()),
+            Some(_) => HttpResponse::Ok().json(rows.swap_remove(0)),
+            None if op == "insert" => HttpResponse::build(StatusCode::CREATED).json(rows),
+            None => HttpResponse::Ok().json(rows),
+        }),
+        Err((code, msg)) => err(code, msg),
+    }
+}
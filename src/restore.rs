@@ -0,0 +1,322 @@
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    cmp::Reverse,
+    fs::{read_dir, remove_file},
+    ops::DerefMut,
+    path::Path as SysPath,
+};
+
+use actix_web::{web, Either};
+use chrono::{DateTime, Utc};
+use rusqlite::{backup::Progress, Connection, DatabaseName, OpenFlags};
+
+use crate::{
+    backup::check_backup_ws,
+    backup_codec::decode,
+    backup_codec::PrivateDir,
+    backup_files::{download_manifest, file_sha256, manifest_sha256},
+    commandline::RestoreArgs,
+    commons::{abort, file_exists, resolve_tilde},
+    db_config::{parse_dbconf, BackupTarget, DbConfig, S3Target},
+    logic::{busy_msg, lock_db},
+    main_config::{split_path, Db},
+    req_res::{BackupFile, BackupListResponse, Response, RestoreRequest, Token},
+    s3::S3Client,
+    wal_archive::rebuild_at,
+};
+
+/// The objects directly under the prefix, but the manifests, with their name relative to it
+fn list_remote_backup_files(s3: &S3Target) -> eyre::Result<Vec<BackupFile>> {
+    let mut ret: Vec<BackupFile> = S3Client::new(s3)?
+        .list(&s3.prefix)?
+        .into_iter()
+        .filter_map(|obj| {
+            let name = obj.key.strip_prefix(&s3.prefix)?;
+            // as in a backup directory, the manifests aren't backups
+            if name.is_empty() || name.contains('/') || name.ends_with(".manifest.json") {
+                return None;
+            }
+            Some(BackupFile {
+                name: name.to_string(),
+                size: obj.size,
+                modified: obj.last_modified,
+            })
+        })
+        .collect();
+    ret.sort_by_key(|f| Reverse(f.modified));
+    Ok(ret)
+}
+
+fn list_backup_files(bkp_dir: &str) -> std::io::Result<Vec<BackupFile>> {
+    let mut ret = vec![];
+    for entry in read_dir(bkp_dir)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        // temporary files of backups or restores in progress, and manifests
+        if !meta.is_file() || name.ends_with(".tmp") || name.ends_with(".manifest.json") {
+            continue;
+        }
+        ret.push(BackupFile {
+            name,
+            size: meta.len(),
+            modified: DateTime::<Utc>::from(meta.modified()?),
+        });
+    }
+    ret.sort_by_key(|f| Reverse(f.modified));
+    Ok(ret)
+}
+
+/// Restores a backup file into the connection, using the online backup API. If the file
+/// is in the manifest, its checksum must match. Compressed and/or encrypted files are
+/// decoded into a plain copy in a private temp dir first, that is removed when dropped; the
+/// plain file must pass an integrity check for the restore to happen.
+fn restore_into(
+    dbconf: &DbConfig,
+    db_path: &str,
+    bkp_file: &str,
+    conn: &mut Connection,
+) -> Result<(), (u16, String)> {
+    if let Some(expected) = manifest_sha256(bkp_file, db_path) {
+        let actual = file_sha256(bkp_file).map_err(|e| (500, e.to_string()))?;
+        if actual != expected {
+            return Err((
+                409,
+                "Checksum of the backup file doesn't match the manifest".to_string(),
+            ));
+        }
+    }
+
+    let key_file = dbconf
+        .backup
+        .as_ref()
+        .and_then(|b| b.encryption.as_ref())
+        .map(|e| e.key_file.as_str());
+
+    let is_plain =
+        !(bkp_file.ends_with(".enc") || bkp_file.ends_with(".zst") || bkp_file.ends_with(".gz"));
+    let (_staging, plain_file) = if is_plain {
+        (None, bkp_file.to_string())
+    } else {
+        let dir = PrivateDir::new().map_err(|e| (500, e.to_string()))?;
+        let plain_file = dir
+            .new_file("restore.db")
+            .map_err(|e| (500, e.to_string()))?;
+        decode(bkp_file, &plain_file, key_file)
+            .map_err(|e| (409, format!("Decoding backup file: {}", e)))?;
+        (Some(dir), plain_file)
+    };
+
+    check_integrity(&plain_file).and_then(|_| {
+        // read-only databases are made writable for the time of the restore
+        let res = conn
+            .execute("PRAGMA query_only = false", [])
+            .and_then(|_| conn.restore(DatabaseName::Main, &plain_file, None::<fn(Progress)>))
+            .map_err(|e| (500, format!("Restoring backup file: {}", e)));
+
+        // the journal mode is the one of the backup file, that may differ
+        let jm = dbconf.journal_mode.to_owned().unwrap_or("WAL".to_string());
+        let _ = conn.query_row(&format!("PRAGMA journal_mode = {}", jm), [], |_| Ok(()));
+        if dbconf.read_only {
+            let _ = conn.execute("PRAGMA query_only = true", []);
+        }
+        res
+    })
+}
+
+fn check_integrity(file: &str) -> Result<(), (u16, String)> {
+    let res: rusqlite::Result<String> =
+        Connection::open_with_flags(file, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .and_then(|c| c.query_row("PRAGMA integrity_check", [], |r| r.get(0)));
+    match res {
+        Ok(s) if s == "ok" => Ok(()),
+        Ok(s) => Err((409, format!("Integrity check failed: {}", s))),
+        Err(e) => Err((409, format!("Integrity check failed: {}", e))),
+    }
+}
+
+/// Lists the files in the backup directory, most recent first. Uses the token of the
+/// webService node, that must be present.
+pub async fn list_handler(
+    db_conf: web::Data<Db>,
+    db_name: web::Data<String>,
+    token: web::Query<Token>,
+) -> Either<Response, web::Json<BackupListResponse>> {
+    let bkp = match check_backup_ws(&db_conf, &db_name, &token.token).await {
+        Ok(bkp) => bkp,
+        Err(res) => return Either::Left(res),
+    };
+
+    // the object storage is called in a thread that can be blocked, as it waits for the network
+    if let Some(BackupTarget::S3(s3)) = &bkp.target {
+        let s3 = s3.to_owned();
+        let bucket = s3.bucket.to_owned();
+        let res = web::block(move || list_remote_backup_files(&s3))
+            .await
+            .unwrap_or_else(|e| Err(eyre!(e.to_string())));
+        return match res {
+            Ok(files) => Either::Right(web::Json(BackupListResponse { files })),
+            Err(e) => Either::Left(Response::new_err(
+                500,
+                -1,
+                format!("Listing backups in bucket '{}': {}", bucket, e),
+            )),
+        };
+    }
+
+    match list_backup_files(&bkp.backup_dir) {
+        Ok(files) => Either::Right(web::Json(BackupListResponse { files })),
+        Err(e) => Either::Left(Response::new_err(
+            500,
+            -1,
+            format!("Listing backup dir '{}': {}", bkp.backup_dir, e),
+        )),
+    }
+}
+
+/// Restores one of the files in the backup directory into the live database. Uses the
+/// token of the webService node, that must be present.
+pub async fn restore_handler(
+    db_conf: web::Data<Db>,
+    db_name: web::Data<String>,
+    req: web::Query<RestoreRequest>,
+) -> Response {
+    let bkp = match check_backup_ws(&db_conf, &db_name, &req.token).await {
+        Ok(bkp) => bkp,
+        Err(res) => return res,
+    };
+
+    // only plain file names, to stay in the backup directory
+    let file_name = SysPath::new(&req.file).file_name();
+    if req.file.is_empty() || file_name.map(|f| f.to_str()) != Some(Some(req.file.as_str())) {
+        return Response::new_err(400, -1, format!("Invalid file name '{}'", req.file));
+    }
+    // from an object storage, the file is downloaded, with the manifest, into a private
+    // directory that is removed when dropped; the download and the restore are performed
+    // in a thread that can be blocked
+    let bkp = bkp.to_owned();
+    let file = req.file.to_owned();
+    web::block(move || {
+        // the staging directory is kept until the end of the restore
+        let (_staging, bkp_file) = if let Some(BackupTarget::S3(s3)) = &bkp.target {
+            let key = format!("{}{}", s3.prefix, file);
+            let db_path = db_conf.backup_base_path(&db_name);
+            let downloaded = PrivateDir::new_in(&bkp.backup_dir).and_then(|dir| {
+                let client = S3Client::new(s3)?;
+                if !client.list(&key)?.iter().any(|obj| obj.key == key) {
+                    return Ok(None);
+                }
+                let bkp_file = dir.path(&file);
+                client.download(&key, &bkp_file)?;
+                download_manifest(&client, &s3.prefix, dir.dir(), &db_path)?;
+                Ok(Some((dir, bkp_file)))
+            });
+            match downloaded {
+                Ok(Some((dir, bkp_file))) => (Some(dir), bkp_file),
+                Ok(None) => {
+                    return Response::new_err(404, -1, format!("File '{}' not found", file))
+                }
+                Err(e) => {
+                    return Response::new_err(500, -1, format!("Downloading backup file: {}", e))
+                }
+            }
+        } else {
+            let bkp_file = SysPath::new(&bkp.backup_dir).join(&file);
+            let bkp_file = bkp_file.to_str().unwrap().to_string();
+            if !file_exists(&bkp_file) {
+                return Response::new_err(404, -1, format!("File '{}' not found", file));
+            }
+            (None, bkp_file)
+        };
+
+        let mut db_lock_guard = match lock_db(&db_name) {
+            Some(guard) => guard,
+            None => return Response::new_err(503, -1, busy_msg(&db_name)),
+        };
+        let conn = db_lock_guard.deref_mut();
+
+        let res = restore_into(
+            &db_conf.conf,
+            &db_conf.backup_base_path(&db_name),
+            &bkp_file,
+            conn,
+        );
+        match res {
+            Ok(_) => Response::new_ok(vec![]),
+            Err((code, msg)) => Response::new_err(code, -1, msg),
+        }
+    })
+    .await
+    .unwrap_or_else(|e| Response::new_err(500, -1, e.to_string()))
+}
+
+/// Restores a backup file into a database file, from the command line; or, with "--to",
+/// rebuilds the database as it was at a given time from its WAL archive, and restores
+/// that. The server may be running: SQLite's locking makes it safe.
+pub fn restore_cli(args: &RestoreArgs) {
+    let (db_path, yaml, db_name) = split_path(&args.db);
+
+    println!("- Restoring database '{}'", db_name);
+
+    let dbconf = if file_exists(&yaml) {
+        parse_dbconf(&yaml).unwrap_or_else(|e| abort(format!("parsing YAML {}: {}", yaml, e)))
+    } else {
+        DbConfig::default()
+    };
+
+    let bkp_file = match (&args.file, &args.to) {
+        (Some(file), _) => {
+            let bkp_file = resolve_tilde(file);
+            println!("  - from backup file '{}'", bkp_file);
+            if !file_exists(&bkp_file) {
+                abort(format!("backup file does not exist: {}", bkp_file));
+            }
+            bkp_file
+        }
+        (None, Some(to)) => {
+            let to = DateTime::parse_from_rfc3339(to)
+                .unwrap_or_else(|e| abort(format!("invalid timestamp '{}': {}", to, e)))
+                .with_timezone(&Utc);
+            let mut wa = match &dbconf.wal_archive {
+                Some(wa) => wa.to_owned(),
+                None => abort(format!("no walArchive configured in '{}'", yaml)),
+            };
+            wa.dir = resolve_tilde(&wa.dir);
+            println!("  - at {}, from WAL archive '{}'", to, wa.dir);
+
+            let tmp_file = format!("{}.pitr.tmp", db_path);
+            match rebuild_at(&wa, &db_path, &to, &tmp_file) {
+                Ok(segments) => println!("  - applied {} WAL segment(s)", segments),
+                Err(e) => abort(e.to_string()),
+            }
+            tmp_file
+        }
+        // enforced by clap
+        (None, None) => unreachable!(),
+    };
+
+    let mut conn = Connection::open(&db_path)
+        .unwrap_or_else(|e| abort(format!("opening database '{}': {}", db_path, e)));
+    let res = restore_into(&dbconf, &db_path, &bkp_file, &mut conn);
+    if args.file.is_none() {
+        let _ = remove_file(&bkp_file);
+    }
+    if let Err((_, msg)) = res {
+        abort(msg);
+    }
+
+    println!("  - restored into '{}'", db_path);
+}
@@ -0,0 +1,145 @@
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    cmp::Reverse,
+    collections::HashSet,
+    fs::{read_dir, remove_file},
+    path::Path,
+};
+
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use eyre::Result;
+
+use crate::{
+    backup_codec::PrivateDir,
+    backup_files::{backup_time, download_manifest, prune_manifest, upload_manifest},
+    db_config::Backup,
+    s3::S3Client,
+};
+
+/// Keeps the most recent file in each of the last `n` periods, as identified by `period`
+fn keep_by_period<T, F>(
+    files: &[(T, DateTime<Utc>)],
+    n: usize,
+    period: F,
+    keep: &mut HashSet<usize>,
+) where
+    F: Fn(&DateTime<Utc>) -> (i32, u32),
+{
+    let mut periods = HashSet::new();
+    for (i, (_, time)) in files.iter().enumerate() {
+        if periods.len() >= n {
+            break;
+        }
+        if periods.insert(period(time)) {
+            keep.insert(i);
+        }
+    }
+}
+
+/// Which of the backup files, sorted most recent first, are retained. The last `numFiles`
+/// files are always kept; if a retention node is configured, so are the most recent file
+/// of each of the last /n/ hours, days, weeks and months (grandfather-father-son).
+fn retained<T>(bkp: &Backup, files: &[(T, DateTime<Utc>)]) -> HashSet<usize> {
+    let mut keep: HashSet<usize> = (0..bkp.num_files.min(files.len())).collect();
+    if let Some(r) = &bkp.retention {
+        keep_by_period(
+            files,
+            r.hourly,
+            |t| (t.num_days_from_ce(), t.hour()),
+            &mut keep,
+        );
+        keep_by_period(files, r.daily, |t| (t.num_days_from_ce(), 0), &mut keep);
+        keep_by_period(
+            files,
+            r.weekly,
+            |t| (t.iso_week().year(), t.iso_week().week()),
+            &mut keep,
+        );
+        keep_by_period(files, r.monthly, |t| (t.year(), t.month()), &mut keep);
+    }
+    keep
+}
+
+/// Deletes the backup files of the database that aren't retained. Other files in the
+/// backup directory are never touched; the manifest is updated.
+pub fn apply_retention(bkp: &Backup, db_path: &str) -> Result<()> {
+    let mut files = vec![];
+    for entry in read_dir(&bkp.backup_dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let name = entry.file_name();
+        if let Some(time) = name.to_str().and_then(|n| backup_time(bkp, n, db_path)) {
+            files.push((entry.path(), time));
+        }
+    }
+    // most recent first
+    files.sort_by_key(|f| Reverse(f.1));
+
+    let keep = retained(bkp, &files);
+    for (i, (path, _)) in files.iter().enumerate() {
+        if !keep.contains(&i) {
+            remove_file(path)?;
+        }
+    }
+
+    let dir = Path::new(&bkp.backup_dir);
+    prune_manifest(dir, db_path, |f| dir.join(f).is_file())
+}
+
+/// Deletes the backup objects of the database that aren't retained, from the storage.
+/// Other objects, also under the prefix, are never touched; the manifest is updated.
+pub fn apply_remote_retention(
+    bkp: &Backup,
+    db_path: &str,
+    client: &S3Client,
+    prefix: &str,
+) -> Result<()> {
+    let mut objects = vec![];
+    for obj in client.list(prefix)? {
+        let time = obj
+            .key
+            .strip_prefix(prefix)
+            .and_then(|name| backup_time(bkp, name, db_path));
+        if let Some(time) = time {
+            objects.push((obj.key, time));
+        }
+    }
+    // most recent first
+    objects.sort_by_key(|f| Reverse(f.1));
+
+    let keep = retained(bkp, &objects);
+    for (i, (key, _)) in objects.iter().enumerate() {
+        if !keep.contains(&i) {
+            client.delete(key)?;
+        }
+    }
+    if keep.len() == objects.len() {
+        return Ok(());
+    }
+
+    let kept: HashSet<&str> = keep
+        .iter()
+        .filter_map(|&i| objects[i].0.strip_prefix(prefix))
+        .collect();
+    let staging = PrivateDir::new_in(&bkp.backup_dir)?;
+    if download_manifest(client, prefix, staging.dir(), db_path)? {
+        prune_manifest(staging.dir(), db_path, |f| kept.contains(f))?;
+        upload_manifest(client, prefix, staging.dir(), db_path)?;
+    }
+    Ok(())
+}
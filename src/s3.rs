@@ -0,0 +1,370 @@
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// A minimal client for S3-compatible object storage, enough for the backups: multipart
+// upload, listing, download and deletion of objects. Requests are signed with AWS
+// Signature Version 4, and use path-style URLs (<endpoint>/<bucket>/<key>), that are
+// supported by MinIO and most S3-compatible services.
+
+use std::{
+    env,
+    fs::File,
+    io::{copy, Read},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use eyre::Result;
+use ring::{
+    digest::{digest, SHA256},
+    hmac,
+};
+
+use crate::db_config::S3Target;
+
+const DEFAULT_REGION: &str = "us-east-1";
+// the minimum for all the parts but the last is 5MiB
+const PART_SIZE: usize = 8 * 1024 * 1024;
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+pub struct S3Client {
+    endpoint: String,
+    host: String,
+    region: String,
+    bucket: String,
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+    agent: ureq::Agent,
+}
+
+pub struct S3Object {
+    pub key: String,
+    pub size: u64,
+    pub last_modified: DateTime<Utc>,
+}
+
+fn env_var(name: &str) -> Result<String> {
+    env::var(name).map_err(|_| eyre!("environment variable {} is not set", name))
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(digest(&SHA256, data).as_ref())
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+    hmac::sign(&key, data.as_bytes()).as_ref().to_vec()
+}
+
+/// Percent-encodes everything but the unreserved characters, and the slashes if required
+fn uri_encode(s: &str, encode_slash: bool) -> String {
+    let mut ret = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                ret.push(b as char)
+            }
+            b'/' if !encode_slash => ret.push('/'),
+            _ => ret.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    ret
+}
+
+fn xml_unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// The contents of the first element with the tag, unescaped
+fn xml_value(xml: &str, tag: &str) -> Option<String> {
+    let start = xml.find(&format!("<{}>", tag))? + tag.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", tag))?;
+    Some(xml_unescape(&xml[start..end]))
+}
+
+/// The contents of all the elements with the tag
+fn xml_elements<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let mut ret = vec![];
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        match rest.find(&close) {
+            Some(end) => {
+                ret.push(&rest[..end]);
+                rest = &rest[end + close.len()..];
+            }
+            None => break,
+        }
+    }
+    ret
+}
+
+impl S3Client {
+    /// The credentials are read from the environment: AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY
+    /// and, optionally, AWS_SESSION_TOKEN. The region is the configured one, or AWS_REGION.
+    pub fn new(target: &S3Target) -> Result<S3Client> {
+        let endpoint = target.endpoint.trim_end_matches('/').to_string();
+        let host = endpoint
+            .strip_prefix("https://")
+            .or_else(|| endpoint.strip_prefix("http://"))
+            .ok_or_else(|| eyre!("endpoint must start with http:// or https://"))?
+            .to_string();
+        if host.is_empty() || host.contains('/') {
+            return Err(eyre!("endpoint must be a URL without path"));
+        }
+        let region = match &target.region {
+            Some(r) => r.to_owned(),
+            None => env::var("AWS_REGION").unwrap_or(DEFAULT_REGION.to_string()),
+        };
+
+        Ok(S3Client {
+            endpoint,
+            host,
+            region,
+            bucket: target.bucket.to_owned(),
+            access_key: env_var("AWS_ACCESS_KEY_ID")?,
+            secret_key: env_var("AWS_SECRET_ACCESS_KEY")?,
+            session_token: env::var("AWS_SESSION_TOKEN").ok(),
+            agent: ureq::AgentBuilder::new().timeout(TIMEOUT).build(),
+        })
+    }
+
+    /// Performs a signed request on an object of the bucket (or on the bucket itself, if
+    /// the key is empty)
+    fn request(
+        &self,
+        method: &str,
+        key: &str,
+        query: &[(&str, &str)],
+        body: &[u8],
+    ) -> Result<ureq::Response> {
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload_hash = sha256_hex(body);
+
+        let path = format!(
+            "/{}/{}",
+            uri_encode(&self.bucket, true),
+            uri_encode(key, false)
+        );
+        let mut query: Vec<(String, String)> = query
+            .iter()
+            .map(|(k, v)| (uri_encode(k, true), uri_encode(v, true)))
+            .collect();
+        query.sort();
+        let query = query
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let mut headers = vec![
+            ("host", self.host.to_owned()),
+            ("x-amz-content-sha256", payload_hash.to_owned()),
+            ("x-amz-date", amz_date.to_owned()),
+        ];
+        if let Some(token) = &self.session_token {
+            headers.push(("x-amz-security-token", token.to_owned()));
+        }
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(k, v)| format!("{}:{}\n", k, v.trim()))
+            .collect();
+        let signed_headers = headers
+            .iter()
+            .map(|(k, _)| *k)
+            .collect::<Vec<_>>()
+            .join(";");
+
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            method, path, query, canonical_headers, signed_headers, payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            sha256_hex(canonical_request.as_bytes())
+        );
+        let mut signing_key = hmac_sha256(format!("AWS4{}", self.secret_key).as_bytes(), &date);
+        for part in [self.region.as_str(), "s3", "aws4_request"] {
+            signing_key = hmac_sha256(&signing_key, part);
+        }
+        let signature = hex::encode(hmac_sha256(&signing_key, &string_to_sign));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key, scope, signed_headers, signature
+        );
+
+        let mut url = format!("{}{}", self.endpoint, path);
+        if !query.is_empty() {
+            url = format!("{}?{}", url, query);
+        }
+        let mut req = self
+            .agent
+            .request(method, &url)
+            .set("Authorization", &authorization);
+        // the host header is set by the agent
+        for (k, v) in headers.iter().skip(1) {
+            req = req.set(k, v);
+        }
+
+        match req.send_bytes(body) {
+            Ok(res) => Ok(res),
+            Err(ureq::Error::Status(code, res)) => {
+                let body = res.into_string().unwrap_or_default();
+                let msg = xml_value(&body, "Message")
+                    .or_else(|| xml_value(&body, "Code"))
+                    .unwrap_or(body);
+                Err(eyre!("S3 {} '{}' failed ({}): {}", method, key, code, msg))
+            }
+            Err(e) => Err(eyre!("S3 {} '{}' failed: {}", method, key, e)),
+        }
+    }
+
+    /// Uploads a file with a multipart upload, that is aborted if a part fails
+    pub fn upload(&self, file: &str, key: &str) -> Result<()> {
+        let res = self.request("POST", key, &[("uploads", "")], &[])?;
+        let upload_id = xml_value(&res.into_string()?, "UploadId")
+            .ok_or_else(|| eyre!("S3 multipart upload of '{}': no UploadId", key))?;
+
+        let res = self.upload_parts(file, key, &upload_id);
+        if res.is_err() {
+            let _ = self.request("DELETE", key, &[("uploadId", &upload_id)], &[]);
+        }
+        res
+    }
+
+    fn upload_parts(&self, file: &str, key: &str, upload_id: &str) -> Result<()> {
+        let mut file = File::open(file)?;
+        let mut etags = vec![];
+        let mut buf = vec![0u8; PART_SIZE];
+        loop {
+            // fills the buffer, unless the file is over
+            let mut len = 0;
+            while len < PART_SIZE {
+                let n = file.read(&mut buf[len..])?;
+                if n == 0 {
+                    break;
+                }
+                len += n;
+            }
+            // an empty file is uploaded as an empty part
+            if len == 0 && !etags.is_empty() {
+                break;
+            }
+
+            let part_number = (etags.len() + 1).to_string();
+            let res = self.request(
+                "PUT",
+                key,
+                &[("partNumber", &part_number), ("uploadId", upload_id)],
+                &buf[..len],
+            )?;
+            let etag = res
+                .header("ETag")
+                .ok_or_else(|| eyre!("S3 upload of part {} of '{}': no ETag", part_number, key))?;
+            etags.push(etag.to_string());
+
+            if len < PART_SIZE {
+                break;
+            }
+        }
+
+        let parts: String = etags
+            .iter()
+            .enumerate()
+            .map(|(i, etag)| {
+                format!(
+                    "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                    i + 1,
+                    etag
+                )
+            })
+            .collect();
+        let body = format!(
+            "<CompleteMultipartUpload>{}</CompleteMultipartUpload>",
+            parts
+        );
+        let res = self.request("POST", key, &[("uploadId", upload_id)], body.as_bytes())?;
+        // errors can also come with a 200 status
+        let res = res.into_string()?;
+        if res.contains("<Error>") {
+            return Err(eyre!(
+                "S3 completion of the upload of '{}': {}",
+                key,
+                xml_value(&res, "Message").unwrap_or(res.to_owned())
+            ));
+        }
+        Ok(())
+    }
+
+    /// The objects whose key starts with the prefix
+    pub fn list(&self, prefix: &str) -> Result<Vec<S3Object>> {
+        let mut ret = vec![];
+        let mut token: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", prefix)];
+            if let Some(t) = &token {
+                query.push(("continuation-token", t));
+            }
+            let res = self.request("GET", "", &query, &[])?.into_string()?;
+            for obj in xml_elements(&res, "Contents") {
+                let (key, size, last_modified) = match (
+                    xml_value(obj, "Key"),
+                    xml_value(obj, "Size"),
+                    xml_value(obj, "LastModified"),
+                ) {
+                    (Some(k), Some(s), Some(lm)) => (k, s, lm),
+                    _ => continue,
+                };
+                ret.push(S3Object {
+                    key,
+                    size: size.parse().unwrap_or(0),
+                    last_modified: DateTime::parse_from_rfc3339(&last_modified)
+                        .map(|t| t.with_timezone(&Utc))
+                        .unwrap_or_default(),
+                });
+            }
+
+            token = match xml_value(&res, "IsTruncated").as_deref() {
+                Some("true") => xml_value(&res, "NextContinuationToken"),
+                _ => None,
+            };
+            if token.is_none() {
+                return Ok(ret);
+            }
+        }
+    }
+
+    pub fn download(&self, key: &str, file: &str) -> Result<()> {
+        let res = self.request("GET", key, &[], &[])?;
+        copy(&mut res.into_reader(), &mut File::create(file)?)?;
+        Ok(())
+    }
+
+    pub fn delete(&self, key: &str) -> Result<()> {
+        self.request("DELETE", key, &[], &[])?;
+        Ok(())
+    }
+}
@@ -0,0 +1,141 @@
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{str::FromStr, time::Duration};
+
+use actix_web::{
+    rt::{spawn, time::sleep},
+    web,
+};
+use chrono::{DateTime, Local, Utc};
+use chrono_tz::Tz;
+use croner::Cron;
+use eyre::Result;
+
+use crate::{
+    db_config::{ExecutionMode, MissedRuns},
+    history::{last_scheduled_run, track, JobId, Trigger},
+    req_res::Response,
+};
+
+#[derive(Debug, Clone)]
+pub enum Schedule {
+    Period(chrono::Duration),
+    // no time zone means the local one
+    Cron(Box<Cron>, Option<Tz>),
+}
+
+impl Schedule {
+    /// Builds the schedule described by an execution node. Returns None if the node
+    /// doesn't call for a periodic execution.
+    pub fn from_execution(ex: &ExecutionMode) -> Result<Option<Schedule>> {
+        match &ex.cron {
+            Some(_) if ex.period > 0 => Err(eyre!("at most one among period and cron can be set")),
+            Some(expr) => {
+                let cron = Cron::from_str(expr)
+                    .map_err(|e| eyre!("invalid cron expression '{}': {}", expr, e))?;
+                let tz = match &ex.time_zone {
+                    Some(tz) => {
+                        Some(Tz::from_str(tz).map_err(|_| eyre!("invalid time zone '{}'", tz))?)
+                    }
+                    None => None,
+                };
+                Ok(Some(Schedule::Cron(Box::new(cron), tz)))
+            }
+            None if ex.time_zone.is_some() => Err(eyre!("timeZone can only be set with cron")),
+            None if ex.period > 0 => Ok(Some(Schedule::Period(chrono::Duration::minutes(
+                ex.period as i64,
+            )))),
+            None => Ok(None),
+        }
+    }
+
+    /// The first scheduled time strictly after the given one
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Schedule::Period(p) => after.checked_add_signed(*p),
+            Schedule::Cron(cron, Some(tz)) => cron
+                .find_next_occurrence(&after.with_timezone(tz), false)
+                .ok()
+                .map(|dt| dt.with_timezone(&Utc)),
+            Schedule::Cron(cron, None) => cron
+                .find_next_occurrence(&after.with_timezone(&Local), false)
+                .ok()
+                .map(|dt| dt.with_timezone(&Utc)),
+        }
+    }
+}
+
+pub fn is_periodic(ex: &ExecutionMode) -> bool {
+    ex.period > 0 || ex.cron.is_some()
+}
+
+/// Executes a run of the job in a thread that can be blocked, because it locks the database
+/// and can take long (e.g. a backup); the job is given back for the next run, unless it
+/// panicked.
+async fn run_blocking<F>(job: &JobId, trigger: Trigger, mut f: F) -> Option<F>
+where
+    F: FnMut() -> Response + Send + 'static,
+{
+    let job = job.to_owned();
+    web::block(move || {
+        track(&job, trigger, &mut f);
+        f
+    })
+    .await
+    .ok()
+}
+
+/// Runs the job at each scheduled time, tracking it in the history. The first execution
+/// is at the first scheduled time after startup, unless a run was missed during downtime
+/// and the policy is to catch up: in this case it's executed once, immediately. A failed
+/// run doesn't stop the following ones.
+pub fn spawn_scheduled<F>(schedule: Schedule, missed_runs: MissedRuns, job: JobId, mut f: F)
+where
+    F: FnMut() -> Response + Send + 'static,
+{
+    spawn(async move {
+        let started = Utc::now();
+
+        if matches!(missed_runs, MissedRuns::CatchUpOnce) {
+            let prev = last_scheduled_run(&job);
+            if let Some(due) = prev.and_then(|p| schedule.next_after(p)) {
+                if due <= started {
+                    f = match run_blocking(&job, Trigger::CatchUp, f).await {
+                        Some(f) => f,
+                        None => return,
+                    };
+                }
+            }
+        }
+
+        let mut next = schedule.next_after(started);
+        while let Some(n) = next {
+            let wait = (n - Utc::now()).to_std().unwrap_or(Duration::ZERO);
+            sleep(wait).await;
+
+            f = match run_blocking(&job, Trigger::Schedule, f).await {
+                Some(f) => f,
+                None => return,
+            };
+
+            // if the job overran one or more scheduled times, they are skipped
+            let now = Utc::now();
+            next = schedule.next_after(n);
+            while matches!(next, Some(nn) if nn <= now) {
+                next = schedule.next_after(next.unwrap());
+            }
+        }
+    });
+}
@@ -0,0 +1,175 @@
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Server-wide endpoints for the orchestrators (/healthz and /readyz) and for the
+// administrators (/info).
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::TryLockError,
+    time::{Duration, Instant},
+};
+
+use actix_web::{rt::time::sleep, web, HttpRequest, HttpResponse};
+
+use crate::{
+    auth::process_req_token,
+    get_sqlite_version,
+    main_config::Db,
+    req_res::{DbInfo, InfoResponse, ReadyResponse},
+    CURRENT_PROTO_VERSION, MUTEXES,
+};
+
+pub const READY_TIMEOUT: Duration = Duration::from_secs(2);
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The process is alive
+pub async fn health_handler() -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({ "status": "ok" }))
+}
+
+/// Waits for the lock of a database, polling so that a worker thread isn't blocked, then
+/// performs a trivial query
+async fn check_db(db_name: &str) -> Result<(), String> {
+    let db_lock = MUTEXES.get().unwrap().get(db_name).unwrap();
+    let start = Instant::now();
+    loop {
+        match db_lock.try_lock() {
+            Ok(conn) => {
+                return conn
+                    .query_row("SELECT 1", [], |_| Ok(()))
+                    .map_err(|e| e.to_string());
+            }
+            Err(TryLockError::Poisoned(_)) => return Err("lock poisoned".to_string()),
+            Err(TryLockError::WouldBlock) => {
+                if start.elapsed() >= READY_TIMEOUT {
+                    return Err(format!(
+                        "lock not acquired in {}ms",
+                        READY_TIMEOUT.as_millis()
+                    ));
+                }
+            }
+        }
+        sleep(LOCK_POLL_INTERVAL).await;
+    }
+}
+
+/// Every database is open and answers a query; 503 otherwise
+pub async fn ready_handler(db_map: web::Data<HashMap<String, Db>>) -> HttpResponse {
+    let mut databases = BTreeMap::new();
+    let mut ready = true;
+    for db_name in db_map.keys() {
+        let status = match check_db(db_name).await {
+            Ok(()) => "ok".to_string(),
+            Err(e) => {
+                ready = false;
+                e
+            }
+        };
+        databases.insert(db_name.to_owned(), status);
+    }
+
+    let res = ReadyResponse { ready, databases };
+    if ready {
+        HttpResponse::Ok().json(res)
+    } else {
+        HttpResponse::ServiceUnavailable().json(res)
+    }
+}
+
+fn features(db: &Db) -> Vec<&'static str> {
+    let conf = &db.conf;
+    let mut ret = vec![];
+    if conf.auth.is_some() {
+        ret.push("auth");
+    }
+    if conf.read_only {
+        ret.push("readOnly");
+    }
+    if conf.cors_origin.is_some() {
+        ret.push("cors");
+    }
+    if !db.stored_statements.is_empty() {
+        ret.push("storedStatements");
+    }
+    if conf.use_only_stored_statements {
+        ret.push("onlyStoredStatements");
+    }
+    if !db.macros.is_empty() {
+        ret.push("macros");
+    }
+    if conf.backup.is_some() {
+        ret.push("backup");
+    }
+    if conf.wal_archive.is_some() {
+        ret.push("walArchive");
+    }
+    if conf.audit.is_some() {
+        ret.push("audit");
+    }
+    if conf.slow_query_ms.is_some() {
+        ret.push("slowQueryLog");
+    }
+    if conf.change_feed.is_some() {
+        ret.push("changeFeed");
+    }
+    if conf.webhooks.is_some() {
+        ret.push("webhooks");
+    }
+    if conf.rest.is_some() {
+        ret.push("rest");
+    }
+    if conf.graphql.is_some() {
+        ret.push("graphql");
+    }
+    if conf
+        .stored_statements
+        .iter()
+        .flatten()
+        .any(|ss| ss.http.is_some())
+    {
+        ret.push("storedStatementsHttp");
+    }
+    ret
+}
+
+/// Versions and databases of this instance. The token is mandatory, as a "token" query
+/// parameter or as a bearer token.
+pub async fn info_handler(
+    req: HttpRequest,
+    db_map: web::Data<HashMap<String, Db>>,
+    info_token: web::Data<String>,
+) -> HttpResponse {
+    if !process_req_token(&req, &info_token) {
+        sleep(Duration::from_millis(1000)).await;
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let mut databases: Vec<DbInfo> = db_map
+        .iter()
+        .map(|(name, db)| DbInfo {
+            name: name.to_owned(),
+            in_memory: db.is_mem,
+            features: features(db),
+        })
+        .collect();
+    databases.sort_by(|a, b| a.name.cmp(&b.name));
+
+    HttpResponse::Ok().json(InfoResponse {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        sqlite_version: get_sqlite_version(),
+        proto_version: CURRENT_PROTO_VERSION,
+        databases,
+    })
+}
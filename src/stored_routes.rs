@@ -0,0 +1,235 @@
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The stored statements with an "http" node, at /<db>/q/<id>: a GET executes a query and
+// returns its rows, a POST executes a statement and returns the number of changed rows. The
+// parameters are in the query string (and, for a POST, also in a JSON object as the body),
+// checked and converted as declared; then the statement is executed as a transaction of one
+// item, as in a request.
+
+use std::time::Duration;
+
+use actix_web::{
+    http::{header::Header, Method},
+    rt::time::sleep,
+    web, Either, HttpMessage, HttpRequest, HttpResponse,
+};
+use actix_web_httpauth::headers::authorization::{Authorization, Basic};
+use serde_json::{json, Map as JsonMap, Value as JsonValue};
+
+use crate::{
+    access_log::AccessInfo,
+    auth::process_auth_async,
+    db_config::{HttpMethod, HttpParam, ParamType, StoredStatementHttp},
+    logic,
+    main_config::Db,
+    metrics::record_auth_failure,
+    req_res::{ReqTransactionItem, Request, Response},
+    telemetry::request_context,
+};
+
+fn type_name(t: &ParamType) -> &'static str {
+    match t {
+        ParamType::Integer => "an integer",
+        ParamType::Real => "a number",
+        ParamType::Text => "a string",
+        ParamType::Boolean => "a boolean",
+    }
+}
+
+/// A value from the query string
+fn typed(param: &HttpParam, v: &str) -> Result<JsonValue, String> {
+    let ret = match param.param_type {
+        ParamType::Integer => v.parse::<i64>().ok().map(JsonValue::from),
+        ParamType::Real => v.parse::<f64>().ok().map(JsonValue::from),
+        // as SQLite does, 1 or 0
+        ParamType::Boolean => match v.to_ascii_lowercase().as_str() {
+            "true" | "1" => Some(JsonValue::from(1)),
+            "false" | "0" => Some(JsonValue::from(0)),
+            _ => None,
+        },
+        ParamType::Text => Some(JsonValue::String(v.to_string())),
+    };
+    ret.ok_or_else(|| {
+        format!(
+            "Parameter '{}' must be {}",
+            param.name,
+            type_name(&param.param_type)
+        )
+    })
+}
+
+/// A value from the body; strings are converted as if they were in the query string
+fn typed_json(param: &HttpParam, v: JsonValue) -> Result<JsonValue, String> {
+    match (&param.param_type, v) {
+        (_, JsonValue::Null) => Ok(JsonValue::Null),
+        (ParamType::Integer, JsonValue::Number(n)) if n.is_i64() => Ok(JsonValue::Number(n)),
+        (ParamType::Real, JsonValue::Number(n)) => Ok(JsonValue::Number(n)),
+        (ParamType::Boolean, JsonValue::Bool(b)) => Ok(JsonValue::from(b as i64)),
+        (_, JsonValue::String(s)) => typed(param, &s),
+        _ => Err(format!(
+            "Parameter '{}' must be {}",
+            param.name,
+            type_name(&param.param_type)
+        )),
+    }
+}
+
+fn values(
+    http: &StoredStatementHttp,
+    query_string: &str,
+    body: &web::Bytes,
+) -> Result<JsonMap<String, JsonValue>, String> {
+    let param = |name: &str| {
+        http.params
+            .iter()
+            .find(|p| p.name == name)
+            .ok_or_else(|| format!("Unknown parameter '{}'", name))
+    };
+
+    let mut ret = JsonMap::new();
+    let pairs = web::Query::<Vec<(String, String)>>::from_query(query_string)
+        .map_err(|e| e.to_string())?
+        .into_inner();
+    for (k, v) in pairs {
+        ret.insert(k.to_owned(), typed(param(&k)?, &v)?);
+    }
+    if http.method == HttpMethod::Post && !body.is_empty() {
+        let obj = match serde_json::from_slice(body) {
+            Ok(JsonValue::Object(obj)) => obj,
+            _ => return Err("The body must be a JSON object".to_string()),
+        };
+        for (k, v) in obj {
+            let v = typed_json(param(&k)?, v)?;
+            ret.insert(k, v);
+        }
+    }
+
+    for p in &http.params {
+        if p.required && !ret.contains_key(&p.name) {
+            return Err(format!("Parameter '{}' is required", p.name));
+        }
+    }
+    Ok(ret)
+}
+
+pub async fn handler(
+    req: HttpRequest,
+    body: web::Bytes,
+    db_conf: web::Data<Db>,
+    db_name: web::Data<String>,
+    id: web::Path<String>,
+) -> Either<Response, HttpResponse> {
+    let db_name = db_name.to_string();
+    let err = |code: u16, msg: String| Either::Left(Response::new_err(code, -1, msg));
+    let http = match db_conf
+        .conf
+        .stored_statements
+        .iter()
+        .flatten()
+        .find(|ss| ss.id == *id)
+        .and_then(|ss| ss.http.as_ref())
+    {
+        Some(http) => http,
+        None => {
+            return err(
+                404,
+                format!(
+                    "Database '{}' doesn't have a stored statement '{}' with an http node",
+                    db_name, id
+                ),
+            )
+        }
+    };
+    let method = match http.method {
+        HttpMethod::Get => Method::GET,
+        HttpMethod::Post => Method::POST,
+    };
+    if *req.method() != method {
+        return err(
+            405,
+            format!("Stored statement '{}' must be called with {}", id, method),
+        );
+    }
+
+    // only HTTP_BASIC, it's checked at startup
+    let mut user = None;
+    if let Some(ac) = &db_conf.conf.auth {
+        let ac_headers = Authorization::<Basic>::parse(&req).ok();
+        match process_auth_async(ac, &None, &ac_headers, &db_name).await {
+            Ok(Some(u)) => user = Some(u),
+            Err(msg) => return err(503, msg),
+            Ok(None) => {
+                record_auth_failure(&db_name, "credentials");
+                sleep(Duration::from_millis(1000)).await;
+
+                return err(ac.auth_error_code, "Authorization failed".to_string());
+            }
+        }
+    }
+    req.extensions_mut().insert(AccessInfo {
+        user: user.to_owned(),
+        items: 1,
+    });
+
+    let values = match values(http, req.query_string(), &body) {
+        Ok(values) => values,
+        Err(msg) => return err(400, msg),
+    };
+    let text = format!("^{}", id);
+    let (query, statement) = match http.method {
+        HttpMethod::Get => (Some(text), None),
+        HttpMethod::Post => (None, Some(text)),
+    };
+    let request = Request {
+        credentials: None,
+        transaction: vec![ReqTransactionItem {
+            no_fail: false,
+            query,
+            statement,
+            values: (!values.is_empty()).then_some(JsonValue::Object(values)),
+            values_batch: None,
+            typed: None,
+        }],
+    };
+
+    // in a thread that can be blocked, as it waits for the database
+    let (worker_conf, span_ctx) = (db_conf.clone(), request_context(&req));
+    let res = web::block(move || {
+        logic::process(
+            &db_name,
+            &request,
+            &worker_conf.stored_statements,
+            &worker_conf.conf,
+            user,
+            "request",
+            span_ctx,
+        )
+    })
+    .await;
+    let res = match res {
+        Ok(Ok(res)) => res,
+        Ok(Err(e)) => return err(500, e.to_string()),
+        Err(e) => return err(500, e.to_string()),
+    };
+    match (res.success, res.results.as_deref()) {
+        (true, Some([item])) => Either::Right(match http.method {
+            HttpMethod::Get => HttpResponse::Ok().json(&item.result_set),
+            HttpMethod::Post => HttpResponse::Ok().json(json!({
+                "rowsUpdated": item.rows_updated
+            })),
+        }),
+        _ => Either::Left(res),
+    }
+}
@@ -0,0 +1,303 @@
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// OpenTelemetry tracing: spans for the requests (continuing the trace of the caller, via the
+// W3C traceparent header), the authentication, the wait for the lock, the items of a
+// transaction, the macros and the backups. They are exported in batches to an OTLP/HTTP
+// collector, with the JSON encoding. Until enabled from the command line, spans are no-ops.
+
+use std::{
+    cell::RefCell,
+    mem::take,
+    sync::{Mutex, OnceLock},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{HttpMessage, HttpRequest};
+use eyre::Result;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde_json::{json, Value as JsonValue};
+
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_BATCH: usize = 512;
+// if the collector can't keep up, further spans are dropped
+const MAX_QUEUE: usize = 8192;
+
+// from the OTLP spec
+const KIND_INTERNAL: u8 = 1;
+const KIND_SERVER: u8 = 2;
+const STATUS_ERROR: u8 = 2;
+
+struct Exporter {
+    url: String,
+    service_name: String,
+    agent: ureq::Agent,
+    queue: Mutex<Vec<JsonValue>>,
+}
+
+static EXPORTER: OnceLock<Exporter> = OnceLock::new();
+
+thread_local! {
+    // the span that is running on this thread, for the spans started without a parent
+    static CURRENT: RefCell<Option<SpanContext>> = const { RefCell::new(None) };
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SpanContext {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    sampled: bool,
+}
+
+fn random_id<const N: usize>() -> [u8; N] {
+    let mut ret = [0u8; N];
+    // an all-zero id is invalid
+    while ret.iter().all(|b| *b == 0) {
+        SystemRandom::new().fill(&mut ret).unwrap();
+    }
+    ret
+}
+
+fn now_nanos() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+impl SpanContext {
+    /// Parses a traceparent header: "00-<trace id>-<parent id>-<flags>", in hex
+    fn from_traceparent(tp: &str) -> Option<SpanContext> {
+        let parts: Vec<&str> = tp.trim().split('-').collect();
+        if parts.len() < 4 || parts[0] == "ff" || parts[0].len() != 2 {
+            return None;
+        }
+        let trace_id: [u8; 16] = hex::decode(parts[1]).ok()?.try_into().ok()?;
+        let span_id: [u8; 8] = hex::decode(parts[2]).ok()?.try_into().ok()?;
+        let flags = u8::from_str_radix(parts[3], 16).ok()?;
+        if trace_id.iter().all(|b| *b == 0) || span_id.iter().all(|b| *b == 0) {
+            return None;
+        }
+        Some(SpanContext {
+            trace_id,
+            span_id,
+            sampled: flags & 1 == 1,
+        })
+    }
+}
+
+struct Record {
+    parent_span_id: Option<[u8; 8]>,
+    name: String,
+    kind: u8,
+    start: u128,
+    attributes: Vec<JsonValue>,
+    error: Option<String>,
+}
+
+/// A span; it's exported when ended. It's a no-op if tracing is disabled or the trace
+/// is not sampled.
+pub struct Span {
+    ctx: Option<SpanContext>,
+    record: Option<Record>,
+}
+
+impl Span {
+    fn new(name: &str, kind: u8, parent: Option<SpanContext>) -> Span {
+        if EXPORTER.get().is_none() {
+            return Span {
+                ctx: None,
+                record: None,
+            };
+        }
+        if let Some(p) = parent.filter(|p| !p.sampled) {
+            // children of a trace that is not sampled aren't recorded either
+            return Span {
+                ctx: Some(p),
+                record: None,
+            };
+        }
+        let ctx = SpanContext {
+            trace_id: parent.map(|p| p.trace_id).unwrap_or_else(random_id),
+            span_id: random_id(),
+            sampled: true,
+        };
+        Span {
+            ctx: Some(ctx),
+            record: Some(Record {
+                parent_span_id: parent.map(|p| p.span_id),
+                name: name.to_string(),
+                kind,
+                start: now_nanos(),
+                attributes: vec![],
+                error: None,
+            }),
+        }
+    }
+
+    /// Starts a span, child of `parent` or, if None, of the span running on this thread
+    /// (if any)
+    pub fn start(name: &str, parent: Option<SpanContext>) -> Span {
+        let parent = parent.or_else(|| CURRENT.with(|c| *c.borrow()));
+        Span::new(name, KIND_INTERNAL, parent)
+    }
+
+    pub fn context(&self) -> Option<SpanContext> {
+        self.ctx
+    }
+
+    pub fn set_name(&mut self, name: &str) {
+        if let Some(r) = &mut self.record {
+            r.name = name.to_string();
+        }
+    }
+
+    fn set_attr(&mut self, key: &str, value: JsonValue) {
+        if let Some(r) = &mut self.record {
+            r.attributes.push(json!({ "key": key, "value": value }));
+        }
+    }
+
+    pub fn set_str(&mut self, key: &str, value: &str) {
+        self.set_attr(key, json!({ "stringValue": value }));
+    }
+
+    pub fn set_int(&mut self, key: &str, value: i64) {
+        // int64 is a string, in the JSON encoding of protobuf
+        self.set_attr(key, json!({ "intValue": value.to_string() }));
+    }
+
+    pub fn set_error(&mut self, message: &str) {
+        if let Some(r) = &mut self.record {
+            r.error = Some(message.to_string());
+        }
+    }
+
+    /// Executes `f` with this as the running span of the thread; `f` must not yield
+    /// to other tasks
+    pub fn in_scope<T>(&self, f: impl FnOnce() -> T) -> T {
+        let prev = CURRENT.with(|c| c.replace(self.ctx));
+        let ret = f();
+        CURRENT.with(|c| *c.borrow_mut() = prev);
+        ret
+    }
+
+    pub fn end(self) {
+        let (ctx, r) = match (self.ctx, self.record, EXPORTER.get()) {
+            (Some(ctx), Some(r), Some(_)) => (ctx, r),
+            _ => return,
+        };
+        let mut span = json!({
+            "traceId": hex::encode(ctx.trace_id),
+            "spanId": hex::encode(ctx.span_id),
+            "name": r.name,
+            "kind": r.kind,
+            "startTimeUnixNano": r.start.to_string(),
+            "endTimeUnixNano": now_nanos().to_string(),
+            "attributes": r.attributes,
+        });
+        if let Some(p) = r.parent_span_id {
+            span["parentSpanId"] = json!(hex::encode(p));
+        }
+        if let Some(e) = r.error {
+            span["status"] = json!({ "code": STATUS_ERROR, "message": e });
+        }
+        let mut queue = EXPORTER.get().unwrap().queue.lock().unwrap();
+        if queue.len() < MAX_QUEUE {
+            queue.push(span);
+        }
+    }
+}
+
+/// Starts the span of a request to a database, continuing the trace in the traceparent
+/// header (if any). Its context is stored in the request, for the handlers.
+pub fn start_request_span(req: &HttpRequest) -> Span {
+    let parent = req
+        .headers()
+        .get("traceparent")
+        .and_then(|h| h.to_str().ok())
+        .and_then(SpanContext::from_traceparent);
+    let mut span = Span::new(
+        &format!("{} {}", req.method(), req.path()),
+        KIND_SERVER,
+        parent,
+    );
+    span.set_str("http.request.method", req.method().as_str());
+    span.set_str("url.path", req.path());
+    if let Some(ctx) = span.context() {
+        req.extensions_mut().insert(ctx);
+    }
+    span
+}
+
+/// The context of the span of a request, to start its child spans
+pub fn request_context(req: &HttpRequest) -> Option<SpanContext> {
+    req.extensions().get::<SpanContext>().copied()
+}
+
+fn export(ex: &Exporter, spans: Vec<JsonValue>) -> Result<()> {
+    let body = json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [
+                    { "key": "service.name", "value": { "stringValue": ex.service_name } },
+                    { "key": "service.version", "value": { "stringValue": env!("CARGO_PKG_VERSION") } },
+                ]
+            },
+            "scopeSpans": [{
+                "scope": { "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans,
+            }]
+        }]
+    });
+    ex.agent
+        .post(&ex.url)
+        .set("Content-Type", "application/json")
+        .send_string(&body.to_string())
+        .map_err(|e| eyre!(e.to_string()))?;
+    Ok(())
+}
+
+/// Sends the pending spans to the collector
+pub fn flush() {
+    if let Some(ex) = EXPORTER.get() {
+        let mut spans = take(&mut *ex.queue.lock().unwrap());
+        while !spans.is_empty() {
+            let rest = spans.split_off(spans.len().min(MAX_BATCH));
+            if let Err(e) = export(ex, spans) {
+                eprintln!("Exporting traces to '{}': {}", ex.url, e);
+            }
+            spans = rest;
+        }
+    }
+}
+
+/// Enables the tracing, exporting to a collector at `endpoint` (e.g. "http://localhost:4318")
+pub fn init(endpoint: &str, service_name: &str) {
+    let exporter = Exporter {
+        url: format!("{}/v1/traces", endpoint.trim_end_matches('/')),
+        service_name: service_name.to_string(),
+        agent: ureq::AgentBuilder::new().timeout(EXPORT_TIMEOUT).build(),
+        queue: Mutex::new(vec![]),
+    };
+    if EXPORTER.set(exporter).is_ok() {
+        // exporting blocks, so it's done in a thread of its own
+        thread::spawn(|| loop {
+            thread::sleep(EXPORT_INTERVAL);
+            flush();
+        });
+    }
+}
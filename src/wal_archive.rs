@@ -0,0 +1,397 @@
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Continuous archiving of the WAL of file-based databases, and point-in-time restore.
+//
+// The archive directory has a subdirectory for each database, named after its file,
+// with a "generation" subdirectory for each snapshot:
+//
+//   <dir>/<db name>/<snapshot ts>/snapshot.db
+//   <dir>/<db name>/<snapshot ts>/<ts>.seg
+//
+// Each segment holds the WAL frames of the transactions committed after the previous
+// one, as they were found in the WAL. sqliterg takes over checkpointing: the WAL is
+// checkpointed only after its frames are archived.
+//
+// A segment is named after the time it was archived, not after the commits it holds (the
+// commit hook of the connection may be taken by the changes feed): a point-in-time restore
+// is precise to the interval of the archiving, as a transaction committed up to that much
+// before a segment is only found in it.
+
+use std::{
+    collections::HashMap,
+    fs::{copy, create_dir_all, read_dir, remove_dir_all, remove_file, rename, File, OpenOptions},
+    io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Write},
+    ops::DerefMut,
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use actix_web::{
+    rt::{spawn, time::sleep},
+    web,
+};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use eyre::Result;
+use rusqlite::{backup::Backup as OnlineBackup, Connection};
+
+use crate::{
+    backup_files::TS_FORMAT, commons::abort, db_config::WalArchive, main_config::Db, MUTEXES,
+};
+
+pub const DEFAULT_INTERVAL: u64 = 10; // seconds
+pub const DEFAULT_SNAPSHOT_PERIOD: u64 = 1440; // minutes
+pub const DEFAULT_NUM_GENERATIONS: usize = 3;
+
+const SNAPSHOT_FILE: &str = "snapshot.db";
+const SEGMENT_EXT: &str = ".seg";
+const SEGMENT_MAGIC: &[u8; 8] = b"SQRGWAL1";
+const WAL_HEADER_SIZE: u64 = 32;
+const FRAME_HEADER_SIZE: usize = 24;
+
+/// Where the archiving of a database is at
+struct Position {
+    generation: PathBuf,
+    started: DateTime<Utc>,
+    // of the WAL the offset refers to; when they change, the WAL was restarted
+    salts: Option<[u8; 8]>,
+    offset: u64,
+}
+
+static POSITIONS: OnceLock<Mutex<HashMap<String, Position>>> = OnceLock::new();
+
+fn positions() -> &'static Mutex<HashMap<String, Position>> {
+    POSITIONS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+fn db_archive_dir(wa: &WalArchive, db_path: &str) -> PathBuf {
+    let name = Path::new(db_path).file_name().unwrap().to_str().unwrap();
+    Path::new(&wa.dir).join(name)
+}
+
+fn wal_file(conn: &Connection) -> String {
+    format!("{}-wal", conn.path().unwrap())
+}
+
+struct WalHeader {
+    page_size: usize,
+    salts: [u8; 8],
+}
+
+fn read_wal_header(wal: &mut File) -> Result<Option<WalHeader>> {
+    let mut buf = [0u8; WAL_HEADER_SIZE as usize];
+    wal.seek(SeekFrom::Start(0))?;
+    match wal.read_exact(&mut buf) {
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    Ok(Some(WalHeader {
+        page_size: u32::from_be_bytes(buf[8..12].try_into()?) as usize,
+        salts: buf[16..24].try_into()?,
+    }))
+}
+
+/// Reads the frames of the WAL from the offset to the last commit, and returns them
+/// with the offset after the last commit. Frames with different salts are left over
+/// from a previous use of the WAL file.
+fn read_committed_frames(
+    wal: &mut File,
+    header: &WalHeader,
+    offset: u64,
+) -> Result<(Vec<u8>, u64)> {
+    let frame_size = FRAME_HEADER_SIZE + header.page_size;
+    let mut reader = BufReader::new(&mut *wal);
+    reader.seek(SeekFrom::Start(offset))?;
+
+    let mut frames = vec![];
+    let mut committed_len = 0;
+    let mut frame = vec![0u8; frame_size];
+    loop {
+        match reader.read_exact(&mut frame) {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        if frame[8..16] != header.salts {
+            break;
+        }
+        frames.extend_from_slice(&frame);
+        if frame[4..8] != [0u8; 4] {
+            committed_len = frames.len();
+        }
+    }
+    frames.truncate(committed_len);
+    Ok((frames, offset + committed_len as u64))
+}
+
+/// Copies the frames committed since the last call into a new segment, then checkpoints
+/// the WAL. To be called with the database locked.
+fn archive_frames(conn: &Connection, pos: &mut Position) -> Result<()> {
+    let mut wal = match File::open(wal_file(conn)) {
+        Ok(wal) => wal,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let header = match read_wal_header(&mut wal)? {
+        Some(header) => header,
+        None => return Ok(()),
+    };
+    if pos.salts != Some(header.salts) {
+        pos.salts = Some(header.salts);
+        pos.offset = WAL_HEADER_SIZE;
+    }
+
+    let (frames, offset) = read_committed_frames(&mut wal, &header, pos.offset)?;
+    if !frames.is_empty() {
+        let seg_file =
+            pos.generation
+                .join(format!("{}{}", Utc::now().format(TS_FORMAT), SEGMENT_EXT));
+        let tmp_file = seg_file.with_extension("tmp");
+        let mut out = File::create(&tmp_file)?;
+        out.write_all(SEGMENT_MAGIC)?;
+        out.write_all(&(header.page_size as u32).to_be_bytes())?;
+        out.write_all(&frames)?;
+        out.sync_all()?;
+        rename(&tmp_file, &seg_file)?;
+        pos.offset = offset;
+    }
+
+    // if it can't complete (e.g. a backup is reading the db) the WAL keeps growing, and
+    // the offset stays valid
+    conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+    Ok(())
+}
+
+/// Starts a new generation with a snapshot of the database, then removes the oldest
+/// generations. To be called with the database locked.
+fn new_generation(wa: &WalArchive, db_path: &str, conn: &Connection) -> Result<Position> {
+    let now = Utc::now();
+    let generation = db_archive_dir(wa, db_path).join(now.format(TS_FORMAT).to_string());
+    create_dir_all(&generation)?;
+
+    let snapshot = generation.join(SNAPSHOT_FILE);
+    let mut dst = Connection::open(&snapshot)?;
+    OnlineBackup::new(conn, &mut dst)?.step(-1)?;
+    dst.query_row("PRAGMA journal_mode = DELETE", [], |_| Ok(()))?;
+    drop(dst);
+
+    // the snapshot already contains what is in the WAL
+    let mut pos = Position {
+        generation,
+        started: now,
+        salts: None,
+        offset: WAL_HEADER_SIZE,
+    };
+    if let Ok(mut wal) = File::open(wal_file(conn)) {
+        if let Some(header) = read_wal_header(&mut wal)? {
+            let (_, offset) = read_committed_frames(&mut wal, &header, WAL_HEADER_SIZE)?;
+            pos.salts = Some(header.salts);
+            pos.offset = offset;
+        }
+    }
+    conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+
+    let mut generations = list_generations(&db_archive_dir(wa, db_path))?;
+    let num_generations = wa.num_generations.unwrap_or(DEFAULT_NUM_GENERATIONS);
+    while generations.len() > num_generations {
+        let (dir, _) = generations.remove(0);
+        remove_dir_all(dir)?;
+    }
+
+    Ok(pos)
+}
+
+/// One round of archiving: the new frames go to the current generation, and a new one is
+/// started if the snapshot period elapsed. To be called with the database locked.
+fn archive(wa: &WalArchive, db_name: &str, db_path: &str, conn: &Connection) -> Result<()> {
+    let mut positions = positions().lock().unwrap();
+    let pos = match positions.get_mut(db_name) {
+        Some(pos) => pos,
+        None => return Ok(()),
+    };
+    archive_frames(conn, pos)?;
+
+    let period = wa.snapshot_period.unwrap_or(DEFAULT_SNAPSHOT_PERIOD);
+    if period > 0 && Utc::now() - pos.started >= chrono::Duration::minutes(period as i64) {
+        *pos = new_generation(wa, db_path, conn)?;
+    }
+    Ok(())
+}
+
+/// Takes over the checkpointing of the database, starts a first generation and schedules
+/// the archiving, in a thread that can be blocked as it locks the database. The database
+/// must be in WAL mode.
+pub fn start_wal_archive(wa: &WalArchive, db_name: &str, db_path: &str, conn: &Connection) {
+    let res = conn
+        .query_row("PRAGMA wal_autocheckpoint = 0", [], |_| Ok(()))
+        .map_err(|e| e.into())
+        .and_then(|_| new_generation(wa, db_path, conn));
+    match res {
+        Ok(pos) => {
+            println!(
+                "  - archiving the WAL into '{}'",
+                pos.generation.to_str().unwrap()
+            );
+            positions().lock().unwrap().insert(db_name.to_string(), pos);
+        }
+        Err(e) => abort(format!("starting the WAL archive: {}", e)),
+    }
+
+    let wa = wa.to_owned();
+    let db_name = db_name.to_string();
+    let db_path = db_path.to_string();
+    spawn(async move {
+        let interval = Duration::from_secs(wa.interval.unwrap_or(DEFAULT_INTERVAL));
+        loop {
+            sleep(interval).await;
+
+            let (wa, db_name, db_path) = (wa.to_owned(), db_name.to_owned(), db_path.to_owned());
+            let _ = web::block(move || {
+                let db_lock = MUTEXES.get().unwrap().get(&db_name).unwrap();
+                let mut db_lock_guard = db_lock.lock().unwrap();
+                let conn = db_lock_guard.deref_mut();
+                if let Err(e) = archive(&wa, &db_name, &db_path, conn) {
+                    eprintln!("Archiving the WAL of '{}': {}", db_name, e);
+                }
+            })
+            .await;
+        }
+    });
+}
+
+/// Archives the last frames, when the server is stopped
+pub fn shutdown_wal_archive(db_conf: &Db, db_name: &str, conn: &mut Connection) {
+    if db_conf.conf.wal_archive.is_none() {
+        return;
+    }
+    if let Some(pos) = positions().lock().unwrap().get_mut(db_name) {
+        if let Err(e) = archive_frames(conn, pos) {
+            eprintln!("Archiving the WAL of '{}': {}", db_name, e);
+        }
+    }
+}
+
+fn parse_ts(name: &str) -> Option<DateTime<Utc>> {
+    NaiveDateTime::parse_from_str(name, TS_FORMAT)
+        .ok()
+        .map(|t| t.and_utc())
+}
+
+/// The generations in the archive of a database, oldest first
+fn list_generations(dir: &Path) -> Result<Vec<(PathBuf, DateTime<Utc>)>> {
+    let mut ret = vec![];
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if let Some(ts) = parse_ts(&name) {
+            if entry.path().join(SNAPSHOT_FILE).is_file() {
+                ret.push((entry.path(), ts));
+            }
+        }
+    }
+    ret.sort_by_key(|(_, ts)| *ts);
+    Ok(ret)
+}
+
+/// The segments of a generation, oldest first
+fn list_segments(dir: &Path) -> Result<Vec<(PathBuf, DateTime<Utc>)>> {
+    let mut ret = vec![];
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if let Some(ts) = name.strip_suffix(SEGMENT_EXT).and_then(parse_ts) {
+            ret.push((entry.path(), ts));
+        }
+    }
+    ret.sort_by_key(|(_, ts)| *ts);
+    Ok(ret)
+}
+
+/// Writes the frames of a segment into a database file. The pages of a transaction are
+/// written when its commit frame is found, and the file is truncated to the size of the
+/// database after the commit.
+fn apply_segment(seg_file: &Path, db: &mut File) -> Result<()> {
+    let mut reader = BufReader::new(File::open(seg_file)?);
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != SEGMENT_MAGIC {
+        return Err(eyre!("not a WAL segment: {}", seg_file.display()));
+    }
+    let mut page_size = [0u8; 4];
+    reader.read_exact(&mut page_size)?;
+    let page_size = u32::from_be_bytes(page_size) as u64;
+
+    let mut frame = vec![0u8; FRAME_HEADER_SIZE + page_size as usize];
+    let mut pending: Vec<(u64, Vec<u8>)> = vec![];
+    loop {
+        match reader.read_exact(&mut frame) {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        let page_no = u32::from_be_bytes(frame[0..4].try_into()?) as u64;
+        let commit_size = u32::from_be_bytes(frame[4..8].try_into()?) as u64;
+        pending.push((page_no, frame[FRAME_HEADER_SIZE..].to_vec()));
+        if commit_size > 0 {
+            for (page_no, page) in pending.drain(..) {
+                db.seek(SeekFrom::Start((page_no - 1) * page_size))?;
+                db.write_all(&page)?;
+            }
+            db.set_len(commit_size * page_size)?;
+        }
+    }
+    Ok(())
+}
+
+/// Rebuilds the database as it was at the given time into a file, from the most recent
+/// snapshot taken before it and the segments archived up to it. Returns the number of
+/// segments that were applied.
+pub fn rebuild_at(
+    wa: &WalArchive,
+    db_path: &str,
+    to: &DateTime<Utc>,
+    dst_file: &str,
+) -> Result<usize> {
+    let dir = db_archive_dir(wa, db_path);
+    let generations = list_generations(&dir)
+        .map_err(|e| eyre!("reading the WAL archive '{}': {}", dir.display(), e))?;
+    let (generation, _) = generations
+        .into_iter()
+        .rev()
+        .find(|(_, ts)| ts <= to)
+        .ok_or_else(|| eyre!("no snapshot in the WAL archive before {}", to))?;
+
+    copy(generation.join(SNAPSHOT_FILE), dst_file)?;
+    let res = (|| {
+        let mut db = OpenOptions::new().write(true).open(dst_file)?;
+        let mut applied = 0;
+        for (seg_file, _) in list_segments(&generation)?
+            .into_iter()
+            .filter(|(_, ts)| ts <= to)
+        {
+            apply_segment(&seg_file, &mut db)?;
+            applied += 1;
+        }
+        db.sync_all()?;
+        // the pages may come from a database in WAL mode
+        Connection::open(dst_file)?.query_row("PRAGMA journal_mode = DELETE", [], |_| Ok(()))?;
+        Ok(applied)
+    })();
+    if res.is_err() {
+        let _ = remove_file(dst_file);
+    }
+    res
+}
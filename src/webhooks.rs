@@ -0,0 +1,335 @@
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Webhooks: the changes to the tables of a database are written to an outbox, a table of the
+// database itself, in the transaction that makes them; so they're committed (and survive a
+// restart) together with the changes. They're POSTed in batches to the configured URLs by a
+// thread of the database, retrying with an exponential backoff. The delivery is "at least
+// once": the receivers can use the ids of the events to deduplicate.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use chrono::{SecondsFormat, Utc};
+use eyre::Result;
+use ring::hmac;
+use rusqlite::{params, Connection};
+use serde_json::{json, Value as JsonValue};
+
+use crate::{
+    changes::{in_tables, Change},
+    db_config::Webhook,
+    MUTEXES,
+};
+
+pub const DEFAULT_BATCH_SIZE: usize = 100;
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 10;
+// seconds
+pub const DEFAULT_INITIAL_DELAY: u64 = 1;
+pub const DEFAULT_MAX_DELAY: u64 = 300;
+
+pub const OPERATIONS: [&str; 3] = ["insert", "update", "delete"];
+
+// in the database; its changes are not captured
+pub const OUTBOX_TABLE: &str = "SQLITERG_OUTBOX";
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const POST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Outbox {
+    webhooks: Vec<Webhook>,
+    // the delivered events are deleted also if the database is read-only
+    read_only: bool,
+    // wakes up the delivery thread
+    notify: Mutex<Sender<()>>,
+}
+
+static OUTBOXES: OnceLock<Mutex<HashMap<String, Arc<Outbox>>>> = OnceLock::new();
+
+fn outboxes() -> &'static Mutex<HashMap<String, Arc<Outbox>>> {
+    OUTBOXES.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+pub fn outbox(db_name: &str) -> Option<Arc<Outbox>> {
+    outboxes().lock().unwrap().get(db_name).cloned()
+}
+
+fn matches(wh: &Webhook, change: &Change) -> bool {
+    in_tables(&wh.tables, &change.table)
+        && wh
+            .operations
+            .as_ref()
+            .is_none_or(|ops| ops.iter().any(|o| o.eq_ignore_ascii_case(change.op)))
+}
+
+impl Outbox {
+    /// None if no webhook is interested in the change; otherwise, whether one wants the row
+    pub fn wants(&self, change: &Change) -> Option<bool> {
+        let mut ret = None;
+        for wh in self.webhooks.iter().filter(|wh| matches(wh, change)) {
+            ret = Some(ret.unwrap_or(false) || wh.include_rows);
+        }
+        ret
+    }
+
+    /// Writes the events for the webhooks into the outbox; to be called in the transaction
+    /// that made the changes, before the commit
+    pub fn write(&self, conn: &Connection, changes: &[(Change, Option<JsonValue>)]) -> Result<()> {
+        let ts = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+        let mut stmt = conn.prepare_cached(&format!(
+            "INSERT INTO {} (URL, EVENT) VALUES (?1, ?2)",
+            OUTBOX_TABLE
+        ))?;
+        for (change, row) in changes {
+            for wh in self.webhooks.iter().filter(|wh| matches(wh, change)) {
+                let mut event = json!({
+                    "ts": ts,
+                    "table": change.table,
+                    "op": change.op,
+                    "rowid": change.rowid,
+                });
+                if let (true, Some(row)) = (wh.include_rows, row) {
+                    event["row"] = row.to_owned();
+                }
+                stmt.execute(params![wh.url, event.to_string()])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Wakes up the delivery, after a commit
+    pub fn notify(&self) {
+        let _ = self.notify.lock().unwrap().send(());
+    }
+}
+
+/// Executes a function with the connection of the database, locking it
+fn with_conn<T>(db_name: &str, f: impl FnOnce(&Connection) -> Result<T>) -> Result<T> {
+    let conn = MUTEXES.get().unwrap().get(db_name).unwrap().lock().unwrap();
+    f(&conn)
+}
+
+struct Delivery {
+    attempts: u32,
+    next_try: Instant,
+}
+
+/// The oldest events for a webhook, as (last id, body)
+fn next_batch(db_name: &str, wh: &Webhook) -> Result<Option<(i64, String)>> {
+    let rows = with_conn(db_name, |conn| {
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT ID, EVENT FROM {} WHERE URL = ?1 ORDER BY ID LIMIT ?2",
+            OUTBOX_TABLE
+        ))?;
+        let rows = stmt
+            .query_map(
+                params![wh.url, wh.batch_size.unwrap_or(DEFAULT_BATCH_SIZE)],
+                |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)),
+            )?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(rows)
+    })?;
+    let mut last_id = None;
+    let mut events = vec![];
+    for (id, event) in rows {
+        let mut event: JsonValue = serde_json::from_str(&event)?;
+        event["id"] = json!(id);
+        events.push(event);
+        last_id = Some(id);
+    }
+    Ok(last_id.map(|id| (id, json!({ "events": events }).to_string())))
+}
+
+fn delete_up_to(db_name: &str, outbox: &Outbox, wh: &Webhook, last_id: i64) -> Result<()> {
+    with_conn(db_name, |conn| {
+        if outbox.read_only {
+            conn.execute_batch("PRAGMA query_only = false")?;
+        }
+        let res = conn.execute(
+            &format!("DELETE FROM {} WHERE URL = ?1 AND ID <= ?2", OUTBOX_TABLE),
+            params![wh.url, last_id],
+        );
+        if outbox.read_only {
+            conn.execute_batch("PRAGMA query_only = true")?;
+        }
+        res?;
+        Ok(())
+    })
+}
+
+fn post(agent: &ureq::Agent, db_name: &str, wh: &Webhook, body: &str) -> Result<()> {
+    let mut req = agent
+        .post(&wh.url)
+        .set("Content-Type", "application/json")
+        .set("X-Sqliterg-Db", db_name);
+    if let Some(secret) = &wh.secret {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+        let signature = hex::encode(hmac::sign(&key, body.as_bytes()).as_ref());
+        req = req.set("X-Sqliterg-Signature", &format!("sha256={}", signature));
+    }
+    req.send_string(body).map_err(|e| eyre!(e.to_string()))?;
+    Ok(())
+}
+
+/// Delivers a batch to a webhook, if one is due; returns true if there may be more
+fn deliver(
+    agent: &ureq::Agent,
+    db_name: &str,
+    outbox: &Outbox,
+    wh: &Webhook,
+    state: &mut Delivery,
+) -> Result<bool> {
+    if Instant::now() < state.next_try {
+        return Ok(false);
+    }
+    let (last_id, body) = match next_batch(db_name, wh)? {
+        Some(batch) => batch,
+        None => return Ok(false),
+    };
+
+    match post(agent, db_name, wh, &body) {
+        Ok(()) => {
+            delete_up_to(db_name, outbox, wh, last_id)?;
+            state.attempts = 0;
+            Ok(true)
+        }
+        Err(e) => {
+            state.attempts += 1;
+            let retry = wh.retry.as_ref();
+            let max_attempts = retry
+                .and_then(|r| r.max_attempts)
+                .unwrap_or(DEFAULT_MAX_ATTEMPTS);
+            if max_attempts > 0 && state.attempts >= max_attempts {
+                eprintln!(
+                    "Webhook '{}' of db '{}': {}; giving up after {} attempts, the events are dropped",
+                    wh.url, db_name, e, state.attempts
+                );
+                delete_up_to(db_name, outbox, wh, last_id)?;
+                state.attempts = 0;
+                return Ok(true);
+            }
+
+            let initial = retry
+                .and_then(|r| r.initial_delay)
+                .unwrap_or(DEFAULT_INITIAL_DELAY);
+            let max = retry.and_then(|r| r.max_delay).unwrap_or(DEFAULT_MAX_DELAY);
+            let delay = initial
+                .saturating_mul(1 << (state.attempts - 1).min(32))
+                .min(max);
+            eprintln!(
+                "Webhook '{}' of db '{}': {}; retrying in {}s",
+                wh.url, db_name, e, delay
+            );
+            state.next_try = Instant::now() + Duration::from_secs(delay);
+            Ok(false)
+        }
+    }
+}
+
+fn delivery_loop(db_name: String, outbox: Arc<Outbox>, wake_up: Receiver<()>) {
+    // the connections are shared when the server starts
+    while MUTEXES.get().is_none() {
+        thread::sleep(POLL_INTERVAL);
+    }
+    let agent = ureq::AgentBuilder::new().timeout(POST_TIMEOUT).build();
+    let mut states: Vec<Delivery> = outbox
+        .webhooks
+        .iter()
+        .map(|_| Delivery {
+            attempts: 0,
+            next_try: Instant::now(),
+        })
+        .collect();
+    loop {
+        let mut more = false;
+        for (wh, state) in outbox.webhooks.iter().zip(states.iter_mut()) {
+            match deliver(&agent, &db_name, &outbox, wh, state) {
+                Ok(m) => more |= m,
+                Err(e) => eprintln!("Webhook '{}' of db '{}': {}", wh.url, db_name, e),
+            }
+        }
+        if more {
+            continue;
+        }
+        let _ = wake_up.recv_timeout(POLL_INTERVAL);
+    }
+}
+
+/// Creates the outbox in the database, if needed, discards the events of the webhooks that
+/// aren't configured anymore and starts the delivery. Returns the number of pending events.
+pub fn init(
+    db_name: &str,
+    webhooks: &[Webhook],
+    read_only: bool,
+    conn: &Connection,
+) -> Result<usize> {
+    conn.execute(
+        &format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                ID INTEGER PRIMARY KEY AUTOINCREMENT,
+                URL TEXT NOT NULL,
+                EVENT TEXT NOT NULL
+            )",
+            OUTBOX_TABLE
+        ),
+        [],
+    )?;
+    let urls: Vec<&str> = webhooks.iter().map(|wh| wh.url.as_str()).collect();
+    let mut stmt = conn.prepare(&format!("SELECT DISTINCT URL FROM {}", OUTBOX_TABLE))?;
+    let stale: Vec<String> = stmt
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?
+        .into_iter()
+        .filter(|url| !urls.contains(&url.as_str()))
+        .collect();
+    drop(stmt);
+    for url in stale {
+        let n = conn.execute(
+            &format!("DELETE FROM {} WHERE URL = ?1", OUTBOX_TABLE),
+            [&url],
+        )?;
+        eprintln!(
+            "Webhook '{}' of db '{}' is not configured anymore: {} pending event(s) discarded",
+            url, db_name, n
+        );
+    }
+    let pending: usize = conn.query_row(
+        &format!("SELECT COUNT(1) FROM {}", OUTBOX_TABLE),
+        [],
+        |row| row.get(0),
+    )?;
+
+    let (tx, rx) = channel();
+    let outbox = Arc::new(Outbox {
+        webhooks: webhooks.to_vec(),
+        read_only,
+        notify: Mutex::new(tx),
+    });
+    outboxes()
+        .lock()
+        .unwrap()
+        .insert(db_name.to_string(), outbox.clone());
+
+    let db_name = db_name.to_string();
+    thread::spawn(move || delivery_loop(db_name, outbox, rx));
+
+    Ok(pending)
+}
@@ -0,0 +1,209 @@
+// Copyright (c) 2023-, Germano Rizzo <oss /AT/ germanorizzo /DOT/ it>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The transaction protocol over a WebSocket, at /<db>/ws. Each text message is a request,
+// with an id that is returned in its response. The client is authenticated once per
+// connection: with HTTP_BASIC at the handshake, with INLINE by the credentials of the
+// first request.
+
+use std::{sync::Arc, time::Duration};
+
+use actix_web::{
+    http::{header::Header, StatusCode},
+    rt::{spawn, time::sleep},
+    web, HttpRequest, HttpResponse,
+};
+use actix_web_httpauth::headers::authorization::{Authorization, Basic};
+use actix_ws::{AggregatedMessage, AggregatedMessageStream, CloseCode, CloseReason, Session};
+use serde_json::Value as JsonValue;
+
+use crate::{
+    auth::process_auth_async,
+    db_config::AuthMode,
+    logic::process,
+    main_config::Db,
+    metrics::record_auth_failure,
+    req_res::{Response, WsRequest, WsResponse},
+    telemetry::{request_context, SpanContext},
+};
+
+// the same as the default limit for a JSON body
+const MAX_MESSAGE_SIZE: usize = 2 * 1024 * 1024;
+
+async fn send(session: &mut Session, id: Option<JsonValue>, response: Response) -> bool {
+    let msg = WsResponse {
+        id,
+        status_code: response.status_code,
+        response,
+    };
+    session
+        .text(serde_json::to_string(&msg).unwrap())
+        .await
+        .is_ok()
+}
+
+struct Connection {
+    db_conf: Arc<Db>,
+    db_name: String,
+    authenticated: bool,
+    user: Option<String>,
+    span_ctx: Option<SpanContext>,
+}
+
+async fn serve(mut conn: Connection, mut session: Session, mut stream: AggregatedMessageStream) {
+    let mut close_reason = None;
+    while let Some(msg) = stream.recv().await {
+        let text = match msg {
+            Ok(AggregatedMessage::Text(text)) => text,
+            Ok(AggregatedMessage::Binary(_)) => {
+                let res = Response::new_err(400, -1, "Only text messages are supported".into());
+                if !send(&mut session, None, res).await {
+                    return;
+                }
+                continue;
+            }
+            Ok(AggregatedMessage::Ping(bytes)) => {
+                if session.pong(&bytes).await.is_err() {
+                    return;
+                }
+                continue;
+            }
+            Ok(AggregatedMessage::Pong(_)) => continue,
+            Ok(AggregatedMessage::Close(_)) => break,
+            Err(e) => {
+                close_reason = Some(CloseReason {
+                    code: CloseCode::Protocol,
+                    description: Some(e.to_string()),
+                });
+                break;
+            }
+        };
+
+        let ws_req: WsRequest = match serde_json::from_str(&text) {
+            Ok(r) => r,
+            Err(e) => {
+                let res = Response::new_err(400, -1, e.to_string());
+                if !send(&mut session, None, res).await {
+                    return;
+                }
+                continue;
+            }
+        };
+
+        if !conn.authenticated {
+            // INLINE authentication, by the first request
+            let ac = conn.db_conf.conf.auth.as_ref().unwrap();
+            match process_auth_async(ac, &ws_req.request.credentials, &None, &conn.db_name).await {
+                Ok(Some(u)) => {
+                    conn.authenticated = true;
+                    conn.user = Some(u);
+                }
+                Err(msg) => {
+                    if !send(&mut session, ws_req.id, Response::new_err(503, -1, msg)).await {
+                        return;
+                    }
+                    continue;
+                }
+                Ok(None) => {
+                    record_auth_failure(&conn.db_name, "credentials");
+                    sleep(Duration::from_millis(1000)).await;
+
+                    let res = Response::new_err(
+                        ac.auth_error_code,
+                        -1,
+                        "Authorization failed".to_string(),
+                    );
+                    send(&mut session, ws_req.id, res).await;
+                    close_reason = Some(CloseReason {
+                        code: CloseCode::Policy,
+                        description: Some("Authorization failed".to_string()),
+                    });
+                    break;
+                }
+            }
+        }
+
+        // in a thread that can be blocked, as it waits for the database
+        let (db_conf, db_name) = (conn.db_conf.clone(), conn.db_name.to_owned());
+        let (user, span_ctx) = (conn.user.to_owned(), conn.span_ctx);
+        let request = ws_req.request;
+        let res = web::block(move || {
+            process(
+                &db_name,
+                &request,
+                &db_conf.stored_statements,
+                &db_conf.conf,
+                user,
+                "request",
+                span_ctx,
+            )
+        })
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|res| res.map_err(|e| e.to_string()))
+        .unwrap_or_else(|e| Response::new_err(500, -1, e));
+        if !send(&mut session, ws_req.id, res).await {
+            return;
+        }
+    }
+
+    let _ = session.close(close_reason).await;
+}
+
+pub async fn handler(
+    req: HttpRequest,
+    body: web::Payload,
+    db_conf: web::Data<Db>,
+    db_name: web::Data<String>,
+) -> actix_web::Result<HttpResponse> {
+    let db_name = db_name.to_string();
+
+    let (authenticated, user) = match &db_conf.conf.auth {
+        None => (true, None),
+        Some(ac) => match ac.mode {
+            AuthMode::HttpBasic => {
+                let ac_headers = Authorization::<Basic>::parse(&req).ok();
+                match process_auth_async(ac, &None, &ac_headers, &db_name).await {
+                    Ok(Some(u)) => (true, Some(u)),
+                    Err(_) => return Ok(HttpResponse::ServiceUnavailable().finish()),
+                    Ok(None) => {
+                        record_auth_failure(&db_name, "credentials");
+                        sleep(Duration::from_millis(1000)).await;
+
+                        let status = StatusCode::from_u16(ac.auth_error_code)
+                            .unwrap_or(StatusCode::UNAUTHORIZED);
+                        return Ok(HttpResponse::build(status).finish());
+                    }
+                }
+            }
+            AuthMode::Inline => (false, None),
+        },
+    };
+
+    let (res, session, stream) = actix_ws::handle(&req, body)?;
+    let stream = stream
+        .max_frame_size(MAX_MESSAGE_SIZE)
+        .aggregate_continuations()
+        .max_continuation_size(MAX_MESSAGE_SIZE);
+    let conn = Connection {
+        db_conf: db_conf.into_inner(),
+        db_name,
+        authenticated,
+        user,
+        span_ctx: request_context(&req),
+    };
+    spawn(serve(conn, session, stream));
+
+    Ok(res)
+}
@@ -3,7 +3,11 @@ module sqliterg_tests
 go 1.21
 
 require (
+	github.com/bufbuild/protocompile v0.14.1
+	github.com/lib/pq v1.10.9
 	github.com/stretchr/testify v1.8.4
+	google.golang.org/grpc v1.64.1
+	google.golang.org/protobuf v1.34.2
 	gopkg.in/yaml.v3 v3.0.1
 )
 
@@ -15,19 +15,45 @@
 package main
 
 import (
+	"bufio"
 	"bytes"
+	"compress/gzip"
+	"context"
+	"crypto/hmac"
+	"crypto/rand"
+	"crypto/sha256"
+	"database/sql"
+	"encoding/base64"
+	"encoding/binary"
+	"encoding/hex"
 	"encoding/json"
 	"fmt"
 	"io"
+	"net"
 	"net/http"
+	"net/http/httptest"
+	"net/url"
 	"os"
 	"os/exec"
+	"path/filepath"
+	"sort"
+	"strconv"
 	"strings"
 	"sync"
 	"testing"
 	"time"
 
+	"github.com/bufbuild/protocompile"
+	_ "github.com/lib/pq"
 	"github.com/stretchr/testify/require"
+	"google.golang.org/grpc"
+	"google.golang.org/grpc/codes"
+	"google.golang.org/grpc/credentials/insecure"
+	"google.golang.org/grpc/metadata"
+	"google.golang.org/grpc/status"
+	"google.golang.org/protobuf/encoding/protojson"
+	"google.golang.org/protobuf/reflect/protoreflect"
+	"google.golang.org/protobuf/types/dynamicpb"
 	"gopkg.in/yaml.v3"
 )
 
@@ -82,6 +108,7 @@ func setupTest(t *testing.T, cfg *db, printOutput bool, argv ...string) func(boo
 			os.Remove("env/test.db")
 			os.Remove("env/test.db-shm")
 			os.Remove("env/test.db-wal")
+			os.Remove("env/test.db.sqliterg.json")
 			os.Remove("env/test1.db")
 			os.Remove("env/test1.db-shm")
 			os.Remove("env/test1.db-wal")
@@ -751,6 +778,7 @@ func TestStoredQueryMem(t *testing.T) {
 }
 
 var TRUE bool = true
+var FALSE bool = false
 
 func TestInitMacro(t *testing.T) {
 	cfg := db{
@@ -930,6 +958,216 @@ func TestPeriodicMacro(t *testing.T) {
 	require.Equal(t, float64(1), res.Results[0].ResultSet[0]["CNT"])
 }
 
+var EVERY_SECOND string = "* * * * * *"
+var EVERY_HOUR string = "0 * * * *"
+var CATCH_UP_ONCE string = "CATCH_UP_ONCE"
+
+func TestCronMacro(t *testing.T) {
+	cfg := db{
+		Macros: []macro{
+			{
+				Id: "M1",
+				Statements: []string{
+					"CREATE TABLE IF NOT EXISTS T1 (ID INT, VAL TEXT)",
+				},
+				Execution: execution{
+					OnCreate: &TRUE,
+				},
+			},
+			{
+				Id: "M2",
+				Statements: []string{
+					"INSERT INTO T1 VALUES (1, '')",
+				},
+				Execution: execution{
+					Cron: &EVERY_SECOND,
+				},
+			},
+		},
+	}
+
+	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
+
+	time.Sleep(2 * time.Second)
+
+	req := request{
+		Transaction: []requestItem{
+			{
+				Query: "SELECT COUNT(1) as CNT FROM T1",
+			},
+		},
+	}
+
+	code, _, res := call(t, "http://localhost:12321/test", req)
+
+	require.Equal(t, http.StatusOK, code)
+
+	require.GreaterOrEqual(t, res.Results[0].ResultSet[0]["CNT"], float64(1))
+}
+
+func TestCronMacroCatchUp(t *testing.T) {
+	cfg := db{
+		Macros: []macro{
+			{
+				Id: "M1",
+				Statements: []string{
+					"CREATE TABLE IF NOT EXISTS T1 (ID INT, VAL TEXT)",
+				},
+				Execution: execution{
+					OnCreate: &TRUE,
+				},
+			},
+			{
+				Id: "M2",
+				Statements: []string{
+					"INSERT INTO T1 VALUES (1, '')",
+				},
+				Execution: execution{
+					Cron:       &EVERY_HOUR,
+					MissedRuns: &CATCH_UP_ONCE,
+				},
+			},
+		},
+	}
+
+	require.NoError(t, os.WriteFile("env/test.db.sqliterg.json", []byte(`{"macro:M2":{"lastScheduledRun":"2000-01-01T00:00:00Z","runs":[]}}`), 0600))
+
+	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
+
+	req := request{
+		Transaction: []requestItem{
+			{
+				Query: "SELECT COUNT(1) as CNT FROM T1",
+			},
+		},
+	}
+
+	code, _, res := call(t, "http://localhost:12321/test", req)
+
+	require.Equal(t, http.StatusOK, code)
+
+	require.Equal(t, float64(1), res.Results[0].ResultSet[0]["CNT"])
+}
+
+func TestCorruptedStateFile(t *testing.T) {
+	require.NoError(t, os.WriteFile("env/test.db.sqliterg.json", []byte(`{"macro:M2":{"lastSch`), 0600))
+	defer os.Remove("env/test.db.sqliterg.json")
+
+	cmd := exec.Command(COMMAND, "--db", "env/test.db")
+	err := cmd.Run()
+	require.Error(t, err)
+	os.Remove("env/test.db")
+}
+
+func TestCronAndPeriodFail(t *testing.T) {
+	cfg := db{
+		Macros: []macro{
+			{
+				Id: "M1",
+				Statements: []string{
+					"CREATE TABLE IF NOT EXISTS T1 (ID INT, VAL TEXT)",
+				},
+				Execution: execution{
+					Period: &PERIOD,
+					Cron:   &EVERY_HOUR,
+				},
+			},
+		},
+	}
+
+	saveCfgToYaml(t, &cfg)
+	defer os.Remove("env/test.yaml")
+
+	cmd := exec.Command(COMMAND, "--db", "env/test.db")
+	err := cmd.Run()
+	require.Error(t, err)
+}
+
+func TestMacroChainingWithCondition(t *testing.T) {
+	// steps other than plain statements are not modeled in the structs
+	yaml := `
+macros:
+  - id: M1
+    statements:
+      - CREATE TABLE IF NOT EXISTS T1 (ID INT, VAL TEXT)
+      - CREATE TABLE IF NOT EXISTS T2 (ID INT, VAL TEXT)
+    execution:
+      onCreate: true
+  - id: ARCHIVE
+    statements:
+      - INSERT INTO T2 SELECT * FROM T1
+      - DELETE FROM T1
+    execution: {}
+  - id: MAINT
+    statements:
+      - macro: ARCHIVE
+        condition: SELECT COUNT(1) > 2 FROM T1
+    execution:
+      webService: {}
+`
+	require.NoError(t, os.WriteFile("env/test.yaml", []byte(yaml), 0600))
+
+	defer setupTest(t, nil, false, "--db", "env/test.db")(true)
+
+	count := func(table string) float64 {
+		req := request{
+			Transaction: []requestItem{
+				{
+					Query: fmt.Sprintf("SELECT COUNT(1) as CNT FROM %s", table),
+				},
+			},
+		}
+		code, _, res := call(t, "http://localhost:12321/test", req)
+		require.Equal(t, http.StatusOK, code)
+		return res.Results[0].ResultSet[0]["CNT"].(float64)
+	}
+
+	insert := request{
+		Transaction: []requestItem{
+			{
+				Statement: "INSERT INTO T1 VALUES (1, ''), (2, '')",
+			},
+		},
+	}
+
+	code, _, _ := call(t, "http://localhost:12321/test", insert)
+	require.Equal(t, http.StatusOK, code)
+
+	// condition not satisfied
+	code, _, _ = call(t, "http://localhost:12321/test/macro/MAINT", request{})
+	require.Equal(t, http.StatusOK, code)
+	require.Equal(t, float64(2), count("T1"))
+	require.Equal(t, float64(0), count("T2"))
+
+	code, _, _ = call(t, "http://localhost:12321/test", insert)
+	require.Equal(t, http.StatusOK, code)
+
+	code, _, _ = call(t, "http://localhost:12321/test/macro/MAINT", request{})
+	require.Equal(t, http.StatusOK, code)
+	require.Equal(t, float64(0), count("T1"))
+	require.Equal(t, float64(4), count("T2"))
+}
+
+func TestMacroRecursionFails(t *testing.T) {
+	yaml := `
+macros:
+  - id: M1
+    statements:
+      - macro: M2
+    execution: {}
+  - id: M2
+    statements:
+      - macro: M1
+    execution: {}
+`
+	require.NoError(t, os.WriteFile("env/test.yaml", []byte(yaml), 0600))
+	defer os.Remove("env/test.yaml")
+
+	cmd := exec.Command(COMMAND, "--db", "env/test.db")
+	err := cmd.Run()
+	require.Error(t, err)
+}
+
 func TestCallableMacro(t *testing.T) {
 	cfg := db{
 		Macros: []macro{
@@ -983,6 +1221,75 @@ func TestCallableMacro(t *testing.T) {
 	require.Equal(t, float64(2), res.Results[0].ResultSet[0]["CNT"])
 }
 
+type run struct {
+	Trigger     string `json:"trigger"`
+	Success     bool   `json:"success"`
+	RowsUpdated *int   `json:"rowsUpdated,omitempty"`
+	Error       string `json:"error,omitempty"`
+}
+
+type runsResponse struct {
+	Runs []run `json:"runs"`
+}
+
+func getRuns(t *testing.T, url string) (int, runsResponse) {
+	resp, err := http.Get(url)
+	require.NoError(t, err)
+
+	bs, err := io.ReadAll(resp.Body)
+	require.NoError(t, err)
+	var obj runsResponse
+	json.Unmarshal(bs, &obj)
+
+	return resp.StatusCode, obj
+}
+
+func TestMacroRuns(t *testing.T) {
+	cfg := db{
+		Macros: []macro{
+			{
+				Id: "M1",
+				Statements: []string{
+					"CREATE TABLE IF NOT EXISTS T1 (ID INT, VAL TEXT)",
+				},
+				Execution: execution{
+					OnCreate:   &TRUE,
+					WebService: &webService{},
+				},
+			},
+			{
+				Id: "M2",
+				Statements: []string{
+					"INSERT INTO T1 VALUES (1, '')",
+					"INSERT INTO NOT_A_TABLE VALUES (1, '')",
+				},
+				Execution: execution{
+					Cron:       &EVERY_SECOND,
+					WebService: &webService{},
+				},
+			},
+		},
+	}
+
+	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
+
+	code, runs := getRuns(t, "http://localhost:12321/test/macro/M1/runs")
+	require.Equal(t, http.StatusOK, code)
+	require.Equal(t, 1, len(runs.Runs))
+	require.Equal(t, "STARTUP", runs.Runs[0].Trigger)
+	require.True(t, runs.Runs[0].Success)
+
+	time.Sleep(2 * time.Second)
+
+	// a failed run doesn't stop the following ones
+	code, runs = getRuns(t, "http://localhost:12321/test/macro/M2/runs")
+	require.Equal(t, http.StatusOK, code)
+	require.GreaterOrEqual(t, len(runs.Runs), 2)
+	require.Equal(t, "SCHEDULE", runs.Runs[0].Trigger)
+	require.False(t, runs.Runs[0].Success)
+	require.Contains(t, runs.Runs[0].Error, "NOT_A_TABLE")
+}
+
 var ciao string = "ciao"
 var hciao string = "b133a0c0e9bee3be20163d2ad31d6248db292aa6dcb1ee087a2aa50e0fc75ae2"
 var custAuthError = 499
@@ -1145,8 +1452,11 @@ func TestCallableMacroAuthFailCustomError(t *testing.T) {
 	require.Equal(t, custAuthError, code)
 }
 
-func now() string {
-	return time.Now().Format("20060102-1504")
+// the files in the backup dir matching a glob pattern
+func backupFiles(t *testing.T, pattern string) []string {
+	files, err := filepath.Glob("env/backups/" + pattern)
+	require.NoError(t, err)
+	return files
 }
 
 func TestInitBackup(t *testing.T) {
@@ -1162,7 +1472,7 @@ func TestInitBackup(t *testing.T) {
 
 	defer setupTest(t, &cfg, false, "--mem-db", "test::env/test.yaml")(true)
 
-	require.FileExists(t, fmt.Sprintf("env/backups/test_%s", now()))
+	require.Len(t, backupFiles(t, "test_*"), 1)
 }
 
 func TestNoInitBackup(t *testing.T) {
@@ -1180,7 +1490,7 @@ func TestNoInitBackup(t *testing.T) {
 
 	defer setupTest(t, &cfg, false, "--mem-db", "test::env/test.yaml")(true)
 
-	require.NoFileExists(t, fmt.Sprintf("env/backups/test_%s.db", now()))
+	require.Empty(t, backupFiles(t, "test_*.db"))
 }
 
 func TestStartupBackup1(t *testing.T) {
@@ -1196,7 +1506,7 @@ func TestStartupBackup1(t *testing.T) {
 
 	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
 
-	require.FileExists(t, fmt.Sprintf("env/backups/test_%s.db", now()))
+	require.Len(t, backupFiles(t, "test_*.db"), 1)
 }
 
 func TestStartupBackup2(t *testing.T) {
@@ -1216,7 +1526,7 @@ func TestStartupBackup2(t *testing.T) {
 
 	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
 
-	require.FileExists(t, fmt.Sprintf("env/backups/test_%s.db", now()))
+	require.Len(t, backupFiles(t, "test_*.db"), 1)
 }
 
 func TestPeriodicBackupWith1File(t *testing.T) {
@@ -1237,15 +1547,14 @@ func TestPeriodicBackupWith1File(t *testing.T) {
 
 	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
 
-	// It can fail even if it's correct, because of timing (2/60~=3% probability)
 	time.Sleep(2 * time.Second)
-	bkp1 := fmt.Sprintf("env/backups/test_%s.db", now())
-	// require.FileExists(t, bkp1)
+	bkps1 := backupFiles(t, "test_*.db")
+	require.Len(t, bkps1, 1)
 
 	time.Sleep(60 * time.Second)
-	bkp2 := fmt.Sprintf("env/backups/test_%s.db", now())
-	require.NoFileExists(t, bkp1)
-	require.FileExists(t, bkp2)
+	bkps2 := backupFiles(t, "test_*.db")
+	require.Len(t, bkps2, 1)
+	require.NotEqual(t, bkps1[0], bkps2[0])
 }
 
 func TestCallableBackup(t *testing.T) {
@@ -1269,7 +1578,7 @@ func TestCallableBackup(t *testing.T) {
 
 	require.Equal(t, http.StatusOK, code)
 
-	require.FileExists(t, fmt.Sprintf("env/backups/test_%s.db", now()))
+	require.Len(t, backupFiles(t, "test_*.db"), 1)
 }
 
 func TestCallableBackupAuthOk(t *testing.T) {
@@ -1295,7 +1604,7 @@ func TestCallableBackupAuthOk(t *testing.T) {
 
 	require.Equal(t, http.StatusOK, code)
 
-	require.FileExists(t, fmt.Sprintf("env/backups/test_%s.db", now()))
+	require.Len(t, backupFiles(t, "test_*.db"), 1)
 }
 
 func TestCallableBackupAuthKo(t *testing.T) {
@@ -1345,7 +1654,7 @@ func TestCallableBackupAuthOkHash(t *testing.T) {
 
 	require.Equal(t, http.StatusOK, code)
 
-	require.FileExists(t, fmt.Sprintf("env/backups/test_%s.db", now()))
+	require.Len(t, backupFiles(t, "test_*.db"), 1)
 }
 
 func TestCallableBackupAuthKoHash(t *testing.T) {
@@ -1397,68 +1706,293 @@ func TestCallableBackupAuthKoCustomError(t *testing.T) {
 	require.Equal(t, custAuthError, code)
 }
 
-func TestFailROButMacroCanModify(t *testing.T) {
+func TestCallableBackupWithHooks(t *testing.T) {
 	cfg := db{
-		ReadOnly: true,
 		Macros: []macro{
 			{
 				Id: "M1",
 				Statements: []string{
-					// if it failed, it would give connection error later on
-					"CREATE TABLE T2 (ID INT PRIMARY KEY, VAL TEXT NOT NULL)",
+					"CREATE TABLE IF NOT EXISTS T1 (ID INT, VAL TEXT)",
 				},
 				Execution: execution{
 					OnCreate: &TRUE,
 				},
 			},
+			{
+				Id: "PRE",
+				Statements: []string{
+					"INSERT INTO T1 VALUES (1, 'pre')",
+				},
+				// execution is mandatory, even if only called as a hook
+				Execution: execution{
+					OnStartup: &FALSE,
+				},
+			},
+			{
+				Id: "POST",
+				Statements: []string{
+					"INSERT INTO T1 VALUES (2, 'post')",
+				},
+				Execution: execution{
+					OnStartup: &FALSE,
+				},
+			},
+		},
+		Backup: backup{
+			BackupDir:  "env/backups",
+			NumFiles:   1,
+			PreBackup:  []string{"PRE"},
+			PostBackup: []string{"POST"},
+			Execution: execution{
+				WebService: &webService{},
+			},
 		},
 	}
 
 	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
 
+	code, _, _ := call(t, "http://localhost:12321/test/backup", request{})
+
+	require.Equal(t, http.StatusOK, code)
+
+	require.Len(t, backupFiles(t, "test_*.db"), 1)
+
 	req := request{
 		Transaction: []requestItem{
 			{
-				Statement: "CREATE TABLE T2 (ID INT PRIMARY KEY, VAL TEXT NOT NULL)",
+				Query: "SELECT VAL FROM T1 ORDER BY ID",
 			},
 		},
 	}
 
-	code, _, _ := call(t, "http://localhost:12321/test", req)
+	code, _, res := call(t, "http://localhost:12321/test", req)
 
-	require.Equal(t, http.StatusInternalServerError, code)
+	require.Equal(t, http.StatusOK, code)
+	require.Equal(t, 2, len(res.Results[0].ResultSet))
+	require.Equal(t, "pre", res.Results[0].ResultSet[0]["VAL"])
+	require.Equal(t, "post", res.Results[0].ResultSet[1]["VAL"])
 }
 
-func TestFailROButMacroCanModify2(t *testing.T) {
+func TestBackupHookUnknownMacroFails(t *testing.T) {
+	cfg := db{
+		Backup: backup{
+			BackupDir: "env/backups",
+			NumFiles:  1,
+			PreBackup: []string{"NOT_A_MACRO"},
+			Execution: execution{
+				WebService: &webService{},
+			},
+		},
+	}
+
+	saveCfgToYaml(t, &cfg)
+	defer os.Remove("env/test.yaml")
+
+	cmd := exec.Command(COMMAND, "--db", "env/test.db")
+	err := cmd.Run()
+	require.Error(t, err)
+}
+
+func TestShutdownMacro(t *testing.T) {
 	cfg := db{
-		ReadOnly: true,
 		Macros: []macro{
 			{
 				Id: "M1",
 				Statements: []string{
-					// if it failed, it would give connection error later on
-					"CREATE TABLE T2 (ID INT PRIMARY KEY, VAL TEXT NOT NULL)",
+					"CREATE TABLE IF NOT EXISTS T1 (ID INT, VAL TEXT)",
 				},
 				Execution: execution{
-					OnStartup: &TRUE,
+					OnCreate: &TRUE,
+				},
+			},
+			{
+				Id: "M2",
+				Statements: []string{
+					"INSERT INTO T1 VALUES (1, '')",
+				},
+				Execution: execution{
+					OnShutdown: &TRUE,
 				},
 			},
 		},
 	}
 
+	setupTest(t, &cfg, false, "--db", "env/test.db")
+
+	// graceful shutdown
+	require.NoError(t, cmd.Process.Signal(os.Interrupt))
+	cmd.Wait()
+	cmd = nil
+
 	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
 
 	req := request{
 		Transaction: []requestItem{
 			{
-				Statement: "CREATE TABLE T2 (ID INT PRIMARY KEY, VAL TEXT NOT NULL)",
+				Query: "SELECT COUNT(1) as CNT FROM T1",
 			},
 		},
 	}
 
-	code, _, _ := call(t, "http://localhost:12321/test", req)
+	code, _, res := call(t, "http://localhost:12321/test", req)
 
-	require.Equal(t, http.StatusInternalServerError, code)
+	require.Equal(t, http.StatusOK, code)
+
+	require.Equal(t, float64(1), res.Results[0].ResultSet[0]["CNT"])
+}
+
+func TestCompressedBackup(t *testing.T) {
+	cfg := db{
+		Macros: []macro{
+			{
+				Id: "M1",
+				Statements: []string{
+					"CREATE TABLE IF NOT EXISTS T1 (ID INT, VAL TEXT)",
+					"INSERT INTO T1 VALUES (1, 'ONE')",
+				},
+				Execution: execution{
+					OnCreate: &TRUE,
+				},
+			},
+		},
+		Backup: backup{
+			BackupDir:   "env/backups",
+			NumFiles:    1,
+			Compression: "gzip",
+			Execution: execution{
+				OnCreate: &TRUE,
+			},
+		},
+	}
+
+	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
+
+	bkpFiles := backupFiles(t, "test_*.db.gz")
+	require.Len(t, bkpFiles, 1)
+	bkpFile := bkpFiles[0]
+
+	f, err := os.Open(bkpFile)
+	require.NoError(t, err)
+	defer f.Close()
+	gz, err := gzip.NewReader(f)
+	require.NoError(t, err)
+	content, err := io.ReadAll(gz)
+	require.NoError(t, err)
+	require.True(t, bytes.HasPrefix(content, []byte("SQLite format 3")))
+}
+
+func TestEncryptedBackup(t *testing.T) {
+	require.NoError(t, os.WriteFile("env/test.key", []byte(strings.Repeat("ab", 32)), 0600))
+	defer os.Remove("env/test.key")
+
+	cfg := db{
+		Backup: backup{
+			BackupDir:   "env/backups",
+			NumFiles:    1,
+			Compression: "zstd",
+			Encryption:  &encryption{KeyFile: "env/test.key"},
+			Execution: execution{
+				OnCreate: &TRUE,
+			},
+		},
+	}
+
+	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
+
+	bkpFiles := backupFiles(t, "test_*.db.zst.enc")
+	require.Len(t, bkpFiles, 1)
+	bkpFile := bkpFiles[0]
+
+	content, err := os.ReadFile(bkpFile)
+	require.NoError(t, err)
+	require.True(t, bytes.HasPrefix(content, []byte("SQRGAES1")))
+}
+
+func TestBadKeyFileFails(t *testing.T) {
+	require.NoError(t, os.WriteFile("env/test.key", []byte("not a key"), 0600))
+	defer os.Remove("env/test.key")
+
+	cfg := db{
+		Backup: backup{
+			BackupDir:  "env/backups",
+			NumFiles:   1,
+			Encryption: &encryption{KeyFile: "env/test.key"},
+			Execution: execution{
+				OnCreate: &TRUE,
+			},
+		},
+	}
+
+	saveCfgToYaml(t, &cfg)
+	defer os.Remove("env/test.yaml")
+
+	cmd := exec.Command(COMMAND, "--db", "env/test.db")
+	err := cmd.Run()
+	require.Error(t, err)
+}
+
+func TestFailROButMacroCanModify(t *testing.T) {
+	cfg := db{
+		ReadOnly: true,
+		Macros: []macro{
+			{
+				Id: "M1",
+				Statements: []string{
+					// if it failed, it would give connection error later on
+					"CREATE TABLE T2 (ID INT PRIMARY KEY, VAL TEXT NOT NULL)",
+				},
+				Execution: execution{
+					OnCreate: &TRUE,
+				},
+			},
+		},
+	}
+
+	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
+
+	req := request{
+		Transaction: []requestItem{
+			{
+				Statement: "CREATE TABLE T2 (ID INT PRIMARY KEY, VAL TEXT NOT NULL)",
+			},
+		},
+	}
+
+	code, _, _ := call(t, "http://localhost:12321/test", req)
+
+	require.Equal(t, http.StatusInternalServerError, code)
+}
+
+func TestFailROButMacroCanModify2(t *testing.T) {
+	cfg := db{
+		ReadOnly: true,
+		Macros: []macro{
+			{
+				Id: "M1",
+				Statements: []string{
+					// if it failed, it would give connection error later on
+					"CREATE TABLE T2 (ID INT PRIMARY KEY, VAL TEXT NOT NULL)",
+				},
+				Execution: execution{
+					OnStartup: &TRUE,
+				},
+			},
+		},
+	}
+
+	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
+
+	req := request{
+		Transaction: []requestItem{
+			{
+				Statement: "CREATE TABLE T2 (ID INT PRIMARY KEY, VAL TEXT NOT NULL)",
+			},
+		},
+	}
+
+	code, _, _ := call(t, "http://localhost:12321/test", req)
+
+	require.Equal(t, http.StatusInternalServerError, code)
 }
 
 func TestDbSegregationMem(t *testing.T) {
@@ -2721,3 +3255,3342 @@ func TestReturnedBool(t *testing.T) {
 
 	require.Equal(t, "true", ret.Results[1].ResultSet[0]["VAL"].(string))
 }
+
+type backupFile struct {
+	Name string `json:"name"`
+	Size int64  `json:"size"`
+}
+
+type backupListResponse struct {
+	Files []backupFile `json:"files"`
+}
+
+func restoreCall(t *testing.T, url string) (int, response) {
+	resp, err := http.Post(url, "", nil)
+	require.NoError(t, err)
+
+	bs, err := io.ReadAll(resp.Body)
+	require.NoError(t, err)
+	var obj response
+	json.Unmarshal(bs, &obj)
+
+	return resp.StatusCode, obj
+}
+
+func TestBackupListAndRestore(t *testing.T) {
+	cfg := db{
+		Macros: []macro{
+			{
+				Id: "M1",
+				Statements: []string{
+					"CREATE TABLE IF NOT EXISTS T1 (ID INT, VAL TEXT)",
+					"INSERT INTO T1 VALUES (1, 'ONE')",
+				},
+				Execution: execution{
+					OnCreate: &TRUE,
+				},
+			},
+		},
+		Backup: backup{
+			BackupDir:   "env/backups",
+			NumFiles:    1,
+			Compression: "gzip",
+			Execution: execution{
+				OnCreate: &TRUE,
+				WebService: &webService{
+					AuthToken: &ciao,
+				},
+			},
+		},
+	}
+
+	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
+
+	resp, err := http.Get("http://localhost:12321/test/backup/list?token=ciao")
+	require.NoError(t, err)
+	require.Equal(t, http.StatusOK, resp.StatusCode)
+	bs, err := io.ReadAll(resp.Body)
+	require.NoError(t, err)
+	var list backupListResponse
+	require.NoError(t, json.Unmarshal(bs, &list))
+	require.Equal(t, 1, len(list.Files))
+	require.Regexp(t, `^test_\d{8}T\d{6}\.\d{3}Z\.db\.gz$`, list.Files[0].Name)
+
+	req := request{
+		Transaction: []requestItem{
+			{
+				Statement: "DELETE FROM T1",
+			},
+		},
+	}
+	code, _, _ := call(t, "http://localhost:12321/test", req)
+	require.Equal(t, http.StatusOK, code)
+
+	code, _ = restoreCall(t, "http://localhost:12321/test/backup/restore?token=wrong&file="+list.Files[0].Name)
+	require.Equal(t, http.StatusUnauthorized, code)
+
+	code, _ = restoreCall(t, "http://localhost:12321/test/backup/restore?token=ciao&file=../test.db")
+	require.Equal(t, http.StatusBadRequest, code)
+
+	code, _ = restoreCall(t, "http://localhost:12321/test/backup/restore?token=ciao&file="+list.Files[0].Name)
+	require.Equal(t, http.StatusOK, code)
+
+	req = request{
+		Transaction: []requestItem{
+			{
+				Query: "SELECT VAL FROM T1",
+			},
+		},
+	}
+	code, _, res := call(t, "http://localhost:12321/test", req)
+	require.Equal(t, http.StatusOK, code)
+	require.Equal(t, 1, len(res.Results[0].ResultSet))
+	require.Equal(t, "ONE", res.Results[0].ResultSet[0]["VAL"])
+}
+
+func TestRestoreCorruptedFileFails(t *testing.T) {
+	cfg := db{
+		Backup: backup{
+			BackupDir: "env/backups",
+			NumFiles:  1,
+			Execution: execution{
+				WebService: &webService{
+					AuthToken: &ciao,
+				},
+			},
+		},
+	}
+
+	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
+
+	require.NoError(t, os.WriteFile("env/backups/corrupted.db", []byte("not a database"), 0600))
+
+	code, _ := restoreCall(t, "http://localhost:12321/test/backup/restore?token=ciao&file=corrupted.db")
+	require.Equal(t, http.StatusConflict, code)
+}
+
+func TestRestoreCLI(t *testing.T) {
+	cfg := db{
+		Macros: []macro{
+			{
+				Id: "M1",
+				Statements: []string{
+					"CREATE TABLE IF NOT EXISTS T1 (ID INT, VAL TEXT)",
+					"INSERT INTO T1 VALUES (1, 'ONE')",
+				},
+				Execution: execution{
+					OnCreate: &TRUE,
+				},
+			},
+		},
+		Backup: backup{
+			BackupDir: "env/backups",
+			NumFiles:  1,
+			Execution: execution{
+				OnCreate: &TRUE,
+			},
+		},
+	}
+
+	setupTest(t, &cfg, false, "--db", "env/test.db")
+	cmd.Process.Kill()
+	cmd.Wait()
+	cmd = nil
+
+	require.NoError(t, os.Remove("env/test.db"))
+	os.Remove("env/test.db-shm")
+	os.Remove("env/test.db-wal")
+
+	restore := exec.Command(COMMAND, "restore", "--db", "env/test.db", "--file", backupFiles(t, "test_*.db")[0])
+	require.NoError(t, restore.Run())
+
+	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
+
+	req := request{
+		Transaction: []requestItem{
+			{
+				Query: "SELECT VAL FROM T1",
+			},
+		},
+	}
+	code, _, res := call(t, "http://localhost:12321/test", req)
+	require.Equal(t, http.StatusOK, code)
+	require.Equal(t, 1, len(res.Results[0].ResultSet))
+}
+
+type backupProgressResponse struct {
+	InProgress bool `json:"inProgress"`
+	PageCount  int  `json:"pageCount"`
+	Remaining  int  `json:"remaining"`
+}
+
+func TestBackupInSteps(t *testing.T) {
+	cfg := db{
+		Macros: []macro{
+			{
+				Id: "M1",
+				Statements: []string{
+					"CREATE TABLE IF NOT EXISTS T1 (ID INT, VAL TEXT)",
+					"WITH RECURSIVE C(X) AS (SELECT 1 UNION ALL SELECT X + 1 FROM C WHERE X < 1000) INSERT INTO T1 SELECT X, HEX(RANDOMBLOB(100)) FROM C",
+				},
+				Execution: execution{
+					OnCreate: &TRUE,
+				},
+			},
+		},
+		Backup: backup{
+			BackupDir:    "env/backups",
+			NumFiles:     1,
+			PagesPerStep: 1,
+			Execution: execution{
+				WebService: &webService{
+					AuthToken: &ciao,
+				},
+			},
+		},
+	}
+
+	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
+
+	code, _, _ := call(t, "http://localhost:12321/test/backup?token=ciao", request{})
+	require.Equal(t, http.StatusOK, code)
+	require.Len(t, backupFiles(t, "test_*.db"), 1)
+
+	resp, err := http.Get("http://localhost:12321/test/backup/progress?token=ciao")
+	require.NoError(t, err)
+	require.Equal(t, http.StatusOK, resp.StatusCode)
+	bs, err := io.ReadAll(resp.Body)
+	require.NoError(t, err)
+	var progress backupProgressResponse
+	require.NoError(t, json.Unmarshal(bs, &progress))
+	require.False(t, progress.InProgress)
+
+	// the backup is a complete database
+	code, _ = restoreCall(t, "http://localhost:12321/test/backup/restore?token=ciao&file="+filepath.Base(backupFiles(t, "test_*.db")[0]))
+	require.Equal(t, http.StatusOK, code)
+
+	req := request{
+		Transaction: []requestItem{
+			{
+				Query: "SELECT COUNT(1) AS CNT FROM T1",
+			},
+		},
+	}
+	code, _, res := call(t, "http://localhost:12321/test", req)
+	require.Equal(t, http.StatusOK, code)
+	require.Equal(t, float64(1000), res.Results[0].ResultSet[0]["CNT"])
+}
+
+func TestBackupRetention(t *testing.T) {
+	cfg := db{
+		Backup: backup{
+			BackupDir: "env/backups",
+			NumFiles:  1,
+			Retention: &retention{
+				Daily:   3,
+				Monthly: 3,
+			},
+			Execution: execution{
+				OnCreate: &TRUE,
+			},
+		},
+	}
+
+	os.Mkdir("env/backups", 0700)
+	for _, f := range []string{
+		"test_20191015-1000.db",
+		"test_20191115-1000.db",
+		"test_20191215-1000.db",
+		"test_20200101-1000.db",
+		"test_20200101-1200.db",
+		"test_20200102-1000.db",
+		"test_20200103-1000.db",
+		"test2_20200101-1000.db",
+		"other.txt",
+	} {
+		require.NoError(t, os.WriteFile("env/backups/"+f, []byte{}, 0600))
+	}
+
+	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
+
+	entries, err := os.ReadDir("env/backups")
+	require.NoError(t, err)
+	var names []string
+	for _, e := range entries {
+		names = append(names, e.Name())
+	}
+
+	// the current backup, two more days, one more month; the files of other dbs are untouched
+	require.ElementsMatch(t, []string{
+		filepath.Base(backupFiles(t, "test_*Z.db")[0]),
+		"test.manifest.json",
+		"test_20200103-1000.db",
+		"test_20200102-1000.db",
+		"test_20191215-1000.db",
+		"test2_20200101-1000.db",
+		"other.txt",
+	}, names)
+}
+
+func TestBackupsInSameMinute(t *testing.T) {
+	cfg := db{
+		Backup: backup{
+			BackupDir: "env/backups",
+			NumFiles:  3,
+			Execution: execution{
+				WebService: &webService{},
+			},
+		},
+	}
+
+	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
+
+	for i := 0; i < 3; i++ {
+		code, _, _ := call(t, "http://localhost:12321/test/backup", request{})
+		require.Equal(t, http.StatusOK, code)
+	}
+
+	require.Len(t, backupFiles(t, "test_*.db"), 3)
+}
+
+type manifestEntry struct {
+	File            string `json:"file"`
+	Size            int64  `json:"size"`
+	Sha256          string `json:"sha256"`
+	SourceDb        string `json:"sourceDb"`
+	SqliteVersion   string `json:"sqliteVersion"`
+	SqlitergVersion string `json:"sqlitergVersion"`
+}
+
+type manifest struct {
+	Backups []manifestEntry `json:"backups"`
+}
+
+func TestBackupFileTemplateAndManifest(t *testing.T) {
+	cfg := db{
+		Backup: backup{
+			BackupDir:    "env/backups",
+			NumFiles:     1,
+			FileTemplate: "nightly-{db}-{ts}{ext}",
+			Execution: execution{
+				OnCreate: &TRUE,
+			},
+		},
+	}
+
+	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
+
+	files := backupFiles(t, "nightly-test-*.db")
+	require.Len(t, files, 1)
+
+	bs, err := os.ReadFile("env/backups/test.manifest.json")
+	require.NoError(t, err)
+	var m manifest
+	require.NoError(t, json.Unmarshal(bs, &m))
+	require.Len(t, m.Backups, 1)
+	require.Equal(t, filepath.Base(files[0]), m.Backups[0].File)
+	require.Equal(t, "env/test.db", m.Backups[0].SourceDb)
+	require.Len(t, m.Backups[0].Sha256, 64)
+	require.NotEmpty(t, m.Backups[0].SqliteVersion)
+	require.NotEmpty(t, m.Backups[0].SqlitergVersion)
+}
+
+func TestBadFileTemplateFails(t *testing.T) {
+	cfg := db{
+		Backup: backup{
+			BackupDir:    "env/backups",
+			NumFiles:     1,
+			FileTemplate: "{db}{ext}",
+			Execution: execution{
+				OnCreate: &TRUE,
+			},
+		},
+	}
+
+	saveCfgToYaml(t, &cfg)
+	defer os.Remove("env/test.yaml")
+
+	cmd := exec.Command(COMMAND, "--db", "env/test.db")
+	err := cmd.Run()
+	require.Error(t, err)
+
+	// without {db}
+	cfg.Backup.FileTemplate = "backup-{ts}{ext}"
+	saveCfgToYaml(t, &cfg)
+
+	cmd = exec.Command(COMMAND, "--db", "env/test.db")
+	err = cmd.Run()
+	require.Error(t, err)
+}
+
+func TestWalArchiveAndPointInTimeRestore(t *testing.T) {
+	cfg := db{
+		Macros: []macro{
+			{
+				Id: "M1",
+				Statements: []string{
+					"CREATE TABLE IF NOT EXISTS T1 (ID INT)",
+				},
+				Execution: execution{
+					OnCreate: &TRUE,
+				},
+			},
+		},
+		WalArchive: &walArchive{
+			Dir:      "env/backups",
+			Interval: 1,
+		},
+	}
+
+	setupTest(t, &cfg, false, "--db", "env/test.db")
+
+	insert := request{
+		Transaction: []requestItem{
+			{
+				Statement: "INSERT INTO T1 VALUES (1), (2), (3)",
+			},
+		},
+	}
+	code, _, _ := call(t, "http://localhost:12321/test", insert)
+	require.Equal(t, http.StatusOK, code)
+
+	time.Sleep(2 * time.Second)
+	beforeDelete := time.Now().UTC().Format(time.RFC3339Nano)
+	time.Sleep(time.Second)
+
+	del := request{
+		Transaction: []requestItem{
+			{
+				Statement: "DELETE FROM T1",
+			},
+		},
+	}
+	code, _, _ = call(t, "http://localhost:12321/test", del)
+	require.Equal(t, http.StatusOK, code)
+
+	time.Sleep(2 * time.Second)
+	cmd.Process.Kill()
+	cmd.Wait()
+	cmd = nil
+
+	snapshots, _ := filepath.Glob("env/backups/test.db/*/snapshot.db")
+	require.Equal(t, 1, len(snapshots))
+	segments, _ := filepath.Glob("env/backups/test.db/*/*.seg")
+	require.Equal(t, 2, len(segments))
+
+	restore := exec.Command(COMMAND, "restore", "--db", "env/test.db::env/test.yaml", "--to", beforeDelete)
+	require.NoError(t, restore.Run())
+
+	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
+
+	req := request{
+		Transaction: []requestItem{
+			{
+				Query: "SELECT ID FROM T1",
+			},
+		},
+	}
+	code, _, res := call(t, "http://localhost:12321/test", req)
+	require.Equal(t, http.StatusOK, code)
+	require.Equal(t, 3, len(res.Results[0].ResultSet))
+}
+
+func TestWalArchiveGenerations(t *testing.T) {
+	cfg := db{
+		WalArchive: &walArchive{
+			Dir:            "env/backups",
+			NumGenerations: 2,
+		},
+	}
+
+	// each startup begins a new generation
+	setupTest(t, &cfg, false, "--db", "env/test.db")
+	setupTest(t, &cfg, false, "--db", "env/test.db")
+	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
+
+	generations, _ := filepath.Glob("env/backups/test.db/*/snapshot.db")
+	require.Equal(t, 2, len(generations))
+}
+
+func TestWalArchiveNeedsWal(t *testing.T) {
+	cfg := db{
+		JournalMode: "DELETE",
+		WalArchive: &walArchive{
+			Dir: "env/backups",
+		},
+	}
+
+	saveCfgToYaml(t, &cfg)
+	defer os.Remove("env/test.yaml")
+	os.Mkdir("env/backups", 0700)
+	defer os.RemoveAll("env/backups")
+
+	cmd := exec.Command(COMMAND, "--db", "env/test.db")
+	err := cmd.Run()
+	require.Error(t, err)
+}
+
+// Needs an S3-compatible storage, e.g. a local MinIO, with an existing bucket:
+//
+//	docker run -p 9000:9000 minio/minio server /data
+//
+// and SQLITERG_TEST_S3_ENDPOINT, SQLITERG_TEST_S3_BUCKET, AWS_ACCESS_KEY_ID and
+// AWS_SECRET_ACCESS_KEY set accordingly.
+// An in-process stand-in for an S3-compatible object storage, enough for the backups: it
+// checks the SigV4 signature of each request, and supports multipart upload, listing (one
+// object per page, to exercise the continuation), download and deletion.
+type s3StandIn struct {
+	bucket    string
+	accessKey string
+	secretKey string
+
+	mu       sync.Mutex
+	objects  map[string]s3Object
+	uploads  map[string]map[int][]byte
+	uploadId int
+	parts    int
+	rejected int
+}
+
+type s3Object struct {
+	data         []byte
+	lastModified time.Time
+}
+
+func newS3StandIn() (*s3StandIn, *httptest.Server) {
+	s3 := &s3StandIn{
+		bucket:    "backups",
+		accessKey: "AKIDSQLITERG",
+		secretKey: "s3cr3t",
+		objects:   map[string]s3Object{},
+		uploads:   map[string]map[int][]byte{},
+	}
+	return s3, httptest.NewServer(s3)
+}
+
+func s3Error(w http.ResponseWriter, status int, code, msg string) {
+	w.WriteHeader(status)
+	fmt.Fprintf(w, "<Error><Code>%s</Code><Message>%s</Message></Error>", code, msg)
+}
+
+// As in the canonical request: all but the unreserved characters are percent-encoded
+func s3Encode(s string, encodeSlash bool) string {
+	var sb strings.Builder
+	for _, b := range []byte(s) {
+		switch {
+		case b >= 'A' && b <= 'Z', b >= 'a' && b <= 'z', b >= '0' && b <= '9', b == '-', b == '_', b == '.', b == '~':
+			sb.WriteByte(b)
+		case b == '/' && !encodeSlash:
+			sb.WriteByte(b)
+		default:
+			fmt.Fprintf(&sb, "%%%02X", b)
+		}
+	}
+	return sb.String()
+}
+
+func hmacSha256(key []byte, data string) []byte {
+	mac := hmac.New(sha256.New, key)
+	mac.Write([]byte(data))
+	return mac.Sum(nil)
+}
+
+func sha256Hex(data []byte) string {
+	sum := sha256.Sum256(data)
+	return hex.EncodeToString(sum[:])
+}
+
+func (s3 *s3StandIn) checkSignature(r *http.Request, body []byte) bool {
+	var credential, signedHeaders, signature string
+	for _, part := range strings.Split(strings.TrimPrefix(r.Header.Get("Authorization"), "AWS4-HMAC-SHA256 "), ", ") {
+		k, v, _ := strings.Cut(part, "=")
+		switch k {
+		case "Credential":
+			credential = v
+		case "SignedHeaders":
+			signedHeaders = v
+		case "Signature":
+			signature = v
+		}
+	}
+	accessKey, scope, _ := strings.Cut(credential, "/")
+	scopeParts := strings.Split(scope, "/")
+	if accessKey != s3.accessKey || len(scopeParts) != 4 {
+		return false
+	}
+	payloadHash := r.Header.Get("X-Amz-Content-Sha256")
+	if payloadHash != sha256Hex(body) {
+		return false
+	}
+
+	var query []string
+	if r.URL.RawQuery != "" {
+		for _, kv := range strings.Split(r.URL.RawQuery, "&") {
+			k, v, _ := strings.Cut(kv, "=")
+			k, _ = url.PathUnescape(k)
+			v, _ = url.PathUnescape(v)
+			query = append(query, s3Encode(k, true)+"="+s3Encode(v, true))
+		}
+	}
+	sort.Strings(query)
+	var headers strings.Builder
+	for _, h := range strings.Split(signedHeaders, ";") {
+		v := r.Header.Get(h)
+		if h == "host" {
+			v = r.Host
+		}
+		headers.WriteString(h + ":" + strings.TrimSpace(v) + "\n")
+	}
+	canonical := strings.Join([]string{
+		r.Method,
+		s3Encode(r.URL.Path, false),
+		strings.Join(query, "&"),
+		headers.String(),
+		signedHeaders,
+		payloadHash,
+	}, "\n")
+
+	toSign := "AWS4-HMAC-SHA256\n" + r.Header.Get("X-Amz-Date") + "\n" + scope + "\n" + sha256Hex([]byte(canonical))
+	key := hmacSha256([]byte("AWS4"+s3.secretKey), scopeParts[0])
+	for _, p := range scopeParts[1:] {
+		key = hmacSha256(key, p)
+	}
+	return hmac.Equal([]byte(hex.EncodeToString(hmacSha256(key, toSign))), []byte(signature))
+}
+
+func (s3 *s3StandIn) ServeHTTP(w http.ResponseWriter, r *http.Request) {
+	body, err := io.ReadAll(r.Body)
+	if err != nil {
+		s3Error(w, http.StatusBadRequest, "BadRequest", err.Error())
+		return
+	}
+
+	s3.mu.Lock()
+	defer s3.mu.Unlock()
+
+	if !s3.checkSignature(r, body) {
+		s3.rejected++
+		s3Error(w, http.StatusForbidden, "SignatureDoesNotMatch", "The signature does not match")
+		return
+	}
+	bucket, key, _ := strings.Cut(strings.TrimPrefix(r.URL.Path, "/"), "/")
+	if bucket != s3.bucket {
+		s3Error(w, http.StatusNotFound, "NoSuchBucket", "The bucket does not exist")
+		return
+	}
+
+	q := r.URL.Query()
+	switch {
+	case r.Method == "GET" && key == "":
+		s3.list(w, q.Get("prefix"), q.Get("continuation-token"))
+	case r.Method == "POST" && q.Has("uploads"):
+		s3.uploadId++
+		id := fmt.Sprintf("upload-%d", s3.uploadId)
+		s3.uploads[id] = map[int][]byte{}
+		fmt.Fprintf(w, "<InitiateMultipartUploadResult><UploadId>%s</UploadId></InitiateMultipartUploadResult>", id)
+	case r.Method == "PUT" && q.Has("uploadId"):
+		parts, ok := s3.uploads[q.Get("uploadId")]
+		num, err := strconv.Atoi(q.Get("partNumber"))
+		if !ok || err != nil {
+			s3Error(w, http.StatusNotFound, "NoSuchUpload", "The upload does not exist")
+			return
+		}
+		parts[num] = body
+		s3.parts++
+		w.Header().Set("ETag", `"`+sha256Hex(body)[:32]+`"`)
+	case r.Method == "POST" && q.Has("uploadId"):
+		parts, ok := s3.uploads[q.Get("uploadId")]
+		if !ok {
+			s3Error(w, http.StatusNotFound, "NoSuchUpload", "The upload does not exist")
+			return
+		}
+		var data []byte
+		for i := 1; i <= len(parts); i++ {
+			part, ok := parts[i]
+			if !ok || !strings.Contains(string(body), `<ETag>"`+sha256Hex(part)[:32]+`"</ETag>`) {
+				s3Error(w, http.StatusBadRequest, "InvalidPart", "A part is missing or its ETag does not match")
+				return
+			}
+			data = append(data, part...)
+		}
+		delete(s3.uploads, q.Get("uploadId"))
+		s3.objects[key] = s3Object{data: data, lastModified: time.Now().UTC()}
+		fmt.Fprintf(w, "<CompleteMultipartUploadResult><Key>%s</Key></CompleteMultipartUploadResult>", key)
+	case r.Method == "DELETE" && q.Has("uploadId"):
+		delete(s3.uploads, q.Get("uploadId"))
+		w.WriteHeader(http.StatusNoContent)
+	case r.Method == "GET":
+		obj, ok := s3.objects[key]
+		if !ok {
+			s3Error(w, http.StatusNotFound, "NoSuchKey", "The key does not exist")
+			return
+		}
+		w.Write(obj.data)
+	case r.Method == "DELETE":
+		delete(s3.objects, key)
+		w.WriteHeader(http.StatusNoContent)
+	default:
+		s3Error(w, http.StatusMethodNotAllowed, "MethodNotAllowed", "Method not allowed")
+	}
+}
+
+// One object per page; the continuation token is the last key returned
+func (s3 *s3StandIn) list(w http.ResponseWriter, prefix, after string) {
+	var keys []string
+	for k := range s3.objects {
+		if strings.HasPrefix(k, prefix) && k > after {
+			keys = append(keys, k)
+		}
+	}
+	sort.Strings(keys)
+
+	var sb strings.Builder
+	sb.WriteString("<ListBucketResult>")
+	if len(keys) > 0 {
+		obj := s3.objects[keys[0]]
+		fmt.Fprintf(&sb, "<Contents><Key>%s</Key><Size>%d</Size><LastModified>%s</LastModified></Contents>",
+			keys[0], len(obj.data), obj.lastModified.Format("2006-01-02T15:04:05.000Z"))
+	}
+	if len(keys) > 1 {
+		fmt.Fprintf(&sb, "<IsTruncated>true</IsTruncated><NextContinuationToken>%s</NextContinuationToken>", keys[0])
+	} else {
+		sb.WriteString("<IsTruncated>false</IsTruncated>")
+	}
+	sb.WriteString("</ListBucketResult>")
+	w.Write([]byte(sb.String()))
+}
+
+func testBackupToS3(t *testing.T, endpoint, bucket string) {
+	cfg := db{
+		Macros: []macro{
+			{
+				Id: "M1",
+				Statements: []string{
+					"CREATE TABLE IF NOT EXISTS T1 (ID INT, VAL TEXT)",
+					"INSERT INTO T1 VALUES (1, 'ONE')",
+				},
+				Execution: execution{
+					OnCreate: &TRUE,
+				},
+			},
+		},
+		Backup: backup{
+			Target: &target{
+				Type:     "S3",
+				Endpoint: endpoint,
+				Bucket:   bucket,
+				Prefix:   fmt.Sprintf("sqliterg-test-%d/", time.Now().UnixNano()),
+			},
+			NumFiles: 2,
+			Execution: execution{
+				WebService: &webService{
+					AuthToken: &ciao,
+				},
+			},
+		},
+	}
+
+	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
+
+	for i := 0; i < 3; i++ {
+		code, _, _ := call(t, "http://localhost:12321/test/backup?token=ciao", request{})
+		require.Equal(t, http.StatusOK, code)
+	}
+
+	resp, err := http.Get("http://localhost:12321/test/backup/list?token=ciao")
+	require.NoError(t, err)
+	require.Equal(t, http.StatusOK, resp.StatusCode)
+	bs, err := io.ReadAll(resp.Body)
+	require.NoError(t, err)
+	var list backupListResponse
+	require.NoError(t, json.Unmarshal(bs, &list))
+	require.Equal(t, 2, len(list.Files))
+
+	req := request{
+		Transaction: []requestItem{
+			{
+				Statement: "DELETE FROM T1",
+			},
+		},
+	}
+	code, _, _ := call(t, "http://localhost:12321/test", req)
+	require.Equal(t, http.StatusOK, code)
+
+	code, _ = restoreCall(t, "http://localhost:12321/test/backup/restore?token=ciao&file="+list.Files[0].Name)
+	require.Equal(t, http.StatusOK, code)
+
+	req = request{
+		Transaction: []requestItem{
+			{
+				Query: "SELECT VAL FROM T1",
+			},
+		},
+	}
+	code, _, res := call(t, "http://localhost:12321/test", req)
+	require.Equal(t, http.StatusOK, code)
+	require.Equal(t, 1, len(res.Results[0].ResultSet))
+}
+
+func TestBackupToS3(t *testing.T) {
+	s3, srv := newS3StandIn()
+	defer srv.Close()
+	t.Setenv("AWS_ACCESS_KEY_ID", s3.accessKey)
+	t.Setenv("AWS_SECRET_ACCESS_KEY", s3.secretKey)
+
+	testBackupToS3(t, srv.URL, s3.bucket)
+
+	s3.mu.Lock()
+	defer s3.mu.Unlock()
+	require.Zero(t, s3.rejected)
+	// one part per backup and per write of the manifest (after each backup, and after the
+	// rotation that deleted one), no pending uploads, and only the last two backups kept
+	require.Equal(t, 7, s3.parts)
+	require.Empty(t, s3.uploads)
+	require.Len(t, s3.objects, 3)
+
+	// the manifest is next to the backups, and records only the retained ones
+	var m manifest
+	var manifestKey string
+	for key, obj := range s3.objects {
+		if strings.HasSuffix(key, "/test.manifest.json") {
+			manifestKey = key
+			require.NoError(t, json.Unmarshal(obj.data, &m))
+		}
+	}
+	require.NotEmpty(t, manifestKey)
+	require.Len(t, m.Backups, 2)
+	for _, e := range m.Backups {
+		obj, ok := s3.objects[strings.TrimSuffix(manifestKey, "test.manifest.json")+e.File]
+		require.True(t, ok)
+		sum := sha256.Sum256(obj.data)
+		require.Equal(t, hex.EncodeToString(sum[:]), e.Sha256)
+	}
+}
+
+func TestBackupToS3WrongSecret(t *testing.T) {
+	s3, srv := newS3StandIn()
+	defer srv.Close()
+	t.Setenv("AWS_ACCESS_KEY_ID", s3.accessKey)
+	t.Setenv("AWS_SECRET_ACCESS_KEY", "wrong")
+
+	cfg := db{
+		Backup: backup{
+			Target: &target{
+				Type:     "S3",
+				Endpoint: srv.URL,
+				Bucket:   s3.bucket,
+			},
+			NumFiles: 2,
+			Execution: execution{
+				WebService: &webService{
+					AuthToken: &ciao,
+				},
+			},
+		},
+	}
+
+	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
+
+	code, _, _ := call(t, "http://localhost:12321/test/backup?token=ciao", request{})
+	require.Equal(t, http.StatusInternalServerError, code)
+
+	s3.mu.Lock()
+	defer s3.mu.Unlock()
+	require.Positive(t, s3.rejected)
+	require.Empty(t, s3.objects)
+}
+
+// Against a real object storage, e.g. MinIO, if configured
+func TestBackupToS3External(t *testing.T) {
+	endpoint := os.Getenv("SQLITERG_TEST_S3_ENDPOINT")
+	bucket := os.Getenv("SQLITERG_TEST_S3_BUCKET")
+	if endpoint == "" || bucket == "" {
+		t.Skip("SQLITERG_TEST_S3_ENDPOINT and SQLITERG_TEST_S3_BUCKET not set")
+	}
+
+	testBackupToS3(t, endpoint, bucket)
+}
+
+func TestBackupWithoutDirOrTargetFails(t *testing.T) {
+	cfg := db{
+		Backup: backup{
+			NumFiles: 1,
+			Execution: execution{
+				OnCreate: &TRUE,
+			},
+		},
+	}
+
+	saveCfgToYaml(t, &cfg)
+	defer os.Remove("env/test.yaml")
+
+	cmd := exec.Command(COMMAND, "--db", "env/test.db")
+	err := cmd.Run()
+	require.Error(t, err)
+}
+
+func getWithBearer(t *testing.T, url string, bearer string) (int, string) {
+	req, err := http.NewRequest("GET", url, nil)
+	require.NoError(t, err)
+	if bearer != "" {
+		req.Header.Set("Authorization", "Bearer "+bearer)
+	}
+	resp, err := http.DefaultClient.Do(req)
+	require.NoError(t, err)
+	defer resp.Body.Close()
+	bs, err := io.ReadAll(resp.Body)
+	require.NoError(t, err)
+	return resp.StatusCode, string(bs)
+}
+
+func TestMetrics(t *testing.T) {
+	cfg := db{
+		Macros: []macro{
+			{
+				Id: "M1",
+				Statements: []string{
+					"CREATE TABLE IF NOT EXISTS T1 (ID INT)",
+				},
+				Execution: execution{
+					OnCreate: &TRUE,
+				},
+			},
+		},
+	}
+
+	defer setupTest(t, &cfg, false, "--db", "env/test.db", "--metrics", "--metrics-token", "ciao")(true)
+
+	req := request{
+		Transaction: []requestItem{
+			{
+				Statement: "INSERT INTO T1 VALUES (1)",
+			},
+		},
+	}
+	code, _, _ := call(t, "http://localhost:12321/test", req)
+	require.Equal(t, http.StatusOK, code)
+
+	code, _ = getWithBearer(t, "http://localhost:12321/metrics", "")
+	require.Equal(t, http.StatusUnauthorized, code)
+
+	code, body := getWithBearer(t, "http://localhost:12321/metrics", "ciao")
+	require.Equal(t, http.StatusOK, code)
+	require.Contains(t, body, `sqliterg_requests_total{db="test",status="200"} 1`)
+	require.Contains(t, body, `sqliterg_request_duration_seconds_count{db="test"} 1`)
+	require.Contains(t, body, `sqliterg_lock_wait_seconds_count{db="test"} 1`)
+	require.Contains(t, body, `sqliterg_job_runs_total{db="test",job="macro:M1",success="true"} 1`)
+	require.Contains(t, body, `sqliterg_db_file_size_bytes{db="test"}`)
+
+	code, _ = getWithBearer(t, "http://localhost:12321/metrics?token=ciao", "")
+	require.Equal(t, http.StatusOK, code)
+}
+
+func TestMetricsDisabled(t *testing.T) {
+	defer setupTest(t, nil, false, "--mem-db", "test")(true)
+
+	code, _ := getWithBearer(t, "http://localhost:12321/metrics", "")
+	require.Equal(t, http.StatusNotFound, code)
+}
+
+func TestAccessLogAndSlowQueries(t *testing.T) {
+	slowQueryMs := uint(0)
+	cfg := db{
+		Auth: &authr{
+			Mode: "INLINE",
+			ByCredentials: []credentialsCfg{
+				{
+					User:     "myUser",
+					Password: "ciao",
+				},
+			},
+		},
+		SlowQueryMs: &slowQueryMs,
+	}
+	saveCfgToYaml(t, &cfg)
+	defer os.Remove("env/test.yaml")
+	defer os.Remove("env/test.db")
+
+	cmd := exec.Command(COMMAND, "--db", "env/test.db", "--access-log", "json")
+	var outb bytes.Buffer
+	cmd.Stdout = &outb
+	require.NoError(t, cmd.Start())
+	time.Sleep(333 * time.Millisecond)
+
+	req := request{
+		Credentials: &credentials{
+			User:     "myUser",
+			Password: "ciao",
+		},
+		Transaction: []requestItem{
+			{
+				Query:  "SELECT :secret AS X",
+				Values: map[string]interface{}{"secret": "p4ssw0rd"},
+			},
+			{
+				Statement: "CREATE TABLE T (ID INT)",
+			},
+		},
+	}
+
+	code, _, _ := call(t, "http://localhost:12321/test", req)
+	require.Equal(t, http.StatusOK, code)
+
+	// a user that fails the authentication isn't logged, nor is a client address that the
+	// client claims, without --trust-proxy-headers
+	bs, err := json.Marshal(request{
+		Credentials: &credentials{
+			User:     "intruder",
+			Password: "wrong",
+		},
+		Transaction: []requestItem{{Query: "SELECT 1"}},
+	})
+	require.NoError(t, err)
+	httpReq, err := http.NewRequest("POST", "http://localhost:12321/test", bytes.NewReader(bs))
+	require.NoError(t, err)
+	httpReq.Header.Set("Content-Type", "application/json")
+	httpReq.Header.Set("X-Forwarded-For", "10.6.6.6")
+	resp, err := http.DefaultClient.Do(httpReq)
+	require.NoError(t, err)
+	resp.Body.Close()
+	require.Equal(t, http.StatusUnauthorized, resp.StatusCode)
+
+	cmd.Process.Kill()
+	cmd.Wait()
+
+	var access, slow []map[string]interface{}
+	for _, line := range strings.Split(outb.String(), "\n") {
+		var obj map[string]interface{}
+		if json.Unmarshal([]byte(line), &obj) != nil {
+			continue
+		}
+		if obj["slowQuery"] == true {
+			slow = append(slow, obj)
+		} else {
+			access = append(access, obj)
+		}
+	}
+
+	require.Len(t, access, 2)
+	require.Equal(t, "test", access[0]["db"])
+	require.Equal(t, "myUser", access[0]["user"])
+	require.Equal(t, float64(2), access[0]["items"])
+	require.Equal(t, float64(200), access[0]["status"])
+	require.Equal(t, "127.0.0.1", access[0]["clientIp"])
+	require.NotNil(t, access[0]["durationMs"])
+
+	require.Nil(t, access[1]["user"])
+	require.Equal(t, float64(1), access[1]["items"])
+	require.Equal(t, float64(401), access[1]["status"])
+	require.Equal(t, "127.0.0.1", access[1]["clientIp"])
+
+	require.Len(t, slow, 2)
+	require.Equal(t, "SELECT :secret AS X", slow[0]["sql"])
+	require.Equal(t, map[string]interface{}{"named": []interface{}{"secret"}}, slow[0]["params"])
+	require.NotContains(t, outb.String(), "p4ssw0rd")
+}
+
+func TestAccessLogRedactsSecrets(t *testing.T) {
+	cfg := db{
+		Auth: &authr{
+			Mode: "INLINE",
+			ByCredentials: []credentialsCfg{
+				{
+					User:     "myUser",
+					Password: "ciao",
+				},
+			},
+		},
+		ChangeFeed: &changeFeed{},
+	}
+	saveCfgToYaml(t, &cfg)
+	defer os.Remove("env/test.yaml")
+	defer os.Remove("env/test.db")
+
+	cmd := exec.Command(COMMAND, "--db", "env/test.db", "--access-log", "common")
+	var outb bytes.Buffer
+	cmd.Stdout = &outb
+	require.NoError(t, cmd.Start())
+	time.Sleep(333 * time.Millisecond)
+
+	resp, err := http.Get("http://localhost:12321/test/changes?user=myUser&password=s3cr3tpw")
+	require.NoError(t, err)
+	resp.Body.Close()
+	require.Equal(t, http.StatusUnauthorized, resp.StatusCode)
+
+	cmd.Process.Kill()
+	cmd.Wait()
+
+	require.Contains(t, outb.String(), "/test/changes?user=myUser&password=***")
+	require.NotContains(t, outb.String(), "s3cr3tpw")
+}
+
+func TestAccessLogInvalidFormat(t *testing.T) {
+	cmd := exec.Command(COMMAND, "--mem-db", "test", "--access-log", "foo")
+	require.Error(t, cmd.Run())
+}
+
+func readAuditLog(t *testing.T, path string) []map[string]interface{} {
+	bs, err := os.ReadFile(path)
+	require.NoError(t, err)
+	var ret []map[string]interface{}
+	for _, line := range strings.Split(strings.TrimSpace(string(bs)), "\n") {
+		var obj map[string]interface{}
+		require.NoError(t, json.Unmarshal([]byte(line), &obj))
+		ret = append(ret, obj)
+	}
+	return ret
+}
+
+func TestAuditLogToFile(t *testing.T) {
+	cfg := db{
+		Auth: &authr{
+			Mode: "HTTP_BASIC",
+			ByCredentials: []credentialsCfg{
+				{
+					User:     "myUser",
+					Password: "ciao",
+				},
+			},
+		},
+		Audit: &audit{
+			File:         "env/audit.jsonl",
+			RedactParams: []string{"pwd"},
+		},
+		Macros: []macro{
+			{
+				Id: "M1",
+				Statements: []string{
+					"CREATE TABLE T (ID INT, PWD TEXT)",
+				},
+				Execution: execution{
+					OnCreate: &TRUE,
+				},
+			},
+		},
+	}
+	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
+	defer os.Remove("env/audit.jsonl")
+
+	req := request{
+		Transaction: []requestItem{
+			{
+				Query: "SELECT * FROM T",
+			},
+			{
+				Statement: "INSERT INTO T VALUES (:id, :pwd)",
+				Values:    map[string]interface{}{"id": 1, "pwd": "p4ssw0rd"},
+			},
+		},
+	}
+	code, _, _ := callWithAuth(t, "http://localhost:12321/test", req, "myUser", "ciao")
+	require.Equal(t, http.StatusOK, code)
+
+	// failed transactions are not logged
+	req = request{
+		Transaction: []requestItem{
+			{
+				Statement: "INSERT INTO T VALUES (2, 'a')",
+			},
+			{
+				Statement: "INSERT INTO NOPE VALUES (2)",
+			},
+		},
+	}
+	code, _, _ = callWithAuth(t, "http://localhost:12321/test", req, "myUser", "ciao")
+	require.Equal(t, http.StatusInternalServerError, code)
+
+	entries := readAuditLog(t, "env/audit.jsonl")
+	require.Len(t, entries, 2)
+
+	require.Equal(t, "macro:M1", entries[0]["source"])
+	require.Nil(t, entries[0]["user"])
+
+	require.Equal(t, "request", entries[1]["source"])
+	require.Equal(t, "myUser", entries[1]["user"])
+	statements := entries[1]["statements"].([]interface{})
+	require.Len(t, statements, 1)
+	stmt := statements[0].(map[string]interface{})
+	require.Equal(t, float64(1), stmt["idx"])
+	require.Equal(t, "INSERT INTO T VALUES (:id, :pwd)", stmt["sql"])
+	require.Equal(t, map[string]interface{}{"id": float64(1), "pwd": "***"}, stmt["values"])
+	require.Equal(t, float64(1), stmt["rowsUpdated"])
+}
+
+func TestAuditLogToDb(t *testing.T) {
+	cfg := db{
+		Audit: &audit{
+			Db:        "env/audit.db",
+			RedactAll: true,
+		},
+	}
+	cleanup := setupTest(t, &cfg, false, "--db", "env/test.db")
+	defer os.Remove("env/audit.db")
+
+	req := request{
+		Transaction: []requestItem{
+			{
+				Statement: "CREATE TABLE T (ID INT)",
+			},
+			{
+				Statement: "INSERT INTO T VALUES (?)",
+				Values:    []interface{}{42},
+			},
+		},
+	}
+	code, _, _ := call(t, "http://localhost:12321/test", req)
+	require.Equal(t, http.StatusOK, code)
+
+	// reads the audit db with another instance
+	cleanup(false)
+	defer setupTest(t, nil, false, "--db", "env/audit.db")(true)
+	defer os.Remove("env/audit.db-shm")
+	defer os.Remove("env/audit.db-wal")
+
+	req = request{
+		Transaction: []requestItem{
+			{
+				Query: "SELECT SOURCE, STATEMENTS FROM AUDIT_LOG",
+			},
+		},
+	}
+	code, body, res := call(t, "http://localhost:12321/audit", req)
+	require.Equal(t, http.StatusOK, code, body)
+	require.Len(t, res.Results[0].ResultSet, 1)
+	require.Equal(t, "request", res.Results[0].ResultSet[0]["SOURCE"])
+	require.Contains(t, res.Results[0].ResultSet[0]["STATEMENTS"], `"values":["***"]`)
+	require.NotContains(t, res.Results[0].ResultSet[0]["STATEMENTS"], "42")
+}
+
+func TestAuditLogFileAndDbFails(t *testing.T) {
+	cfg := db{
+		Audit: &audit{
+			File: "env/audit.jsonl",
+			Db:   "env/audit.db",
+		},
+	}
+	saveCfgToYaml(t, &cfg)
+	defer os.Remove("env/test.yaml")
+	defer os.Remove("env/test.db")
+
+	cmd := exec.Command(COMMAND, "--db", "env/test.db")
+	require.Error(t, cmd.Run())
+}
+
+func TestHealthAndReady(t *testing.T) {
+	defer setupTest(t, nil, false, "--db", "env/test.db", "--mem-db", "test2")(true)
+
+	code, body := getWithBearer(t, "http://localhost:12321/healthz", "")
+	require.Equal(t, http.StatusOK, code)
+	require.Contains(t, body, `"ok"`)
+
+	code, body = getWithBearer(t, "http://localhost:12321/readyz", "")
+	require.Equal(t, http.StatusOK, code)
+	var res struct {
+		Ready     bool              `json:"ready"`
+		Databases map[string]string `json:"databases"`
+	}
+	require.NoError(t, json.Unmarshal([]byte(body), &res))
+	require.True(t, res.Ready)
+	require.Equal(t, map[string]string{"test": "ok", "test2": "ok"}, res.Databases)
+}
+
+func TestReservedDbNames(t *testing.T) {
+	for _, name := range []string{"healthz", "readyz", "metrics", "info", "openapi.json"} {
+		cmd := exec.Command(COMMAND, "--mem-db", name)
+		require.Error(t, cmd.Run(), name)
+	}
+}
+
+func TestInfo(t *testing.T) {
+	cfg := db{
+		Auth: &authr{
+			Mode: "INLINE",
+			ByCredentials: []credentialsCfg{
+				{
+					User:     "myUser",
+					Password: "ciao",
+				},
+			},
+		},
+		ReadOnly: true,
+	}
+	defer setupTest(t, &cfg, false, "--db", "env/test.db", "--info-token", "ciao")(true)
+
+	code, _ := getWithBearer(t, "http://localhost:12321/info", "")
+	require.Equal(t, http.StatusUnauthorized, code)
+
+	code, _ = getWithBearer(t, "http://localhost:12321/info", "wrong")
+	require.Equal(t, http.StatusUnauthorized, code)
+
+	code, body := getWithBearer(t, "http://localhost:12321/info?token=ciao", "")
+	require.Equal(t, http.StatusOK, code)
+
+	code, body = getWithBearer(t, "http://localhost:12321/info", "ciao")
+	require.Equal(t, http.StatusOK, code)
+	var res struct {
+		Version       string `json:"version"`
+		SqliteVersion string `json:"sqliteVersion"`
+		ProtoVersion  int    `json:"protoVersion"`
+		Databases     []struct {
+			Name     string   `json:"name"`
+			InMemory bool     `json:"inMemory"`
+			Features []string `json:"features"`
+		} `json:"databases"`
+	}
+	require.NoError(t, json.Unmarshal([]byte(body), &res))
+	require.NotEmpty(t, res.Version)
+	require.NotEmpty(t, res.SqliteVersion)
+	require.Equal(t, 1, res.ProtoVersion)
+	require.Len(t, res.Databases, 1)
+	require.Equal(t, "test", res.Databases[0].Name)
+	require.False(t, res.Databases[0].InMemory)
+	require.Equal(t, []string{"auth", "readOnly"}, res.Databases[0].Features)
+}
+
+func TestInfoDisabled(t *testing.T) {
+	defer setupTest(t, nil, false, "--mem-db", "test")(true)
+
+	code, _ := getWithBearer(t, "http://localhost:12321/info", "")
+	require.Equal(t, http.StatusNotFound, code)
+}
+
+type otlpSpan struct {
+	TraceId      string `json:"traceId"`
+	SpanId       string `json:"spanId"`
+	ParentSpanId string `json:"parentSpanId"`
+	Name         string `json:"name"`
+	Kind         int    `json:"kind"`
+	Status       *struct {
+		Code    int    `json:"code"`
+		Message string `json:"message"`
+	} `json:"status"`
+}
+
+type otlpRequest struct {
+	ResourceSpans []struct {
+		ScopeSpans []struct {
+			Spans []otlpSpan `json:"spans"`
+		} `json:"scopeSpans"`
+	} `json:"resourceSpans"`
+}
+
+// a stand-in for an OpenTelemetry collector, that accumulates the received spans
+func startCollector(t *testing.T) (*httptest.Server, func() []otlpSpan) {
+	var mutex sync.Mutex
+	var spans []otlpSpan
+	srv := httptest.NewServer(http.HandlerFunc(func(w http.ResponseWriter, r *http.Request) {
+		require.Equal(t, "/v1/traces", r.URL.Path)
+		var req otlpRequest
+		require.NoError(t, json.NewDecoder(r.Body).Decode(&req))
+		mutex.Lock()
+		defer mutex.Unlock()
+		for _, rs := range req.ResourceSpans {
+			for _, ss := range rs.ScopeSpans {
+				spans = append(spans, ss.Spans...)
+			}
+		}
+		w.Write([]byte("{}"))
+	}))
+	return srv, func() []otlpSpan {
+		mutex.Lock()
+		defer mutex.Unlock()
+		return append([]otlpSpan{}, spans...)
+	}
+}
+
+func TestTracing(t *testing.T) {
+	collector, received := startCollector(t)
+	defer collector.Close()
+
+	cfg := db{
+		Auth: &authr{
+			Mode: "INLINE",
+			ByCredentials: []credentialsCfg{
+				{
+					User:     "myUser",
+					Password: "ciao",
+				},
+			},
+		},
+	}
+	defer setupTest(t, &cfg, false, "--db", "env/test.db", "--otlp-endpoint", collector.URL)(true)
+
+	req := request{
+		Credentials: &credentials{
+			User:     "myUser",
+			Password: "ciao",
+		},
+		Transaction: []requestItem{
+			{
+				Statement: "CREATE TABLE T (ID INT)",
+			},
+			{
+				Statement: "INSERT INTO NOPE VALUES (1)",
+				NoFail:    true,
+			},
+		},
+	}
+	reqbytes, err := json.Marshal(req)
+	require.NoError(t, err)
+	post, err := http.NewRequest("POST", "http://localhost:12321/test", bytes.NewBuffer(reqbytes))
+	require.NoError(t, err)
+	post.Header.Add("Content-Type", "application/json")
+	post.Header.Add("traceparent", "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01")
+	resp, err := http.DefaultClient.Do(post)
+	require.NoError(t, err)
+	require.Equal(t, http.StatusOK, resp.StatusCode)
+
+	// spans are exported every 5 seconds
+	time.Sleep(6 * time.Second)
+
+	byName := map[string][]otlpSpan{}
+	for _, s := range received() {
+		require.Equal(t, "0af7651916cd43dd8448eb211c80319c", s.TraceId)
+		byName[s.Name] = append(byName[s.Name], s)
+	}
+
+	require.Len(t, byName["POST /test"], 1)
+	server := byName["POST /test"][0]
+	require.Equal(t, "b7ad6b7169203331", server.ParentSpanId)
+	require.Equal(t, 2, server.Kind)
+
+	require.Len(t, byName["auth"], 1)
+	require.Len(t, byName["lock wait"], 1)
+	require.Len(t, byName["statement"], 2)
+	for _, s := range append(byName["auth"], byName["statement"]...) {
+		require.Equal(t, server.SpanId, s.ParentSpanId)
+	}
+	require.Nil(t, byName["statement"][0].Status)
+	require.NotNil(t, byName["statement"][1].Status)
+	require.Equal(t, 2, byName["statement"][1].Status.Code)
+}
+
+// A minimal WebSocket client, enough for the tests: unfragmented frames only
+
+type wsConn struct {
+	conn   net.Conn
+	reader *bufio.Reader
+}
+
+func wsDial(t *testing.T, path string, headers map[string]string) (*wsConn, int) {
+	conn, err := net.Dial("tcp", "localhost:12321")
+	require.NoError(t, err)
+	key := make([]byte, 16)
+	rand.Read(key)
+	req := fmt.Sprintf("GET %s HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n"+
+		"Sec-WebSocket-Key: %s\r\nSec-WebSocket-Version: 13\r\n", path, base64.StdEncoding.EncodeToString(key))
+	for k, v := range headers {
+		req += fmt.Sprintf("%s: %s\r\n", k, v)
+	}
+	_, err = conn.Write([]byte(req + "\r\n"))
+	require.NoError(t, err)
+
+	reader := bufio.NewReader(conn)
+	resp, err := http.ReadResponse(reader, nil)
+	require.NoError(t, err)
+	return &wsConn{conn, reader}, resp.StatusCode
+}
+
+func (ws *wsConn) send(t *testing.T, opcode byte, payload []byte) {
+	frame := []byte{0x80 | opcode}
+	switch {
+	case len(payload) < 126:
+		frame = append(frame, 0x80|byte(len(payload)))
+	case len(payload) < 65536:
+		frame = append(frame, 0x80|126)
+		frame = binary.BigEndian.AppendUint16(frame, uint16(len(payload)))
+	default:
+		frame = append(frame, 0x80|127)
+		frame = binary.BigEndian.AppendUint64(frame, uint64(len(payload)))
+	}
+	mask := make([]byte, 4)
+	rand.Read(mask)
+	frame = append(frame, mask...)
+	for i, b := range payload {
+		frame = append(frame, b^mask[i%4])
+	}
+	_, err := ws.conn.Write(frame)
+	require.NoError(t, err)
+}
+
+func (ws *wsConn) sendJSON(t *testing.T, obj interface{}) {
+	bs, err := json.Marshal(obj)
+	require.NoError(t, err)
+	ws.send(t, 1, bs)
+}
+
+func (ws *wsConn) recv(t *testing.T) (byte, []byte) {
+	ws.conn.SetReadDeadline(time.Now().Add(5 * time.Second))
+	hdr := make([]byte, 2)
+	_, err := io.ReadFull(ws.reader, hdr)
+	require.NoError(t, err)
+	length := uint64(hdr[1] & 0x7f)
+	switch length {
+	case 126:
+		ext := make([]byte, 2)
+		_, err = io.ReadFull(ws.reader, ext)
+		require.NoError(t, err)
+		length = uint64(binary.BigEndian.Uint16(ext))
+	case 127:
+		ext := make([]byte, 8)
+		_, err = io.ReadFull(ws.reader, ext)
+		require.NoError(t, err)
+		length = binary.BigEndian.Uint64(ext)
+	}
+	payload := make([]byte, length)
+	_, err = io.ReadFull(ws.reader, payload)
+	require.NoError(t, err)
+	return hdr[0] & 0x0f, payload
+}
+
+func (ws *wsConn) recvJSON(t *testing.T) wsResponse {
+	opcode, payload := ws.recv(t)
+	require.Equal(t, byte(1), opcode)
+	var res wsResponse
+	require.NoError(t, json.Unmarshal(payload, &res))
+	return res
+}
+
+func TestWebSocket(t *testing.T) {
+	defer setupTest(t, nil, false, "--db", "env/test.db")(true)
+
+	ws, code := wsDial(t, "/test/ws", nil)
+	require.Equal(t, http.StatusSwitchingProtocols, code)
+	defer ws.conn.Close()
+
+	ws.sendJSON(t, wsRequest{
+		Id: 1,
+		Transaction: []requestItem{
+			{Statement: "CREATE TABLE T (ID INT)"},
+			{Statement: "INSERT INTO T VALUES (?)", Values: []interface{}{42}},
+		},
+	})
+	res := ws.recvJSON(t)
+	require.Equal(t, float64(1), res.Id)
+	require.Equal(t, http.StatusOK, res.StatusCode)
+	require.Equal(t, 1, *res.Results[1].RowsUpdated)
+
+	ws.sendJSON(t, wsRequest{
+		Id:          "two",
+		Transaction: []requestItem{{Query: "SELECT ID FROM T"}},
+	})
+	res = ws.recvJSON(t)
+	require.Equal(t, "two", res.Id)
+	require.Equal(t, float64(42), res.Results[0].ResultSet[0]["ID"])
+
+	ws.sendJSON(t, wsRequest{
+		Id:          3,
+		Transaction: []requestItem{{Query: "SELECT * FROM NOPE"}},
+	})
+	res = ws.recvJSON(t)
+	require.Equal(t, float64(3), res.Id)
+	require.Equal(t, http.StatusInternalServerError, res.StatusCode)
+	require.Equal(t, 0, res.ReqIdx)
+
+	ws.send(t, 1, []byte("not json"))
+	res = ws.recvJSON(t)
+	require.Nil(t, res.Id)
+	require.Equal(t, http.StatusBadRequest, res.StatusCode)
+}
+
+func TestWebSocketInlineAuth(t *testing.T) {
+	cfg := db{
+		Auth: &authr{
+			Mode: "INLINE",
+			ByCredentials: []credentialsCfg{
+				{
+					User:     "myUser",
+					Password: "ciao",
+				},
+			},
+		},
+	}
+	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
+
+	// authenticated by the first request, then no more credentials are needed
+	ws, code := wsDial(t, "/test/ws", nil)
+	require.Equal(t, http.StatusSwitchingProtocols, code)
+	ws.sendJSON(t, wsRequest{
+		Id:          1,
+		Credentials: &credentials{User: "myUser", Password: "ciao"},
+		Transaction: []requestItem{{Query: "SELECT 1"}},
+	})
+	require.Equal(t, http.StatusOK, ws.recvJSON(t).StatusCode)
+	ws.sendJSON(t, wsRequest{
+		Id:          2,
+		Transaction: []requestItem{{Query: "SELECT 1"}},
+	})
+	require.Equal(t, http.StatusOK, ws.recvJSON(t).StatusCode)
+	ws.conn.Close()
+
+	// wrong credentials close the connection
+	ws, _ = wsDial(t, "/test/ws", nil)
+	defer ws.conn.Close()
+	ws.sendJSON(t, wsRequest{
+		Id:          1,
+		Credentials: &credentials{User: "myUser", Password: "wrong"},
+		Transaction: []requestItem{{Query: "SELECT 1"}},
+	})
+	require.Equal(t, http.StatusUnauthorized, ws.recvJSON(t).StatusCode)
+	opcode, _ := ws.recv(t)
+	require.Equal(t, byte(8), opcode)
+}
+
+func TestWebSocketHttpBasicAuth(t *testing.T) {
+	cfg := db{
+		Auth: &authr{
+			Mode: "HTTP_BASIC",
+			ByCredentials: []credentialsCfg{
+				{
+					User:     "myUser",
+					Password: "ciao",
+				},
+			},
+		},
+	}
+	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
+
+	_, code := wsDial(t, "/test/ws", nil)
+	require.Equal(t, http.StatusUnauthorized, code)
+
+	auth := "Basic " + base64.StdEncoding.EncodeToString([]byte("myUser:ciao"))
+	ws, code := wsDial(t, "/test/ws", map[string]string{"Authorization": auth})
+	require.Equal(t, http.StatusSwitchingProtocols, code)
+	defer ws.conn.Close()
+
+	ws.sendJSON(t, wsRequest{Transaction: []requestItem{{Query: "SELECT 1"}}})
+	require.Equal(t, http.StatusOK, ws.recvJSON(t).StatusCode)
+}
+
+// Opens the change feed; the events are read with nextChange
+func openChanges(t *testing.T, url string) (*http.Response, *bufio.Reader) {
+	resp, err := http.Get(url)
+	require.NoError(t, err)
+	require.Equal(t, http.StatusOK, resp.StatusCode)
+	require.Equal(t, "text/event-stream", resp.Header.Get("Content-Type"))
+	return resp, bufio.NewReader(resp.Body)
+}
+
+func nextChange(t *testing.T, reader *bufio.Reader) changeEvent {
+	lines := make(chan string)
+	go func() {
+		for {
+			line, err := reader.ReadString('\n')
+			if err != nil {
+				close(lines)
+				return
+			}
+			if strings.HasPrefix(line, "data: ") {
+				lines <- strings.TrimPrefix(line, "data: ")
+				return
+			}
+		}
+	}()
+	select {
+	case data, ok := <-lines:
+		require.True(t, ok)
+		var ev changeEvent
+		require.NoError(t, json.Unmarshal([]byte(data), &ev))
+		return ev
+	case <-time.After(5 * time.Second):
+		require.Fail(t, "no change event")
+		return changeEvent{}
+	}
+}
+
+func TestChangeFeed(t *testing.T) {
+	cfg := db{
+		ChangeFeed: &changeFeed{
+			Tables:      []string{"T", "U"},
+			IncludeRows: true,
+		},
+	}
+	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
+
+	call(t, "http://localhost:12321/test", request{
+		Transaction: []requestItem{
+			{Statement: "CREATE TABLE T (ID INT, VAL TEXT)"},
+			{Statement: "CREATE TABLE U (ID INT)"},
+			{Statement: "CREATE TABLE X (ID INT)"},
+		},
+	})
+
+	all, allReader := openChanges(t, "http://localhost:12321/test/changes?rows=true")
+	defer all.Body.Close()
+	onlyU, onlyUReader := openChanges(t, "http://localhost:12321/test/changes?tables=u")
+	defer onlyU.Body.Close()
+
+	code, _, _ := call(t, "http://localhost:12321/test", request{
+		Transaction: []requestItem{
+			{Statement: "INSERT INTO T VALUES (1, 'a')"},
+			{Statement: "INSERT INTO X VALUES (1)"},
+			{Statement: "UPDATE T SET VAL = 'b' WHERE ID = 1"},
+		},
+	})
+	require.Equal(t, http.StatusOK, code)
+
+	// rolled back, so not published
+	code, _, _ = call(t, "http://localhost:12321/test", request{
+		Transaction: []requestItem{
+			{Statement: "INSERT INTO U VALUES (1)"},
+			{Statement: "INSERT INTO NOPE VALUES (1)"},
+		},
+	})
+	require.Equal(t, http.StatusInternalServerError, code)
+
+	code, _, _ = call(t, "http://localhost:12321/test", request{
+		Transaction: []requestItem{
+			{Statement: "INSERT INTO U VALUES (2)"},
+			{Statement: "DELETE FROM U"},
+		},
+	})
+	require.Equal(t, http.StatusOK, code)
+
+	ev := nextChange(t, allReader)
+	require.Equal(t, "T", ev.Table)
+	require.Equal(t, "insert", ev.Op)
+	require.Equal(t, int64(1), ev.Rowid)
+	// the row as it is after the commit
+	require.Equal(t, "b", ev.Row["VAL"])
+	ev = nextChange(t, allReader)
+	require.Equal(t, "update", ev.Op)
+	ev = nextChange(t, allReader)
+	require.Equal(t, "U", ev.Table)
+	require.Equal(t, "insert", ev.Op)
+	ev = nextChange(t, allReader)
+	require.Equal(t, "U", ev.Table)
+	require.Equal(t, "delete", ev.Op)
+	require.Nil(t, ev.Row)
+
+	ev = nextChange(t, onlyUReader)
+	require.Equal(t, "insert", ev.Op)
+	require.Nil(t, ev.Row)
+	ev = nextChange(t, onlyUReader)
+	require.Equal(t, "delete", ev.Op)
+
+	// a table that is not in the feed
+	resp, err := http.Get("http://localhost:12321/test/changes?tables=X")
+	require.NoError(t, err)
+	require.Equal(t, http.StatusBadRequest, resp.StatusCode)
+	resp.Body.Close()
+}
+
+func TestChangeFeedAuth(t *testing.T) {
+	cfg := db{
+		Auth: &authr{
+			Mode: "INLINE",
+			ByCredentials: []credentialsCfg{
+				{
+					User:     "myUser",
+					Password: "ciao",
+				},
+			},
+		},
+		ChangeFeed: &changeFeed{},
+	}
+	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
+
+	resp, err := http.Get("http://localhost:12321/test/changes?user=myUser&password=wrong")
+	require.NoError(t, err)
+	require.Equal(t, http.StatusUnauthorized, resp.StatusCode)
+	resp.Body.Close()
+
+	// rows are not allowed by the configuration
+	resp, err = http.Get("http://localhost:12321/test/changes?user=myUser&password=ciao&rows=true")
+	require.NoError(t, err)
+	require.Equal(t, http.StatusBadRequest, resp.StatusCode)
+	resp.Body.Close()
+
+	resp, _ = openChanges(t, "http://localhost:12321/test/changes?user=myUser&password=ciao")
+	resp.Body.Close()
+}
+
+func TestChangeFeedDisabled(t *testing.T) {
+	defer setupTest(t, nil, false, "--db", "env/test.db")(true)
+
+	resp, err := http.Get("http://localhost:12321/test/changes")
+	require.NoError(t, err)
+	require.Equal(t, http.StatusNotFound, resp.StatusCode)
+	resp.Body.Close()
+}
+
+
+// A stand-in for the receiver of the webhooks; it answers 503 while down, and checks the
+// signature of the body if a secret is given
+type webhookReceiver struct {
+	srv    *httptest.Server
+	mutex  sync.Mutex
+	down   bool
+	posts  int
+	events []webhookEvent
+}
+
+func startWebhookReceiver(t *testing.T, secret string) *webhookReceiver {
+	wr := &webhookReceiver{}
+	wr.srv = httptest.NewServer(http.HandlerFunc(func(w http.ResponseWriter, r *http.Request) {
+		body, err := io.ReadAll(r.Body)
+		require.NoError(t, err)
+		wr.mutex.Lock()
+		defer wr.mutex.Unlock()
+		wr.posts++
+		if wr.down {
+			w.WriteHeader(http.StatusServiceUnavailable)
+			return
+		}
+		if secret != "" {
+			mac := hmac.New(sha256.New, []byte(secret))
+			mac.Write(body)
+			require.Equal(t, "sha256="+hex.EncodeToString(mac.Sum(nil)), r.Header.Get("X-Sqliterg-Signature"))
+		}
+		var req struct {
+			Events []webhookEvent `json:"events"`
+		}
+		require.NoError(t, json.Unmarshal(body, &req))
+		wr.events = append(wr.events, req.Events...)
+	}))
+	return wr
+}
+
+func (wr *webhookReceiver) setDown(down bool) {
+	wr.mutex.Lock()
+	defer wr.mutex.Unlock()
+	wr.down = down
+}
+
+// Waits for at least n events
+func (wr *webhookReceiver) waitFor(t *testing.T, n int) []webhookEvent {
+	for i := 0; i < 100; i++ {
+		wr.mutex.Lock()
+		if len(wr.events) >= n {
+			ret := append([]webhookEvent{}, wr.events...)
+			wr.mutex.Unlock()
+			return ret
+		}
+		wr.mutex.Unlock()
+		time.Sleep(100 * time.Millisecond)
+	}
+	require.Fail(t, "webhook events not received")
+	return nil
+}
+
+func TestWebhooks(t *testing.T) {
+	receiver := startWebhookReceiver(t, "s3cr3t")
+	defer receiver.srv.Close()
+	// the first delivery fails, and is retried
+	receiver.setDown(true)
+
+	cfg := db{
+		Webhooks: []webhook{
+			{
+				URL:         receiver.srv.URL + "/hook",
+				Tables:      []string{"T"},
+				Operations:  []string{"insert", "delete"},
+				Secret:      "s3cr3t",
+				IncludeRows: true,
+				Retry: &webhookRetry{
+					InitialDelay: 1,
+				},
+			},
+		},
+	}
+	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
+
+	code, _, _ := call(t, "http://localhost:12321/test", request{
+		Transaction: []requestItem{
+			{Statement: "CREATE TABLE T (ID INT, VAL TEXT)"},
+			{Statement: "CREATE TABLE U (ID INT)"},
+			{Statement: "INSERT INTO T VALUES (1, 'a')"},
+			{Statement: "INSERT INTO U VALUES (1)"},
+			{Statement: "UPDATE T SET VAL = 'b'"},
+			{Statement: "INSERT INTO T VALUES (2, 'c')"},
+			{Statement: "DELETE FROM T WHERE ID = 1"},
+		},
+	})
+	require.Equal(t, http.StatusOK, code)
+
+	time.Sleep(500 * time.Millisecond)
+	receiver.setDown(false)
+
+	events := receiver.waitFor(t, 3)
+	require.Len(t, events, 3)
+	require.Equal(t, "insert", events[0].Op)
+	require.Equal(t, "T", events[0].Table)
+	require.Equal(t, "insert", events[1].Op)
+	require.Equal(t, "c", events[1].Row["VAL"])
+	require.Equal(t, "delete", events[2].Op)
+	require.Nil(t, events[2].Row)
+	require.Less(t, events[0].Id, events[1].Id)
+	require.Less(t, events[1].Id, events[2].Id)
+	receiver.mutex.Lock()
+	require.Greater(t, receiver.posts, 1)
+	receiver.mutex.Unlock()
+}
+
+func TestWebhooksSurviveRestart(t *testing.T) {
+	receiver := startWebhookReceiver(t, "")
+	defer receiver.srv.Close()
+	receiver.setDown(true)
+
+	cfg := db{
+		Webhooks: []webhook{
+			{
+				URL: receiver.srv.URL,
+			},
+		},
+	}
+	cleanup := setupTest(t, &cfg, false, "--db", "env/test.db")
+
+	code, _, _ := call(t, "http://localhost:12321/test", request{
+		Transaction: []requestItem{
+			{Statement: "CREATE TABLE T (ID INT)"},
+			{Statement: "INSERT INTO T VALUES (1)"},
+		},
+	})
+	require.Equal(t, http.StatusOK, code)
+	time.Sleep(500 * time.Millisecond)
+
+	// the event is in the database, committed with the insert
+	code, _, obj := call(t, "http://localhost:12321/test", request{
+		Transaction: []requestItem{
+			{Query: "SELECT COUNT(1) AS N FROM SQLITERG_OUTBOX"},
+		},
+	})
+	require.Equal(t, http.StatusOK, code)
+	require.Equal(t, 1, int(obj.Results[0].ResultSet[0]["N"].(float64)))
+	cleanup(false)
+
+	receiver.setDown(false)
+	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
+
+	events := receiver.waitFor(t, 1)
+	require.Len(t, events, 1)
+	require.Equal(t, "insert", events[0].Op)
+	require.Equal(t, int64(1), events[0].Rowid)
+}
+
+func TestWebhooksInvalidOperation(t *testing.T) {
+	cfg := db{
+		Webhooks: []webhook{
+			{
+				URL:        "http://localhost:1/hook",
+				Operations: []string{"truncate"},
+			},
+		},
+	}
+	saveCfgToYaml(t, &cfg)
+	defer os.Remove("env/test.yaml")
+	defer os.Remove("env/test.db")
+
+	cmd := exec.Command(COMMAND, "--db", "env/test.db")
+	require.Error(t, cmd.Run())
+}
+
+// A minimal PostgreSQL client, enough for the tests: protocol 3.0, cleartext password
+
+type pgConn struct {
+	conn   net.Conn
+	reader *bufio.Reader
+}
+
+type pgResult struct {
+	Columns []string
+	Types   []uint32
+	Rows    [][]*string
+	Tag     string
+	Code    string // SQLSTATE of the error, if any
+	Status  byte   // of ReadyForQuery
+}
+
+func pgMessage(tag byte, body []byte) []byte {
+	msg := []byte{tag}
+	msg = binary.BigEndian.AppendUint32(msg, uint32(len(body)+4))
+	return append(msg, body...)
+}
+
+func pgCstr(s string) []byte {
+	return append([]byte(s), 0)
+}
+
+func (pg *pgConn) read(t *testing.T) (byte, []byte) {
+	pg.conn.SetReadDeadline(time.Now().Add(5 * time.Second))
+	hdr := make([]byte, 5)
+	_, err := io.ReadFull(pg.reader, hdr)
+	require.NoError(t, err)
+	body := make([]byte, binary.BigEndian.Uint32(hdr[1:])-4)
+	_, err = io.ReadFull(pg.reader, body)
+	require.NoError(t, err)
+	return hdr[0], body
+}
+
+func pgErrorCode(body []byte) string {
+	for _, field := range bytes.Split(body, []byte{0}) {
+		if len(field) > 0 && field[0] == 'C' {
+			return string(field[1:])
+		}
+	}
+	return ""
+}
+
+// Connects and authenticates; returns the SQLSTATE if the server refused the connection
+func pgDial(t *testing.T, database, user, password string) (*pgConn, string) {
+	conn, err := net.Dial("tcp", "localhost:15432")
+	require.NoError(t, err)
+	pg := &pgConn{conn, bufio.NewReader(conn)}
+
+	body := binary.BigEndian.AppendUint32(nil, 196608)
+	body = append(body, pgCstr("user")...)
+	body = append(body, pgCstr(user)...)
+	body = append(body, pgCstr("database")...)
+	body = append(body, pgCstr(database)...)
+	body = append(body, 0)
+	_, err = conn.Write(append(binary.BigEndian.AppendUint32(nil, uint32(len(body)+4)), body...))
+	require.NoError(t, err)
+
+	for {
+		tag, body := pg.read(t)
+		switch tag {
+		case 'R':
+			if binary.BigEndian.Uint32(body) == 3 {
+				_, err = conn.Write(pgMessage('p', pgCstr(password)))
+				require.NoError(t, err)
+			}
+		case 'E':
+			conn.Close()
+			return nil, pgErrorCode(body)
+		case 'Z':
+			return pg, ""
+		}
+	}
+}
+
+// Reads the results until ReadyForQuery; the values are in text format
+func (pg *pgConn) results(t *testing.T) []pgResult {
+	var ret []pgResult
+	cur := pgResult{}
+	for {
+		tag, body := pg.read(t)
+		switch tag {
+		case 'T':
+			n := int(binary.BigEndian.Uint16(body))
+			pos := 2
+			for i := 0; i < n; i++ {
+				end := pos + bytes.IndexByte(body[pos:], 0)
+				cur.Columns = append(cur.Columns, string(body[pos:end]))
+				cur.Types = append(cur.Types, binary.BigEndian.Uint32(body[end+7:]))
+				pos = end + 19
+			}
+		case 'D':
+			n := int(binary.BigEndian.Uint16(body))
+			pos := 2
+			row := make([]*string, n)
+			for i := 0; i < n; i++ {
+				l := int32(binary.BigEndian.Uint32(body[pos:]))
+				pos += 4
+				if l >= 0 {
+					s := string(body[pos : pos+int(l)])
+					row[i] = &s
+					pos += int(l)
+				}
+			}
+			cur.Rows = append(cur.Rows, row)
+		case 'C':
+			cur.Tag = string(body[:len(body)-1])
+			ret = append(ret, cur)
+			cur = pgResult{}
+		case 'E':
+			cur.Code = pgErrorCode(body)
+			ret = append(ret, cur)
+			cur = pgResult{}
+		case 'Z':
+			for i := range ret {
+				ret[i].Status = body[0]
+			}
+			if len(ret) == 0 {
+				ret = append(ret, pgResult{Status: body[0]})
+			}
+			return ret
+		}
+	}
+}
+
+// Simple query protocol; returns the result of the last statement
+func (pg *pgConn) query(t *testing.T, sql string) pgResult {
+	_, err := pg.conn.Write(pgMessage('Q', pgCstr(sql)))
+	require.NoError(t, err)
+	res := pg.results(t)
+	return res[len(res)-1]
+}
+
+// Extended query protocol, with the parameters in text format
+func (pg *pgConn) queryParams(t *testing.T, sql string, params ...*string) pgResult {
+	parse := append(pgCstr(""), pgCstr(sql)...)
+	parse = append(parse, 0, 0)
+	bind := append(pgCstr(""), pgCstr("")...)
+	bind = append(bind, 0, 0)
+	bind = binary.BigEndian.AppendUint16(bind, uint16(len(params)))
+	for _, p := range params {
+		if p == nil {
+			bind = binary.BigEndian.AppendUint32(bind, 0xffffffff)
+			continue
+		}
+		bind = binary.BigEndian.AppendUint32(bind, uint32(len(*p)))
+		bind = append(bind, *p...)
+	}
+	bind = append(bind, 0, 0)
+	var msgs []byte
+	msgs = append(msgs, pgMessage('P', parse)...)
+	msgs = append(msgs, pgMessage('B', bind)...)
+	msgs = append(msgs, pgMessage('D', append([]byte{'P'}, pgCstr("")...))...)
+	msgs = append(msgs, pgMessage('E', append(pgCstr(""), 0, 0, 0, 0))...)
+	msgs = append(msgs, pgMessage('S', nil)...)
+	_, err := pg.conn.Write(msgs)
+	require.NoError(t, err)
+	res := pg.results(t)
+	return res[len(res)-1]
+}
+
+func strPtr(s string) *string {
+	return &s
+}
+
+func TestPgWire(t *testing.T) {
+	defer setupTest(t, nil, false, "--db", "env/test.db", "--pg-port", "15432")(true)
+
+	pg, code := pgDial(t, "test", "anybody", "")
+	require.Empty(t, code)
+	defer pg.conn.Close()
+
+	res := pg.query(t, "CREATE TABLE T (ID INTEGER PRIMARY KEY, NAME TEXT, SCORE REAL, OK BOOLEAN, DATA BLOB)")
+	require.Equal(t, "CREATE TABLE", res.Tag)
+	res = pg.query(t, "INSERT INTO T VALUES (1, 'a', 1.5, 1, x'0102'), (2, 'b', NULL, 0, NULL)")
+	require.Equal(t, "INSERT 0 2", res.Tag)
+
+	res = pg.query(t, "SELECT *, ID * 2 AS DOUBLED FROM T ORDER BY ID")
+	require.Equal(t, "SELECT 2", res.Tag)
+	require.Equal(t, []string{"ID", "NAME", "SCORE", "OK", "DATA", "DOUBLED"}, res.Columns)
+	// int8, text, float8, bool, bytea and, inferred from the values, int8
+	require.Equal(t, []uint32{20, 25, 701, 16, 17, 20}, res.Types)
+	require.Equal(t, "1", *res.Rows[0][0])
+	require.Equal(t, "1.5", *res.Rows[0][2])
+	require.Equal(t, "t", *res.Rows[0][3])
+	require.Equal(t, "\\x0102", *res.Rows[0][4])
+	require.Equal(t, "f", *res.Rows[1][3])
+	require.Nil(t, res.Rows[1][2])
+	require.Equal(t, "4", *res.Rows[1][5])
+
+	// several statements in a query, with a semicolon in a literal
+	_, err := pg.conn.Write(pgMessage('Q', pgCstr("UPDATE T SET NAME = 'c;d' WHERE ID = 2; DELETE FROM T WHERE ID = 1")))
+	require.NoError(t, err)
+	results := pg.results(t)
+	require.Len(t, results, 2)
+	require.Equal(t, "UPDATE 1", results[0].Tag)
+	require.Equal(t, "DELETE 1", results[1].Tag)
+
+	res = pg.query(t, "SELECT * FROM NOPE")
+	require.Equal(t, "42P01", res.Code)
+	require.Equal(t, byte('I'), res.Status)
+
+	// the changes are visible to the web service
+	code2, _, resp := call(t, "http://localhost:12321/test", request{
+		Transaction: []requestItem{{Query: "SELECT NAME FROM T"}},
+	})
+	require.Equal(t, http.StatusOK, code2)
+	require.Len(t, resp.Results[0].ResultSet, 1)
+	require.Equal(t, "c;d", resp.Results[0].ResultSet[0]["NAME"])
+}
+
+func TestPgWireTransaction(t *testing.T) {
+	defer setupTest(t, nil, false, "--db", "env/test.db", "--pg-port", "15432")(true)
+
+	pg, _ := pgDial(t, "test", "anybody", "")
+	defer pg.conn.Close()
+	pg.query(t, "CREATE TABLE T (ID INTEGER PRIMARY KEY)")
+
+	res := pg.query(t, "BEGIN")
+	require.Equal(t, byte('T'), res.Status)
+	pg.query(t, "INSERT INTO T VALUES (1)")
+	res = pg.query(t, "SELECT NOPE")
+	require.Equal(t, byte('E'), res.Status)
+	// after an error, the transaction only accepts its end...
+	res = pg.query(t, "SELECT 1")
+	require.Equal(t, "25P02", res.Code)
+	// ...and committing rolls it back
+	res = pg.query(t, "COMMIT")
+	require.Equal(t, "ROLLBACK", res.Tag)
+	require.Equal(t, byte('I'), res.Status)
+	require.Equal(t, "0", *pg.query(t, "SELECT COUNT(1) FROM T").Rows[0][0])
+
+	pg.query(t, "BEGIN")
+	pg.query(t, "INSERT INTO T VALUES (2)")
+	require.Equal(t, "COMMIT", pg.query(t, "COMMIT").Tag)
+	require.Equal(t, "1", *pg.query(t, "SELECT COUNT(1) FROM T").Rows[0][0])
+
+	// a transaction left open is rolled back when the client goes away
+	pg2, _ := pgDial(t, "test", "anybody", "")
+	pg2.query(t, "BEGIN")
+	pg2.query(t, "INSERT INTO T VALUES (3)")
+	pg2.conn.Close()
+	time.Sleep(100 * time.Millisecond)
+	require.Equal(t, "1", *pg.query(t, "SELECT COUNT(1) FROM T").Rows[0][0])
+}
+
+func TestPgWireExtended(t *testing.T) {
+	defer setupTest(t, nil, false, "--db", "env/test.db", "--pg-port", "15432")(true)
+
+	pg, _ := pgDial(t, "test", "anybody", "")
+	defer pg.conn.Close()
+	pg.query(t, "CREATE TABLE T (ID INTEGER PRIMARY KEY, NAME TEXT)")
+
+	res := pg.queryParams(t, "INSERT INTO T VALUES ($1, $2)", strPtr("1"), strPtr("one"))
+	require.Equal(t, "INSERT 0 1", res.Tag)
+	res = pg.queryParams(t, "INSERT INTO T VALUES ($1, $2)", strPtr("2"), nil)
+	require.Equal(t, "INSERT 0 1", res.Tag)
+
+	// the placeholders are positional, whatever their order in the statement
+	res = pg.queryParams(t, "SELECT NAME, ID FROM T WHERE NAME = $2 OR ID = $1 ORDER BY ID", strPtr("2"), strPtr("one"))
+	require.Equal(t, "SELECT 2", res.Tag)
+	require.Equal(t, []uint32{25, 20}, res.Types)
+	require.Equal(t, "one", *res.Rows[0][0])
+	require.Nil(t, res.Rows[1][0])
+
+	// "$1" in a literal is not a placeholder
+	res = pg.queryParams(t, "SELECT '$1' AS S")
+	require.Equal(t, "$1", *res.Rows[0][0])
+
+	res = pg.queryParams(t, "SELECT * FROM T WHERE ID = $1")
+	require.Equal(t, "08P01", res.Code)
+	require.Equal(t, byte('I'), res.Status)
+}
+
+func TestPgWireAuth(t *testing.T) {
+	cfg := db{
+		Auth: &authr{
+			Mode: "HTTP_BASIC",
+			ByCredentials: []credentialsCfg{
+				{
+					User:     "myUser",
+					Password: "ciao",
+				},
+			},
+		},
+	}
+	defer setupTest(t, &cfg, false, "--db", "env/test.db", "--pg-port", "15432")(true)
+
+	pg, code := pgDial(t, "test", "myUser", "ciao")
+	require.Empty(t, code)
+	require.Equal(t, "1", *pg.query(t, "SELECT 1").Rows[0][0])
+	pg.conn.Close()
+
+	_, code = pgDial(t, "test", "myUser", "wrong")
+	require.Equal(t, "28P01", code)
+
+	_, code = pgDial(t, "nope", "myUser", "ciao")
+	require.Equal(t, "3D000", code)
+}
+
+func TestPgWireReadOnly(t *testing.T) {
+	cfg := db{
+		ReadOnly: true,
+	}
+	defer setupTest(t, &cfg, false, "--db", "env/test.db", "--pg-port", "15432")(true)
+
+	pg, _ := pgDial(t, "test", "anybody", "")
+	defer pg.conn.Close()
+
+	require.Equal(t, "25006", pg.query(t, "CREATE TABLE T (ID INT)").Code)
+	require.Equal(t, "SELECT 1", pg.query(t, "SELECT 1").Tag)
+}
+
+func TestPgWireOnlyStoredStatements(t *testing.T) {
+	cfg := db{
+		UseOnlyStoredStatements: true,
+		StoredStatement: []storedStatement{
+			{Id: "Q", Sql: "SELECT $1 * 2 AS DOUBLED"},
+		},
+	}
+	defer setupTest(t, &cfg, false, "--db", "env/test.db", "--pg-port", "15432")(true)
+
+	pg, _ := pgDial(t, "test", "anybody", "")
+	defer pg.conn.Close()
+
+	require.Equal(t, "42501", pg.query(t, "SELECT 1").Code)
+	res := pg.queryParams(t, "^Q", strPtr("21"))
+	require.Equal(t, "42", *res.Rows[0][0])
+}
+
+// With a real driver: lib/pq, on database/sql
+func pqOpen(t *testing.T) *sql.DB {
+	pq, err := sql.Open("postgres", "host=localhost port=15432 user=anybody dbname=test sslmode=disable")
+	require.NoError(t, err)
+	return pq
+}
+
+func TestPgWireLibPq(t *testing.T) {
+	defer setupTest(t, nil, false, "--db", "env/test.db", "--pg-port", "15432")(true)
+
+	pq := pqOpen(t)
+	defer pq.Close()
+
+	_, err := pq.Exec("CREATE TABLE T (ID INTEGER PRIMARY KEY, NAME TEXT, VAL REAL)")
+	require.NoError(t, err)
+	res, err := pq.Exec("INSERT INTO T VALUES ($1, $2, $3)", 1, "one", 1.5)
+	require.NoError(t, err)
+	n, err := res.RowsAffected()
+	require.NoError(t, err)
+	require.Equal(t, int64(1), n)
+
+	tx, err := pq.Begin()
+	require.NoError(t, err)
+	_, err = tx.Exec("INSERT INTO T VALUES ($1, $2, $3)", 2, nil, 2.5)
+	require.NoError(t, err)
+	require.NoError(t, tx.Commit())
+
+	tx, err = pq.Begin()
+	require.NoError(t, err)
+	_, err = tx.Exec("INSERT INTO T VALUES ($1, $2, $3)", 3, "three", 3.5)
+	require.NoError(t, err)
+	require.NoError(t, tx.Rollback())
+
+	rows, err := pq.Query("SELECT ID, NAME, VAL FROM T WHERE ID >= $1 ORDER BY ID", 1)
+	require.NoError(t, err)
+	var ids []int64
+	var names []sql.NullString
+	var vals []float64
+	for rows.Next() {
+		var id int64
+		var name sql.NullString
+		var val float64
+		require.NoError(t, rows.Scan(&id, &name, &val))
+		ids = append(ids, id)
+		names = append(names, name)
+		vals = append(vals, val)
+	}
+	require.NoError(t, rows.Err())
+	require.Equal(t, []int64{1, 2}, ids)
+	require.Equal(t, []sql.NullString{{String: "one", Valid: true}, {}}, names)
+	require.Equal(t, []float64{1.5, 2.5}, vals)
+
+	var count int
+	require.NoError(t, pq.QueryRow("SELECT COUNT(1) FROM T").Scan(&count))
+	require.Equal(t, 2, count)
+
+	_, err = pq.Exec("SELECT NOPE")
+	require.Error(t, err)
+}
+
+func TestPgWireTransactionHttpFailsFast(t *testing.T) {
+	defer setupTest(t, nil, false, "--db", "env/test.db", "--pg-port", "15432")(true)
+
+	pq := pqOpen(t)
+	defer pq.Close()
+
+	tx, err := pq.Begin()
+	require.NoError(t, err)
+	_, err = tx.Exec("CREATE TABLE T (ID INT)")
+	require.NoError(t, err)
+
+	// the database is locked by the transaction: the HTTP requests don't wait for it
+	start := time.Now()
+	code, _, _ := call(t, "http://localhost:12321/test", request{Transaction: []requestItem{{Query: "SELECT 1"}}})
+	require.Equal(t, http.StatusServiceUnavailable, code)
+	require.Less(t, time.Since(start), time.Second)
+
+	require.NoError(t, tx.Commit())
+	code, _, _ = call(t, "http://localhost:12321/test", request{Transaction: []requestItem{{Query: "SELECT COUNT(1) FROM T"}}})
+	require.Equal(t, http.StatusOK, code)
+}
+
+// The gRPC tests use grpc-go, with the messages built at runtime (dynamicpb) from
+// proto/sqliterg.proto: the requests are written in the JSON mapping of protobuf.
+
+var grpcProto = sync.OnceValues(func() (protoreflect.FileDescriptor, error) {
+	compiler := protocompile.Compiler{
+		Resolver: &protocompile.SourceResolver{ImportPaths: []string{"../proto"}},
+	}
+	files, err := compiler.Compile(context.Background(), "sqliterg.proto")
+	if err != nil {
+		return nil, err
+	}
+	return files[0], nil
+})
+
+func grpcMsg(t *testing.T, name, js string) *dynamicpb.Message {
+	fd, err := grpcProto()
+	require.NoError(t, err)
+	desc := fd.Messages().ByName(protoreflect.Name(name))
+	require.NotNil(t, desc)
+	msg := dynamicpb.NewMessage(desc)
+	if js != "" {
+		require.NoError(t, protojson.Unmarshal([]byte(js), msg))
+	}
+	return msg
+}
+
+func grpcConn(t *testing.T) *grpc.ClientConn {
+	conn, err := grpc.NewClient("localhost:15051", grpc.WithTransportCredentials(insecure.NewCredentials()))
+	require.NoError(t, err)
+	t.Cleanup(func() { conn.Close() })
+	return conn
+}
+
+// Calls Execute (or another unary method); md are the key/value pairs of the metadata
+func grpcExecute(t *testing.T, method, req string, md ...string) (*dynamicpb.Message, *status.Status, metadata.MD) {
+	ctx := metadata.AppendToOutgoingContext(context.Background(), md...)
+	resp := grpcMsg(t, "Response", "")
+	var trailer metadata.MD
+	err := grpcConn(t).Invoke(ctx, "/sqliterg.Sqliterg/"+method, grpcMsg(t, "Request", req), resp, grpc.Trailer(&trailer))
+	return resp, status.Convert(err), trailer
+}
+
+// Calls Query, returning the messages of the stream
+func grpcQuery(t *testing.T, req string, md ...string) ([]*dynamicpb.Message, *status.Status) {
+	ctx := metadata.AppendToOutgoingContext(context.Background(), md...)
+	stream, err := grpcConn(t).NewStream(ctx, &grpc.StreamDesc{ServerStreams: true}, "/sqliterg.Sqliterg/Query")
+	require.NoError(t, err)
+	require.NoError(t, stream.SendMsg(grpcMsg(t, "QueryRequest", req)))
+	require.NoError(t, stream.CloseSend())
+	var ret []*dynamicpb.Message
+	for {
+		msg := grpcMsg(t, "QueryResponse", "")
+		err := stream.RecvMsg(msg)
+		if err == io.EOF {
+			return ret, status.Convert(nil)
+		} else if err != nil {
+			return ret, status.Convert(err)
+		}
+		ret = append(ret, msg)
+	}
+}
+
+func grpcField(m protoreflect.Message, name string) protoreflect.Value {
+	return m.Get(m.Descriptor().Fields().ByName(protoreflect.Name(name)))
+}
+
+func grpcList(m protoreflect.Message, name string) []protoreflect.Value {
+	list := grpcField(m, name).List()
+	var ret []protoreflect.Value
+	for i := 0; i < list.Len(); i++ {
+		ret = append(ret, list.Get(i))
+	}
+	return ret
+}
+
+func grpcStrings(m protoreflect.Message, name string) []string {
+	var ret []string
+	for _, v := range grpcList(m, name) {
+		ret = append(ret, v.String())
+	}
+	return ret
+}
+
+func grpcValue(m protoreflect.Message) interface{} {
+	fd := m.WhichOneof(m.Descriptor().Oneofs().ByName("kind"))
+	if fd == nil {
+		return nil
+	}
+	v := m.Get(fd)
+	switch fd.Name() {
+	case "integer":
+		return v.Int()
+	case "real":
+		return v.Float()
+	case "text":
+		return v.String()
+	case "blob":
+		return append([]byte{}, v.Bytes()...)
+	case "boolean":
+		return v.Bool()
+	}
+	return nil
+}
+
+// Rows, from the repeated Row messages in a field
+func grpcRows(m protoreflect.Message, name string) [][]interface{} {
+	var ret [][]interface{}
+	for _, r := range grpcList(m, name) {
+		var row []interface{}
+		for _, v := range grpcList(r.Message(), "values") {
+			row = append(row, grpcValue(v.Message()))
+		}
+		ret = append(ret, row)
+	}
+	return ret
+}
+
+func grpcBasic(user, password string) []string {
+	return []string{"authorization", "Basic " + base64.StdEncoding.EncodeToString([]byte(user+":"+password))}
+}
+
+func TestGrpcExecute(t *testing.T) {
+	cfg := db{
+		StoredStatement: []storedStatement{
+			{Id: "Q", Sql: "SELECT :x * 2 AS DOUBLED"},
+		},
+	}
+	defer setupTest(t, &cfg, false, "--db", "env/test.db", "--grpc-port", "15051")(true)
+
+	res, st, _ := grpcExecute(t, "Execute", `{"db": "test", "transaction": [
+		{"statement": "CREATE TABLE T (ID INTEGER, VAL TEXT, DATA BLOB, NUM REAL)"},
+		{
+			"statement": "INSERT INTO T VALUES (:id, :val, :data, :num)",
+			"values": {"named": {"id": {"integer": 1}, "val": {"text": "one"}, "data": {"blob": "AAEC"}, "num": {"real": 1.5}}}
+		},
+		{
+			"statement": "INSERT INTO T VALUES (?, ?, ?, ?)",
+			"valuesBatch": [
+				{"positional": [{"integer": 2}, {"text": "two"}, {"null": true}, {"boolean": true}]},
+				{"positional": [{"integer": 3}, {"null": true}, {"blob": ""}, {"real": -2}]}
+			]
+		},
+		{"query": "SELECT VAL, ID, DATA, NUM FROM T ORDER BY ID"},
+		{"query": "^Q", "values": {"named": {"x": {"integer": 21}}}},
+		{"query": "SELECT * FROM NOPE", "noFail": true}
+	]}`)
+	require.Equal(t, codes.OK, st.Code())
+
+	items := grpcList(res, "results")
+	require.Len(t, items, 6)
+	for _, it := range items[:5] {
+		require.True(t, grpcField(it.Message(), "success").Bool())
+	}
+
+	require.Equal(t, int64(1), grpcField(items[1].Message(), "rows_updated").Int())
+
+	var batch []int64
+	for _, v := range grpcList(items[2].Message(), "rows_updated_batch") {
+		batch = append(batch, v.Int())
+	}
+	require.Equal(t, []int64{1, 1}, batch)
+
+	query := items[3].Message()
+	require.Equal(t, []string{"VAL", "ID", "DATA", "NUM"}, grpcStrings(query, "columns"))
+	require.Equal(t, [][]interface{}{
+		{"one", int64(1), []byte{0, 1, 2}, 1.5},
+		{"two", int64(2), nil, 1.0},
+		{nil, int64(3), []byte{}, -2.0},
+	}, grpcRows(query, "result_set"))
+
+	require.Equal(t, [][]interface{}{{int64(42)}}, grpcRows(items[4].Message(), "result_set"))
+
+	failed := items[5].Message()
+	require.False(t, grpcField(failed, "success").Bool())
+	require.Equal(t, "no such table: NOPE", grpcField(failed, "error").String())
+}
+
+func TestGrpcExecuteErrors(t *testing.T) {
+	cfg := db{}
+	defer setupTest(t, &cfg, false, "--db", "env/test.db", "--grpc-port", "15051")(true)
+
+	_, st, trailer := grpcExecute(t, "Execute", `{"db": "test", "transaction": [
+		{"statement": "CREATE TABLE T (ID INTEGER)"},
+		{"query": "SELECT * FROM NOPE"}
+	]}`)
+	require.Equal(t, codes.Internal, st.Code())
+	require.Equal(t, []string{"1"}, trailer.Get("sqliterg-req-idx"))
+	require.Equal(t, "no such table: NOPE", st.Message())
+
+	// rolled back
+	_, st, _ = grpcExecute(t, "Execute", `{"db": "test", "transaction": [{"query": "SELECT * FROM T"}]}`)
+	require.Equal(t, codes.Internal, st.Code())
+
+	_, st, trailer = grpcExecute(t, "Execute", `{"db": "test", "transaction": [{"values": {"positional": [{"integer": 1}]}}]}`)
+	require.Equal(t, codes.InvalidArgument, st.Code())
+	require.Equal(t, []string{"0"}, trailer.Get("sqliterg-req-idx"))
+
+	_, st, trailer = grpcExecute(t, "Execute", `{"db": "test", "transaction": [
+		{"query": "SELECT 1"},
+		{"query": "SELECT :a", "values": {"named": {"a": {"integer": 1}}, "positional": [{"integer": 1}]}}
+	]}`)
+	require.Equal(t, codes.InvalidArgument, st.Code())
+	require.Equal(t, []string{"1"}, trailer.Get("sqliterg-req-idx"))
+
+	_, st, _ = grpcExecute(t, "Execute", `{"db": "test", "transaction": [{"query": "^NOPE"}]}`)
+	require.Equal(t, codes.FailedPrecondition, st.Code())
+
+	_, st, _ = grpcExecute(t, "Execute", `{"db": "nope", "transaction": [{"query": "SELECT 1"}]}`)
+	require.Equal(t, codes.NotFound, st.Code())
+
+	_, st, _ = grpcExecute(t, "Nope", "")
+	require.Equal(t, codes.Unimplemented, st.Code())
+}
+
+func TestGrpcOnlyStoredStatements(t *testing.T) {
+	cfg := db{
+		UseOnlyStoredStatements: true,
+		StoredStatement: []storedStatement{
+			{Id: "Q", Sql: "SELECT 1"},
+		},
+	}
+	defer setupTest(t, &cfg, false, "--db", "env/test.db", "--grpc-port", "15051")(true)
+
+	_, st, _ := grpcExecute(t, "Execute", `{"db": "test", "transaction": [{"query": "SELECT 1"}]}`)
+	require.Equal(t, codes.FailedPrecondition, st.Code())
+
+	_, st = grpcQuery(t, `{"db": "test", "query": "SELECT 1"}`)
+	require.Equal(t, codes.FailedPrecondition, st.Code())
+
+	msgs, st := grpcQuery(t, `{"db": "test", "query": "^Q"}`)
+	require.Equal(t, codes.OK, st.Code())
+	require.Equal(t, [][]interface{}{{int64(1)}}, grpcRows(msgs[0], "rows"))
+}
+
+func TestGrpcQueryStream(t *testing.T) {
+	cfg := db{}
+	defer setupTest(t, &cfg, false, "--db", "env/test.db", "--grpc-port", "15051")(true)
+
+	msgs, st := grpcQuery(t, `{
+		"db": "test",
+		"query": "WITH RECURSIVE C(X) AS (SELECT 1 UNION ALL SELECT X + 1 FROM C WHERE X < :max) SELECT X, X * 2 AS Y FROM C",
+		"values": {"named": {"max": {"integer": 1234}}},
+		"batchSize": 500
+	}`)
+	require.Equal(t, codes.OK, st.Code())
+	require.Len(t, msgs, 3)
+	require.Equal(t, []string{"X", "Y"}, grpcStrings(msgs[0], "columns"))
+	require.Empty(t, grpcStrings(msgs[1], "columns"))
+
+	var rows [][]interface{}
+	for _, msg := range msgs {
+		rows = append(rows, grpcRows(msg, "rows")...)
+	}
+	require.Len(t, rows, 1234)
+	require.Equal(t, []interface{}{int64(1234), int64(2468)}, rows[1233])
+
+	// no rows: only the columns
+	msgs, st = grpcQuery(t, `{"db": "test", "query": "SELECT 1 AS ONE WHERE 0"}`)
+	require.Equal(t, codes.OK, st.Code())
+	require.Len(t, msgs, 1)
+	require.Equal(t, []string{"ONE"}, grpcStrings(msgs[0], "columns"))
+
+	_, st = grpcQuery(t, `{"db": "test", "query": "SELECT * FROM NOPE"}`)
+	require.Equal(t, codes.Internal, st.Code())
+}
+
+func TestGrpcQueryDoesNotHoldTheDatabase(t *testing.T) {
+	cfg := db{}
+	defer setupTest(t, &cfg, false, "--db", "env/test.db", "--grpc-port", "15051")(true)
+
+	// a client that doesn't read the stream: the rows are kept aside, not the database;
+	// 50000 rows of 1KB fit in what can be kept aside, 100000 don't
+	for _, n := range []int{50000, 100000} {
+		ctx, cancel := context.WithCancel(context.Background())
+		stream, err := grpcConn(t).NewStream(ctx, &grpc.StreamDesc{ServerStreams: true}, "/sqliterg.Sqliterg/Query")
+		require.NoError(t, err)
+		require.NoError(t, stream.SendMsg(grpcMsg(t, "QueryRequest", fmt.Sprintf(`{
+			"db": "test",
+			"query": "WITH RECURSIVE C(X) AS (SELECT 1 UNION ALL SELECT X + 1 FROM C WHERE X < %d) SELECT X, randomblob(1000) FROM C",
+			"batchSize": 10
+		}`, n))))
+		require.NoError(t, stream.CloseSend())
+		time.Sleep(500 * time.Millisecond)
+
+		start := time.Now()
+		code, _, _ := call(t, "http://localhost:12321/test", request{Transaction: []requestItem{{Query: "SELECT 1"}}})
+		require.Equal(t, http.StatusOK, code)
+		require.Less(t, time.Since(start), time.Second)
+
+		var rows int
+		for {
+			msg := grpcMsg(t, "QueryResponse", "")
+			err = stream.RecvMsg(msg)
+			if err != nil {
+				break
+			}
+			rows += len(grpcRows(msg, "rows"))
+		}
+		if n == 50000 {
+			require.Equal(t, io.EOF, err)
+			require.Equal(t, n, rows)
+		} else {
+			require.Equal(t, codes.ResourceExhausted, status.Code(err))
+			require.Less(t, rows, n)
+		}
+		cancel()
+	}
+}
+
+func TestGrpcAuth(t *testing.T) {
+	cfg := db{
+		Auth: &authr{
+			Mode: "HTTP_BASIC",
+			ByCredentials: []credentialsCfg{
+				{
+					User:     "myUser",
+					Password: "ciao",
+				},
+			},
+		},
+	}
+	defer setupTest(t, &cfg, false, "--db", "env/test.db", "--grpc-port", "15051")(true)
+
+	req := `{"db": "test", "transaction": [{"query": "SELECT 1"}]}`
+	_, st, _ := grpcExecute(t, "Execute", req, grpcBasic("myUser", "ciao")...)
+	require.Equal(t, codes.OK, st.Code())
+	_, st, _ = grpcExecute(t, "Execute", req, grpcBasic("myUser", "wrong")...)
+	require.Equal(t, codes.Unauthenticated, st.Code())
+	_, st, _ = grpcExecute(t, "Execute", req)
+	require.Equal(t, codes.Unauthenticated, st.Code())
+
+	query := `{"db": "test", "query": "SELECT 1"}`
+	_, st = grpcQuery(t, query, grpcBasic("myUser", "ciao")...)
+	require.Equal(t, codes.OK, st.Code())
+	_, st = grpcQuery(t, query)
+	require.Equal(t, codes.Unauthenticated, st.Code())
+}
+
+func TestGrpcAuthInline(t *testing.T) {
+	cfg := db{
+		Auth: &authr{
+			Mode: "INLINE",
+			ByCredentials: []credentialsCfg{
+				{
+					User:     "myUser",
+					Password: "ciao",
+				},
+			},
+		},
+	}
+	defer setupTest(t, &cfg, false, "--db", "env/test.db", "--grpc-port", "15051")(true)
+
+	_, st, _ := grpcExecute(t, "Execute", `{"db": "test", "credentials": {"user": "myUser", "password": "ciao"}, "transaction": [{"query": "SELECT 1"}]}`)
+	require.Equal(t, codes.OK, st.Code())
+
+	_, st, _ = grpcExecute(t, "Execute", `{"db": "test", "credentials": {"user": "myUser", "password": "wrong"}, "transaction": [{"query": "SELECT 1"}]}`)
+	require.Equal(t, codes.Unauthenticated, st.Code())
+}
+
+// Calls a URL that answers with JSON; the body, if any, is JSON. Optionally, user and
+// password.
+func jsonCall(t *testing.T, method, url, body string, auth ...string) (int, interface{}) {
+	var reader io.Reader
+	if body != "" {
+		reader = strings.NewReader(body)
+	}
+	req, err := http.NewRequest(method, url, reader)
+	require.NoError(t, err)
+	if body != "" {
+		req.Header.Set("Content-Type", "application/json")
+	}
+	if len(auth) == 2 {
+		req.SetBasicAuth(auth[0], auth[1])
+	}
+	resp, err := http.DefaultClient.Do(req)
+	require.NoError(t, err)
+	defer resp.Body.Close()
+	var ret interface{}
+	require.NoError(t, json.NewDecoder(resp.Body).Decode(&ret))
+	return resp.StatusCode, ret
+}
+
+// Calls a REST endpoint; the body, if any, is JSON. Optionally, user and password.
+func restCall(t *testing.T, method, path, body string, auth ...string) (int, interface{}) {
+	return jsonCall(t, method, "http://localhost:12321/test/tables/"+path, body, auth...)
+}
+
+func TestRest(t *testing.T) {
+	cfg := db{
+		Rest: &rest{
+			Tables: []restTable{
+				{Name: "T"},
+			},
+		},
+	}
+	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
+
+	code, _, _ := call(t, "http://localhost:12321/test", request{
+		Transaction: []requestItem{
+			{Statement: "CREATE TABLE T (ID INTEGER PRIMARY KEY, NAME TEXT NOT NULL, AGE INT)"},
+		},
+	})
+	require.Equal(t, http.StatusOK, code)
+
+	code, res := restCall(t, "POST", "T", `[{"name": "ann", "age": 30}, {"name": "bob", "age": 40}, {"name": "carl"}]`)
+	require.Equal(t, http.StatusCreated, code)
+	require.Equal(t, []interface{}{
+		map[string]interface{}{"ID": 1.0, "NAME": "ann", "AGE": 30.0},
+		map[string]interface{}{"ID": 2.0, "NAME": "bob", "AGE": 40.0},
+		map[string]interface{}{"ID": 3.0, "NAME": "carl", "AGE": nil},
+	}, res)
+
+	code, res = restCall(t, "GET", "T?order=age.desc.nullslast&limit=2&select=name", "")
+	require.Equal(t, http.StatusOK, code)
+	require.Equal(t, []interface{}{
+		map[string]interface{}{"NAME": "bob"},
+		map[string]interface{}{"NAME": "ann"},
+	}, res)
+
+	code, res = restCall(t, "GET", "T?age=gte.35", "")
+	require.Equal(t, http.StatusOK, code)
+	require.Len(t, res, 1)
+
+	code, res = restCall(t, "GET", "T?name=like.*a*&age=not.is.null", "")
+	require.Equal(t, http.StatusOK, code)
+	require.Len(t, res, 1)
+
+	code, res = restCall(t, "GET", "T?id=in.(1,3)&order=id&offset=1", "")
+	require.Equal(t, http.StatusOK, code)
+	require.Equal(t, "carl", res.([]interface{})[0].(map[string]interface{})["NAME"])
+
+	// values are bound, never interpolated
+	code, res = restCall(t, "GET", "T?name=eq.x'%20OR%20'1'='1", "")
+	require.Equal(t, http.StatusOK, code)
+	require.Len(t, res, 0)
+
+	code, res = restCall(t, "GET", "T/2", "")
+	require.Equal(t, http.StatusOK, code)
+	require.Equal(t, "bob", res.(map[string]interface{})["NAME"])
+
+	code, _ = restCall(t, "GET", "T/99", "")
+	require.Equal(t, http.StatusNotFound, code)
+
+	code, res = restCall(t, "PATCH", "T/2", `{"age": 41}`)
+	require.Equal(t, http.StatusOK, code)
+	require.Equal(t, 41.0, res.(map[string]interface{})["AGE"])
+
+	code, res = restCall(t, "PATCH", "T?age=lt.35", `{"name": "young"}`)
+	require.Equal(t, http.StatusOK, code)
+	require.Len(t, res, 1)
+
+	// no filters, no updates to the whole table
+	code, _ = restCall(t, "PATCH", "T", `{"name": "all"}`)
+	require.Equal(t, http.StatusBadRequest, code)
+
+	code, _ = restCall(t, "PATCH", "T/2", `{"name": null}`)
+	require.Equal(t, http.StatusConflict, code)
+
+	code, _ = restCall(t, "POST", "T", `{"id": 1, "name": "dup"}`)
+	require.Equal(t, http.StatusConflict, code)
+
+	code, res = restCall(t, "DELETE", "T/3", "")
+	require.Equal(t, http.StatusOK, code)
+	require.Equal(t, "carl", res.(map[string]interface{})["NAME"])
+
+	code, _ = restCall(t, "DELETE", "T", "")
+	require.Equal(t, http.StatusBadRequest, code)
+
+	code, res = restCall(t, "GET", "t", "")
+	require.Equal(t, http.StatusOK, code)
+	require.Len(t, res, 2)
+
+	code, _ = restCall(t, "GET", "T?nope=eq.1", "")
+	require.Equal(t, http.StatusBadRequest, code)
+
+	code, _ = restCall(t, "GET", "T?age=zz.1", "")
+	require.Equal(t, http.StatusBadRequest, code)
+
+	code, _ = restCall(t, "POST", "T", `{"nope": 1}`)
+	require.Equal(t, http.StatusBadRequest, code)
+}
+
+func TestRestAllowList(t *testing.T) {
+	cfg := db{
+		Rest: &rest{
+			Tables: []restTable{
+				{Name: "T", Operations: []string{"read"}},
+				{Name: "C"},
+			},
+		},
+	}
+	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
+
+	code, _, _ := call(t, "http://localhost:12321/test", request{
+		Transaction: []requestItem{
+			{Statement: "CREATE TABLE T (ID INT)"},
+			{Statement: "CREATE TABLE U (ID INT)"},
+			{Statement: "CREATE TABLE C (A INT, B INT, PRIMARY KEY (A, B))"},
+			{Statement: "INSERT INTO C VALUES (1, 2)"},
+		},
+	})
+	require.Equal(t, http.StatusOK, code)
+
+	code, _ = restCall(t, "GET", "T", "")
+	require.Equal(t, http.StatusOK, code)
+
+	code, _ = restCall(t, "POST", "T", `{"id": 1}`)
+	require.Equal(t, http.StatusMethodNotAllowed, code)
+
+	code, _ = restCall(t, "GET", "U", "")
+	require.Equal(t, http.StatusNotFound, code)
+
+	// composite primary key: only by filters
+	code, _ = restCall(t, "GET", "C/1", "")
+	require.Equal(t, http.StatusBadRequest, code)
+
+	code, res := restCall(t, "GET", "C?a=eq.1&b=eq.2", "")
+	require.Equal(t, http.StatusOK, code)
+	require.Len(t, res, 1)
+}
+
+func TestRestReadOnly(t *testing.T) {
+	cfg := db{
+		ReadOnly: true,
+		Rest: &rest{
+			Tables: []restTable{
+				{Name: "sqlite_master"},
+			},
+		},
+	}
+	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
+
+	code, _ := restCall(t, "GET", "sqlite_master", "")
+	require.Equal(t, http.StatusOK, code)
+
+	code, _ = restCall(t, "DELETE", "sqlite_master?name=eq.x", "")
+	require.Equal(t, http.StatusMethodNotAllowed, code)
+}
+
+func TestRestAuth(t *testing.T) {
+	cfg := db{
+		Auth: &authr{
+			Mode: "HTTP_BASIC",
+			ByCredentials: []credentialsCfg{
+				{
+					User:     "myUser",
+					Password: "ciao",
+				},
+			},
+		},
+		Rest: &rest{
+			Tables: []restTable{
+				{Name: "sqlite_master"},
+			},
+		},
+	}
+	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
+
+	code, _ := restCall(t, "GET", "sqlite_master", "", "myUser", "ciao")
+	require.Equal(t, http.StatusOK, code)
+
+	code, _ = restCall(t, "GET", "sqlite_master", "", "myUser", "wrong")
+	require.Equal(t, http.StatusUnauthorized, code)
+
+	code, _ = restCall(t, "GET", "sqlite_master", "")
+	require.Equal(t, http.StatusUnauthorized, code)
+}
+
+func TestRestInlineAuth(t *testing.T) {
+	cfg := db{
+		Auth: &authr{
+			Mode: "INLINE",
+			ByCredentials: []credentialsCfg{
+				{
+					User:     "myUser",
+					Password: "ciao",
+				},
+			},
+		},
+		Rest: &rest{
+			Tables: []restTable{
+				{Name: "T"},
+			},
+		},
+	}
+	saveCfgToYaml(t, &cfg)
+	defer os.Remove("env/test.yaml")
+
+	cmd := exec.Command(COMMAND, "--db", "env/test.db")
+	err := cmd.Run()
+	require.Error(t, err)
+}
+
+func graphQLCall(t *testing.T, body string, auth ...string) (int, map[string]interface{}) {
+	code, ret := jsonCall(t, "POST", "http://localhost:12321/test/graphql", body, auth...)
+	obj, ok := ret.(map[string]interface{})
+	require.True(t, ok)
+	return code, obj
+}
+
+func TestGraphQL(t *testing.T) {
+	cfg := db{
+		GraphQL: &graphQL{
+			Tables: []string{"CUSTOMERS", "ORDERS", "T"},
+		},
+	}
+	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
+
+	code, _, _ := call(t, "http://localhost:12321/test", request{
+		Transaction: []requestItem{
+			{Statement: "CREATE TABLE CUSTOMERS (ID INTEGER PRIMARY KEY, NAME TEXT NOT NULL)"},
+			{Statement: "CREATE TABLE ORDERS (ID INTEGER PRIMARY KEY, CUSTOMER INTEGER REFERENCES CUSTOMERS, AMOUNT REAL CHECK (AMOUNT >= 0))"},
+		},
+	})
+	require.Equal(t, http.StatusOK, code)
+
+	code, res := graphQLCall(t, `{"query": "mutation { insert_CUSTOMERS(objects: [{NAME: \"ann\"}, {NAME: \"bob\"}]) { ID NAME } insert_ORDERS(objects: [{CUSTOMER: 1, AMOUNT: 10}, {CUSTOMER: 1, AMOUNT: 20}, {CUSTOMER: 2, AMOUNT: 5}]) { ID } }"}`)
+	require.Equal(t, http.StatusOK, code)
+	require.Nil(t, res["errors"])
+	require.Equal(t, []interface{}{
+		map[string]interface{}{"ID": 1.0, "NAME": "ann"},
+		map[string]interface{}{"ID": 2.0, "NAME": "bob"},
+	}, res["data"].(map[string]interface{})["insert_CUSTOMERS"])
+
+	// filters, ordering, pagination and the relations, in both directions
+	code, res = graphQLCall(t, `{"query": "query($min: Float) { CUSTOMERS(where: {NAME: {like: \"a%\"}}) { NAME ORDERS_list_by_CUSTOMER(where: {AMOUNT: {gte: $min}}, order_by: [{AMOUNT: DESC}], limit: 1) { AMOUNT CUSTOMERS_by_CUSTOMER { NAME } } } ORDERS_count(where: {or: [{AMOUNT: {lt: 6}}, {ID: {in: [1]}}]}) CUSTOMERS_by_pk(ID: 2) { NAME } }", "variables": {"min": 10}}`)
+	require.Equal(t, http.StatusOK, code)
+	require.Nil(t, res["errors"])
+	require.Equal(t, map[string]interface{}{
+		"CUSTOMERS": []interface{}{
+			map[string]interface{}{
+				"NAME": "ann",
+				"ORDERS_list_by_CUSTOMER": []interface{}{
+					map[string]interface{}{"AMOUNT": 20.0, "CUSTOMERS_by_CUSTOMER": map[string]interface{}{"NAME": "ann"}},
+				},
+			},
+		},
+		"ORDERS_count":    2.0,
+		"CUSTOMERS_by_pk": map[string]interface{}{"NAME": "bob"},
+	}, res["data"])
+
+	code, res = graphQLCall(t, `{"query": "mutation { update_ORDERS(where: {CUSTOMER: {eq: 1}}, set: {AMOUNT: 1}) { ID } delete_ORDERS_by_pk(ID: 3) { AMOUNT } }"}`)
+	require.Equal(t, http.StatusOK, code)
+	require.Nil(t, res["errors"])
+	require.Len(t, res["data"].(map[string]interface{})["update_ORDERS"], 2)
+	require.Equal(t, 5.0, res["data"].(map[string]interface{})["delete_ORDERS_by_pk"].(map[string]interface{})["AMOUNT"])
+
+	// an error rolls back the whole request
+	code, res = graphQLCall(t, `{"query": "mutation { insert_CUSTOMERS(objects: [{NAME: \"carl\"}]) { ID } insert_ORDERS(objects: [{AMOUNT: -1}]) { ID } }"}`)
+	require.Equal(t, http.StatusOK, code)
+	require.NotNil(t, res["errors"])
+
+	code, res = graphQLCall(t, `{"query": "{ CUSTOMERS_count }"}`)
+	require.Equal(t, http.StatusOK, code)
+	require.Equal(t, 2.0, res["data"].(map[string]interface{})["CUSTOMERS_count"])
+
+	// the schema follows the changes to the database
+	code, _, _ = call(t, "http://localhost:12321/test", request{
+		Transaction: []requestItem{
+			{Statement: "CREATE TABLE T (ID INTEGER PRIMARY KEY)"},
+		},
+	})
+	require.Equal(t, http.StatusOK, code)
+
+	code, res = graphQLCall(t, `{"query": "{ T_count }"}`)
+	require.Equal(t, http.StatusOK, code)
+	require.Nil(t, res["errors"])
+}
+
+func TestGraphQLAllowListReadOnly(t *testing.T) {
+	cfg := db{}
+	teardown := setupTest(t, &cfg, false, "--db", "env/test.db")
+	code, _, _ := call(t, "http://localhost:12321/test", request{
+		Transaction: []requestItem{
+			{Statement: "CREATE TABLE T1 (ID INTEGER PRIMARY KEY)"},
+			{Statement: "CREATE TABLE T2 (ID INTEGER PRIMARY KEY)"},
+		},
+	})
+	require.Equal(t, http.StatusOK, code)
+	teardown(false)
+
+	cfg = db{
+		ReadOnly: true,
+		GraphQL: &graphQL{
+			Tables: []string{"T1"},
+		},
+	}
+	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
+
+	code, res := graphQLCall(t, `{"query": "{ __schema { queryType { fields { name } } mutationType { name } } }"}`)
+	require.Equal(t, http.StatusOK, code)
+	require.Nil(t, res["errors"])
+	schema := res["data"].(map[string]interface{})["__schema"].(map[string]interface{})
+	require.Nil(t, schema["mutationType"])
+	var fields []interface{}
+	for _, f := range schema["queryType"].(map[string]interface{})["fields"].([]interface{}) {
+		fields = append(fields, f.(map[string]interface{})["name"])
+	}
+	require.Equal(t, []interface{}{"T1", "T1_count", "T1_by_pk"}, fields)
+
+	code, res = graphQLCall(t, `{"query": "{ T2 { ID } }"}`)
+	require.Equal(t, http.StatusOK, code)
+	require.NotNil(t, res["errors"])
+}
+
+func TestGraphQLLimits(t *testing.T) {
+	cfg := db{
+		GraphQL: &graphQL{
+			Tables:        []string{"T"},
+			DefaultLimit:  3,
+			MaxLimit:      5,
+			MaxComplexity: 4,
+		},
+	}
+	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
+
+	code, _, _ := call(t, "http://localhost:12321/test", request{
+		Transaction: []requestItem{
+			{Statement: "CREATE TABLE T (ID INTEGER PRIMARY KEY, PARENT INTEGER REFERENCES T)"},
+			{Statement: "WITH RECURSIVE C(X) AS (SELECT 1 UNION ALL SELECT X + 1 FROM C WHERE X < 10) INSERT INTO T SELECT X, 1 FROM C"},
+		},
+	})
+	require.Equal(t, http.StatusOK, code)
+
+	// without a limit, the default one
+	code, res := graphQLCall(t, `{"query": "{ T { ID } }"}`)
+	require.Equal(t, http.StatusOK, code)
+	require.Nil(t, res["errors"])
+	require.Len(t, res["data"].(map[string]interface{})["T"], 3)
+
+	code, res = graphQLCall(t, `{"query": "{ T(limit: 5) { ID } }"}`)
+	require.Equal(t, http.StatusOK, code)
+	require.Nil(t, res["errors"])
+	require.Len(t, res["data"].(map[string]interface{})["T"], 5)
+
+	// also the nested lists
+	code, res = graphQLCall(t, `{"query": "{ T_by_pk(ID: 1) { T_list_by_PARENT { ID } } }"}`)
+	require.Equal(t, http.StatusOK, code)
+	require.Nil(t, res["errors"])
+	require.Len(t, res["data"].(map[string]interface{})["T_by_pk"].(map[string]interface{})["T_list_by_PARENT"], 3)
+
+	code, res = graphQLCall(t, `{"query": "{ T(limit: 6) { ID } }"}`)
+	require.Equal(t, http.StatusOK, code)
+	require.NotNil(t, res["errors"])
+
+	code, res = graphQLCall(t, `{"query": "{ T(limit: -1) { ID } }"}`)
+	require.Equal(t, http.StatusOK, code)
+	require.NotNil(t, res["errors"])
+
+	// too complex
+	code, res = graphQLCall(t, `{"query": "{ T { ID T_list_by_PARENT { ID T_list_by_PARENT { ID } } } }"}`)
+	require.Equal(t, http.StatusOK, code)
+	require.NotNil(t, res["errors"])
+	require.Nil(t, res["data"])
+}
+
+func TestGraphQLAuth(t *testing.T) {
+	cfg := db{
+		Auth: &authr{
+			Mode: "INLINE",
+			ByCredentials: []credentialsCfg{
+				{
+					User:     "myUser",
+					Password: "ciao",
+				},
+			},
+		},
+		GraphQL: &graphQL{
+			Tables: []string{"T1"},
+		},
+	}
+	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
+
+	code, _, _ := call(t, "http://localhost:12321/test", request{
+		Credentials: &credentials{
+			User:     "myUser",
+			Password: "ciao",
+		},
+		Transaction: []requestItem{
+			{Statement: "CREATE TABLE T1 (ID INTEGER PRIMARY KEY)"},
+		},
+	})
+	require.Equal(t, http.StatusOK, code)
+
+	code, _ = graphQLCall(t, `{"query": "{ T1_count }", "credentials": {"user": "myUser", "password": "ciao"}}`)
+	require.Equal(t, http.StatusOK, code)
+
+	code, _ = graphQLCall(t, `{"query": "{ T1_count }", "credentials": {"user": "myUser", "password": "wrong"}}`)
+	require.Equal(t, http.StatusUnauthorized, code)
+
+	code, _ = graphQLCall(t, `{"query": "{ T1_count }"}`)
+	require.Equal(t, http.StatusUnauthorized, code)
+}
+
+func TestOpenAPI(t *testing.T) {
+	token := "tok"
+	cfg := db{
+		Auth: &authr{
+			Mode: "HTTP_BASIC",
+			ByCredentials: []credentialsCfg{
+				{
+					User:     "myUser",
+					Password: "ciao",
+				},
+			},
+		},
+		StoredStatement: []storedStatement{
+			{Id: "Q1", Sql: "SELECT :id AS ID, :val AS VAL"},
+			{Id: "Q2", Sql: "SELECT ?, ?"},
+			{Id: "Q3", Sql: "SELECT * FROM MISSING"},
+		},
+		Macros: []macro{
+			{
+				Id:         "M1",
+				Statements: []string{"SELECT 1"},
+				Execution: execution{
+					WebService: &webService{
+						AuthToken: &token,
+					},
+				},
+			},
+		},
+	}
+	defer setupTest(t, &cfg, false, "--db", "env/test.db", "--openapi")(true)
+
+	resp, err := http.Get("http://localhost:12321/openapi.json")
+	require.NoError(t, err)
+	defer resp.Body.Close()
+	require.Equal(t, http.StatusOK, resp.StatusCode)
+	var spec map[string]interface{}
+	require.NoError(t, json.NewDecoder(resp.Body).Decode(&spec))
+	require.Equal(t, "3.0.3", spec["openapi"])
+
+	paths := spec["paths"].(map[string]interface{})
+	require.Contains(t, paths, "/test")
+	require.Contains(t, paths, "/test/macro/M1")
+	require.Contains(t, paths, "/test/macro/M1/runs")
+	require.NotContains(t, paths, "/test/backup")
+
+	post := paths["/test"].(map[string]interface{})["post"].(map[string]interface{})
+	require.Equal(t, []interface{}{map[string]interface{}{"httpBasic": []interface{}{}}}, post["security"])
+	macroPost := paths["/test/macro/M1"].(map[string]interface{})["post"].(map[string]interface{})
+	require.Equal(t, []interface{}{map[string]interface{}{"token": []interface{}{}}}, macroPost["security"])
+
+	// the parameters, as found by SQLite
+	schemas := spec["components"].(map[string]interface{})["schemas"].(map[string]interface{})
+	q1 := schemas["test.statement.Q1"].(map[string]interface{})["properties"].(map[string]interface{})
+	require.Equal(t, map[string]interface{}{"id": map[string]interface{}{}, "val": map[string]interface{}{}},
+		q1["values"].(map[string]interface{})["properties"])
+	q2 := schemas["test.statement.Q2"].(map[string]interface{})["properties"].(map[string]interface{})
+	require.Equal(t, 2.0, q2["values"].(map[string]interface{})["maxItems"])
+	q3 := schemas["test.statement.Q3"].(map[string]interface{})["properties"].(map[string]interface{})
+	require.Equal(t, "#/components/schemas/Values", q3["values"].(map[string]interface{})["$ref"])
+}
+
+func TestOpenAPIDisabled(t *testing.T) {
+	defer setupTest(t, nil, false, "--db", "env/test.db")(true)
+
+	resp, err := http.Get("http://localhost:12321/openapi.json")
+	require.NoError(t, err)
+	defer resp.Body.Close()
+	require.Equal(t, http.StatusNotFound, resp.StatusCode)
+}
+
+func storedCall(t *testing.T, method, path, body string, auth ...string) (int, interface{}) {
+	return jsonCall(t, method, "http://localhost:12321/test/q/"+path, body, auth...)
+}
+
+func TestStoredStatementsHttp(t *testing.T) {
+	cfg := db{
+		StoredStatement: []storedStatement{
+			{Id: "CREATE", Sql: "CREATE TABLE T (ID INTEGER PRIMARY KEY, NAME TEXT, OK BOOLEAN)"},
+			{
+				Id:  "Q",
+				Sql: "SELECT * FROM T WHERE ID >= :min AND (:name IS NULL OR NAME = :name) ORDER BY ID",
+				Http: &storedStatementHttp{
+					Params: []httpParam{
+						{Name: "min", Type: "INTEGER", Required: true},
+						{Name: "name"},
+					},
+				},
+			},
+			{
+				Id:  "INS",
+				Sql: "INSERT INTO T (ID, NAME, OK) VALUES (:id, :name, :ok)",
+				Http: &storedStatementHttp{
+					Method: "POST",
+					Params: []httpParam{
+						{Name: "id", Type: "INTEGER"},
+						{Name: "name"},
+						{Name: "ok", Type: "BOOLEAN"},
+					},
+				},
+			},
+		},
+	}
+	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
+
+	code, _, _ := call(t, "http://localhost:12321/test", request{
+		Transaction: []requestItem{
+			{Statement: "^CREATE"},
+		},
+	})
+	require.Equal(t, http.StatusOK, code)
+
+	// parameters in the query string and in the body
+	code, res := storedCall(t, "POST", "INS?id=1&name=a&ok=true", "")
+	require.Equal(t, http.StatusOK, code)
+	require.Equal(t, map[string]interface{}{"rowsUpdated": 1.0}, res)
+	code, res = storedCall(t, "POST", "INS?id=2", `{"name": "b", "ok": false}`)
+	require.Equal(t, http.StatusOK, code)
+	require.Equal(t, map[string]interface{}{"rowsUpdated": 1.0}, res)
+
+	code, res = storedCall(t, "GET", "Q?min=1", "")
+	require.Equal(t, http.StatusOK, code)
+	require.Equal(t, []interface{}{
+		map[string]interface{}{"ID": 1.0, "NAME": "a", "OK": 1.0},
+		map[string]interface{}{"ID": 2.0, "NAME": "b", "OK": 0.0},
+	}, res)
+	code, res = storedCall(t, "GET", "Q?min=1&name=b", "")
+	require.Equal(t, http.StatusOK, code)
+	require.Len(t, res, 1)
+
+	// errors
+	code, _ = storedCall(t, "GET", "Q", "")
+	require.Equal(t, http.StatusBadRequest, code)
+	code, _ = storedCall(t, "GET", "Q?min=x", "")
+	require.Equal(t, http.StatusBadRequest, code)
+	code, _ = storedCall(t, "GET", "Q?min=1&foo=1", "")
+	require.Equal(t, http.StatusBadRequest, code)
+	code, _ = storedCall(t, "POST", "INS", `{"id": "x"}`)
+	require.Equal(t, http.StatusBadRequest, code)
+	code, _ = storedCall(t, "POST", "INS", `[1]`)
+	require.Equal(t, http.StatusBadRequest, code)
+	code, _ = storedCall(t, "POST", "Q?min=1", "")
+	require.Equal(t, http.StatusMethodNotAllowed, code)
+	code, _ = storedCall(t, "GET", "CREATE", "")
+	require.Equal(t, http.StatusNotFound, code)
+	code, _ = storedCall(t, "GET", "MISSING", "")
+	require.Equal(t, http.StatusNotFound, code)
+	code, _ = storedCall(t, "POST", "INS?id=1", "")
+	require.Equal(t, http.StatusInternalServerError, code)
+}
+
+func TestStoredStatementsHttpAuth(t *testing.T) {
+	cfg := db{
+		Auth: &authr{
+			Mode: "HTTP_BASIC",
+			ByCredentials: []credentialsCfg{
+				{
+					User:     "myUser",
+					Password: "ciao",
+				},
+			},
+		},
+		StoredStatement: []storedStatement{
+			{Id: "Q", Sql: "SELECT 1 AS X", Http: &storedStatementHttp{}},
+		},
+	}
+	defer setupTest(t, &cfg, false, "--db", "env/test.db")(true)
+
+	code, res := storedCall(t, "GET", "Q", "", "myUser", "ciao")
+	require.Equal(t, http.StatusOK, code)
+	require.Equal(t, []interface{}{map[string]interface{}{"X": 1.0}}, res)
+
+	code, _ = storedCall(t, "GET", "Q", "", "myUser", "wrong")
+	require.Equal(t, http.StatusUnauthorized, code)
+
+	code, _ = storedCall(t, "GET", "Q", "")
+	require.Equal(t, http.StatusUnauthorized, code)
+}
+
+func TestStoredStatementsHttpInlineAuth(t *testing.T) {
+	cfg := db{
+		Auth: &authr{
+			Mode: "INLINE",
+			ByCredentials: []credentialsCfg{
+				{
+					User:     "myUser",
+					Password: "ciao",
+				},
+			},
+		},
+		StoredStatement: []storedStatement{
+			{Id: "Q", Sql: "SELECT 1", Http: &storedStatementHttp{}},
+		},
+	}
+	saveCfgToYaml(t, &cfg)
+	defer os.Remove("env/test.yaml")
+
+	cmd := exec.Command(COMMAND, "--db", "env/test.db")
+	err := cmd.Run()
+	require.Error(t, err)
+}
@@ -32,9 +32,21 @@ type authr struct {
 	ByCredentials   []credentialsCfg `yaml:"byCredentials,omitempty"`
 }
 
+type httpParam struct {
+	Name     string `yaml:"name,omitempty"`
+	Type     string `yaml:"type,omitempty"`
+	Required bool   `yaml:"required,omitempty"`
+}
+
+type storedStatementHttp struct {
+	Method string      `yaml:"method,omitempty"`
+	Params []httpParam `yaml:"params,omitempty"`
+}
+
 type storedStatement struct {
-	Id  string `yaml:"id,omitempty"`
-	Sql string `yaml:"sql,omitempty"`
+	Id   string               `yaml:"id,omitempty"`
+	Sql  string               `yaml:"sql,omitempty"`
+	Http *storedStatementHttp `yaml:"http,omitempty"`
 }
 
 type webService struct {
@@ -46,7 +58,11 @@ type webService struct {
 type execution struct {
 	OnCreate   *bool       `yaml:"onCreate,omitempty"`
 	OnStartup  *bool       `yaml:"onStartup,omitempty"`
+	OnShutdown *bool       `yaml:"onShutdown,omitempty"`
 	Period     *uint       `yaml:"period,omitempty"`
+	Cron       *string     `yaml:"cron,omitempty"`
+	TimeZone   *string     `yaml:"timeZone,omitempty"`
+	MissedRuns *string     `yaml:"missedRuns,omitempty"`
 	WebService *webService `yaml:"webService,omitempty"`
 }
 
@@ -57,10 +73,90 @@ type macro struct {
 	Execution          execution `yaml:"execution,omitempty"`
 }
 
+type encryption struct {
+	KeyFile string `yaml:"keyFile"`
+}
+
+type retention struct {
+	Hourly  uint `yaml:"hourly,omitempty"`
+	Daily   uint `yaml:"daily,omitempty"`
+	Weekly  uint `yaml:"weekly,omitempty"`
+	Monthly uint `yaml:"monthly,omitempty"`
+}
+
+type target struct {
+	Type     string `yaml:"type,omitempty"`
+	Endpoint string `yaml:"endpoint,omitempty"`
+	Bucket   string `yaml:"bucket,omitempty"`
+	Prefix   string `yaml:"prefix,omitempty"`
+	Region   string `yaml:"region,omitempty"`
+}
+
 type backup struct {
-	BackupDir string    `yaml:"backupDir,omitempty"`
-	NumFiles  uint      `yaml:"numFiles,omitempty"`
-	Execution execution `yaml:"execution,omitempty"`
+	BackupDir    string      `yaml:"backupDir,omitempty"`
+	Target       *target     `yaml:"target,omitempty"`
+	NumFiles     uint        `yaml:"numFiles,omitempty"`
+	Retention    *retention  `yaml:"retention,omitempty"`
+	FileTemplate string      `yaml:"fileTemplate,omitempty"`
+	Compression  string      `yaml:"compression,omitempty"`
+	Encryption   *encryption `yaml:"encryption,omitempty"`
+	PagesPerStep uint        `yaml:"pagesPerStep,omitempty"`
+	PreBackup    []string    `yaml:"preBackup,omitempty"`
+	PostBackup   []string    `yaml:"postBackup,omitempty"`
+	Execution    execution   `yaml:"execution,omitempty"`
+}
+
+type walArchive struct {
+	Dir            string `yaml:"dir,omitempty"`
+	Interval       uint   `yaml:"interval,omitempty"`
+	SnapshotPeriod uint   `yaml:"snapshotPeriod,omitempty"`
+	NumGenerations uint   `yaml:"numGenerations,omitempty"`
+}
+
+type audit struct {
+	File         string   `yaml:"file,omitempty"`
+	Db           string   `yaml:"db,omitempty"`
+	MaxSizeMb    uint     `yaml:"maxSizeMb,omitempty"`
+	NumFiles     *uint    `yaml:"numFiles,omitempty"`
+	RedactParams []string `yaml:"redactParams,omitempty"`
+	RedactAll    bool     `yaml:"redactAll,omitempty"`
+}
+
+type changeFeed struct {
+	Tables      []string `yaml:"tables,omitempty"`
+	IncludeRows bool     `yaml:"includeRows,omitempty"`
+}
+
+type webhookRetry struct {
+	MaxAttempts  *uint `yaml:"maxAttempts,omitempty"`
+	InitialDelay uint  `yaml:"initialDelay,omitempty"`
+	MaxDelay     uint  `yaml:"maxDelay,omitempty"`
+}
+
+type webhook struct {
+	URL         string        `yaml:"url"`
+	Tables      []string      `yaml:"tables,omitempty"`
+	Operations  []string      `yaml:"operations,omitempty"`
+	Secret      string        `yaml:"secret,omitempty"`
+	IncludeRows bool          `yaml:"includeRows,omitempty"`
+	BatchSize   uint          `yaml:"batchSize,omitempty"`
+	Retry       *webhookRetry `yaml:"retry,omitempty"`
+}
+
+type restTable struct {
+	Name       string   `yaml:"name"`
+	Operations []string `yaml:"operations,omitempty"`
+}
+
+type rest struct {
+	Tables []restTable `yaml:"tables"`
+}
+
+type graphQL struct {
+	Tables        []string `yaml:"tables"`
+	DefaultLimit  int      `yaml:"defaultLimit,omitempty"`
+	MaxLimit      int      `yaml:"maxLimit,omitempty"`
+	MaxComplexity int      `yaml:"maxComplexity,omitempty"`
 }
 
 type db struct {
@@ -72,6 +168,14 @@ type db struct {
 	StoredStatement         []storedStatement `yaml:"storedStatements,omitempty"`
 	Macros                  []macro           `yaml:"macros,omitempty"`
 	Backup                  backup            `yaml:"backup,omitempty"`
+	WalArchive              *walArchive       `yaml:"walArchive,omitempty"`
+	SlowQueryMs             *uint             `yaml:"slowQueryMs,omitempty"`
+	Audit                   *audit            `yaml:"audit,omitempty"`
+	ChangeFeed              *changeFeed       `yaml:"changeFeed,omitempty"`
+	Webhooks                []webhook         `yaml:"webhooks,omitempty"`
+	Rest                    *rest             `yaml:"rest,omitempty"`
+	GraphQL                 *graphQL          `yaml:"graphql,omitempty"`
+	RunHistorySize          *uint             `yaml:"runHistorySize,omitempty"`
 }
 
 // These are for parsing the request (from JSON)
@@ -107,3 +211,39 @@ type responseItem struct {
 type response struct {
 	Results []responseItem `json:"results"`
 }
+
+// These are for the WebSocket endpoint
+
+type wsRequest struct {
+	Id          interface{}   `json:"id,omitempty"`
+	Credentials *credentials  `json:"credentials,omitempty"`
+	Transaction []requestItem `json:"transaction"`
+}
+
+type wsResponse struct {
+	Id         interface{}    `json:"id"`
+	StatusCode int            `json:"statusCode"`
+	Results    []responseItem `json:"results"`
+	ReqIdx     int            `json:"reqIdx"`
+	Message    string         `json:"message"`
+}
+
+// This is for the change feed
+
+type changeEvent struct {
+	Table string                 `json:"table"`
+	Op    string                 `json:"op"`
+	Rowid int64                  `json:"rowid"`
+	Row   map[string]interface{} `json:"row"`
+}
+
+// This is for the webhooks
+
+type webhookEvent struct {
+	Id    int64                  `json:"id"`
+	Ts    string                 `json:"ts"`
+	Table string                 `json:"table"`
+	Op    string                 `json:"op"`
+	Rowid int64                  `json:"rowid"`
+	Row   map[string]interface{} `json:"row"`
+}